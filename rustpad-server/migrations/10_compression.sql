@@ -0,0 +1,4 @@
+ALTER TABLE document ADD COLUMN compression TEXT;
+ALTER TABLE document ADD COLUMN compressed_text BLOB;
+ALTER TABLE quarantined_document ADD COLUMN compression TEXT;
+ALTER TABLE quarantined_document ADD COLUMN compressed_text BLOB
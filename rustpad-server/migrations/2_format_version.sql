@@ -0,0 +1 @@
+ALTER TABLE document ADD COLUMN format_version INTEGER NOT NULL DEFAULT 0
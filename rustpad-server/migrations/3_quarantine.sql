@@ -0,0 +1,8 @@
+CREATE TABLE quarantined_document(
+    id TEXT NOT NULL,
+    text,
+    language,
+    format_version,
+    reason TEXT NOT NULL,
+    quarantined_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP
+)
@@ -0,0 +1,2 @@
+ALTER TABLE document ADD COLUMN password_hash TEXT;
+ALTER TABLE quarantined_document ADD COLUMN password_hash TEXT
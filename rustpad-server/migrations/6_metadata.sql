@@ -0,0 +1,2 @@
+ALTER TABLE document ADD COLUMN metadata TEXT;
+ALTER TABLE quarantined_document ADD COLUMN metadata TEXT
@@ -0,0 +1,2 @@
+ALTER TABLE document ADD COLUMN share_tokens TEXT;
+ALTER TABLE quarantined_document ADD COLUMN share_tokens TEXT
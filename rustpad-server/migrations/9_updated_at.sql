@@ -0,0 +1 @@
+ALTER TABLE document ADD COLUMN updated_at BIGINT
@@ -0,0 +1,6 @@
+CREATE TABLE document(
+    id TEXT PRIMARY KEY,
+    text TEXT NOT NULL,
+    language TEXT,
+    format_version BIGINT NOT NULL DEFAULT 0
+)
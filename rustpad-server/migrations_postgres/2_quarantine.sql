@@ -0,0 +1,8 @@
+CREATE TABLE quarantined_document(
+    id TEXT NOT NULL,
+    text TEXT,
+    language TEXT,
+    format_version BIGINT,
+    reason TEXT NOT NULL,
+    quarantined_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP
+)
@@ -0,0 +1,2 @@
+ALTER TABLE document ADD COLUMN acl TEXT;
+ALTER TABLE quarantined_document ADD COLUMN acl TEXT
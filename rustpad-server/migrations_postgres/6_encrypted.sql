@@ -0,0 +1,2 @@
+ALTER TABLE document ADD COLUMN encrypted BOOLEAN NOT NULL DEFAULT FALSE;
+ALTER TABLE quarantined_document ADD COLUMN encrypted BOOLEAN NOT NULL DEFAULT FALSE
@@ -0,0 +1,60 @@
+//! Per-document access control, with separate lists of readers and writers.
+
+use serde::{Deserialize, Serialize};
+
+use crate::auth::normalize_username;
+
+/// Who may view and who may edit a document.
+///
+/// Anyone who may write may also read. Anonymous connections only pass a
+/// list that is unrestricted.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DocumentAcl {
+    /// Users who may view the document, or `None` for anyone.
+    #[serde(default)]
+    pub read: Option<Vec<String>>,
+    /// Users who may edit the document, or `None` for everyone who may read.
+    #[serde(default)]
+    pub write: Option<Vec<String>>,
+}
+
+/// What a connection is allowed to do with a document.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    /// View and edit the document.
+    Write,
+    /// View the document without editing it.
+    Read,
+    /// Not even view the document.
+    Denied,
+}
+
+impl DocumentAcl {
+    /// Returns whether anyone may read and write, as for a new document.
+    pub fn is_open(&self) -> bool {
+        self.read.is_none() && self.write.is_none()
+    }
+
+    /// Decide what a user, or an anonymous connection if `None`, may do.
+    ///
+    /// Usernames are matched ignoring case, like logins.
+    pub fn access(&self, username: Option<&str>) -> Access {
+        let username = username.map(normalize_username);
+        let listed = |list: &Vec<String>| {
+            username.as_ref().map_or(false, |username| {
+                list.iter()
+                    .any(|user| normalize_username(user) == *username)
+            })
+        };
+        let read = self.read.as_ref().map_or(true, listed);
+        let write = match &self.write {
+            Some(list) => listed(list),
+            None => read,
+        };
+        match (read, write) {
+            (_, true) => Access::Write,
+            (true, false) => Access::Read,
+            (false, false) => Access::Denied,
+        }
+    }
+}
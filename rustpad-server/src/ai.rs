@@ -1,9 +1,43 @@
 //! AI integration with OpenRouter API for document assistance.
 
 use anyhow::{Context, Result};
+use futures::future::{AbortHandle, Abortable};
+use futures::stream::{BoxStream, StreamExt};
 use serde::{Deserialize, Serialize};
 use log::info;
-use std::sync::{Arc, RwLock};
+use rand::Rng;
+use regex::Regex;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::future::Future;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use crate::ai_log::{LoggedRequest, RequestLog, RequestLogConfig};
+use crate::error::ApiError;
+
+/// Delay before the first retry of a request to OpenRouter, doubled for each
+/// retry after it
+const RETRY_BASE_DELAY: Duration = Duration::from_millis(500);
+
+/// Longest wait before a retry; a `Retry-After` beyond this fails instead
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+/// Rough number of characters per token, for fitting text into a context window
+const CHARS_PER_TOKEN: usize = 4;
+
+/// Context window assumed for models missing from the known model lists, in tokens
+const DEFAULT_CONTEXT_LENGTH: u32 = 8192;
+
+/// Tokens left for the reply when a request doesn't cap them
+const DEFAULT_REPLY_TOKENS: u32 = 1024;
+
+/// Stands in for the text left out of a truncated document
+const TRUNCATION_MARKER: &str = "\n[... truncated ...]\n";
+
+/// Bytes of streamed content held back from the client in case a redaction
+/// pattern matches across later deltas
+const REDACTION_WINDOW: usize = 256;
 
 /// Configuration for AI features
 #[derive(Debug, Clone)]
@@ -14,6 +48,208 @@ pub struct AiConfig {
     pub api_key: String,
     /// Optional custom base URL for OpenRouter
     pub base_url: String,
+    /// Default cap on chat requests per user
+    pub rate_limit: RateLimit,
+    /// Patterns removed from the content of chat responses
+    pub redactions: Vec<Regex>,
+    /// Text that replaces each redacted match
+    pub redaction_placeholder: String,
+    /// How long a fetched list of models is served before fetching it again
+    pub models_cache_ttl: Duration,
+    /// Attempts made at each chat request, including the first, when
+    /// OpenRouter fails transiently
+    pub max_attempts: u32,
+    /// Adjustments to chat requests for models with quirks, the first
+    /// matching one applying
+    pub request_transforms: Vec<RequestTransform>,
+    /// Where chat completions are logged, if anywhere
+    pub request_log: Option<RequestLogConfig>,
+    /// System prompts that chat requests may name, by name
+    pub system_prompts: BTreeMap<String, String>,
+    /// Which part of a document is kept when it is too long to send whole as
+    /// chat context
+    pub context_truncation: ContextTruncation,
+}
+
+/// Which part of a document's text is kept when it doesn't fit a model's
+/// context window
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ContextTruncation {
+    /// Keep the start of the text
+    Head,
+    /// Keep the end of the text
+    Tail,
+    /// Keep the start and end, leaving out the middle
+    #[default]
+    Middle,
+}
+
+impl ContextTruncation {
+    /// Cut text down to at most `max_chars` characters, marking where text
+    /// was left out
+    pub fn truncate(self, text: &str, max_chars: usize) -> String {
+        let total = text.chars().count();
+        if total <= max_chars {
+            return text.to_string();
+        }
+        // Byte offset of the character at index `n`
+        let offset = |n: usize| text.char_indices().nth(n).map_or(text.len(), |(i, _)| i);
+        match self {
+            Self::Head => format!("{}{}", &text[..offset(max_chars)], TRUNCATION_MARKER),
+            Self::Tail => format!(
+                "{}{}",
+                TRUNCATION_MARKER,
+                &text[offset(total - max_chars)..]
+            ),
+            Self::Middle => {
+                let head = max_chars / 2;
+                let tail = max_chars - head;
+                format!(
+                    "{}{}{}",
+                    &text[..offset(head)],
+                    TRUNCATION_MARKER,
+                    &text[offset(total - tail)..]
+                )
+            }
+        }
+    }
+}
+
+impl FromStr for ContextTruncation {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "head" => Ok(Self::Head),
+            "tail" => Ok(Self::Tail),
+            "middle" => Ok(Self::Middle),
+            _ => anyhow::bail!(
+                "unknown truncation strategy {:?}, expected head, tail or middle",
+                s
+            ),
+        }
+    }
+}
+
+/// A field of a chat request that a model may not support
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RequestField {
+    MaxTokens,
+    Temperature,
+}
+
+/// Changes made to chat requests sent to some models, to fit their quirks
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RequestTransform {
+    /// Model id this applies to, or a prefix of ids when ending in `*`
+    pub model: String,
+    /// Fields removed from the request
+    #[serde(default)]
+    pub drop: Vec<RequestField>,
+    /// Token limit used when the request doesn't set one
+    #[serde(default)]
+    pub max_tokens: Option<u32>,
+    /// Lowest temperature the model accepts
+    #[serde(default)]
+    pub min_temperature: Option<f32>,
+    /// Highest temperature the model accepts
+    #[serde(default)]
+    pub max_temperature: Option<f32>,
+    /// Message roles renamed to ones the model supports
+    #[serde(default)]
+    pub roles: HashMap<String, String>,
+    /// Join consecutive messages from the same role into one
+    #[serde(default)]
+    pub merge_consecutive: bool,
+}
+
+impl RequestTransform {
+    /// Whether this applies to a model
+    fn matches(&self, model: &str) -> bool {
+        match self.model.strip_suffix('*') {
+            Some(prefix) => model.starts_with(prefix),
+            None => model == self.model,
+        }
+    }
+
+    /// Adjust a request to fit the model
+    fn apply(&self, request: &mut ChatCompletionRequest) {
+        for message in &mut request.messages {
+            if let Some(role) = self.roles.get(&message.role) {
+                message.role = role.clone();
+            }
+        }
+        if self.merge_consecutive {
+            let mut merged: Vec<ChatMessage> = Vec::with_capacity(request.messages.len());
+            for message in request.messages.drain(..) {
+                match merged.last_mut() {
+                    Some(last) if last.role == message.role => {
+                        last.content.push_str("\n\n");
+                        last.content.push_str(&message.content);
+                    }
+                    _ => merged.push(message),
+                }
+            }
+            request.messages = merged;
+        }
+        if request.max_tokens.is_none() {
+            request.max_tokens = self.max_tokens;
+        }
+        if let Some(temperature) = &mut request.temperature {
+            if let Some(min) = self.min_temperature {
+                *temperature = temperature.max(min);
+            }
+            if let Some(max) = self.max_temperature {
+                *temperature = temperature.min(max);
+            }
+        }
+        for field in &self.drop {
+            match field {
+                RequestField::MaxTokens => request.max_tokens = None,
+                RequestField::Temperature => request.temperature = None,
+            }
+        }
+    }
+}
+
+/// Caps on how many chat requests a user may make, over sliding windows
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RateLimit {
+    /// Requests allowed in any one minute
+    #[serde(default)]
+    pub per_minute: Option<u32>,
+    /// Requests allowed in any one hour
+    #[serde(default)]
+    pub per_hour: Option<u32>,
+}
+
+impl RateLimit {
+    /// Check that every limit that is set allows at least one request
+    pub fn validate(&self) -> Result<()> {
+        if self.per_minute == Some(0) || self.per_hour == Some(0) {
+            anyhow::bail!(ApiError::BadRequest(
+                "Rate limits must allow at least one request".into()
+            ));
+        }
+        Ok(())
+    }
+
+    /// Fill in any limits this one leaves unset from `defaults`
+    pub fn or(self, defaults: RateLimit) -> RateLimit {
+        RateLimit {
+            per_minute: self.per_minute.or(defaults.per_minute),
+            per_hour: self.per_hour.or(defaults.per_hour),
+        }
+    }
+}
+
+/// A request refused for exceeding a rate limit
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimited {
+    /// How long until another request would be allowed
+    pub retry_after: Duration,
 }
 
 impl Default for AiConfig {
@@ -22,10 +258,29 @@ impl Default for AiConfig {
             enabled: false,
             api_key: String::new(),
             base_url: "https://openrouter.ai/api/v1".to_string(),
+            rate_limit: RateLimit::default(),
+            redactions: Vec::new(),
+            redaction_placeholder: "[REDACTED]".to_string(),
+            models_cache_ttl: Duration::from_secs(3600),
+            max_attempts: 3,
+            request_transforms: Vec::new(),
+            request_log: None,
+            system_prompts: BTreeMap::new(),
+            context_truncation: ContextTruncation::default(),
         }
     }
 }
 
+/// Read a rate limit from the environment, refusing zero, which would turn
+/// away every request.
+fn rate_limit_var(name: &str) -> Option<u32> {
+    let limit = std::env::var(name).ok().and_then(|s| s.parse().ok())?;
+    if limit == 0 {
+        panic!("{} must be at least 1", name);
+    }
+    Some(limit)
+}
+
 impl AiConfig {
     /// Create config from environment variables
     pub fn from_env() -> Self {
@@ -44,14 +299,123 @@ impl AiConfig {
             log::warn!("AI features enabled but OPENROUTER_API_KEY not set");
         }
 
+        let rate_limit = RateLimit {
+            per_minute: rate_limit_var("AI_RATE_LIMIT_PER_MINUTE"),
+            per_hour: rate_limit_var("AI_RATE_LIMIT_PER_HOUR"),
+        };
+
+        // One regex per line; a bad pattern must not silently disable redaction.
+        let redactions = match std::env::var("AI_REDACT_PATTERNS_FILE") {
+            Ok(path) => std::fs::read_to_string(&path)
+                .expect("Unable to read AI_REDACT_PATTERNS_FILE")
+                .lines()
+                .filter(|line| !line.trim().is_empty())
+                .map(|line| Regex::new(line).expect("Invalid pattern in AI_REDACT_PATTERNS_FILE"))
+                .collect(),
+            Err(_) => Vec::new(),
+        };
+
+        let redaction_placeholder = std::env::var("AI_REDACT_PLACEHOLDER")
+            .unwrap_or_else(|_| "[REDACTED]".to_string());
+
+        let models_cache_ttl = std::env::var("AI_MODELS_CACHE_MINUTES")
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .map(|minutes: u64| Duration::from_secs(60 * minutes))
+            .unwrap_or(Duration::from_secs(3600));
+
+        let max_attempts = std::env::var("AI_MAX_ATTEMPTS")
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(3);
+
+        // A JSON array of transforms; a bad file must not be silently ignored.
+        let request_transforms = match std::env::var("AI_MODEL_TRANSFORMS_FILE") {
+            Ok(path) => serde_json::from_str(
+                &std::fs::read_to_string(&path).expect("Unable to read AI_MODEL_TRANSFORMS_FILE"),
+            )
+            .expect("Invalid AI_MODEL_TRANSFORMS_FILE"),
+            Err(_) => Vec::new(),
+        };
+
+        // A missing or unreadable directory must not silently drop prompts.
+        let system_prompts = match std::env::var("AI_PROMPTS_DIR") {
+            Ok(dir) => load_system_prompts(dir).expect("Unable to read AI_PROMPTS_DIR"),
+            Err(_) => BTreeMap::new(),
+        };
+
+        let context_truncation = std::env::var("AI_CONTEXT_TRUNCATION")
+            .map(|s| s.parse().expect("Invalid AI_CONTEXT_TRUNCATION"))
+            .unwrap_or_default();
+
         Self {
             enabled,
             api_key,
             base_url,
+            rate_limit,
+            redactions,
+            redaction_placeholder,
+            models_cache_ttl,
+            max_attempts,
+            request_transforms,
+            request_log: RequestLogConfig::from_env(),
+            system_prompts,
+            context_truncation,
         }
     }
 }
 
+/// Read every file in a directory as a system prompt named by its file stem,
+/// so `coding.txt` holds the prompt named `coding`
+pub fn load_system_prompts(dir: impl AsRef<std::path::Path>) -> Result<BTreeMap<String, String>> {
+    let dir = dir.as_ref();
+    let mut prompts = BTreeMap::new();
+    let entries = std::fs::read_dir(dir)
+        .with_context(|| format!("Failed to read prompt directory {:?}", dir))?;
+    for entry in entries {
+        let path = entry?.path();
+        if !path.is_file() {
+            continue;
+        }
+        let name = match path.file_stem().and_then(|stem| stem.to_str()) {
+            Some(name) => name.to_string(),
+            None => continue,
+        };
+        let prompt = std::fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read prompt {:?}", path))?;
+        prompts.insert(name, prompt.trim_end().to_string());
+    }
+    Ok(prompts)
+}
+
+/// Whether an OpenRouter response status is worth retrying
+fn is_retryable(status: reqwest::StatusCode) -> bool {
+    matches!(status.as_u16(), 429 | 502 | 503 | 504)
+}
+
+/// The delay a response asks for in its `Retry-After` header, in seconds
+fn retry_after(response: &reqwest::Response) -> Option<Duration> {
+    let value = response.headers().get(reqwest::header::RETRY_AFTER)?;
+    value.to_str().ok()?.trim().parse().ok().map(Duration::from_secs)
+}
+
+/// Exponential backoff with jitter before retrying a failed attempt
+fn backoff(attempt: u32) -> Duration {
+    let delay = RETRY_BASE_DELAY.saturating_mul(2u32.saturating_pow(attempt - 1));
+    let jitter = rand::thread_rng().gen_range(Duration::ZERO..=delay / 2);
+    (delay + jitter).min(MAX_RETRY_DELAY)
+}
+
+/// Append an entry to the request log off the async runtime, only warning if
+/// that fails so logging never fails a request
+async fn append_logged(log: Arc<RequestLog>, entry: LoggedRequest) {
+    match tokio::task::spawn_blocking(move || log.append(&entry)).await {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => log::warn!("Failed to log AI request: {:#}", e),
+        Err(e) => log::warn!("Failed to log AI request: {}", e),
+    }
+}
+
 /// Message in a chat conversation
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ChatMessage {
@@ -71,6 +435,15 @@ struct ChatCompletionRequest {
     #[serde(skip_serializing_if = "Option::is_none")]
     temperature: Option<f32>,
     stream: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stream_options: Option<StreamOptions>,
+}
+
+/// Options for a streamed chat completion request
+#[derive(Debug, Serialize)]
+struct StreamOptions {
+    /// Ask for a final chunk reporting the tokens used
+    include_usage: bool,
 }
 
 /// Response from OpenRouter chat completion API
@@ -79,6 +452,7 @@ pub struct ChatCompletionResponse {
     /// Unique identifier for the completion
     pub id: String,
     /// List of completion choices
+    #[serde(default)]
     pub choices: Vec<ChatChoice>,
     /// Token usage information
     pub usage: Option<Usage>,
@@ -93,8 +467,186 @@ pub struct ChatChoice {
     pub finish_reason: Option<String>,
 }
 
+/// A chunk of a streamed chat completion
+#[derive(Debug, Deserialize)]
+struct ChatCompletionChunk {
+    #[serde(default)]
+    choices: Vec<ChunkChoice>,
+    /// Set when the upstream fails after the stream has started
+    #[serde(default)]
+    error: Option<serde_json::Value>,
+    /// Tokens used, sent in a final chunk when requested
+    #[serde(default)]
+    usage: Option<Usage>,
+}
+
+/// A single choice within a streamed chunk
+#[derive(Debug, Deserialize)]
+struct ChunkChoice {
+    #[serde(default)]
+    delta: ChunkDelta,
+}
+
+/// New content generated since the previous chunk
+#[derive(Debug, Default, Deserialize)]
+struct ChunkDelta {
+    #[serde(default)]
+    content: Option<String>,
+}
+
+/// Splits a byte stream into complete lines, holding back any partial line
+/// until the rest of it arrives in a later network frame.
+#[derive(Debug, Default)]
+struct LineBuffer {
+    buf: Vec<u8>,
+}
+
+impl LineBuffer {
+    /// Append bytes and return every line that is now complete.
+    fn push(&mut self, bytes: &[u8]) -> Vec<String> {
+        self.buf.extend_from_slice(bytes);
+        let mut lines = Vec::new();
+        while let Some(pos) = self.buf.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = self.buf.drain(..=pos).collect();
+            let line = String::from_utf8_lossy(&line);
+            lines.push(line.trim_end_matches(['\r', '\n']).to_string());
+        }
+        lines
+    }
+}
+
+/// What a single line of an OpenRouter event stream means
+enum StreamLine {
+    /// Generated content, or the tokens used, in the order received
+    Events(Vec<StreamEvent>),
+    /// The generation is complete
+    Done,
+    /// Nothing to forward, e.g. a comment or keep-alive
+    Skip,
+}
+
+/// Interpret one line of an OpenRouter event stream
+fn parse_stream_line(line: &str) -> Result<StreamLine> {
+    let data = match line.strip_prefix("data:") {
+        Some(data) => data.trim_start(),
+        // Blank lines end events, and lines starting with `:` are comments.
+        None => return Ok(StreamLine::Skip),
+    };
+    if data == "[DONE]" {
+        return Ok(StreamLine::Done);
+    }
+    let chunk: ChatCompletionChunk =
+        serde_json::from_str(data).context("Failed to parse OpenRouter stream chunk")?;
+    if let Some(error) = chunk.error {
+        anyhow::bail!("OpenRouter stream error: {}", error);
+    }
+    let content: String = chunk
+        .choices
+        .into_iter()
+        .filter_map(|choice| choice.delta.content)
+        .collect();
+    let events: Vec<_> = (!content.is_empty())
+        .then_some(StreamEvent::Delta(content))
+        .into_iter()
+        .chain(chunk.usage.map(StreamEvent::Usage))
+        .collect();
+    Ok(if events.is_empty() {
+        StreamLine::Skip
+    } else {
+        StreamLine::Events(events)
+    })
+}
+
+/// Replace every match of the patterns in some text, in order, returning the
+/// number of matches replaced
+fn redact_text(patterns: &[Regex], placeholder: &str, text: &mut String) -> usize {
+    let mut count = 0;
+    for pattern in patterns {
+        let matches = pattern.find_iter(text).count();
+        if matches > 0 {
+            count += matches;
+            *text = pattern.replace_all(text, placeholder).into_owned();
+        }
+    }
+    count
+}
+
+/// Redacts streamed content, which may split a match across deltas
+///
+/// The last [`REDACTION_WINDOW`] bytes received are held back until more
+/// content shows they can't be part of a match, along with any match that
+/// runs into them, since it might still grow.
+#[derive(Debug)]
+pub struct StreamRedactor {
+    patterns: Vec<Regex>,
+    placeholder: String,
+    /// Content received but not yet released
+    pending: String,
+    /// Matches replaced so far
+    redacted: usize,
+}
+
+impl StreamRedactor {
+    /// Take in a delta, returning the redacted content that can now be sent,
+    /// which may be empty
+    pub fn push(&mut self, delta: &str) -> String {
+        self.pending.push_str(delta);
+        let Some(end) = self.pending.len().checked_sub(REDACTION_WINDOW) else {
+            return String::new();
+        };
+        let mut cut = (0..=end)
+            .rev()
+            .find(|&i| self.pending.is_char_boundary(i))
+            .unwrap_or(0);
+        loop {
+            let straddling = self
+                .patterns
+                .iter()
+                .flat_map(|pattern| pattern.find_iter(&self.pending))
+                .filter(|found| found.start() < cut && found.end() > cut)
+                .map(|found| found.start())
+                .min();
+            match straddling {
+                Some(start) => cut = start,
+                None => break,
+            }
+        }
+        let mut released: String = self.pending.drain(..cut).collect();
+        self.redacted += redact_text(&self.patterns, &self.placeholder, &mut released);
+        released
+    }
+
+    /// Release the redacted content still held back, once the stream ends
+    pub fn finish(&mut self) -> String {
+        let mut released = std::mem::take(&mut self.pending);
+        self.redacted += redact_text(&self.patterns, &self.placeholder, &mut released);
+        released
+    }
+
+    /// Number of matches replaced so far
+    pub fn redacted(&self) -> usize {
+        self.redacted
+    }
+}
+
+/// Something received from a streamed chat completion
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StreamEvent {
+    /// Content generated since the previous delta
+    Delta(String),
+    /// Tokens used by the whole request, reported once the generation ends
+    Usage(Usage),
+}
+
+/// Roughly how many tokens a number of characters of text takes up, for
+/// requests whose usage is never reported
+pub fn estimate_tokens(chars: usize) -> u32 {
+    let tokens = chars.div_ceil(CHARS_PER_TOKEN);
+    tokens.try_into().unwrap_or(u32::MAX)
+}
+
 /// Token usage statistics
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Usage {
     /// Number of tokens in the prompt
     pub prompt_tokens: u32,
@@ -152,10 +704,81 @@ pub struct ModelPricing {
     pub completion: String,
 }
 
+/// Outcome category of an AI connectivity check
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConnectionStatus {
+    /// The key was accepted
+    Ok,
+    /// No API key is configured
+    NotConfigured,
+    /// The key was rejected
+    AuthFailed,
+    /// The account has run out of credits
+    QuotaExceeded,
+    /// Too many requests were made with this key
+    RateLimited,
+    /// The endpoint could not be reached
+    NetworkError,
+    /// The endpoint returned an unexpected error
+    UpstreamError,
+}
+
+/// Result of checking the configured API key against OpenRouter
+#[derive(Debug, Clone, Serialize)]
+pub struct ConnectionCheck {
+    /// Outcome category
+    pub status: ConnectionStatus,
+    /// Human-readable diagnostic, never containing the API key
+    pub message: String,
+    /// HTTP status returned by the endpoint, if it responded
+    pub http_status: Option<u16>,
+    /// Round-trip time of the check, in milliseconds
+    pub latency_ms: u64,
+}
+
+/// OpenRouter API key information response
+#[derive(Debug, Deserialize)]
+struct OpenRouterKeyResponse {
+    data: OpenRouterKeyInfo,
+}
+
+/// Usage and limits of an OpenRouter API key
+#[derive(Debug, Deserialize)]
+struct OpenRouterKeyInfo {
+    #[serde(default)]
+    limit_remaining: Option<f64>,
+}
+
+/// An in-flight AI request that can be cancelled by its owner
+struct Job {
+    username: String,
+    handle: AbortHandle,
+}
+
+/// Removes a job from the registry once its request finishes or is dropped
+struct JobGuard<'a> {
+    jobs: &'a Mutex<HashMap<String, Job>>,
+    id: String,
+}
+
+impl Drop for JobGuard<'_> {
+    fn drop(&mut self) {
+        self.jobs.lock().unwrap().remove(&self.id);
+    }
+}
+
 /// Manager for AI operations
 pub struct AiManager {
     config: Arc<RwLock<AiConfig>>,
     client: reqwest::Client,
+    jobs: Mutex<HashMap<String, Job>>,
+    /// Times of each user's recent chat requests, oldest first
+    requests: Mutex<HashMap<String, VecDeque<Instant>>>,
+    /// Models last fetched from OpenRouter, and when
+    models_cache: RwLock<Option<(Instant, Vec<ModelInfo>)>>,
+    /// Log of chat completions, if enabled
+    request_log: Option<Arc<RequestLog>>,
 }
 
 impl std::fmt::Debug for AiManager {
@@ -163,6 +786,7 @@ impl std::fmt::Debug for AiManager {
         f.debug_struct("AiManager")
             .field("config", &self.config)
             .field("client", &"<reqwest::Client>")
+            .field("jobs", &self.jobs.lock().unwrap().len())
             .finish()
     }
 }
@@ -178,10 +802,22 @@ impl AiManager {
         if config.enabled {
             info!("AI features enabled with OpenRouter");
         }
+        let request_log = config.request_log.clone().map(|log_config| {
+            info!(
+                "Logging AI requests to {:?}{}",
+                log_config.path,
+                if log_config.include_content { ", with message content" } else { "" }
+            );
+            Arc::new(RequestLog::new(log_config))
+        });
 
-        Ok(Self { 
+        Ok(Self {
             config: Arc::new(RwLock::new(config)),
-            client 
+            client,
+            jobs: Mutex::new(HashMap::new()),
+            requests: Mutex::new(HashMap::new()),
+            models_cache: RwLock::new(None),
+            request_log,
         })
     }
 
@@ -206,6 +842,172 @@ impl AiManager {
         Ok(())
     }
 
+    /// Count a chat request against a user's rate limit
+    ///
+    /// `limit` overrides the configured defaults for this user. On success,
+    /// returns how many more requests the user may make right now, or `None`
+    /// if they are unlimited. Refused requests are not counted.
+    pub fn check_rate_limit(
+        &self,
+        username: &str,
+        limit: Option<RateLimit>,
+    ) -> Result<Option<u32>, RateLimited> {
+        self.rate_limit_allowance(username, limit, true)
+    }
+
+    /// How many chat requests a user may make right now, without counting
+    /// one, or `None` if they are unlimited
+    pub fn rate_limit_remaining(&self, username: &str, limit: Option<RateLimit>) -> Option<u32> {
+        self.rate_limit_allowance(username, limit, false)
+            .unwrap_or(Some(0))
+    }
+
+    /// Requests left under a user's rate limit, after counting one if `count`
+    fn rate_limit_allowance(
+        &self,
+        username: &str,
+        limit: Option<RateLimit>,
+        count: bool,
+    ) -> Result<Option<u32>, RateLimited> {
+        const MINUTE: Duration = Duration::from_secs(60);
+        const HOUR: Duration = Duration::from_secs(3600);
+
+        let defaults = self.config.read().unwrap().rate_limit;
+        let limit = limit.unwrap_or_default().or(defaults);
+        if limit == RateLimit::default() {
+            return Ok(None);
+        }
+
+        let now = Instant::now();
+        let mut requests = self.requests.lock().unwrap();
+        let times = requests.entry(username.to_string()).or_default();
+        while times.front().is_some_and(|t| now.duration_since(*t) >= HOUR) {
+            times.pop_front();
+        }
+
+        let mut remaining = None;
+        for (max, window) in [(limit.per_minute, MINUTE), (limit.per_hour, HOUR)] {
+            let max = match max {
+                Some(max) => max,
+                None => continue,
+            };
+            if max == 0 {
+                return Err(RateLimited {
+                    retry_after: window,
+                });
+            }
+            let recent: Vec<Instant> = times
+                .iter()
+                .copied()
+                .filter(|t| now.duration_since(*t) < window)
+                .collect();
+            if recent.len() >= max as usize {
+                // The window frees up once enough of the oldest requests age out.
+                let oldest = recent[recent.len() - max as usize];
+                return Err(RateLimited {
+                    retry_after: window.saturating_sub(now.duration_since(oldest)),
+                });
+            }
+            let left = max - recent.len() as u32 - u32::from(count);
+            remaining = Some(remaining.map_or(left, |r: u32| r.min(left)));
+        }
+        if count {
+            times.push_back(now);
+        }
+        Ok(remaining)
+    }
+
+    /// Check that the configured API key works, without spending credits
+    ///
+    /// This queries OpenRouter's key information endpoint, which requires a
+    /// valid key but does not run a model.
+    pub async fn test_connection(&self) -> ConnectionCheck {
+        let (url, api_key) = {
+            let config = self.config.read().unwrap();
+            (format!("{}/key", config.base_url), config.api_key.clone())
+        };
+        let check = |status, message: String, http_status: Option<u16>, started: std::time::Instant| {
+            ConnectionCheck {
+                status,
+                // Upstream errors may echo request details; never return the key.
+                message: if api_key.is_empty() {
+                    message
+                } else {
+                    message.replace(&api_key, "<redacted>")
+                },
+                http_status,
+                latency_ms: started.elapsed().as_millis() as u64,
+            }
+        };
+
+        let started = std::time::Instant::now();
+        if api_key.is_empty() {
+            return check(
+                ConnectionStatus::NotConfigured,
+                "No OpenRouter API key is configured".to_string(),
+                None,
+                started,
+            );
+        }
+
+        let response = match self
+            .client
+            .get(&url)
+            .header("Authorization", format!("Bearer {}", api_key))
+            .timeout(std::time::Duration::from_secs(10))
+            .send()
+            .await
+        {
+            Ok(response) => response,
+            Err(e) => {
+                return check(
+                    ConnectionStatus::NetworkError,
+                    format!("Could not reach {}: {}", url, e),
+                    None,
+                    started,
+                );
+            }
+        };
+
+        let http_status = response.status();
+        let code = Some(http_status.as_u16());
+        if http_status.is_success() {
+            let remaining = response
+                .json::<OpenRouterKeyResponse>()
+                .await
+                .ok()
+                .and_then(|key| key.data.limit_remaining);
+            let message = match remaining {
+                Some(remaining) if remaining <= 0.0 => {
+                    return check(
+                        ConnectionStatus::QuotaExceeded,
+                        "API key is valid, but its credit limit has been reached".to_string(),
+                        code,
+                        started,
+                    );
+                }
+                Some(remaining) => format!("API key is valid ({} credits remaining)", remaining),
+                None => "API key is valid".to_string(),
+            };
+            return check(ConnectionStatus::Ok, message, code, started);
+        }
+
+        let body = response.text().await.unwrap_or_default();
+        let detail: String = body.chars().take(200).collect();
+        let (status, summary) = match http_status.as_u16() {
+            401 | 403 => (ConnectionStatus::AuthFailed, "API key was rejected"),
+            402 => (ConnectionStatus::QuotaExceeded, "Account has insufficient credits"),
+            429 => (ConnectionStatus::RateLimited, "Rate limited by OpenRouter"),
+            _ => (ConnectionStatus::UpstreamError, "OpenRouter returned an error"),
+        };
+        check(
+            status,
+            format!("{} ({}): {}", summary, http_status, detail),
+            code,
+            started,
+        )
+    }
+
     /// Get available models (returns fallback list synchronously)
     /// For full dynamic list, use get_available_models_async()
     pub fn get_available_models(&self) -> Vec<ModelInfo> {
@@ -292,9 +1094,7 @@ impl AiManager {
         if !response.status().is_success() {
             let status = response.status();
             let error_text = response.text().await.unwrap_or_else(|_| "Unknown error".to_string());
-            log::warn!("Failed to fetch models from OpenRouter ({}): {}", status, error_text);
-            // Return fallback list if API call fails
-            return Ok(self.get_available_models());
+            anyhow::bail!("Failed to fetch models from OpenRouter ({}): {}", status, error_text);
         }
 
         let models_response = response
@@ -334,13 +1134,69 @@ impl AiManager {
         Ok(models)
     }
 
+    /// Get available models, fetching them from OpenRouter at most once per
+    /// cache TTL
+    ///
+    /// If a fetch fails, the last models fetched are served however old they
+    /// are, and the fallback list only when none have been fetched yet.
+    pub async fn get_models_cached(&self) -> Vec<ModelInfo> {
+        let ttl = self.config.read().unwrap().models_cache_ttl;
+        if let Some((fetched_at, models)) = &*self.models_cache.read().unwrap() {
+            if fetched_at.elapsed() < ttl {
+                return models.clone();
+            }
+        }
+
+        match self.get_available_models_async().await {
+            Ok(models) => {
+                *self.models_cache.write().unwrap() = Some((Instant::now(), models.clone()));
+                models
+            }
+            Err(e) => {
+                log::warn!("Failed to fetch models dynamically: {:#}", e);
+                match &*self.models_cache.read().unwrap() {
+                    Some((_, models)) => models.clone(),
+                    None => self.get_available_models(),
+                }
+            }
+        }
+    }
+
     /// Send a chat completion request
+    ///
+    /// The request and its outcome are written to the request log, if one is
+    /// configured.
     pub async fn chat_completion(
         &self,
         model: &str,
         messages: Vec<ChatMessage>,
         max_tokens: Option<u32>,
         temperature: Option<f32>,
+    ) -> Result<ChatCompletionResponse> {
+        let logged = self.request_log.as_ref().map(|log| {
+            let entry = log.entry(model, &messages, max_tokens, temperature, false);
+            (Arc::clone(log), entry)
+        });
+        let result = self
+            .send_chat_completion(model, messages, max_tokens, temperature)
+            .await;
+        if let Some((log, mut entry)) = logged {
+            match &result {
+                Ok(completion) => entry.response = Some(log.response(completion)),
+                Err(e) => entry.error = Some(format!("{:#}", e)),
+            }
+            append_logged(log, entry).await;
+        }
+        result
+    }
+
+    /// Send a chat completion request, without logging it
+    async fn send_chat_completion(
+        &self,
+        model: &str,
+        messages: Vec<ChatMessage>,
+        max_tokens: Option<u32>,
+        temperature: Option<f32>,
     ) -> Result<ChatCompletionResponse> {
         if !self.is_enabled() {
             anyhow::bail!("AI features are not enabled");
@@ -351,26 +1207,20 @@ impl AiManager {
             (format!("{}/chat/completions", config.base_url), config.api_key.clone())
         };
         
-        let request = ChatCompletionRequest {
+        let mut request = ChatCompletionRequest {
             model: model.to_string(),
             messages,
             max_tokens,
             temperature,
             stream: false,
+            stream_options: None,
         };
+        self.transform_request(&mut request);
 
         info!("Sending chat completion request to OpenRouter with model: {}", model);
         info!("API key length: {}, starts with: {}", api_key.len(), &api_key[..15.min(api_key.len())]);
 
-        let response = self.client
-            .post(&url)
-            .header("Authorization", format!("Bearer {}", api_key))
-            .header("HTTP-Referer", "https://rustpad.io")
-            .header("X-Title", "Rustpad")
-            .json(&request)
-            .send()
-            .await
-            .context("Failed to send request to OpenRouter")?;
+        let response = self.post_chat(&url, &api_key, &request).await?;
 
         if !response.status().is_success() {
             let status = response.status();
@@ -378,10 +1228,18 @@ impl AiManager {
             anyhow::bail!("OpenRouter API error ({}): {}", status, error_text);
         }
 
+        // Some models and upstream errors answer 200 without any choices,
+        // which is no more use to the client than a malformed body.
         let completion = response
             .json::<ChatCompletionResponse>()
             .await
-            .context("Failed to parse OpenRouter response")?;
+            .context(ApiError::BadGateway("The AI model returned a malformed response".into()))?;
+        if completion.choices.is_empty() {
+            log::warn!("OpenRouter returned no choices for model {}: {}", model, completion.id);
+            anyhow::bail!(ApiError::BadGateway(
+                "The AI model returned no response, try again or choose another model".into()
+            ));
+        }
 
         info!(
             "Chat completion successful, tokens used: {:?}",
@@ -390,4 +1248,359 @@ impl AiManager {
 
         Ok(completion)
     }
+
+    /// Adjust a chat request with the first transform matching its model
+    fn transform_request(&self, request: &mut ChatCompletionRequest) {
+        let config = self.config.read().unwrap();
+        if let Some(transform) = config
+            .request_transforms
+            .iter()
+            .find(|transform| transform.matches(&request.model))
+        {
+            transform.apply(request);
+        }
+    }
+
+    /// Post a chat request to OpenRouter, retrying transient failures
+    ///
+    /// Connection errors and 429, 502, 503 and 504 responses are retried up
+    /// to the configured number of attempts, after the response's
+    /// `Retry-After` if it has one and otherwise with exponential backoff and
+    /// jitter. Any other response is returned for the caller to check.
+    async fn post_chat(
+        &self,
+        url: &str,
+        api_key: &str,
+        request: &ChatCompletionRequest,
+    ) -> Result<reqwest::Response> {
+        let max_attempts = self.config.read().unwrap().max_attempts.max(1);
+        let mut attempt = 1;
+        loop {
+            let result = self.client
+                .post(url)
+                .header("Authorization", format!("Bearer {}", api_key))
+                .header("HTTP-Referer", "https://rustpad.io")
+                .header("X-Title", "Rustpad")
+                .json(request)
+                .send()
+                .await;
+            let (reason, delay) = match &result {
+                Ok(response) if is_retryable(response.status()) => (
+                    response.status().to_string(),
+                    retry_after(response).unwrap_or_else(|| backoff(attempt)),
+                ),
+                Err(e) if e.is_connect() => (e.to_string(), backoff(attempt)),
+                _ => return result.context("Failed to send request to OpenRouter"),
+            };
+            if attempt >= max_attempts || delay > MAX_RETRY_DELAY {
+                return result.context("Failed to send request to OpenRouter");
+            }
+            attempt += 1;
+            log::warn!(
+                "OpenRouter request failed ({}), retrying in {:?} (attempt {} of {})",
+                reason,
+                delay,
+                attempt,
+                max_attempts
+            );
+            tokio::time::sleep(delay).await;
+        }
+    }
+
+    /// Names of the configured system prompts, in order
+    pub fn system_prompt_names(&self) -> Vec<String> {
+        let config = self.config.read().unwrap();
+        config.system_prompts.keys().cloned().collect()
+    }
+
+    /// Start a conversation with the named system prompt, unless it already
+    /// has a system message of its own
+    pub fn apply_system_prompt(&self, name: &str, messages: &mut Vec<ChatMessage>) -> Result<()> {
+        let config = self.config.read().unwrap();
+        let Some(prompt) = config.system_prompts.get(name) else {
+            anyhow::bail!(ApiError::BadRequest(format!("Unknown system prompt: {}", name)));
+        };
+        if !messages.iter().any(|message| message.role == "system") {
+            messages.insert(
+                0,
+                ChatMessage {
+                    role: "system".into(),
+                    content: prompt.clone(),
+                },
+            );
+        }
+        Ok(())
+    }
+
+    /// Context window of a model in tokens, from the last models fetched or
+    /// else the fallback list, without fetching them
+    fn context_length(&self, model: &str) -> u32 {
+        let find = |models: &[ModelInfo]| {
+            models
+                .iter()
+                .find(|info| info.id == model)
+                .map(|info| info.context_length)
+        };
+        let cached = match &*self.models_cache.read().unwrap() {
+            Some((_, models)) => find(models),
+            None => None,
+        };
+        cached
+            .or_else(|| find(&self.get_available_models()))
+            .unwrap_or(DEFAULT_CONTEXT_LENGTH)
+    }
+
+    /// Give a conversation the text of the document it is about, as a system
+    /// message after any leading ones
+    ///
+    /// The text is truncated to fit the model's context window alongside the
+    /// other messages and the reply, keeping the configured part of it.
+    pub fn add_document_context(
+        &self,
+        model: &str,
+        document_id: &str,
+        language: Option<&str>,
+        text: &str,
+        messages: &mut Vec<ChatMessage>,
+        max_tokens: Option<u32>,
+    ) {
+        let truncation = self.config.read().unwrap().context_truncation;
+        let used: usize = messages.iter().map(|message| message.content.len()).sum();
+        let budget = (self.context_length(model) as usize)
+            .saturating_sub(max_tokens.unwrap_or(DEFAULT_REPLY_TOKENS) as usize)
+            .saturating_sub(used.div_ceil(CHARS_PER_TOKEN))
+            * CHARS_PER_TOKEN;
+        let language = language.map(|language| format!(", written in {}", language));
+        let content = format!(
+            "The user is working on the document \"{}\"{}. Its current text is:\n\n{}",
+            document_id,
+            language.unwrap_or_default(),
+            truncation.truncate(text, budget),
+        );
+        let position = messages
+            .iter()
+            .position(|message| message.role != "system")
+            .unwrap_or(messages.len());
+        messages.insert(
+            position,
+            ChatMessage {
+                role: "system".into(),
+                content,
+            },
+        );
+    }
+
+    /// The log of chat completions, if one is configured
+    pub fn request_log(&self) -> Option<&Arc<RequestLog>> {
+        self.request_log.as_ref()
+    }
+
+    /// Replace configured patterns in the content of a chat response
+    ///
+    /// Returns the number of matches that were replaced.
+    pub fn redact(&self, completion: &mut ChatCompletionResponse) -> usize {
+        let config = self.config.read().unwrap();
+        completion
+            .choices
+            .iter_mut()
+            .map(|choice| {
+                redact_text(
+                    &config.redactions,
+                    &config.redaction_placeholder,
+                    &mut choice.message.content,
+                )
+            })
+            .sum()
+    }
+
+    /// A redactor for streamed chat content, if any patterns are configured
+    pub fn stream_redactor(&self) -> Option<StreamRedactor> {
+        let config = self.config.read().unwrap();
+        if config.redactions.is_empty() {
+            return None;
+        }
+        Some(StreamRedactor {
+            patterns: config.redactions.clone(),
+            placeholder: config.redaction_placeholder.clone(),
+            pending: String::new(),
+            redacted: 0,
+        })
+    }
+
+    /// Send a chat completion request, streaming the generated content
+    ///
+    /// The returned stream yields content deltas as OpenRouter produces them,
+    /// then the tokens used, and ends when the generation is complete.
+    /// Dropping the stream closes the upstream connection.
+    ///
+    /// With a request log configured, the request is logged along with the
+    /// content generated once the stream ends, or with the error if it fails.
+    /// A stream dropped before it ends is not logged.
+    pub async fn chat_completion_stream(
+        &self,
+        model: &str,
+        messages: Vec<ChatMessage>,
+        max_tokens: Option<u32>,
+        temperature: Option<f32>,
+    ) -> Result<BoxStream<'static, Result<StreamEvent>>> {
+        let Some(log) = self.request_log.clone() else {
+            return self
+                .send_chat_completion_stream(model, messages, max_tokens, temperature)
+                .await;
+        };
+        let mut entry = log.entry(model, &messages, max_tokens, temperature, true);
+        let stream = match self
+            .send_chat_completion_stream(model, messages, max_tokens, temperature)
+            .await
+        {
+            Ok(stream) => stream,
+            Err(e) => {
+                entry.error = Some(format!("{:#}", e));
+                append_logged(log, entry).await;
+                return Err(e);
+            }
+        };
+
+        let outcome = Arc::new(Mutex::new((String::new(), None)));
+        let recorded = Arc::clone(&outcome);
+        let logged = futures::stream::once(async move {
+            let (content, error) = std::mem::take(&mut *recorded.lock().unwrap());
+            entry.response = Some(log.streamed_response(&content));
+            entry.error = error;
+            append_logged(log, entry).await;
+        });
+        let stream = stream
+            .inspect(move |item| {
+                let mut outcome = outcome.lock().unwrap();
+                match item {
+                    Ok(StreamEvent::Delta(content)) => outcome.0.push_str(content),
+                    Ok(StreamEvent::Usage(_)) => {}
+                    Err(e) => outcome.1 = Some(format!("{:#}", e)),
+                }
+            })
+            .chain(logged.filter_map(|()| async { None }));
+        Ok(stream.boxed())
+    }
+
+    /// Send a chat completion request for streaming, without logging it
+    async fn send_chat_completion_stream(
+        &self,
+        model: &str,
+        messages: Vec<ChatMessage>,
+        max_tokens: Option<u32>,
+        temperature: Option<f32>,
+    ) -> Result<BoxStream<'static, Result<StreamEvent>>> {
+        if !self.is_enabled() {
+            anyhow::bail!("AI features are not enabled");
+        }
+
+        let (url, api_key) = {
+            let config = self.config.read().unwrap();
+            (format!("{}/chat/completions", config.base_url), config.api_key.clone())
+        };
+
+        let mut request = ChatCompletionRequest {
+            model: model.to_string(),
+            messages,
+            max_tokens,
+            temperature,
+            stream: true,
+            stream_options: Some(StreamOptions {
+                include_usage: true,
+            }),
+        };
+        self.transform_request(&mut request);
+
+        info!("Sending streaming chat completion request to OpenRouter with model: {}", model);
+
+        let response = self.post_chat(&url, &api_key, &request).await?;
+
+        if !response.status().is_success() {
+            let status = response.status();
+            let error_text = response.text().await.unwrap_or_else(|_| "Unknown error".to_string());
+            anyhow::bail!("OpenRouter API error ({}): {}", status, error_text);
+        }
+
+        let state = (Some(response), LineBuffer::default(), VecDeque::new(), VecDeque::new());
+        Ok(futures::stream::unfold(state, |(mut response, mut lines, mut pending, mut ready)| async move {
+            loop {
+                if let Some(event) = ready.pop_front() {
+                    return Some((Ok(event), (response, lines, pending, ready)));
+                }
+                if let Some(line) = pending.pop_front() {
+                    match parse_stream_line(&line) {
+                        Ok(StreamLine::Events(events)) => ready.extend(events),
+                        Ok(StreamLine::Skip) => {}
+                        Ok(StreamLine::Done) => return None,
+                        Err(e) => {
+                            let state = (None, lines, VecDeque::new(), VecDeque::new());
+                            return Some((Err(e), state));
+                        }
+                    }
+                    continue;
+                }
+                let chunk = match response.as_mut()?.chunk().await {
+                    Ok(Some(chunk)) => chunk,
+                    // The upstream closed without sending `[DONE]`.
+                    Ok(None) => return None,
+                    Err(e) => {
+                        let err = anyhow::Error::new(e).context("OpenRouter stream failed");
+                        return Some((Err(err), (None, lines, pending, ready)));
+                    }
+                };
+                pending.extend(lines.push(&chunk));
+            }
+        })
+        .boxed())
+    }
+
+    /// Run a request as a cancellable job owned by `username`
+    ///
+    /// Cancelling the job drops the request future, which tears down the
+    /// upstream connection instead of letting the generation run to completion.
+    pub async fn run_job<T>(
+        &self,
+        job_id: &str,
+        username: &str,
+        request: impl Future<Output = Result<T>>,
+    ) -> Result<T> {
+        let (handle, registration) = AbortHandle::new_pair();
+        {
+            let mut jobs = self.jobs.lock().unwrap();
+            if jobs.contains_key(job_id) {
+                anyhow::bail!(ApiError::Conflict("A job with this id is already running".into()));
+            }
+            jobs.insert(
+                job_id.to_string(),
+                Job {
+                    username: username.to_string(),
+                    handle,
+                },
+            );
+        }
+        let _guard = JobGuard {
+            jobs: &self.jobs,
+            id: job_id.to_string(),
+        };
+
+        match Abortable::new(request, registration).await {
+            Ok(result) => result,
+            Err(_) => {
+                info!("AI job {} was cancelled", job_id);
+                anyhow::bail!("AI request was cancelled")
+            }
+        }
+    }
+
+    /// Cancel an in-flight job, if it belongs to `username`
+    pub fn cancel_job(&self, job_id: &str, username: &str) -> Result<()> {
+        let jobs = self.jobs.lock().unwrap();
+        match jobs.get(job_id) {
+            Some(job) if job.username == username => {
+                job.handle.abort();
+                Ok(())
+            }
+            _ => anyhow::bail!(ApiError::NotFound("Job not found".into())),
+        }
+    }
 }
@@ -0,0 +1,250 @@
+//! Opt-in log of AI chat completions, for debugging and building evaluation
+//! datasets.
+
+use std::fs::{self, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::ai::{ChatCompletionResponse, ChatMessage, Usage};
+
+/// Where chat completions are logged, and how much of them.
+#[derive(Clone, Debug)]
+pub struct RequestLogConfig {
+    /// JSON Lines file that entries are appended to.
+    pub path: PathBuf,
+    /// Whether message content is logged, rather than only its length.
+    pub include_content: bool,
+    /// Size past which the log is rotated to `<path>.1`, replacing the
+    /// previous rotated log.
+    pub max_bytes: u64,
+}
+
+impl RequestLogConfig {
+    /// Read the configuration from the environment, if `AI_REQUEST_LOG` is set.
+    pub fn from_env() -> Option<Self> {
+        let path = std::env::var("AI_REQUEST_LOG").ok().filter(|s| !s.is_empty())?;
+        let include_content = std::env::var("AI_REQUEST_LOG_CONTENT")
+            .map(|s| s.parse().expect("Unable to parse AI_REQUEST_LOG_CONTENT"))
+            .unwrap_or(false);
+        let max_mb: u64 = std::env::var("AI_REQUEST_LOG_MAX_MB")
+            .map(|s| s.parse().expect("Unable to parse AI_REQUEST_LOG_MAX_MB"))
+            .unwrap_or(10);
+        Some(Self {
+            path: path.into(),
+            include_content,
+            max_bytes: max_mb.max(1) * 1024 * 1024,
+        })
+    }
+}
+
+/// A message as logged, with its content only if that is enabled.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct LoggedMessage {
+    /// Role of the message sender.
+    pub role: String,
+    /// Content of the message, if content is logged.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content: Option<String>,
+    /// Length of the content in characters.
+    pub chars: usize,
+}
+
+/// A completion choice as logged.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct LoggedChoice {
+    /// The generated message.
+    pub message: LoggedMessage,
+    /// Why generation finished, if known.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub finish_reason: Option<String>,
+}
+
+/// The answer to a logged request.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct LoggedResponse {
+    /// OpenRouter's id for the completion, unknown for streamed ones.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    /// Generated choices.
+    pub choices: Vec<LoggedChoice>,
+    /// Tokens used, if reported.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub usage: Option<Usage>,
+}
+
+/// One line of the request log.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct LoggedRequest {
+    /// Unique id of the entry, for replaying it.
+    pub id: String,
+    /// When the request was made.
+    pub timestamp: DateTime<Utc>,
+    /// Model the request was sent to.
+    pub model: String,
+    /// Messages sent, before any per-model transforms.
+    pub messages: Vec<LoggedMessage>,
+    /// Requested cap on generated tokens.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_tokens: Option<u32>,
+    /// Requested sampling temperature.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f32>,
+    /// Whether the completion was streamed.
+    #[serde(default)]
+    pub stream: bool,
+    /// The completion, if the request succeeded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub response: Option<LoggedResponse>,
+    /// Why the request failed, if it did.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl LoggedRequest {
+    /// The messages to send again when replaying this request, if their
+    /// content was logged.
+    pub fn replay_messages(&self) -> Option<Vec<ChatMessage>> {
+        self.messages
+            .iter()
+            .map(|message| {
+                Some(ChatMessage {
+                    role: message.role.clone(),
+                    content: message.content.clone()?,
+                })
+            })
+            .collect()
+    }
+}
+
+/// Appends chat completions to a JSON Lines file, rotating it when it grows
+/// past its size limit.
+#[derive(Debug)]
+pub struct RequestLog {
+    config: RequestLogConfig,
+    /// Serializes writes and rotation.
+    lock: parking_lot::Mutex<()>,
+}
+
+impl RequestLog {
+    /// Create a log writing to the configured file.
+    pub fn new(config: RequestLogConfig) -> Self {
+        Self {
+            config,
+            lock: parking_lot::Mutex::new(()),
+        }
+    }
+
+    /// A message as it should be logged.
+    fn message(&self, role: &str, content: &str) -> LoggedMessage {
+        LoggedMessage {
+            role: role.to_string(),
+            content: self.config.include_content.then(|| content.to_string()),
+            chars: content.chars().count(),
+        }
+    }
+
+    /// Start an entry for a request about to be sent.
+    pub fn entry(
+        &self,
+        model: &str,
+        messages: &[ChatMessage],
+        max_tokens: Option<u32>,
+        temperature: Option<f32>,
+        stream: bool,
+    ) -> LoggedRequest {
+        LoggedRequest {
+            id: uuid::Uuid::new_v4().to_string(),
+            timestamp: Utc::now(),
+            model: model.to_string(),
+            messages: messages
+                .iter()
+                .map(|message| self.message(&message.role, &message.content))
+                .collect(),
+            max_tokens,
+            temperature,
+            stream,
+            response: None,
+            error: None,
+        }
+    }
+
+    /// The logged form of a completion.
+    pub fn response(&self, completion: &ChatCompletionResponse) -> LoggedResponse {
+        LoggedResponse {
+            id: Some(completion.id.clone()),
+            choices: completion
+                .choices
+                .iter()
+                .map(|choice| LoggedChoice {
+                    message: self.message(&choice.message.role, &choice.message.content),
+                    finish_reason: choice.finish_reason.clone(),
+                })
+                .collect(),
+            usage: completion.usage.clone(),
+        }
+    }
+
+    /// The logged form of a streamed completion's content.
+    pub fn streamed_response(&self, content: &str) -> LoggedResponse {
+        LoggedResponse {
+            id: None,
+            choices: vec![LoggedChoice {
+                message: self.message("assistant", content),
+                finish_reason: None,
+            }],
+            usage: None,
+        }
+    }
+
+    /// Append an entry, first rotating the log if it would grow too large.
+    pub fn append(&self, entry: &LoggedRequest) -> Result<()> {
+        let mut line = serde_json::to_vec(entry)?;
+        line.push(b'\n');
+
+        let _held = self.lock.lock();
+        let path = &self.config.path;
+        let size = fs::metadata(path).map(|meta| meta.len()).unwrap_or(0);
+        if size > 0 && size + line.len() as u64 > self.config.max_bytes {
+            fs::rename(path, rotated_path(path)).context("Failed to rotate AI request log")?;
+        }
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .context("Failed to open AI request log")?;
+        file.write_all(&line).context("Failed to write AI request log")?;
+        Ok(())
+    }
+
+    /// Find an entry by id, in the current log or the rotated one.
+    pub fn find(&self, id: &str) -> Result<Option<LoggedRequest>> {
+        let _held = self.lock.lock();
+        for path in [self.config.path.clone(), rotated_path(&self.config.path)] {
+            let file = match fs::File::open(&path) {
+                Ok(file) => file,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e).context("Failed to read AI request log"),
+            };
+            for line in BufReader::new(file).lines() {
+                // Skip lines that aren't entries, like one cut short by a crash.
+                if let Ok(entry) = serde_json::from_str::<LoggedRequest>(&line?) {
+                    if entry.id == id {
+                        return Ok(Some(entry));
+                    }
+                }
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// Where a full log is moved when it is rotated.
+fn rotated_path(path: &Path) -> PathBuf {
+    let mut rotated = path.as_os_str().to_owned();
+    rotated.push(".1");
+    rotated.into()
+}
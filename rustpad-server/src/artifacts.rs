@@ -1,11 +1,25 @@
 //! Artifact storage for AI-generated multi-file outputs.
 
 use anyhow::{Context, Result};
-use chrono::{DateTime, Utc};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::{DateTime, Duration, Utc};
 use log::info;
 use serde::{Deserialize, Serialize};
 use std::fs;
-use std::path::PathBuf;
+use std::io::{Cursor, Read, Seek, Write};
+use std::path::{Component, Path, PathBuf};
+
+use crate::error::ApiError;
+use crate::signer::Signer;
+
+/// What to do with metadata fields that exceed their maximum length
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OversizePolicy {
+    /// Cut the field down to the maximum length
+    Truncate,
+    /// Refuse to store the artifact
+    Reject,
+}
 
 /// Configuration for artifact storage
 #[derive(Debug, Clone)]
@@ -14,6 +28,20 @@ pub struct ArtifactConfig {
     pub enabled: bool,
     /// Directory where artifacts are stored
     pub storage_dir: PathBuf,
+    /// Maximum length of a stored prompt, in bytes
+    pub max_prompt_len: usize,
+    /// Maximum length of the model and document id fields, in bytes
+    pub max_field_len: usize,
+    /// How oversized metadata fields are handled
+    pub oversize_policy: OversizePolicy,
+    /// Key for signing share links, generated at startup if not set
+    pub share_key: Option<String>,
+    /// How long a share link stays valid after it is created
+    pub share_ttl: Duration,
+    /// Maximum number of files in an imported ZIP archive
+    pub max_import_files: usize,
+    /// Maximum total size of the files extracted from a ZIP archive, in bytes
+    pub max_import_size: u64,
 }
 
 impl Default for ArtifactConfig {
@@ -21,6 +49,13 @@ impl Default for ArtifactConfig {
         Self {
             enabled: false,
             storage_dir: PathBuf::from("./artifacts"),
+            max_prompt_len: 64 * 1024, // 64 KiB
+            max_field_len: 256,
+            oversize_policy: OversizePolicy::Truncate,
+            share_key: None,
+            share_ttl: Duration::days(7),
+            max_import_files: 100,
+            max_import_size: 10 * 1024 * 1024, // 10 MiB
         }
     }
 }
@@ -37,9 +72,42 @@ impl ArtifactConfig {
             .map(PathBuf::from)
             .unwrap_or_else(|_| PathBuf::from("./artifacts"));
 
+        let max_prompt_len = std::env::var("ARTIFACT_MAX_PROMPT_LEN")
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(64 * 1024);
+
+        let oversize_policy = match std::env::var("ARTIFACT_OVERSIZE_POLICY").as_deref() {
+            Ok("reject") => OversizePolicy::Reject,
+            _ => OversizePolicy::Truncate,
+        };
+
+        let share_ttl = std::env::var("ARTIFACT_SHARE_TTL_HOURS")
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .map(Duration::hours)
+            .unwrap_or_else(|| Duration::days(7));
+
+        let max_import_files = std::env::var("ARTIFACT_IMPORT_MAX_FILES")
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(100);
+
+        let max_import_size = std::env::var("ARTIFACT_IMPORT_MAX_BYTES")
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(10 * 1024 * 1024);
+
         Self {
             enabled,
             storage_dir,
+            max_prompt_len,
+            max_field_len: 256,
+            oversize_policy,
+            share_key: std::env::var("ARTIFACT_SHARE_KEY").ok(),
+            share_ttl,
+            max_import_files,
+            max_import_size,
         }
     }
 }
@@ -86,10 +154,22 @@ pub struct Artifact {
     pub files: Vec<ArtifactFile>,
 }
 
+/// A signed link granting read-only access to one artifact until it expires
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ShareLink {
+    /// Artifact the link grants access to
+    pub artifact_id: String,
+    /// Expiry time, in seconds since the Unix epoch
+    pub exp: i64,
+    /// URL-safe base64 HMAC-SHA256 of the artifact ID and expiry
+    pub sig: String,
+}
+
 /// Manager for artifact storage operations
 #[derive(Debug)]
 pub struct ArtifactManager {
     config: ArtifactConfig,
+    share_signer: Signer,
 }
 
 impl ArtifactManager {
@@ -101,7 +181,20 @@ impl ArtifactManager {
             info!("Artifact storage enabled, directory: {:?}", config.storage_dir);
         }
 
-        Ok(Self { config })
+        let share_signer = match &config.share_key {
+            Some(key) => Signer::new(key),
+            None => {
+                if config.enabled {
+                    info!("ARTIFACT_SHARE_KEY not set, share links are signed with a random key");
+                }
+                Signer::random()
+            }
+        };
+
+        Ok(Self {
+            config,
+            share_signer,
+        })
     }
 
     /// Check if artifact storage is enabled
@@ -122,6 +215,14 @@ impl ArtifactManager {
             anyhow::bail!("Artifact storage is not enabled");
         }
 
+        for file in &files {
+            validate_file_name(&file.name)?;
+        }
+
+        let document_id = self.limit_field("document_id", document_id, self.config.max_field_len)?;
+        let model = self.limit_field("model", model, self.config.max_field_len)?;
+        let prompt = self.limit_field("prompt", prompt, self.config.max_prompt_len)?;
+
         // Generate unique artifact ID
         let artifact_id = uuid::Uuid::new_v4().to_string();
 
@@ -132,9 +233,9 @@ impl ArtifactManager {
         let metadata = ArtifactMetadata {
             id: artifact_id.clone(),
             username: username.to_string(),
-            document_id: document_id.to_string(),
-            model: model.to_string(),
-            prompt: prompt.to_string(),
+            document_id,
+            model,
+            prompt,
             file_count: files.len(),
             created_at: Utc::now(),
             total_size,
@@ -173,6 +274,88 @@ impl ArtifactManager {
         Ok(metadata)
     }
 
+    /// Store a new artifact with the files extracted from a ZIP archive
+    ///
+    /// Directories are skipped. The archive is rejected if any entry has an
+    /// unsafe path or is not UTF-8 text, or if it has too many files or too
+    /// much content, which is measured as it is extracted rather than trusted
+    /// from the archive's headers.
+    pub fn import_zip(
+        &self,
+        username: &str,
+        document_id: &str,
+        model: &str,
+        prompt: &str,
+        archive: &[u8],
+    ) -> Result<ArtifactMetadata> {
+        if !self.config.enabled {
+            anyhow::bail!("Artifact storage is not enabled");
+        }
+
+        let mut archive =
+            zip::ZipArchive::new(Cursor::new(archive)).context("Invalid ZIP archive")?;
+        let mut files = Vec::new();
+        let mut remaining = self.config.max_import_size;
+        for index in 0..archive.len() {
+            let entry = archive.by_index(index).context("Invalid ZIP archive")?;
+            if entry.is_dir() {
+                continue;
+            }
+            let name = entry.name().to_string();
+            validate_file_name(&name)?;
+            if files.len() == self.config.max_import_files {
+                anyhow::bail!(
+                    "ZIP archive has more than {} files",
+                    self.config.max_import_files
+                );
+            }
+
+            let mut content = Vec::new();
+            entry
+                .take(remaining + 1)
+                .read_to_end(&mut content)
+                .with_context(|| format!("Failed to extract {}", name))?;
+            let size = content.len() as u64;
+            if size > remaining {
+                anyhow::bail!(
+                    "ZIP archive contents exceed {} bytes",
+                    self.config.max_import_size
+                );
+            }
+            remaining -= size;
+
+            let content = String::from_utf8(content)
+                .with_context(|| format!("{} is not a UTF-8 text file", name))?;
+            files.push(ArtifactFile {
+                name,
+                content,
+                size,
+            });
+        }
+
+        self.store_artifact(username, document_id, model, prompt, files)
+    }
+
+    /// Apply the configured length limit to a metadata field
+    fn limit_field(&self, field: &str, value: &str, max_len: usize) -> Result<String> {
+        if value.len() <= max_len {
+            return Ok(value.to_string());
+        }
+        match self.config.oversize_policy {
+            OversizePolicy::Reject => anyhow::bail!(ApiError::PayloadTooLarge(format!(
+                "Artifact {} exceeds maximum length of {} bytes",
+                field, max_len
+            ))),
+            OversizePolicy::Truncate => {
+                let mut end = max_len;
+                while !value.is_char_boundary(end) {
+                    end -= 1;
+                }
+                Ok(value[..end].to_string())
+            }
+        }
+    }
+
     /// List artifacts for a user
     pub fn list_artifacts(&self, username: &str) -> Result<Vec<ArtifactMetadata>> {
         if !self.config.enabled {
@@ -210,13 +393,29 @@ impl ArtifactManager {
 
     /// Get a specific artifact with all its files
     pub fn get_artifact(&self, username: &str, artifact_id: &str) -> Result<Artifact> {
+        self.get_artifact_matching(username, artifact_id, None)
+    }
+
+    /// Get a specific artifact with only the files whose names match a glob
+    ///
+    /// Patterns support `*` for any run of characters and `?` for a single
+    /// character. Files that don't match are never read from disk.
+    pub fn get_artifact_matching(
+        &self,
+        username: &str,
+        artifact_id: &str,
+        pattern: Option<&str>,
+    ) -> Result<Artifact> {
         if !self.config.enabled {
             anyhow::bail!("Artifact storage is not enabled");
         }
+        if let Some(pattern) = pattern {
+            validate_glob(pattern)?;
+        }
 
         let artifact_dir = self.config.storage_dir.join(username).join(artifact_id);
         if !artifact_dir.exists() {
-            anyhow::bail!("Artifact not found");
+            anyhow::bail!(ApiError::NotFound("Artifact not found".into()));
         }
 
         // Load metadata
@@ -224,36 +423,64 @@ impl ArtifactManager {
         let metadata_json = fs::read_to_string(&metadata_path)?;
         let metadata: ArtifactMetadata = serde_json::from_str(&metadata_json)?;
 
-        // Load all files
+        // Load all files, including those in subdirectories
         let mut files = Vec::new();
-        for entry in fs::read_dir(&artifact_dir)? {
-            let entry = entry?;
-            let path = entry.path();
-            
-            // Skip metadata.json
-            if path.file_name().and_then(|n| n.to_str()) == Some("metadata.json") {
+        for (name, path) in artifact_files(&artifact_dir)? {
+            if matches!(pattern, Some(pattern) if !glob_matches(pattern, &name)) {
                 continue;
             }
-
-            if path.is_file() {
-                let name = path
-                    .strip_prefix(&artifact_dir)?
-                    .to_string_lossy()
-                    .to_string();
-                let content = fs::read_to_string(&path)?;
-                let size = content.len() as u64;
-
-                files.push(ArtifactFile {
-                    name,
-                    content,
-                    size,
-                });
-            }
+            let content = fs::read_to_string(&path)?;
+            let size = content.len() as u64;
+
+            files.push(ArtifactFile {
+                name,
+                content,
+                size,
+            });
         }
 
         Ok(Artifact { metadata, files })
     }
 
+    /// Whether a user has an artifact with this ID
+    pub fn has_artifact(&self, username: &str, artifact_id: &str) -> bool {
+        self.config.enabled
+            && validate_artifact_id(artifact_id).is_ok()
+            && self
+                .config
+                .storage_dir
+                .join(username)
+                .join(artifact_id)
+                .join("metadata.json")
+                .is_file()
+    }
+
+    /// Write an artifact's files to a ZIP archive, keeping their nested names
+    ///
+    /// Files are copied from disk one at a time rather than read into memory.
+    pub fn write_zip<W: Write + Seek>(
+        &self,
+        username: &str,
+        artifact_id: &str,
+        writer: W,
+    ) -> Result<W> {
+        if !self.has_artifact(username, artifact_id) {
+            anyhow::bail!(ApiError::NotFound("Artifact not found".into()));
+        }
+        let artifact_dir = self.config.storage_dir.join(username).join(artifact_id);
+        let mut files = artifact_files(&artifact_dir)?;
+        files.sort();
+
+        let mut archive = zip::ZipWriter::new(writer);
+        let options = zip::write::FileOptions::default()
+            .compression_method(zip::CompressionMethod::Deflated);
+        for (name, path) in files {
+            archive.start_file(name, options)?;
+            std::io::copy(&mut fs::File::open(&path)?, &mut archive)?;
+        }
+        Ok(archive.finish()?)
+    }
+
     /// Delete an artifact
     pub fn delete_artifact(&self, username: &str, artifact_id: &str) -> Result<()> {
         if !self.config.enabled {
@@ -262,7 +489,7 @@ impl ArtifactManager {
 
         let artifact_dir = self.config.storage_dir.join(username).join(artifact_id);
         if !artifact_dir.exists() {
-            anyhow::bail!("Artifact not found");
+            anyhow::bail!(ApiError::NotFound("Artifact not found".into()));
         }
 
         fs::remove_dir_all(&artifact_dir)?;
@@ -270,4 +497,166 @@ impl ArtifactManager {
 
         Ok(())
     }
+
+    /// Create a share link for one of a user's artifacts
+    pub fn share_artifact(&self, username: &str, artifact_id: &str) -> Result<ShareLink> {
+        if !self.config.enabled {
+            anyhow::bail!("Artifact storage is not enabled");
+        }
+        validate_artifact_id(artifact_id)?;
+        if !self.config.storage_dir.join(username).join(artifact_id).is_dir() {
+            anyhow::bail!(ApiError::NotFound("Artifact not found".into()));
+        }
+
+        let exp = (Utc::now() + self.config.share_ttl).timestamp();
+        let payload = Self::share_payload(artifact_id, exp);
+        let sig = URL_SAFE_NO_PAD.encode(self.share_signer.sign(&payload));
+        info!("Shared artifact {} for user {} until {}", artifact_id, username, exp);
+
+        Ok(ShareLink {
+            artifact_id: artifact_id.to_string(),
+            exp,
+            sig,
+        })
+    }
+
+    /// Check that a share link is unexpired and was signed by this server
+    pub fn verify_share_link(&self, link: &ShareLink) -> Result<()> {
+        if Utc::now().timestamp() >= link.exp {
+            anyhow::bail!("Share link has expired");
+        }
+        let sig = URL_SAFE_NO_PAD.decode(&link.sig).context("Malformed share link")?;
+        let payload = Self::share_payload(&link.artifact_id, link.exp);
+        if !self.share_signer.verify(&payload, &sig) {
+            anyhow::bail!("Invalid share link signature");
+        }
+        Ok(())
+    }
+
+    /// Get an artifact by ID alone, whichever user owns it
+    ///
+    /// Only for links that have passed [`Self::verify_share_link`].
+    pub fn get_shared_artifact(&self, artifact_id: &str) -> Result<Artifact> {
+        if !self.config.enabled {
+            anyhow::bail!("Artifact storage is not enabled");
+        }
+        validate_artifact_id(artifact_id)?;
+
+        // Artifact IDs are unique, so the owner is whoever has a directory
+        // by that name.
+        for entry in fs::read_dir(&self.config.storage_dir)? {
+            let entry = entry?;
+            if entry.file_type()?.is_dir() && entry.path().join(artifact_id).is_dir() {
+                let username = entry.file_name().to_string_lossy().to_string();
+                return self.get_artifact(&username, artifact_id);
+            }
+        }
+        anyhow::bail!(ApiError::NotFound("Artifact not found".into()))
+    }
+
+    /// The bytes a share link's signature covers
+    fn share_payload(artifact_id: &str, exp: i64) -> Vec<u8> {
+        format!("{}:{}", artifact_id, exp).into_bytes()
+    }
+}
+
+/// Check that a file name stays inside its artifact's directory
+fn validate_file_name(name: &str) -> Result<()> {
+    let path = Path::new(name);
+    let normal = path
+        .components()
+        .all(|component| matches!(component, Component::Normal(_)));
+    if name.is_empty() || name.contains('\\') || !normal {
+        anyhow::bail!("Unsafe artifact file name {:?}", name);
+    }
+    if path == Path::new("metadata.json") {
+        anyhow::bail!("Artifact file name metadata.json is reserved");
+    }
+    Ok(())
+}
+
+/// Every file stored for an artifact, by its name relative to the artifact,
+/// including those in subdirectories but not the metadata
+fn artifact_files(artifact_dir: &Path) -> Result<Vec<(String, PathBuf)>> {
+    let mut files = Vec::new();
+    let mut dirs = vec![artifact_dir.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+
+            // Skip metadata.json
+            if path == artifact_dir.join("metadata.json") {
+                continue;
+            }
+
+            if path.is_dir() {
+                dirs.push(path);
+            } else if path.is_file() {
+                let name = path
+                    .strip_prefix(artifact_dir)?
+                    .to_string_lossy()
+                    .to_string();
+                files.push((name, path));
+            }
+        }
+    }
+    Ok(files)
+}
+
+/// Check that an artifact ID can't escape its user's directory
+fn validate_artifact_id(artifact_id: &str) -> Result<()> {
+    uuid::Uuid::parse_str(artifact_id)
+        .map_err(|_| ApiError::BadRequest("Invalid artifact ID".into()))?;
+    Ok(())
+}
+
+/// Longest glob pattern accepted when filtering artifact files
+const MAX_GLOB_LEN: usize = 256;
+
+/// Check that a file name glob is reasonable before using it
+fn validate_glob(pattern: &str) -> Result<()> {
+    if pattern.is_empty() || pattern.len() > MAX_GLOB_LEN {
+        anyhow::bail!(ApiError::BadRequest(format!(
+            "File pattern must be between 1 and {} bytes",
+            MAX_GLOB_LEN
+        )));
+    }
+    if pattern.chars().any(|c| c.is_control() || c == '/' || c == '\\') {
+        anyhow::bail!(ApiError::BadRequest(
+            "File pattern may not contain path separators or control characters".into()
+        ));
+    }
+    Ok(())
+}
+
+/// Match a name against a glob with `*` and `?` wildcards
+///
+/// Backtracks only to the most recent `*`, so matching takes linear time in
+/// the common case and never more than quadratic.
+fn glob_matches(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    let (mut p, mut n) = (0, 0);
+    let mut star = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, n));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match star {
+                Some((star_p, star_n)) => {
+                    p = star_p + 1;
+                    n = star_n + 1;
+                    star = Some((star_p, star_n + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
 }
@@ -0,0 +1,220 @@
+//! Opt-in log of WebSocket connections and authenticated requests, with the
+//! client's address and, given a GeoIP database, where it is, for spotting
+//! unusual access.
+
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+/// Where connections are logged, and how client addresses are found.
+#[derive(Clone, Debug)]
+pub struct AuditConfig {
+    /// JSON Lines file that entries are appended to.
+    pub path: PathBuf,
+    /// Whether the first address in `X-Forwarded-For` is taken as the
+    /// client's, which is only safe behind a proxy that sets it.
+    pub trust_forwarded_for: bool,
+}
+
+impl AuditConfig {
+    /// Read the configuration from the environment, if `AUDIT_LOG` is set.
+    pub fn from_env() -> Option<Self> {
+        let path = std::env::var("AUDIT_LOG").ok().filter(|s| !s.is_empty())?;
+        let trust_forwarded_for = std::env::var("AUDIT_TRUST_FORWARDED_FOR")
+            .map(|s| s == "true")
+            .unwrap_or(false);
+        Some(Self {
+            path: path.into(),
+            trust_forwarded_for,
+        })
+    }
+}
+
+/// Where an address is, as far as a GeoIP database knows.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Location {
+    /// ISO 3166-1 code of the country.
+    pub country: Option<String>,
+    /// ISO 3166-2 code of the region within the country, without the
+    /// country prefix.
+    pub region: Option<String>,
+}
+
+/// Finds where IP addresses are.
+pub trait GeoLookup: Send + Sync {
+    /// The location of an address, if known.
+    fn locate(&self, ip: IpAddr) -> Option<Location>;
+}
+
+/// A MaxMind-format GeoIP database, such as GeoLite2 City or Country, read
+/// into memory.
+pub struct MaxMindLookup {
+    reader: maxminddb::Reader<Vec<u8>>,
+}
+
+impl MaxMindLookup {
+    /// Read a database file.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let reader = maxminddb::Reader::open_readfile(path)
+            .with_context(|| format!("Failed to read GeoIP database {:?}", path))?;
+        Ok(Self { reader })
+    }
+}
+
+impl GeoLookup for MaxMindLookup {
+    fn locate(&self, ip: IpAddr) -> Option<Location> {
+        // Country databases lack subdivisions, which the City record leaves
+        // unset, so it reads both.
+        let city: maxminddb::geoip2::City = self.reader.lookup(ip).ok()?;
+        Some(Location {
+            country: city
+                .country
+                .and_then(|country| country.iso_code)
+                .map(String::from),
+            region: city
+                .subdivisions
+                .and_then(|subdivisions| subdivisions.into_iter().next())
+                .and_then(|subdivision| subdivision.iso_code)
+                .map(String::from),
+        })
+    }
+}
+
+/// What a logged client did.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum AuditEvent {
+    /// Opened a WebSocket connection to a document.
+    Connect {
+        /// Id of the document.
+        document: String,
+        /// User the connection's session belongs to, if any.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        username: Option<String>,
+    },
+    /// Made an HTTP request with an `Authorization` header.
+    Request {
+        /// HTTP method.
+        method: String,
+        /// Path requested, without the query string.
+        path: String,
+        /// Status code of the response.
+        status: u16,
+    },
+}
+
+/// One line of the audit log.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AuditEntry {
+    /// When the client connected or made the request.
+    pub timestamp: DateTime<Utc>,
+    /// The client's address, if known.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ip: Option<IpAddr>,
+    /// Country of the address, if a GeoIP database knows it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub country: Option<String>,
+    /// Region of the address, if a GeoIP database knows it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub region: Option<String>,
+    /// What the client did.
+    #[serde(flatten)]
+    pub event: AuditEvent,
+}
+
+/// Appends connections to a JSON Lines file.
+pub struct AuditLog {
+    config: AuditConfig,
+    geo: Option<Box<dyn GeoLookup>>,
+    /// Serializes writes, so lines are never interleaved.
+    lock: parking_lot::Mutex<()>,
+}
+
+impl std::fmt::Debug for AuditLog {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AuditLog")
+            .field("config", &self.config)
+            .field("geo", &self.geo.is_some())
+            .finish()
+    }
+}
+
+impl AuditLog {
+    /// Create a log writing to the configured file, logging only addresses.
+    pub fn new(config: AuditConfig) -> Self {
+        Self {
+            config,
+            geo: None,
+            lock: parking_lot::Mutex::new(()),
+        }
+    }
+
+    /// Also log where each address is.
+    pub fn with_geo(mut self, geo: impl GeoLookup + 'static) -> Self {
+        self.geo = Some(Box::new(geo));
+        self
+    }
+
+    /// The client's address, from the peer address or, if trusted, from
+    /// `X-Forwarded-For`.
+    pub fn client_ip(
+        &self,
+        peer: Option<SocketAddr>,
+        forwarded_for: Option<&str>,
+    ) -> Option<IpAddr> {
+        let forwarded = forwarded_for
+            .filter(|_| self.config.trust_forwarded_for)
+            .and_then(|header| header.split(',').next())
+            .and_then(|ip| ip.trim().parse().ok());
+        forwarded.or_else(|| peer.map(|peer| peer.ip()))
+    }
+
+    /// An entry for an event, located if a GeoIP database is configured.
+    pub fn entry(&self, ip: Option<IpAddr>, event: AuditEvent) -> AuditEntry {
+        let location = ip
+            .zip(self.geo.as_ref())
+            .and_then(|(ip, geo)| geo.locate(ip))
+            .unwrap_or_default();
+        AuditEntry {
+            timestamp: Utc::now(),
+            ip,
+            country: location.country,
+            region: location.region,
+            event,
+        }
+    }
+
+    /// Append an entry.
+    pub fn append(&self, entry: &AuditEntry) -> Result<()> {
+        let mut line = serde_json::to_vec(entry)?;
+        line.push(b'\n');
+
+        let _held = self.lock.lock();
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.config.path)
+            .context("Failed to open audit log")?;
+        file.write_all(&line).context("Failed to write audit log")?;
+        Ok(())
+    }
+
+    /// Log an event on the blocking thread pool, warning if it can't be
+    /// written rather than failing what is being logged.
+    pub fn record(self: &Arc<Self>, ip: Option<IpAddr>, event: AuditEvent) {
+        let entry = self.entry(ip, event);
+        let log = Arc::clone(self);
+        tokio::task::spawn_blocking(move || {
+            if let Err(e) = log.append(&entry) {
+                log::warn!("{:#}", e);
+            }
+        });
+    }
+}
@@ -1,18 +1,29 @@
 //! Simple authentication system for file freeze feature.
 
 use anyhow::{bail, Context, Result};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
 use bcrypt::{hash, verify, DEFAULT_COST};
-use log::info;
+use chrono::{DateTime, Utc};
+use log::{info, warn};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::fs;
+use std::net::{IpAddr, SocketAddr};
 use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use crate::ai::RateLimit;
+use crate::error::ApiError;
 
 /// User account information
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct User {
-    /// Username
+    /// Username, in lowercase for accounts registered since usernames
+    /// became case-insensitive
     pub username: String,
+    /// Username as it was typed when registering, if it differs in case
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    display_name: Option<String>,
     /// Hashed password
     password_hash: String,
     /// Creation timestamp
@@ -23,8 +34,112 @@ pub struct User {
     /// Whether this user is an administrator
     #[serde(default)]
     pub is_admin: bool,
+    /// Chat rate limits for this user, overriding the server defaults
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ai_rate_limit: Option<RateLimit>,
+    /// AI tokens used during `usage_period`
+    #[serde(default)]
+    pub token_usage_this_period: u64,
+    /// Month the token usage was counted in, as `YYYY-MM`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub usage_period: Option<String>,
+    /// Revoked session ids, kept until the session would have expired, so
+    /// their access tokens stay rejected after a restart
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    revoked_sessions: HashMap<String, DateTime<Utc>>,
 }
 
+impl User {
+    /// Username to show, in the case it was registered with
+    pub fn display_name(&self) -> &str {
+        self.display_name.as_deref().unwrap_or(&self.username)
+    }
+
+    /// Tokens used in the current month, which is zero once a new month starts
+    pub fn tokens_used(&self) -> u64 {
+        if self.usage_period.as_deref() == Some(current_usage_period().as_str()) {
+            self.token_usage_this_period
+        } else {
+            0
+        }
+    }
+}
+
+/// The form of a username used as its key, so names differing only in case
+/// are the same user, even on case-sensitive filesystems
+pub(crate) fn normalize_username(username: &str) -> String {
+    username.to_lowercase()
+}
+
+/// The month that AI token usage is currently counted against, as `YYYY-MM`
+pub fn current_usage_period() -> String {
+    Utc::now().format("%Y-%m").to_string()
+}
+
+/// A server-side login session
+#[derive(Debug, Clone, Serialize)]
+pub struct Session {
+    /// Opaque session identifier
+    pub id: String,
+    /// Username the session belongs to
+    pub username: String,
+    /// Creation timestamp
+    pub created_at: DateTime<Utc>,
+    /// Expiry timestamp, after which the session is no longer valid
+    pub expires_at: DateTime<Utc>,
+    /// When the session was last used to authenticate
+    pub last_seen: DateTime<Utc>,
+    /// User agent of the client that logged in, if known
+    pub user_agent: Option<String>,
+    /// Address of the client that logged in, if known
+    pub remote_addr: Option<String>,
+}
+
+/// Details of the client that a session is created for
+#[derive(Debug, Clone, Default)]
+pub struct SessionClient {
+    /// User agent reported by the client
+    pub user_agent: Option<String>,
+    /// Network address of the client
+    pub remote_addr: Option<String>,
+}
+
+/// Claims carried by a signed access token
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TokenClaims {
+    /// Username the token was issued to
+    pub sub: String,
+    /// Whether the user was an administrator when the token was issued
+    pub is_admin: bool,
+    /// Whether AI features were enabled when the token was issued
+    pub ai_enabled: bool,
+    /// Issue time, in seconds since the Unix epoch
+    pub iat: i64,
+    /// Expiry time, in seconds since the Unix epoch
+    pub exp: i64,
+    /// Id of the session backing the token, so it can be revoked
+    pub jti: String,
+}
+
+/// Header of every token issued by [`AuthManager::issue_token`]
+const TOKEN_HEADER: &str = r#"{"alg":"HS256","typ":"JWT"}"#;
+
+/// Lowest bcrypt cost accepted for password hashes
+const MIN_COST: u32 = 4;
+
+/// Highest bcrypt cost accepted for password hashes
+const MAX_COST: u32 = 31;
+
+/// Usernames reserved when `RESERVED_USERNAMES` is not set
+const DEFAULT_RESERVED_USERNAMES: &[&str] = &[
+    "admin",
+    "administrator",
+    "api",
+    "root",
+    "support",
+    "system",
+];
+
 /// Configuration for authentication
 #[derive(Debug, Clone)]
 pub struct AuthConfig {
@@ -32,6 +147,32 @@ pub struct AuthConfig {
     pub enabled: bool,
     /// Directory where user data is stored
     pub data_dir: PathBuf,
+    /// How long a session stays valid after it is created
+    pub session_ttl: Duration,
+    /// How often expired sessions are swept from memory
+    pub session_cleanup_interval: Duration,
+    /// Secret for signing access tokens, generated at startup if not set
+    pub jwt_secret: Option<String>,
+    /// Failed logins allowed within the lockout window before locking out
+    pub max_login_attempts: u32,
+    /// Window over which failed logins are counted, and how long a lockout lasts
+    pub login_lockout_window: Duration,
+    /// Also lock out client addresses that fail too many logins, which
+    /// behind a proxy needs `trust_forwarded_for` to tell clients apart
+    pub lockout_by_address: bool,
+    /// Take the client's address from the first entry of `X-Forwarded-For`,
+    /// which is only safe behind a proxy that sets it
+    pub trust_forwarded_for: bool,
+    /// AI tokens each user may use per calendar month, if limited
+    pub monthly_token_limit: Option<u32>,
+    /// Bcrypt cost for new password hashes, from 4 to 31; each step doubles
+    /// the time to hash and verify a password
+    pub bcrypt_cost: u32,
+    /// Usernames nobody may register, in any case
+    pub reserved_usernames: Vec<String>,
+    /// Answer registrations of taken usernames as if they succeeded, so
+    /// they can't be used to find out which usernames exist
+    pub hide_existing_usernames: bool,
 }
 
 impl Default for AuthConfig {
@@ -39,6 +180,17 @@ impl Default for AuthConfig {
         Self {
             enabled: false,
             data_dir: PathBuf::from("./frozen_documents/users"),
+            session_ttl: Duration::from_secs(24 * 3600),
+            session_cleanup_interval: Duration::from_secs(3600),
+            jwt_secret: None,
+            max_login_attempts: 5,
+            login_lockout_window: Duration::from_secs(15 * 60),
+            lockout_by_address: false,
+            trust_forwarded_for: false,
+            monthly_token_limit: None,
+            bcrypt_cost: DEFAULT_COST,
+            reserved_usernames: Vec::new(),
+            hide_existing_usernames: false,
         }
     }
 }
@@ -46,11 +198,143 @@ impl Default for AuthConfig {
 impl AuthConfig {
     /// Create config from environment and freeze config
     pub fn from_env(freeze_enabled: bool, save_dir: &PathBuf) -> Self {
+        let session_ttl_hours: u64 = std::env::var("SESSION_TTL_HOURS")
+            .unwrap_or_else(|_| String::from("24"))
+            .parse()
+            .unwrap_or(24);
+
+        let session_cleanup_minutes: u64 = std::env::var("SESSION_CLEANUP_MINUTES")
+            .unwrap_or_else(|_| String::from("60"))
+            .parse()
+            .unwrap_or(60);
+
+        let max_login_attempts: u32 = std::env::var("MAX_LOGIN_ATTEMPTS")
+            .unwrap_or_else(|_| String::from("5"))
+            .parse()
+            .unwrap_or(5);
+
+        let login_lockout_minutes: u64 = std::env::var("LOGIN_LOCKOUT_MINUTES")
+            .unwrap_or_else(|_| String::from("15"))
+            .parse()
+            .unwrap_or(15);
+
+        let bcrypt_cost: u32 = std::env::var("BCRYPT_COST")
+            .map(|s| s.parse().expect("Unable to parse BCRYPT_COST"))
+            .unwrap_or(DEFAULT_COST);
+
+        let reserved_usernames = match std::env::var("RESERVED_USERNAMES") {
+            Ok(names) => names
+                .split(',')
+                .map(|name| normalize_username(name.trim()))
+                .filter(|name| !name.is_empty())
+                .collect(),
+            Err(_) => DEFAULT_RESERVED_USERNAMES.iter().map(|name| name.to_string()).collect(),
+        };
+
         Self {
             enabled: freeze_enabled,
             data_dir: save_dir.join("users"),
+            session_ttl: Duration::from_secs(session_ttl_hours * 3600),
+            session_cleanup_interval: Duration::from_secs(session_cleanup_minutes.max(1) * 60),
+            jwt_secret: std::env::var("JWT_SECRET").ok().filter(|s| !s.is_empty()),
+            max_login_attempts: max_login_attempts.max(1),
+            login_lockout_window: Duration::from_secs(login_lockout_minutes * 60),
+            lockout_by_address: std::env::var("LOCKOUT_BY_ADDRESS")
+                .map(|s| s == "true")
+                .unwrap_or(false),
+            trust_forwarded_for: std::env::var("AUTH_TRUST_FORWARDED_FOR")
+                .map(|s| s == "true")
+                .unwrap_or(false),
+            monthly_token_limit: std::env::var("AI_MONTHLY_TOKEN_LIMIT")
+                .ok()
+                .and_then(|s| s.parse().ok()),
+            bcrypt_cost,
+            reserved_usernames,
+            hide_existing_usernames: std::env::var("HIDE_EXISTING_USERNAMES")
+                .map(|s| s == "true")
+                .unwrap_or(false),
+        }
+    }
+}
+
+/// Failed login attempts against one username or source address
+#[derive(Debug, Clone, Copy)]
+struct FailedLogins {
+    /// Number of consecutive failures
+    count: u32,
+    /// When the first failure in the current window happened
+    first_failure: Instant,
+}
+
+/// Check a new password against the rules for registering
+fn validate_password(password: &str) -> Result<()> {
+    if password.len() < 6 {
+        bail!(ApiError::BadRequest("Password must be at least 6 characters".into()));
+    }
+    Ok(())
+}
+
+/// Order of users when listing them
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UserSort {
+    /// By normalized username
+    #[default]
+    Username,
+    /// Oldest accounts first, then by username
+    CreatedAt,
+}
+
+/// One page of the registered users
+#[derive(Debug, Clone, Default)]
+pub struct UserPage {
+    /// Users on this page
+    pub users: Vec<User>,
+    /// Number of users in total
+    pub total: usize,
+}
+
+/// When the user stored in a file registered, read without the rest of the
+/// user, or `None` if the file has no valid timestamp
+fn created_at(path: &std::path::Path) -> Option<DateTime<chrono::FixedOffset>> {
+    #[derive(Deserialize)]
+    struct Created {
+        created_at: String,
+    }
+    let content = fs::read_to_string(path).ok()?;
+    let created: Created = serde_json::from_str(&content).ok()?;
+    DateTime::parse_from_rfc3339(&created.created_at).ok()
+}
+
+/// Rename user files written before usernames were case-insensitive, so they
+/// are found under their normalized names
+///
+/// The username stored in each file is kept, since documents and artifacts
+/// may be owned under it. A file whose normalized name is already taken is
+/// left alone, with a warning.
+fn normalize_user_files(data_dir: &PathBuf) -> Result<()> {
+    for entry in fs::read_dir(data_dir).context("Failed to read auth data directory")? {
+        let path = entry?.path();
+        if path.extension().and_then(|e| e.to_str()) != Some("json") {
+            continue;
+        }
+        let stem = match path.file_stem().and_then(|s| s.to_str()) {
+            Some(stem) => stem,
+            None => continue,
+        };
+        let key = normalize_username(stem);
+        if key == stem {
+            continue;
+        }
+        let target = data_dir.join(format!("{}.json", key));
+        if target.exists() {
+            warn!("User file {:?} clashes with {:?} when ignoring case, skipping", path, target);
+            continue;
         }
+        fs::rename(&path, &target).context("Failed to rename user file")?;
+        info!("Renamed user file {:?} to {:?}", path, target);
     }
+    Ok(())
 }
 
 /// Manager for user authentication
@@ -58,24 +342,59 @@ impl AuthConfig {
 pub struct AuthManager {
     config: AuthConfig,
     users_cache: parking_lot::RwLock<HashMap<String, User>>,
+    sessions: parking_lot::RwLock<HashMap<String, Session>>,
+    /// Key for signing access tokens
+    token_signer: Signer,
+    /// Recent failed logins, keyed by username and by source address
+    failed_logins: parking_lot::RwLock<HashMap<String, FailedLogins>>,
 }
 
 impl AuthManager {
     /// Create a new auth manager
     pub fn new(config: AuthConfig) -> Result<Self> {
+        if !(MIN_COST..=MAX_COST).contains(&config.bcrypt_cost) {
+            bail!(
+                "bcrypt cost {} is outside the supported range of {} to {}",
+                config.bcrypt_cost,
+                MIN_COST,
+                MAX_COST
+            );
+        }
         if config.enabled {
             fs::create_dir_all(&config.data_dir)
                 .context("Failed to create auth data directory")?;
+            normalize_user_files(&config.data_dir)?;
             info!("Authentication enabled, data directory: {:?}", config.data_dir);
         }
 
+        let token_signer = match &config.jwt_secret {
+            Some(secret) => Signer::new(secret),
+            None => {
+                if config.enabled {
+                    warn!(
+                        "JWT_SECRET not set, generated a random signing key; \
+                         access tokens will not survive a restart"
+                    );
+                }
+                Signer::random()
+            }
+        };
+
         Ok(Self {
             config,
+            token_signer,
             users_cache: parking_lot::RwLock::new(HashMap::new()),
+            sessions: parking_lot::RwLock::new(HashMap::new()),
+            failed_logins: parking_lot::RwLock::new(HashMap::new()),
         })
     }
 
     /// Register a new user
+    ///
+    /// With `hide_existing_usernames`, a taken username returns the user that
+    /// would have been registered without saving it, so the caller sees the
+    /// same answer as for a new one. The password is hashed either way, so
+    /// the time taken doesn't tell them apart.
     pub fn register(&self, username: &str, password: &str, ai_enabled: bool, is_admin: bool) -> Result<User> {
         if !self.config.enabled {
             bail!("Authentication feature is not enabled");
@@ -83,62 +402,122 @@ impl AuthManager {
 
         // Validate username
         if username.is_empty() || username.len() < 3 {
-            bail!("Username must be at least 3 characters");
+            bail!(ApiError::BadRequest("Username must be at least 3 characters".into()));
         }
         if !username.chars().all(|c| c.is_alphanumeric() || c == '_' || c == '-') {
-            bail!("Username can only contain letters, numbers, underscores, and hyphens");
+            bail!(ApiError::BadRequest(
+                "Username can only contain letters, numbers, underscores, and hyphens".into()
+            ));
         }
 
-        // Validate password
-        if password.len() < 6 {
-            bail!("Password must be at least 6 characters");
+        let key = normalize_username(username);
+        if self.config.reserved_usernames.iter().any(|name| normalize_username(name) == key) {
+            bail!(ApiError::BadRequest("Username is reserved".into()));
         }
 
-        // Check if user already exists
-        if self.user_exists(username)? {
-            bail!("Username already exists");
-        }
+        validate_password(password)?;
 
-        // Hash password
-        let password_hash = hash(password, DEFAULT_COST)
+        // Hash password before looking the user up, so taken usernames are
+        // answered no faster than new ones
+        let password_hash = hash(password, self.config.bcrypt_cost)
             .context("Failed to hash password")?;
 
+        // Check if user already exists, in any case
+        let exists = self.user_exists(&key)?;
+        if exists && !self.config.hide_existing_usernames {
+            bail!(ApiError::Conflict("Username already exists".into()));
+        }
+
         let user = User {
-            username: username.to_string(),
+            username: key.clone(),
+            display_name: Some(username.to_string()).filter(|name| *name != key),
             password_hash,
             created_at: chrono::Utc::now().to_rfc3339(),
             ai_enabled,
             is_admin,
+            ai_rate_limit: None,
+            token_usage_this_period: 0,
+            usage_period: None,
+            revoked_sessions: HashMap::new(),
         };
+        if exists {
+            info!("Registration of existing user {} hidden", username);
+            return Ok(user);
+        }
 
         // Save user
         self.save_user(&user)?;
 
         // Cache user (without password hash in response)
         let mut cache = self.users_cache.write();
-        cache.insert(username.to_string(), user.clone());
+        cache.insert(key, user.clone());
 
         info!("User registered: {}", username);
 
         Ok(user)
     }
 
+    /// The client's address, from the peer address or, if trusted, from
+    /// `X-Forwarded-For`.
+    pub fn client_addr(
+        &self,
+        peer: Option<SocketAddr>,
+        forwarded_for: Option<&str>,
+    ) -> Option<IpAddr> {
+        let forwarded = forwarded_for
+            .filter(|_| self.config.trust_forwarded_for)
+            .and_then(|header| header.split(',').next())
+            .and_then(|ip| ip.trim().parse().ok());
+        forwarded.or_else(|| peer.map(|peer| peer.ip()))
+    }
+
     /// Authenticate a user
-    pub fn login(&self, username: &str, password: &str) -> Result<User> {
+    ///
+    /// Repeated failures lock out the username, and with `lockout_by_address`
+    /// the source address when one is given, until the lockout window has
+    /// passed.
+    pub fn login(&self, username: &str, password: &str, remote_addr: Option<&str>) -> Result<User> {
         if !self.config.enabled {
             bail!("Authentication feature is not enabled");
         }
 
+        let remote_addr = remote_addr.filter(|_| self.config.lockout_by_address);
+        let keys: Vec<String> = std::iter::once(format!("user:{}", normalize_username(username)))
+            .chain(remote_addr.map(|addr| format!("addr:{}", addr)))
+            .collect();
+
+        // Refuse locked out attempts before doing any expensive hashing
+        if let Some(remaining) = keys.iter().filter_map(|key| self.lockout_remaining(key)).max() {
+            bail!(ApiError::TooManyRequests(format!(
+                "Account temporarily locked, try again in {} seconds",
+                remaining.as_secs().max(1)
+            )));
+        }
+
         // Load user
-        let user = self.load_user(username)
-            .context("Invalid username or password")?;
+        let user = match self.load_user(username) {
+            Ok(user) => user,
+            Err(err) => {
+                self.record_failed_login(&keys);
+                let message = "Invalid username or password".to_string();
+                return Err(err.context(ApiError::Unauthorized(message)));
+            }
+        };
 
         // Verify password
         let valid = verify(password, &user.password_hash)
             .context("Failed to verify password")?;
 
         if !valid {
-            bail!("Invalid username or password");
+            self.record_failed_login(&keys);
+            bail!(ApiError::Unauthorized("Invalid username or password".into()));
+        }
+
+        {
+            let mut failed = self.failed_logins.write();
+            for key in &keys {
+                failed.remove(key);
+            }
         }
 
         info!("User logged in: {}", username);
@@ -146,58 +525,146 @@ impl AuthManager {
         Ok(user)
     }
 
+    /// Change a user's password, given their current one
+    ///
+    /// The current password is checked like a login, so failures count
+    /// towards a lockout. The new one must meet the rules for registering.
+    pub fn change_password(&self, username: &str, old_password: &str, new_password: &str) -> Result<()> {
+        let user = self.login(username, old_password, None)?;
+        validate_password(new_password)?;
+        let password_hash = hash(new_password, self.config.bcrypt_cost)
+            .context("Failed to hash password")?;
+
+        self.update_user(username, |current| {
+            // Hashing is slow, so it happens before taking the lock; make sure
+            // the password checked is still the one being replaced.
+            if current.password_hash != user.password_hash {
+                bail!(ApiError::Conflict("Password was changed by another request".into()));
+            }
+            current.password_hash = password_hash;
+            Ok(())
+        })?;
+
+        info!("Changed password for user {}", username);
+        Ok(())
+    }
+
+    /// How much longer a username or address is locked out, if it is
+    fn lockout_remaining(&self, key: &str) -> Option<Duration> {
+        let failed = *self.failed_logins.read().get(key)?;
+        if failed.count < self.config.max_login_attempts {
+            return None;
+        }
+        self.config
+            .login_lockout_window
+            .checked_sub(failed.first_failure.elapsed())
+            .filter(|remaining| !remaining.is_zero())
+    }
+
+    /// Count a failed login against each of the given keys
+    fn record_failed_login(&self, keys: &[String]) {
+        let now = Instant::now();
+        let mut failed = self.failed_logins.write();
+        for key in keys {
+            let entry = failed.entry(key.clone()).or_insert(FailedLogins {
+                count: 0,
+                first_failure: now,
+            });
+            if now.duration_since(entry.first_failure) >= self.config.login_lockout_window {
+                *entry = FailedLogins {
+                    count: 0,
+                    first_failure: now,
+                };
+            }
+            entry.count += 1;
+            if entry.count == self.config.max_login_attempts {
+                warn!("Too many failed logins for {}, locking out", key);
+            }
+        }
+    }
+
     /// Check if a user exists
     fn user_exists(&self, username: &str) -> Result<bool> {
+        let key = normalize_username(username);
+
         // Check cache first
         {
             let cache = self.users_cache.read();
-            if cache.contains_key(username) {
+            if cache.contains_key(&key) {
                 return Ok(true);
             }
         }
 
         // Check filesystem
-        let user_file = self.config.data_dir.join(format!("{}.json", username));
-        Ok(user_file.exists())
+        Ok(self.user_file(&key).exists())
     }
 
     /// Load user from disk
     fn load_user(&self, username: &str) -> Result<User> {
+        let key = normalize_username(username);
+
         // Check cache first
         {
             let cache = self.users_cache.read();
-            if let Some(user) = cache.get(username) {
+            if let Some(user) = cache.get(&key) {
                 return Ok(user.clone());
             }
         }
 
-        // Load from disk
-        let user_file = self.config.data_dir.join(format!("{}.json", username));
+        let user = self.read_user_file(&key)?;
+
+        // Update cache
+        let mut cache = self.users_cache.write();
+        cache.insert(key, user.clone());
+
+        Ok(user)
+    }
+
+    /// Load a user from disk, bypassing the cache
+    fn read_user_file(&self, username: &str) -> Result<User> {
+        let user_file = self.user_file(username);
         if !user_file.exists() {
-            bail!("User not found");
+            bail!(ApiError::NotFound("User not found".into()));
         }
 
         let content = fs::read_to_string(&user_file)
             .context("Failed to read user file")?;
-        let user: User = serde_json::from_str(&content)
-            .context("Failed to parse user data")?;
+        serde_json::from_str(&content).context("Failed to parse user data")
+    }
 
-        // Update cache
+    /// Change a user and save the result
+    ///
+    /// The cache lock is held from reading the user until it is written back,
+    /// so concurrent updates to the same user can't undo each other.
+    fn update_user<T>(&self, username: &str, update: impl FnOnce(&mut User) -> Result<T>) -> Result<T> {
+        let key = normalize_username(username);
         let mut cache = self.users_cache.write();
-        cache.insert(username.to_string(), user.clone());
-
-        Ok(user)
+        let mut user = match cache.get(&key) {
+            Some(user) => user.clone(),
+            None => self.read_user_file(&key)?,
+        };
+        let result = update(&mut user)?;
+        self.save_user(&user)?;
+        cache.insert(key, user);
+        Ok(result)
     }
 
     /// Save user to disk
     fn save_user(&self, user: &User) -> Result<()> {
-        let user_file = self.config.data_dir.join(format!("{}.json", user.username));
+        let user_file = self.user_file(&user.username);
         let user_json = serde_json::to_string_pretty(user)?;
         fs::write(&user_file, user_json)
             .context("Failed to write user file")?;
         Ok(())
     }
 
+    /// The file a user is stored in, named after their normalized username
+    fn user_file(&self, username: &str) -> PathBuf {
+        self.config
+            .data_dir
+            .join(format!("{}.json", normalize_username(username)))
+    }
+
     /// Validate a username (without password)
     pub fn validate_user(&self, username: &str) -> Result<bool> {
         if !self.config.enabled {
@@ -206,27 +673,53 @@ impl AuthManager {
         self.user_exists(username)
     }
 
-    /// List all users (admin only)
-    pub fn list_users(&self) -> Result<Vec<User>> {
+    /// List a page of users, and how many there are in all (admin only)
+    ///
+    /// Users are stored one per file, named after their normalized username,
+    /// so sorting by username reads only the users on the page. Sorting by
+    /// creation time reads just the timestamp of every user first. Files that
+    /// can't be read as users are counted but left out of the page.
+    pub fn list_users(&self, sort: UserSort, offset: usize, limit: usize) -> Result<UserPage> {
         if !self.config.enabled {
-            return Ok(Vec::new());
+            return Ok(UserPage::default());
         }
 
-        let mut users = Vec::new();
+        let mut files = Vec::new();
         for entry in fs::read_dir(&self.config.data_dir)? {
-            let entry = entry?;
-            let path = entry.path();
-            if path.extension().and_then(|e| e.to_str()) == Some("json") {
-                let content = fs::read_to_string(&path)?;
-                if let Ok(user) = serde_json::from_str::<User>(&content) {
-                    users.push(user);
-                }
+            let path = entry?.path();
+            if path.extension().and_then(|e| e.to_str()) != Some("json") {
+                continue;
+            }
+            if let Some(stem) = path.file_stem().and_then(|s| s.to_str()) {
+                files.push((stem.to_string(), path));
+            }
+        }
+        match sort {
+            UserSort::Username => files.sort(),
+            UserSort::CreatedAt => {
+                let mut stamped: Vec<_> = files
+                    .into_iter()
+                    .map(|(stem, path)| (created_at(&path), stem, path))
+                    .collect();
+                stamped.sort();
+                files = stamped
+                    .into_iter()
+                    .map(|(_, stem, path)| (stem, path))
+                    .collect();
             }
         }
 
-        // Sort by username
-        users.sort_by(|a, b| a.username.cmp(&b.username));
-        Ok(users)
+        let total = files.len();
+        let users = files
+            .into_iter()
+            .skip(offset)
+            .take(limit)
+            .filter_map(|(_, path)| {
+                let content = fs::read_to_string(&path).ok()?;
+                serde_json::from_str::<User>(&content).ok()
+            })
+            .collect();
+        Ok(UserPage { users, total })
     }
 
     /// Update user's AI access (admin only)
@@ -235,36 +728,287 @@ impl AuthManager {
             anyhow::bail!("Authentication feature is not enabled");
         }
 
-        let mut user = self.load_user(username)?;
-        user.ai_enabled = ai_enabled;
-        self.save_user(&user)?;
-
-        // Update cache
-        let mut cache = self.users_cache.write();
-        cache.insert(username.to_string(), user);
+        self.update_user(username, |user| {
+            user.ai_enabled = ai_enabled;
+            Ok(())
+        })?;
 
         info!("Updated AI access for user {}: {}", username, ai_enabled);
         Ok(())
     }
 
+    /// Override a user's AI rate limits, or restore the defaults with `None` (admin only)
+    pub fn update_ai_rate_limit(&self, username: &str, limit: Option<RateLimit>) -> Result<()> {
+        if !self.config.enabled {
+            anyhow::bail!("Authentication feature is not enabled");
+        }
+        if let Some(limit) = &limit {
+            limit.validate()?;
+        }
+
+        self.update_user(username, |user| {
+            user.ai_rate_limit = limit;
+            Ok(())
+        })?;
+
+        info!("Updated AI rate limit for user {}: {:?}", username, limit);
+        Ok(())
+    }
+
+    /// Add AI tokens to a user's usage for the current month
+    pub fn record_usage(&self, username: &str, tokens: u32) -> Result<()> {
+        if !self.config.enabled {
+            anyhow::bail!("Authentication feature is not enabled");
+        }
+
+        self.update_user(username, |user| {
+            user.token_usage_this_period = user.tokens_used().saturating_add(tokens.into());
+            user.usage_period = Some(current_usage_period());
+            Ok(())
+        })
+    }
+
+    /// AI tokens a user has used this month
+    pub fn token_usage(&self, username: &str) -> Result<u64> {
+        Ok(self.load_user(username)?.tokens_used())
+    }
+
+    /// AI tokens a user has left this month, or `None` if usage is unlimited
+    pub fn remaining_quota(&self, username: &str) -> Option<u32> {
+        let limit = self.config.monthly_token_limit?;
+        let used = self.token_usage(username).unwrap_or(0);
+        Some(u64::from(limit).saturating_sub(used) as u32)
+    }
+
+    /// The monthly AI token limit for each user, if there is one
+    pub fn monthly_token_limit(&self) -> Option<u32> {
+        self.config.monthly_token_limit
+    }
+
+    /// Clear a user's AI token usage for the current month (admin only)
+    pub fn reset_usage(&self, username: &str) -> Result<()> {
+        if !self.config.enabled {
+            anyhow::bail!("Authentication feature is not enabled");
+        }
+
+        self.update_user(username, |user| {
+            user.token_usage_this_period = 0;
+            user.usage_period = None;
+            Ok(())
+        })?;
+
+        info!("Reset AI token usage for user {}", username);
+        Ok(())
+    }
+
     /// Delete a user (admin only)
     pub fn delete_user(&self, username: &str) -> Result<()> {
         if !self.config.enabled {
             anyhow::bail!("Authentication feature is not enabled");
         }
 
-        let user_file = self.config.data_dir.join(format!("{}.json", username));
+        let user_file = self.user_file(username);
         if !user_file.exists() {
-            anyhow::bail!("User not found");
+            bail!(ApiError::NotFound("User not found".into()));
         }
 
         fs::remove_file(&user_file)?;
 
         // Remove from cache
         let mut cache = self.users_cache.write();
-        cache.remove(username);
+        cache.remove(&normalize_username(username));
 
         info!("Deleted user: {}", username);
         Ok(())
     }
+
+    /// Start a new session for a user
+    pub fn create_session(&self, username: &str) -> Result<Session> {
+        self.create_session_for(username, SessionClient::default())
+    }
+
+    /// Start a new session for a user, recording which client it belongs to
+    pub fn create_session_for(&self, username: &str, client: SessionClient) -> Result<Session> {
+        if !self.config.enabled {
+            bail!("Authentication feature is not enabled");
+        }
+
+        let created_at = Utc::now();
+        let ttl = chrono::Duration::from_std(self.config.session_ttl)
+            .context("Session lifetime is out of range")?;
+        let session = Session {
+            id: uuid::Uuid::new_v4().to_string(),
+            username: username.to_string(),
+            created_at,
+            expires_at: created_at + ttl,
+            last_seen: created_at,
+            user_agent: client.user_agent,
+            remote_addr: client.remote_addr,
+        };
+
+        let mut sessions = self.sessions.write();
+        sessions.insert(session.id.clone(), session.clone());
+
+        Ok(session)
+    }
+
+    /// Look up a session, rejecting it if it has expired or been revoked
+    pub fn get_session(&self, session_id: &str) -> Result<Session> {
+        let mut sessions = self.sessions.write();
+        let session = sessions
+            .get_mut(session_id)
+            .ok_or_else(|| ApiError::Unauthorized("Session not found".into()))?;
+        let now = Utc::now();
+        if session.expires_at <= now {
+            bail!(ApiError::Unauthorized("Session expired".into()));
+        }
+        session.last_seen = now;
+        Ok(session.clone())
+    }
+
+    /// List a user's active sessions, oldest first
+    pub fn list_sessions(&self, username: &str) -> Vec<Session> {
+        let now = Utc::now();
+        let sessions = self.sessions.read();
+        let mut list: Vec<Session> = sessions
+            .values()
+            .filter(|session| session.username == username && session.expires_at > now)
+            .cloned()
+            .collect();
+        list.sort_by_key(|session| session.created_at);
+        list
+    }
+
+    /// Revoke one of a user's sessions so that it can no longer be used
+    pub fn revoke_session(&self, username: &str, session_id: &str) -> Result<()> {
+        let session = {
+            let mut sessions = self.sessions.write();
+            match sessions.get(session_id) {
+                Some(session) if session.username == username => {
+                    sessions.remove(session_id).expect("session exists")
+                }
+                _ => bail!(ApiError::NotFound("Session not found".into())),
+            }
+        };
+        self.remember_revoked(username, vec![session])?;
+        Ok(())
+    }
+
+    /// Revoke all of a user's sessions, returning how many were revoked
+    pub fn revoke_all_sessions(&self, username: &str) -> Result<usize> {
+        let mut revoked = Vec::new();
+        self.sessions.write().retain(|_, session| {
+            if session.username == username {
+                revoked.push(session.clone());
+                false
+            } else {
+                true
+            }
+        });
+        let count = revoked.len();
+        self.remember_revoked(username, revoked)?;
+        Ok(count)
+    }
+
+    /// Record revoked sessions with their user, so tokens issued for them
+    /// are still rejected after a restart
+    ///
+    /// Entries for sessions that have since expired are dropped, as their
+    /// tokens are rejected anyway.
+    fn remember_revoked(&self, username: &str, sessions: Vec<Session>) -> Result<()> {
+        if sessions.is_empty() {
+            return Ok(());
+        }
+        let now = Utc::now();
+        self.update_user(username, |user| {
+            let revoked = &mut user.revoked_sessions;
+            revoked.retain(|_, expires_at| *expires_at > now);
+            for session in sessions {
+                revoked.insert(session.id, session.expires_at);
+            }
+            Ok(())
+        })
+    }
+
+    /// Issue a signed access token for a user, backed by a new session
+    pub fn issue_token(&self, user: &User) -> Result<String> {
+        let session = self.create_session(&user.username)?;
+        self.session_token(user, &session)
+    }
+
+    /// Sign an access token for an existing session of a user
+    pub fn session_token(&self, user: &User, session: &Session) -> Result<String> {
+        let claims = TokenClaims {
+            sub: user.username.clone(),
+            is_admin: user.is_admin,
+            ai_enabled: user.ai_enabled,
+            iat: session.created_at.timestamp(),
+            exp: session.expires_at.timestamp(),
+            jti: session.id.clone(),
+        };
+        let payload = format!(
+            "{}.{}",
+            URL_SAFE_NO_PAD.encode(TOKEN_HEADER),
+            URL_SAFE_NO_PAD.encode(serde_json::to_vec(&claims)?),
+        );
+        let signature = URL_SAFE_NO_PAD.encode(self.token_signer.sign(payload.as_bytes()));
+        Ok(format!("{}.{}", payload, signature))
+    }
+
+    /// Verify an access token, returning the user it was issued to
+    ///
+    /// Tokens are rejected once expired, once their session is revoked, or
+    /// if the user no longer exists.
+    pub fn verify_token(&self, token: &str) -> Result<User> {
+        if !self.config.enabled {
+            bail!("Authentication feature is not enabled");
+        }
+
+        let (payload, signature) = token.rsplit_once('.').context("Malformed token")?;
+        let signature = URL_SAFE_NO_PAD.decode(signature).context("Malformed token")?;
+        if !self.token_signer.verify(payload.as_bytes(), &signature) {
+            bail!("Invalid token signature");
+        }
+
+        let (header, claims) = payload.split_once('.').context("Malformed token")?;
+        let header = URL_SAFE_NO_PAD.decode(header).context("Malformed token")?;
+        if header != TOKEN_HEADER.as_bytes() {
+            bail!("Unsupported token header");
+        }
+        let claims: TokenClaims = serde_json::from_slice(
+            &URL_SAFE_NO_PAD.decode(claims).context("Malformed token")?,
+        )
+        .context("Malformed token claims")?;
+
+        if claims.exp <= Utc::now().timestamp() {
+            bail!("Token expired");
+        }
+        let user = self.load_user(&claims.sub).context("User not found")?;
+        if user.revoked_sessions.contains_key(&claims.jti) {
+            bail!("Token revoked");
+        }
+        if let Some(session) = self.sessions.write().get_mut(&claims.jti) {
+            session.last_seen = Utc::now();
+        }
+
+        Ok(user)
+    }
+
+    /// Remove expired sessions, returning how many were removed
+    pub fn prune_expired_sessions(&self) -> usize {
+        let now = Utc::now();
+        let mut sessions = self.sessions.write();
+        let original_len = sessions.len();
+        sessions.retain(|_, session| session.expires_at > now);
+        let window = self.config.login_lockout_window;
+        self.failed_logins
+            .write()
+            .retain(|_, failed| failed.first_failure.elapsed() < window);
+        original_len - sessions.len()
+    }
+
+    /// How often expired sessions should be swept
+    pub fn session_cleanup_interval(&self) -> Duration {
+        self.config.session_cleanup_interval
+    }
 }
@@ -0,0 +1,96 @@
+//! Periodic backups of the document database.
+
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+use log::{error, info, warn};
+
+use crate::database::Database;
+
+const BACKUP_PREFIX: &str = "rustpad-";
+const BACKUP_SUFFIX: &str = ".db";
+
+/// Where and how often the database is backed up.
+#[derive(Clone, Debug)]
+pub struct BackupConfig {
+    /// Directory that backups are written to.
+    pub dir: PathBuf,
+    /// Time between backups.
+    pub interval: Duration,
+    /// Number of most recent backups kept; older ones are deleted.
+    pub retain: usize,
+}
+
+impl BackupConfig {
+    /// Read the configuration from the environment, if `BACKUP_DIR` is set.
+    pub fn from_env() -> Option<Self> {
+        let dir = std::env::var("BACKUP_DIR").ok()?;
+        let hours: u64 = std::env::var("BACKUP_INTERVAL_HOURS")
+            .map(|s| s.parse().expect("Unable to parse BACKUP_INTERVAL_HOURS"))
+            .ok()
+            .filter(|&hours| hours > 0)
+            .unwrap_or(24);
+        let retain = std::env::var("BACKUP_RETAIN")
+            .map(|s| s.parse().expect("Unable to parse BACKUP_RETAIN"))
+            .unwrap_or(7);
+        Some(Self {
+            dir: dir.into(),
+            interval: Duration::from_secs(hours * 3600),
+            retain,
+        })
+    }
+}
+
+/// Write a consistent copy of the database to a new timestamped file in the
+/// backup directory, then prune old backups. Returns the new file's path.
+pub async fn run_backup(database: &Database, config: &BackupConfig) -> Result<PathBuf> {
+    tokio::fs::create_dir_all(&config.dir)
+        .await
+        .with_context(|| format!("Failed to create backup directory {:?}", config.dir))?;
+    let name = format!(
+        "{}{}{}",
+        BACKUP_PREFIX,
+        chrono::Utc::now().format("%Y%m%dT%H%M%S%.6fZ"),
+        BACKUP_SUFFIX
+    );
+    let path = config.dir.join(name);
+    database.backup(&path).await?;
+    prune_backups(&config.dir, config.retain).await?;
+    Ok(path)
+}
+
+/// Delete all but the `retain` newest backups in a directory.
+///
+/// Backups are named by their UTC timestamp, so they sort oldest first. Other
+/// files in the directory are left alone.
+async fn prune_backups(dir: &Path, retain: usize) -> Result<()> {
+    let mut backups = Vec::new();
+    let mut entries = tokio::fs::read_dir(dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if name.starts_with(BACKUP_PREFIX) && name.ends_with(BACKUP_SUFFIX) {
+            backups.push(name);
+        }
+    }
+    backups.sort();
+    let excess = backups.len().saturating_sub(retain);
+    for name in &backups[..excess] {
+        if let Err(e) = tokio::fs::remove_file(dir.join(name)).await {
+            warn!("failed to remove old backup {}: {}", name, e);
+        }
+    }
+    Ok(())
+}
+
+/// Back up the database at a fixed interval, for as long as the server runs.
+pub(crate) async fn backup_task(database: Database, config: BackupConfig) {
+    loop {
+        tokio::time::sleep(config.interval).await;
+        let start = Instant::now();
+        match run_backup(&database, &config).await {
+            Ok(path) => info!("backed up database to {:?} in {:?}", path, start.elapsed()),
+            Err(e) => error!("database backup failed after {:?}: {:#}", start.elapsed(), e),
+        }
+    }
+}
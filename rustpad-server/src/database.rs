@@ -1,27 +1,455 @@
-//! Backend SQLite database handlers for persisting documents.
+//! Backend database handlers for persisting documents, in SQLite or, with the
+//! `postgres` feature, PostgreSQL.
 
+use std::path::Path;
 use std::str::FromStr;
+use std::sync::Arc;
 
-use anyhow::{bail, Result};
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Utc};
+use log::info;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+#[cfg(feature = "postgres")]
+use sqlx::PgPool;
 use sqlx::{sqlite::SqliteConnectOptions, ConnectOptions, SqlitePool};
+use tokio::sync::Semaphore;
+
+use crate::{acl::DocumentAcl, freeze::Compression, metadata::DocumentMetadata};
+
+/// Version of the snapshot format written by [`Database::store`].
+///
+/// Rows written before the format was versioned read as version 0, which has
+/// the same layout as version 1. Version 2 added access control lists,
+/// version 3 password hashes, version 4 metadata, version 5 end-to-end
+/// encryption, version 6 read-only share tokens, and version 7 compressed
+/// text, which older servers would otherwise silently drop or, for an
+/// encrypted or compressed document, serve as if it were its text.
+pub const CURRENT_FORMAT_VERSION: i64 = 7;
+
+/// Default number of concurrent writes, matching SQLite's single writer.
+pub const DEFAULT_MAX_CONCURRENT_WRITES: usize = 1;
+
+/// Most bytes of text a compressed row may expand to, far beyond any real
+/// document, so a corrupt row can't exhaust memory.
+const MAX_DECOMPRESSED_TEXT: u64 = 1 << 30;
+
+const LOAD_SQL: &str =
+    "SELECT text, language, acl, password_hash, share_tokens, metadata, encrypted, compression, compressed_text, format_version FROM document WHERE id = $1";
+
+const STORE_SQL: &str = r#"
+INSERT INTO
+    document (id, text, language, acl, password_hash, share_tokens, metadata, encrypted, compression, compressed_text, format_version, updated_at)
+VALUES
+    ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12)
+ON CONFLICT(id) DO UPDATE SET
+    text = excluded.text,
+    language = excluded.language,
+    acl = excluded.acl,
+    password_hash = excluded.password_hash,
+    share_tokens = excluded.share_tokens,
+    metadata = excluded.metadata,
+    encrypted = excluded.encrypted,
+    compression = excluded.compression,
+    compressed_text = excluded.compressed_text,
+    format_version = excluded.format_version,
+    updated_at = excluded.updated_at"#;
+
+const QUARANTINE_SQL: &str = r#"
+INSERT INTO
+    quarantined_document (id, text, language, acl, password_hash, share_tokens, metadata, encrypted, compression, compressed_text, format_version, reason)
+SELECT
+    id, text, language, acl, password_hash, share_tokens, metadata, encrypted, compression, compressed_text, format_version, $2
+FROM
+    document
+WHERE
+    id = $1"#;
+
+const DELETE_SQL: &str = "DELETE FROM document WHERE id = $1";
+
+const COUNT_SQL: &str = "SELECT count(*) FROM document";
+
+const LIST_SQLITE_SQL: &str = "SELECT id, language, coalesce(length(compressed_text), length(CAST(text AS BLOB))) AS size, updated_at FROM document ORDER BY id LIMIT $1 OFFSET $2";
+
+#[cfg(feature = "postgres")]
+const LIST_POSTGRES_SQL: &str = "SELECT id, language, coalesce(octet_length(compressed_text), octet_length(text))::BIGINT AS size, updated_at FROM document ORDER BY id LIMIT $1 OFFSET $2";
+
+const EXISTS_SQL: &str = "SELECT count(*) FROM document WHERE id = $1";
+
+const PING_SQL: &str = "SELECT 1";
+
+const BACKUP_SQL: &str = "VACUUM INTO $1";
 
 /// Represents a document persisted in database storage.
-#[derive(sqlx::FromRow, PartialEq, Eq, Clone, Debug)]
+#[derive(PartialEq, Eq, Clone, Debug, Default)]
 pub struct PersistedDocument {
     /// Text content of the document.
     pub text: String,
     /// Language of the document for editor syntax highlighting.
     pub language: Option<String>,
+    /// Who may read and write the document.
+    pub acl: DocumentAcl,
+    /// Bcrypt hash of the password needed to open the document, if any.
+    pub password_hash: Option<String>,
+    /// SHA-256 hashes of the tokens that open the document read-only.
+    pub share_tokens: Vec<String>,
+    /// Application-level information about the document.
+    pub metadata: DocumentMetadata,
+    /// Whether the document is end-to-end encrypted, in which case `text`
+    /// holds ciphertext that only clients can read.
+    pub encrypted: bool,
 }
 
-/// A driver for database operations wrapping a pool connection.
+impl PersistedDocument {
+    /// Returns whether a password opens this document, which is always the
+    /// case for a document without one.
+    pub fn check_password(&self, password: Option<&str>) -> bool {
+        password_matches(self.password_hash.as_deref(), password)
+    }
+
+    /// The access control list as stored in the `acl` column, or `None` if
+    /// the document is open to everyone.
+    fn acl_json(&self) -> Result<Option<String>> {
+        if self.acl.is_open() {
+            return Ok(None);
+        }
+        Ok(Some(serde_json::to_string(&self.acl)?))
+    }
+
+    /// The share token hashes as stored in the `share_tokens` column, or
+    /// `None` if there are none.
+    fn share_tokens_json(&self) -> Result<Option<String>> {
+        if self.share_tokens.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(serde_json::to_string(&self.share_tokens)?))
+    }
+
+    /// The text as stored: in the `text` column, or in the `compressed_text`
+    /// column along with the name of its `compression`, if compressing it
+    /// makes it smaller.
+    fn stored_text(
+        &self,
+        compression: Option<Compression>,
+    ) -> Result<(&str, Option<&'static str>, Option<Vec<u8>>)> {
+        if let Some(compression) = compression {
+            let compressed = compression.compress(self.text.as_bytes())?;
+            if compressed.len() < self.text.len() {
+                return Ok(("", Some(compression.name()), Some(compressed)));
+            }
+        }
+        Ok((&self.text, None, None))
+    }
+
+    /// The metadata as stored in the `metadata` column, or `None` if empty.
+    fn metadata_json(&self) -> Result<Option<String>> {
+        if self.metadata.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(serde_json::to_string(&self.metadata)?))
+    }
+}
+
+/// A persisted document as listed for browsing, without its text.
+#[derive(PartialEq, Eq, Clone, Debug, Serialize)]
+pub struct DocumentSummary {
+    /// Id of the document.
+    pub id: String,
+    /// Language of the document for editor syntax highlighting.
+    pub language: Option<String>,
+    /// Size of the document's text in bytes, as stored, so after any
+    /// compression.
+    pub size: u64,
+    /// When the document was last stored, unknown for documents not stored
+    /// since this was tracked.
+    pub updated_at: Option<DateTime<Utc>>,
+}
+
+/// A row of the document listing, before conversion to a summary.
+#[derive(sqlx::FromRow)]
+struct SummaryRow {
+    id: String,
+    language: Option<String>,
+    size: i64,
+    updated_at: Option<i64>,
+}
+
+impl From<SummaryRow> for DocumentSummary {
+    fn from(row: SummaryRow) -> Self {
+        Self {
+            id: row.id,
+            language: row.language,
+            size: row.size.max(0) as u64,
+            updated_at: row.updated_at.and_then(|secs| DateTime::from_timestamp(secs, 0)),
+        }
+    }
+}
+
+/// Check a password against an optional bcrypt hash.
+pub(crate) fn password_matches(hash: Option<&str>, password: Option<&str>) -> bool {
+    match (hash, password) {
+        (None, _) => true,
+        (Some(hash), Some(password)) => bcrypt::verify(password, hash).unwrap_or(false),
+        (Some(_), None) => false,
+    }
+}
+
+/// Hash a read-only share token for storage.
+///
+/// Tokens are long and random, so a plain SHA-256 is enough to keep a leaked
+/// database from revealing them.
+pub(crate) fn hash_share_token(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
+/// A persisted row, in whichever format version it was written.
+#[derive(sqlx::FromRow)]
+struct VersionedRow {
+    text: String,
+    language: Option<String>,
+    acl: Option<String>,
+    password_hash: Option<String>,
+    share_tokens: Option<String>,
+    metadata: Option<String>,
+    encrypted: bool,
+    compression: Option<String>,
+    compressed_text: Option<Vec<u8>>,
+    format_version: i64,
+}
+
+impl VersionedRow {
+    /// Upgrade a row to the current format, one version at a time.
+    fn migrate(mut self) -> Result<PersistedDocument> {
+        if self.format_version > CURRENT_FORMAT_VERSION {
+            bail!(
+                "document uses format version {}, but this server only supports up to {}",
+                self.format_version,
+                CURRENT_FORMAT_VERSION,
+            );
+        }
+        while self.format_version < CURRENT_FORMAT_VERSION {
+            match self.format_version {
+                // Version 0 only lacked the version marker itself.
+                0 => {}
+                // Version 1 had no access control, so its `acl` is empty.
+                1 => {}
+                // Version 2 had no passwords, so its `password_hash` is empty.
+                2 => {}
+                // Version 3 had no metadata, so its `metadata` is empty.
+                3 => {}
+                // Version 4 had no encryption, so `encrypted` is false.
+                4 => {}
+                // Version 5 had no share tokens, so `share_tokens` is empty.
+                5 => {}
+                // Version 6 had no compression, so `compression` is empty.
+                6 => {}
+                version => bail!("no migration from document format version {}", version),
+            }
+            self.format_version += 1;
+        }
+        let acl = match self.acl {
+            Some(acl) => serde_json::from_str(&acl).context("malformed access control list")?,
+            None => DocumentAcl::default(),
+        };
+        let share_tokens = match self.share_tokens {
+            Some(tokens) => serde_json::from_str(&tokens).context("malformed share tokens")?,
+            None => Vec::new(),
+        };
+        let metadata = match self.metadata {
+            Some(metadata) => serde_json::from_str(&metadata).context("malformed metadata")?,
+            None => DocumentMetadata::default(),
+        };
+        let text = match self.compression {
+            Some(compression) => {
+                let compression: Compression = compression.parse()?;
+                let compressed = self.compressed_text.unwrap_or_default();
+                let text = compression
+                    .decompress(&compressed, MAX_DECOMPRESSED_TEXT)
+                    .context("malformed compressed text")?;
+                String::from_utf8(text).context("compressed text is not UTF-8")?
+            }
+            None => self.text,
+        };
+        Ok(PersistedDocument {
+            text,
+            language: self.language,
+            acl,
+            password_hash: self.password_hash,
+            share_tokens,
+            metadata,
+            encrypted: self.encrypted,
+        })
+    }
+}
+
+/// A driver for database operations, chosen by the scheme of its URI.
 #[derive(Clone, Debug)]
-pub struct Database {
-    pool: SqlitePool,
+pub enum Database {
+    /// A SQLite database file, for `sqlite:` URIs.
+    Sqlite(SqliteDatabase),
+    /// A PostgreSQL server, for `postgres:` URIs, which lets several server
+    /// replicas share documents.
+    #[cfg(feature = "postgres")]
+    Postgres(PostgresDatabase),
 }
 
 impl Database {
-    /// Construct a new database from Postgres connection URI.
+    /// Connect to the database at a `sqlite:` or `postgres:` URI.
+    pub async fn new(uri: &str) -> Result<Self> {
+        if uri.starts_with("postgres:") || uri.starts_with("postgresql:") {
+            #[cfg(feature = "postgres")]
+            return Ok(Database::Postgres(PostgresDatabase::new(uri).await?));
+            #[cfg(not(feature = "postgres"))]
+            bail!("PostgreSQL support requires building with the `postgres` feature");
+        }
+        Ok(Database::Sqlite(SqliteDatabase::new(uri).await?))
+    }
+
+    /// Set how many documents may be written to the database at once.
+    pub fn with_max_concurrent_writes(self, max: usize) -> Self {
+        match self {
+            Database::Sqlite(db) => Database::Sqlite(db.with_max_concurrent_writes(max)),
+            #[cfg(feature = "postgres")]
+            Database::Postgres(db) => Database::Postgres(db.with_max_concurrent_writes(max)),
+        }
+    }
+
+    /// Compress the text of documents stored from now on, or store it plainly
+    /// with `None`. Documents are loaded however they were stored.
+    pub fn with_compression(self, compression: Option<Compression>) -> Self {
+        match self {
+            Database::Sqlite(db) => Database::Sqlite(db.with_compression(compression)),
+            #[cfg(feature = "postgres")]
+            Database::Postgres(db) => Database::Postgres(db.with_compression(compression)),
+        }
+    }
+
+    /// Load the text of a document from the database.
+    ///
+    /// Documents stored in an older format are upgraded and written back, and
+    /// documents from a newer, unsupported format are rejected.
+    pub async fn load(&self, document_id: &str) -> Result<PersistedDocument> {
+        match self {
+            Database::Sqlite(db) => db.load(document_id).await,
+            #[cfg(feature = "postgres")]
+            Database::Postgres(db) => db.load(document_id).await,
+        }
+    }
+
+    /// Store the text of a document in the database.
+    ///
+    /// Writes queue for a permit, so a burst of dirty documents is written a
+    /// few at a time rather than contending for the database lock.
+    pub async fn store(&self, document_id: &str, document: &PersistedDocument) -> Result<()> {
+        match self {
+            Database::Sqlite(db) => db.store(document_id, document).await,
+            #[cfg(feature = "postgres")]
+            Database::Postgres(db) => db.store(document_id, document).await,
+        }
+    }
+
+    /// Move a document that failed to load out of the way, keeping its raw
+    /// row for inspection, so that the id can be reused.
+    pub async fn quarantine(&self, document_id: &str, reason: &str) -> Result<()> {
+        match self {
+            Database::Sqlite(db) => db.quarantine(document_id, reason).await,
+            #[cfg(feature = "postgres")]
+            Database::Postgres(db) => db.quarantine(document_id, reason).await,
+        }
+    }
+
+    /// Remove a document from the database, returning whether it was there.
+    pub async fn delete(&self, document_id: &str) -> Result<bool> {
+        match self {
+            Database::Sqlite(db) => db.delete(document_id).await,
+            #[cfg(feature = "postgres")]
+            Database::Postgres(db) => db.delete(document_id).await,
+        }
+    }
+
+    /// List the ids of quarantined documents, oldest first.
+    pub async fn quarantined(&self) -> Result<Vec<String>> {
+        match self {
+            Database::Sqlite(db) => db.quarantined().await,
+            #[cfg(feature = "postgres")]
+            Database::Postgres(db) => db.quarantined().await,
+        }
+    }
+
+    /// Count the number of documents in the database.
+    pub async fn count(&self) -> Result<usize> {
+        match self {
+            Database::Sqlite(db) => db.count().await,
+            #[cfg(feature = "postgres")]
+            Database::Postgres(db) => db.count().await,
+        }
+    }
+
+    /// List persisted documents in order of id, without loading their text.
+    pub async fn list(&self, limit: usize, offset: usize) -> Result<Vec<DocumentSummary>> {
+        match self {
+            Database::Sqlite(db) => db.list(limit, offset).await,
+            #[cfg(feature = "postgres")]
+            Database::Postgres(db) => db.list(limit, offset).await,
+        }
+    }
+
+    /// Check whether a document with the given id has been persisted.
+    pub async fn exists(&self, document_id: &str) -> Result<bool> {
+        match self {
+            Database::Sqlite(db) => db.exists(document_id).await,
+            #[cfg(feature = "postgres")]
+            Database::Postgres(db) => db.exists(document_id).await,
+        }
+    }
+
+    /// Check that the database is reachable, without touching any table.
+    pub async fn ping(&self) -> Result<()> {
+        match self {
+            Database::Sqlite(db) => db.ping().await,
+            #[cfg(feature = "postgres")]
+            Database::Postgres(db) => db.ping().await,
+        }
+    }
+
+    /// Write a consistent copy of the whole database to a new file.
+    ///
+    /// Only SQLite databases can be copied this way; PostgreSQL deployments
+    /// should use `pg_dump` instead.
+    pub async fn backup(&self, path: &Path) -> Result<()> {
+        match self {
+            Database::Sqlite(db) => db.backup(path).await,
+            #[cfg(feature = "postgres")]
+            Database::Postgres(_) => {
+                bail!("backups are not supported for PostgreSQL, use pg_dump instead")
+            }
+        }
+    }
+}
+
+/// Check that a store wrote exactly the one row it upserted.
+fn check_stored(rows_affected: u64) -> Result<()> {
+    if rows_affected != 1 {
+        bail!(
+            "expected store() to receive 1 row affected, but it affected {} rows instead",
+            rows_affected,
+        );
+    }
+    Ok(())
+}
+
+/// Documents persisted in a SQLite database file.
+#[derive(Clone, Debug)]
+pub struct SqliteDatabase {
+    pool: SqlitePool,
+    /// Limits concurrent writes shared by all clones of this database.
+    write_permits: Arc<Semaphore>,
+    /// How the text of stored documents is compressed, if at all.
+    compression: Option<Compression>,
+}
+
+impl SqliteDatabase {
+    /// Open a SQLite database, creating the file if it is missing.
     pub async fn new(uri: &str) -> Result<Self> {
         {
             // Create database file if missing, and run migrations.
@@ -31,51 +459,259 @@ impl Database {
                 .await?;
             sqlx::migrate!().run(&mut conn).await?;
         }
-        Ok(Database {
+        Ok(SqliteDatabase {
             pool: SqlitePool::connect(uri).await?,
+            write_permits: Arc::new(Semaphore::new(DEFAULT_MAX_CONCURRENT_WRITES)),
+            compression: None,
         })
     }
 
-    /// Load the text of a document from the database.
-    pub async fn load(&self, document_id: &str) -> Result<PersistedDocument> {
-        sqlx::query_as(r#"SELECT text, language FROM document WHERE id = $1"#)
+    fn with_max_concurrent_writes(mut self, max: usize) -> Self {
+        self.write_permits = Arc::new(Semaphore::new(max.max(1)));
+        self
+    }
+
+    fn with_compression(mut self, compression: Option<Compression>) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    async fn load(&self, document_id: &str) -> Result<PersistedDocument> {
+        let row: VersionedRow = sqlx::query_as(LOAD_SQL)
             .bind(document_id)
             .fetch_one(&self.pool)
-            .await
-            .map_err(|e| e.into())
+            .await?;
+        let version = row.format_version;
+        let document = row.migrate()?;
+        if version < CURRENT_FORMAT_VERSION {
+            info!(
+                "upgrading document {} from format version {} to {}",
+                document_id, version, CURRENT_FORMAT_VERSION
+            );
+            self.store(document_id, &document).await?;
+        }
+        Ok(document)
     }
 
-    /// Store the text of a document in the database.
-    pub async fn store(&self, document_id: &str, document: &PersistedDocument) -> Result<()> {
-        let result = sqlx::query(
-            r#"
-INSERT INTO
-    document (id, text, language)
-VALUES
-    ($1, $2, $3)
-ON CONFLICT(id) DO UPDATE SET
-    text = excluded.text,
-    language = excluded.language"#,
-        )
-        .bind(document_id)
-        .bind(&document.text)
-        .bind(&document.language)
-        .execute(&self.pool)
-        .await?;
-        if result.rows_affected() != 1 {
-            bail!(
-                "expected store() to receive 1 row affected, but it affected {} rows instead",
-                result.rows_affected(),
+    async fn store(&self, document_id: &str, document: &PersistedDocument) -> Result<()> {
+        let (text, compression, compressed_text) = document.stored_text(self.compression)?;
+        let _permit = self.write_permits.acquire().await?;
+        let result = sqlx::query(STORE_SQL)
+            .bind(document_id)
+            .bind(text)
+            .bind(&document.language)
+            .bind(document.acl_json()?)
+            .bind(&document.password_hash)
+            .bind(document.share_tokens_json()?)
+            .bind(document.metadata_json()?)
+            .bind(document.encrypted)
+            .bind(compression)
+            .bind(compressed_text)
+            .bind(CURRENT_FORMAT_VERSION)
+            .bind(Utc::now().timestamp())
+            .execute(&self.pool)
+            .await?;
+        check_stored(result.rows_affected())
+    }
+
+    async fn quarantine(&self, document_id: &str, reason: &str) -> Result<()> {
+        let _permit = self.write_permits.acquire().await?;
+        let mut tx = self.pool.begin().await?;
+        sqlx::query(QUARANTINE_SQL)
+            .bind(document_id)
+            .bind(reason)
+            .execute(&mut tx)
+            .await?;
+        sqlx::query(DELETE_SQL)
+            .bind(document_id)
+            .execute(&mut tx)
+            .await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn delete(&self, document_id: &str) -> Result<bool> {
+        let _permit = self.write_permits.acquire().await?;
+        let result = sqlx::query(DELETE_SQL)
+            .bind(document_id)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn quarantined(&self) -> Result<Vec<String>> {
+        let rows: Vec<(String,)> =
+            sqlx::query_as("SELECT id FROM quarantined_document ORDER BY rowid")
+                .fetch_all(&self.pool)
+                .await?;
+        Ok(rows.into_iter().map(|(id,)| id).collect())
+    }
+
+    async fn count(&self) -> Result<usize> {
+        let row: (i64,) = sqlx::query_as(COUNT_SQL).fetch_one(&self.pool).await?;
+        Ok(row.0 as usize)
+    }
+
+    async fn list(&self, limit: usize, offset: usize) -> Result<Vec<DocumentSummary>> {
+        let rows: Vec<SummaryRow> = sqlx::query_as(LIST_SQLITE_SQL)
+            .bind(limit as i64)
+            .bind(offset as i64)
+            .fetch_all(&self.pool)
+            .await?;
+        Ok(rows.into_iter().map(DocumentSummary::from).collect())
+    }
+
+    async fn exists(&self, document_id: &str) -> Result<bool> {
+        let row: (i64,) = sqlx::query_as(EXISTS_SQL)
+            .bind(document_id)
+            .fetch_one(&self.pool)
+            .await?;
+        Ok(row.0 > 0)
+    }
+
+    async fn ping(&self) -> Result<()> {
+        sqlx::query(PING_SQL).execute(&self.pool).await?;
+        Ok(())
+    }
+
+    async fn backup(&self, path: &Path) -> Result<()> {
+        // Unlike copying the file, this sees a single transaction's view of
+        // the database even while documents are being written.
+        let path = path.to_str().context("backup path is not valid UTF-8")?;
+        sqlx::query(BACKUP_SQL).bind(path).execute(&self.pool).await?;
+        Ok(())
+    }
+}
+
+/// Documents persisted in a PostgreSQL database.
+#[cfg(feature = "postgres")]
+#[derive(Clone, Debug)]
+pub struct PostgresDatabase {
+    pool: PgPool,
+    /// Limits concurrent writes shared by all clones of this database.
+    write_permits: Arc<Semaphore>,
+    /// How the text of stored documents is compressed, if at all.
+    compression: Option<Compression>,
+}
+
+#[cfg(feature = "postgres")]
+impl PostgresDatabase {
+    /// Connect to a PostgreSQL server, and run its migrations.
+    pub async fn new(uri: &str) -> Result<Self> {
+        let pool = PgPool::connect(uri).await?;
+        sqlx::migrate!("./migrations_postgres").run(&pool).await?;
+        Ok(PostgresDatabase {
+            pool,
+            write_permits: Arc::new(Semaphore::new(DEFAULT_MAX_CONCURRENT_WRITES)),
+            compression: None,
+        })
+    }
+
+    fn with_max_concurrent_writes(mut self, max: usize) -> Self {
+        self.write_permits = Arc::new(Semaphore::new(max.max(1)));
+        self
+    }
+
+    fn with_compression(mut self, compression: Option<Compression>) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    async fn load(&self, document_id: &str) -> Result<PersistedDocument> {
+        let row: VersionedRow = sqlx::query_as(LOAD_SQL)
+            .bind(document_id)
+            .fetch_one(&self.pool)
+            .await?;
+        let version = row.format_version;
+        let document = row.migrate()?;
+        if version < CURRENT_FORMAT_VERSION {
+            info!(
+                "upgrading document {} from format version {} to {}",
+                document_id, version, CURRENT_FORMAT_VERSION
             );
+            self.store(document_id, &document).await?;
         }
+        Ok(document)
+    }
+
+    async fn store(&self, document_id: &str, document: &PersistedDocument) -> Result<()> {
+        let (text, compression, compressed_text) = document.stored_text(self.compression)?;
+        let _permit = self.write_permits.acquire().await?;
+        let result = sqlx::query(STORE_SQL)
+            .bind(document_id)
+            .bind(text)
+            .bind(&document.language)
+            .bind(document.acl_json()?)
+            .bind(&document.password_hash)
+            .bind(document.share_tokens_json()?)
+            .bind(document.metadata_json()?)
+            .bind(document.encrypted)
+            .bind(compression)
+            .bind(compressed_text)
+            .bind(CURRENT_FORMAT_VERSION)
+            .bind(Utc::now().timestamp())
+            .execute(&self.pool)
+            .await?;
+        check_stored(result.rows_affected())
+    }
+
+    async fn quarantine(&self, document_id: &str, reason: &str) -> Result<()> {
+        let _permit = self.write_permits.acquire().await?;
+        let mut tx = self.pool.begin().await?;
+        sqlx::query(QUARANTINE_SQL)
+            .bind(document_id)
+            .bind(reason)
+            .execute(&mut tx)
+            .await?;
+        sqlx::query(DELETE_SQL)
+            .bind(document_id)
+            .execute(&mut tx)
+            .await?;
+        tx.commit().await?;
         Ok(())
     }
 
-    /// Count the number of documents in the database.
-    pub async fn count(&self) -> Result<usize> {
-        let row: (i64,) = sqlx::query_as("SELECT count(*) FROM document")
-            .fetch_one(&self.pool)
+    async fn delete(&self, document_id: &str) -> Result<bool> {
+        let _permit = self.write_permits.acquire().await?;
+        let result = sqlx::query(DELETE_SQL)
+            .bind(document_id)
+            .execute(&self.pool)
             .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn quarantined(&self) -> Result<Vec<String>> {
+        let rows: Vec<(String,)> =
+            sqlx::query_as("SELECT id FROM quarantined_document ORDER BY quarantined_at, id")
+                .fetch_all(&self.pool)
+                .await?;
+        Ok(rows.into_iter().map(|(id,)| id).collect())
+    }
+
+    async fn count(&self) -> Result<usize> {
+        let row: (i64,) = sqlx::query_as(COUNT_SQL).fetch_one(&self.pool).await?;
         Ok(row.0 as usize)
     }
+
+    async fn list(&self, limit: usize, offset: usize) -> Result<Vec<DocumentSummary>> {
+        let rows: Vec<SummaryRow> = sqlx::query_as(LIST_POSTGRES_SQL)
+            .bind(limit as i64)
+            .bind(offset as i64)
+            .fetch_all(&self.pool)
+            .await?;
+        Ok(rows.into_iter().map(DocumentSummary::from).collect())
+    }
+
+    async fn exists(&self, document_id: &str) -> Result<bool> {
+        let row: (i64,) = sqlx::query_as(EXISTS_SQL)
+            .bind(document_id)
+            .fetch_one(&self.pool)
+            .await?;
+        Ok(row.0 > 0)
+    }
+
+    async fn ping(&self) -> Result<()> {
+        sqlx::query(PING_SQL).execute(&self.pool).await?;
+        Ok(())
+    }
 }
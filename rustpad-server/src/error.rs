@@ -0,0 +1,88 @@
+//! Errors that API clients can act on, with the status codes they map to.
+
+use std::fmt;
+
+use warp::http::StatusCode;
+
+/// An error caused by the request rather than the server, answered with a
+/// matching status code and a JSON body like `{ "error": "..." }`.
+///
+/// Managers return these inside [`anyhow::Error`], as the error itself or as
+/// context, so handlers can pass errors through unchanged. Any other error is
+/// answered with `500 Internal Server Error`, without its details.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ApiError {
+    /// The request is malformed or breaks a rule, e.g. a short password.
+    BadRequest(String),
+    /// The request lacks valid credentials.
+    Unauthorized(String),
+    /// The user is known, but may not do this.
+    Forbidden(String),
+    /// The resource doesn't exist, or the feature serving it is disabled.
+    NotFound(String),
+    /// The request conflicts with the current state, e.g. a taken username.
+    Conflict(String),
+    /// The request, or what it would store, is too large.
+    PayloadTooLarge(String),
+    /// The client must wait before trying again.
+    TooManyRequests(String),
+    /// An upstream service answered with something that can't be used.
+    BadGateway(String),
+    /// The server has no room for the request right now, e.g. at capacity.
+    ServiceUnavailable(String),
+    /// The request took too long to handle.
+    GatewayTimeout(String),
+    /// The request needs something the server isn't configured with.
+    NotImplemented(String),
+}
+
+impl ApiError {
+    /// The status code the error is answered with.
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Self::BadRequest(_) => StatusCode::BAD_REQUEST,
+            Self::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            Self::Forbidden(_) => StatusCode::FORBIDDEN,
+            Self::NotFound(_) => StatusCode::NOT_FOUND,
+            Self::Conflict(_) => StatusCode::CONFLICT,
+            Self::PayloadTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            Self::TooManyRequests(_) => StatusCode::TOO_MANY_REQUESTS,
+            Self::BadGateway(_) => StatusCode::BAD_GATEWAY,
+            Self::ServiceUnavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+            Self::GatewayTimeout(_) => StatusCode::GATEWAY_TIMEOUT,
+            Self::NotImplemented(_) => StatusCode::NOT_IMPLEMENTED,
+        }
+    }
+
+    /// The message shown to the client.
+    pub fn message(&self) -> &str {
+        match self {
+            Self::BadRequest(message)
+            | Self::Unauthorized(message)
+            | Self::Forbidden(message)
+            | Self::NotFound(message)
+            | Self::Conflict(message)
+            | Self::PayloadTooLarge(message)
+            | Self::TooManyRequests(message)
+            | Self::BadGateway(message)
+            | Self::ServiceUnavailable(message)
+            | Self::GatewayTimeout(message)
+            | Self::NotImplemented(message) => message,
+        }
+    }
+
+    /// The outermost API error in an error or its context, if any.
+    pub fn find(error: &anyhow::Error) -> Option<&ApiError> {
+        error
+            .downcast_ref()
+            .or_else(|| error.chain().find_map(|cause| cause.downcast_ref()))
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.message())
+    }
+}
+
+impl std::error::Error for ApiError {}
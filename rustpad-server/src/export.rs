@@ -0,0 +1,179 @@
+//! Export of all data held about a single user, for data access requests.
+
+use std::sync::Arc;
+
+use anyhow::Result;
+use log::warn;
+use serde::Serialize;
+use warp::hyper::Body;
+
+use crate::{
+    artifacts::{ArtifactManager, ArtifactMetadata},
+    freeze::{FreezeManager, FrozenDocument},
+    load::IoLimiter,
+};
+
+/// Maximum number of bytes of user content included in a single export.
+pub const MAX_EXPORT_BYTES: usize = 64 * 1024 * 1024;
+
+/// A unit of output, rendered lazily as the export is streamed.
+enum Piece {
+    Start(String),
+    Field(&'static str, String),
+    Open(&'static str),
+    Frozen(FrozenDocument),
+    Artifact(ArtifactMetadata),
+    Close,
+    End,
+}
+
+/// AI tokens a user has used, as counted towards their monthly quota.
+///
+/// Only the month being counted is kept, so there is no older history to
+/// export.
+#[derive(Serialize)]
+pub struct AiUsage {
+    /// Month the tokens were used in, as `YYYY-MM`.
+    pub period: Option<String>,
+    /// Tokens used in that month.
+    pub tokens: u64,
+}
+
+/// A frozen document together with its content.
+#[derive(Serialize)]
+struct ExportedFrozenDocument<'a> {
+    #[serde(flatten)]
+    metadata: &'a FrozenDocument,
+    content: String,
+}
+
+/// Renders a user's data as a single JSON document, one item at a time.
+///
+/// File contents are only read when the corresponding chunk is requested, so
+/// the whole export is never buffered in memory. Items that would exceed
+/// [`MAX_EXPORT_BYTES`] are skipped and the export is marked as truncated.
+///
+/// The AI request log is not included, as its entries don't record who made
+/// each request.
+pub struct UserExport {
+    username: String,
+    freeze_manager: Option<Arc<FreezeManager>>,
+    artifact_manager: Option<Arc<ArtifactManager>>,
+    pieces: std::vec::IntoIter<Piece>,
+    remaining: usize,
+    first: bool,
+    truncated: bool,
+}
+
+impl UserExport {
+    /// Collect the listing of everything stored for `username`.
+    pub fn new(
+        account: &impl Serialize,
+        ai_usage: &AiUsage,
+        username: &str,
+        freeze_manager: Option<Arc<FreezeManager>>,
+        artifact_manager: Option<Arc<ArtifactManager>>,
+    ) -> Result<Self> {
+        let mut pieces = vec![
+            Piece::Start(serde_json::to_string(account)?),
+            Piece::Field("ai_usage", serde_json::to_string(ai_usage)?),
+            Piece::Open("frozen_documents"),
+        ];
+        if let Some(freeze_manager) = &freeze_manager {
+            let documents = freeze_manager.list_frozen_documents(username)?;
+            pieces.extend(documents.into_iter().map(Piece::Frozen));
+        }
+        pieces.push(Piece::Close);
+        pieces.push(Piece::Open("artifacts"));
+        if let Some(artifact_manager) = &artifact_manager {
+            let artifacts = artifact_manager.list_artifacts(username)?;
+            pieces.extend(artifacts.into_iter().map(Piece::Artifact));
+        }
+        pieces.push(Piece::Close);
+        pieces.push(Piece::End);
+
+        Ok(Self {
+            username: username.to_string(),
+            freeze_manager,
+            artifact_manager,
+            pieces: pieces.into_iter(),
+            remaining: MAX_EXPORT_BYTES,
+            first: true,
+            truncated: false,
+        })
+    }
+
+    /// Convert this export into a streaming response body.
+    ///
+    /// Each chunk is rendered through `io_limiter`, as reading and
+    /// decompressing stored files blocks.
+    pub fn into_body(self, io_limiter: IoLimiter) -> Body {
+        let chunks = futures::stream::unfold(Some(self), move |export| {
+            let io_limiter = io_limiter.clone();
+            async move {
+                let mut export = export?;
+                match io_limiter.run(move || Ok((export.next(), export))).await {
+                    Ok((Some(chunk), export)) => Some((chunk, Some(export))),
+                    Ok((None, _)) => None,
+                    Err(e) => Some((Err(e), None)),
+                }
+            }
+        });
+        Body::wrap_stream(chunks)
+    }
+
+    /// Render a single frozen document or artifact as JSON.
+    fn render(&self, piece: &Piece) -> Result<Option<String>> {
+        match (piece, &self.freeze_manager, &self.artifact_manager) {
+            (Piece::Frozen(metadata), Some(freeze_manager), _) => {
+                let content =
+                    freeze_manager.get_frozen_document(&self.username, &metadata.document_id)?;
+                let document = ExportedFrozenDocument { metadata, content };
+                Ok(Some(serde_json::to_string(&document)?))
+            }
+            (Piece::Artifact(metadata), _, Some(artifact_manager)) => {
+                let artifact = artifact_manager.get_artifact(&self.username, &metadata.id)?;
+                Ok(Some(serde_json::to_string(&artifact)?))
+            }
+            _ => Ok(None),
+        }
+    }
+}
+
+impl Iterator for UserExport {
+    type Item = Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let chunk = match self.pieces.next()? {
+                Piece::Start(account) => format!(r#"{{"account":{}"#, account),
+                Piece::Field(name, value) => format!(r#","{}":{}"#, name, value),
+                Piece::Open(name) => {
+                    self.first = true;
+                    format!(r#","{}":["#, name)
+                }
+                Piece::Close => String::from("]"),
+                Piece::End => format!(r#","truncated":{}}}"#, self.truncated),
+                item => {
+                    let rendered = match self.render(&item) {
+                        Ok(Some(rendered)) => rendered,
+                        Ok(None) => continue,
+                        Err(e) => {
+                            warn!("skipping item in export for {}: {}", self.username, e);
+                            continue;
+                        }
+                    };
+                    if rendered.len() > self.remaining {
+                        self.truncated = true;
+                        continue;
+                    }
+                    self.remaining -= rendered.len();
+                    let separator = if self.first { "" } else { "," };
+                    self.first = false;
+                    format!("{}{}", separator, rendered)
+                }
+            };
+            return Some(Ok(chunk.into_bytes()));
+        }
+    }
+}
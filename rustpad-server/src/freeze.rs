@@ -4,11 +4,17 @@ use anyhow::{bail, Context, Result};
 use chrono::{DateTime, Duration, Utc};
 use log::{info, warn};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::fs;
-use std::path::PathBuf;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 use uuid::Uuid;
 
+use crate::error::ApiError;
+use crate::signer::Signer;
+
 /// Metadata about a frozen document
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct FrozenDocument {
@@ -26,8 +32,187 @@ pub struct FrozenDocument {
     pub expires_at: DateTime<Utc>,
     /// Path to the frozen file on disk
     pub file_path: PathBuf,
-    /// Size of the file in bytes
+    /// Size of the content in bytes, before any compression
     pub file_size: u64,
+    /// Hex-encoded SHA-256 hash of the content, missing for older documents
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_hash: Option<String>,
+    /// Whether the file on disk is compressed, as named by its suffix
+    #[serde(default)]
+    pub compressed: bool,
+}
+
+/// Compute the hex-encoded SHA-256 hash of a document's content
+pub fn content_hash(content: &str) -> String {
+    format!("{:x}", Sha256::digest(content.as_bytes()))
+}
+
+/// Guess a document's language, first from an extension in its id like
+/// `foo.py`, then from its content
+pub fn infer_language(document_id: &str, content: &str) -> Option<&'static str> {
+    language_from_id(document_id).or_else(|| language_from_content(content))
+}
+
+/// Language named by the extension at the end of a document id
+fn language_from_id(document_id: &str) -> Option<&'static str> {
+    let (_, extension) = document_id.rsplit_once('.')?;
+    Some(match extension.to_ascii_lowercase().as_str() {
+        "rs" => "rust",
+        "py" => "python",
+        "js" | "mjs" | "cjs" => "javascript",
+        "ts" => "typescript",
+        "java" => "java",
+        "cpp" | "cc" | "cxx" | "hpp" => "cpp",
+        "c" | "h" => "c",
+        "go" => "go",
+        "rb" => "ruby",
+        "php" => "php",
+        "swift" => "swift",
+        "kt" => "kotlin",
+        "scala" => "scala",
+        "html" | "htm" => "html",
+        "css" => "css",
+        "json" => "json",
+        "xml" => "xml",
+        "yaml" | "yml" => "yaml",
+        "md" => "markdown",
+        "sql" => "sql",
+        "sh" | "bash" => "shell",
+        _ => return None,
+    })
+}
+
+/// Language recognized from a shebang, markup prologue, or telltale lines
+fn language_from_content(content: &str) -> Option<&'static str> {
+    let trimmed = content.trim_start();
+    let first_line = trimmed.lines().next()?;
+    if let Some(command) = first_line.strip_prefix("#!") {
+        // Skip `env` in `#!/usr/bin/env python3`, and the version suffix.
+        let interpreter = command
+            .split_whitespace()
+            .map(|word| word.rsplit('/').next().unwrap_or(word))
+            .find(|&word| word != "env")?;
+        return match interpreter.trim_end_matches(|c: char| c.is_ascii_digit() || c == '.') {
+            "python" => Some("python"),
+            "sh" | "bash" | "zsh" => Some("shell"),
+            "node" => Some("javascript"),
+            "ruby" => Some("ruby"),
+            "php" => Some("php"),
+            _ => None,
+        };
+    }
+
+    let lowercase = first_line.to_ascii_lowercase();
+    if lowercase.starts_with("<?php") {
+        return Some("php");
+    }
+    if lowercase.starts_with("<!doctype html") || lowercase.starts_with("<html") {
+        return Some("html");
+    }
+    if lowercase.starts_with("<?xml") {
+        return Some("xml");
+    }
+    if (trimmed.starts_with('{') || trimmed.starts_with('['))
+        && serde_json::from_str::<serde_json::Value>(trimmed).is_ok()
+    {
+        return Some("json");
+    }
+
+    let lines = || trimmed.lines().map(str::trim_start);
+    let has = |prefixes: &[&str]| lines().any(|line| prefixes.iter().any(|p| line.starts_with(p)));
+    if has(&["fn main(", "pub fn ", "use std::", "impl "]) {
+        return Some("rust");
+    }
+    if has(&["package "]) && has(&["func "]) {
+        return Some("go");
+    }
+    if has(&["#include "]) {
+        return Some(if has(&["class ", "namespace ", "std::"]) { "cpp" } else { "c" });
+    }
+    if lines().any(|line| {
+        (line.starts_with("def ") || line.starts_with("class ")) && line.trim_end().ends_with(':')
+    }) {
+        return Some("python");
+    }
+    None
+}
+
+/// Algorithm used to compress frozen files on disk
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    /// gzip, stored with a `.gz` suffix
+    Gzip,
+    /// Zstandard, stored with a `.zst` suffix
+    Zstd,
+}
+
+impl Compression {
+    /// Suffix appended to the names of files compressed this way
+    fn suffix(self) -> &'static str {
+        match self {
+            Self::Gzip => "gz",
+            Self::Zstd => "zst",
+        }
+    }
+
+    /// The compression a file was written with, judging by its suffix
+    fn from_path(path: &Path) -> Option<Self> {
+        match path.extension()?.to_str()? {
+            "gz" => Some(Self::Gzip),
+            "zst" => Some(Self::Zstd),
+            _ => None,
+        }
+    }
+
+    /// Name of the algorithm, as parsed by [`FromStr`]
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            Self::Gzip => "gzip",
+            Self::Zstd => "zstd",
+        }
+    }
+
+    pub(crate) fn compress(self, bytes: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            Self::Gzip => {
+                let mut encoder =
+                    flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(bytes)?;
+                Ok(encoder.finish()?)
+            }
+            Self::Zstd => Ok(zstd::encode_all(bytes, zstd::DEFAULT_COMPRESSION_LEVEL)?),
+        }
+    }
+
+    /// Wrap a reader of compressed bytes in a matching decoder
+    fn decoder<'a>(self, reader: impl Read + 'a) -> Result<Box<dyn Read + 'a>> {
+        Ok(match self {
+            Self::Gzip => Box::new(flate2::read::GzDecoder::new(reader)),
+            Self::Zstd => Box::new(zstd::Decoder::new(reader)?),
+        })
+    }
+
+    /// Decompress at most `limit` bytes of content
+    pub(crate) fn decompress(self, bytes: &[u8], limit: u64) -> Result<Vec<u8>> {
+        let mut content = Vec::new();
+        self.decoder(bytes)?.take(limit + 1).read_to_end(&mut content)?;
+        if content.len() as u64 > limit {
+            bail!("Decompressed document exceeds maximum size ({})", limit);
+        }
+        Ok(content)
+    }
+}
+
+impl FromStr for Compression {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "gzip" | "gz" => Ok(Self::Gzip),
+            "zstd" | "zst" => Ok(Self::Zstd),
+            _ => bail!("unknown compression {:?}, expected gzip, zstd, or none", s),
+        }
+    }
 }
 
 /// Configuration for file freeze feature
@@ -39,6 +224,19 @@ pub struct FreezeConfig {
     pub save_dir: PathBuf,
     /// Maximum file size in bytes
     pub max_file_size: u64,
+    /// Key for signing manifests, generated at startup if not set
+    pub manifest_key: Option<String>,
+    /// How newly frozen files are compressed, if at all
+    pub compression: Option<Compression>,
+    /// Longest a document may stay frozen, counting extensions, in days
+    pub max_lifetime_days: u32,
+    /// Most frozen documents listed per request, and the default page size
+    pub page_size: usize,
+    /// Whether documents frozen without a language get one inferred from their
+    /// id and content, rather than defaulting to plaintext
+    pub infer_language: bool,
+    /// Languages that may be frozen, in lowercase, or `None` to allow all
+    pub allowed_languages: Option<BTreeSet<String>>,
 }
 
 impl Default for FreezeConfig {
@@ -47,6 +245,12 @@ impl Default for FreezeConfig {
             enabled: false,
             save_dir: PathBuf::from("./frozen_documents"),
             max_file_size: 10 * 1024 * 1024, // 10 MB
+            manifest_key: None,
+            compression: None,
+            max_lifetime_days: 90,
+            page_size: 50,
+            infer_language: true,
+            allowed_languages: None,
         }
     }
 }
@@ -67,6 +271,70 @@ impl FreezeConfig {
             enabled,
             save_dir,
             max_file_size: 10 * 1024 * 1024,
+            manifest_key: std::env::var("FREEZE_MANIFEST_KEY").ok(),
+            compression: std::env::var("FREEZE_COMPRESSION")
+                .ok()
+                .filter(|s| !s.is_empty() && s != "none")
+                .map(|s| s.parse().expect("Unable to parse FREEZE_COMPRESSION")),
+            max_lifetime_days: std::env::var("FREEZE_MAX_LIFETIME_DAYS")
+                .ok()
+                .and_then(|s| s.parse().ok())
+                .unwrap_or(90),
+            page_size: std::env::var("FREEZE_PAGE_SIZE")
+                .ok()
+                .and_then(|s| s.parse().ok())
+                .filter(|&size| size > 0)
+                .unwrap_or(50),
+            infer_language: std::env::var("FREEZE_INFER_LANGUAGE")
+                .ok()
+                .and_then(|s| s.parse().ok())
+                .unwrap_or(true),
+            allowed_languages: std::env::var("FREEZE_ALLOWED_LANGUAGES")
+                .ok()
+                .filter(|s| !s.trim().is_empty())
+                .map(|s| {
+                    s.split(',')
+                        .map(|language| language.trim().to_lowercase())
+                        .filter(|language| !language.is_empty())
+                        .collect()
+                }),
+        }
+    }
+}
+
+/// One page of a user's frozen documents, newest first
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FrozenPage {
+    /// Documents on this page
+    pub documents: Vec<FrozenDocument>,
+    /// Number of frozen documents the user has in total
+    pub total: usize,
+    /// Position of the first document on this page
+    pub offset: usize,
+    /// Most documents this page could hold
+    pub limit: usize,
+}
+
+/// Most matching lines reported for a single document in a search
+const MAX_HITS_PER_DOCUMENT: usize = 5;
+
+/// Longest snippet reported for a matching line, in characters
+const MAX_SNIPPET_CHARS: usize = 200;
+
+/// A line of a frozen document that matched a search
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SearchHit {
+    /// Line number, counting from 1
+    pub line: usize,
+    /// The matching line, trimmed and cut short if it is long
+    pub snippet: String,
+}
+
+impl SearchHit {
+    fn new(line: usize, text: &str) -> Self {
+        Self {
+            line,
+            snippet: text.trim().chars().take(MAX_SNIPPET_CHARS).collect(),
         }
     }
 }
@@ -76,6 +344,51 @@ impl FreezeConfig {
 pub struct FreezeManager {
     config: FreezeConfig,
     metadata_cache: parking_lot::RwLock<HashMap<String, Vec<FrozenDocument>>>,
+    manifest_signer: Signer,
+}
+
+/// One frozen document listed in a [`Manifest`]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    /// Unique identifier for the document
+    pub document_id: String,
+    /// Name the file is downloaded as
+    pub file_name: String,
+    /// Programming language of the document
+    pub language: String,
+    /// Size of the file in bytes
+    pub file_size: u64,
+    /// Hex-encoded SHA-256 hash of the content
+    pub sha256: String,
+    /// Timestamp when document was frozen
+    pub frozen_at: DateTime<Utc>,
+    /// Timestamp when document expires
+    pub expires_at: DateTime<Utc>,
+}
+
+/// A signed listing of a user's frozen documents, for verifying downloads
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Manifest {
+    /// User who owns the documents
+    pub username: String,
+    /// When the manifest was generated
+    pub generated_at: DateTime<Utc>,
+    /// Every frozen document, oldest first
+    pub documents: Vec<ManifestEntry>,
+    /// Hex-encoded HMAC-SHA256 of the other fields, keyed by the server
+    #[serde(default)]
+    pub signature: String,
+}
+
+impl Manifest {
+    /// The bytes covered by the signature: the manifest without it, as JSON.
+    fn signed_bytes(&self) -> Result<Vec<u8>> {
+        let unsigned = Manifest {
+            signature: String::new(),
+            ..self.clone()
+        };
+        Ok(serde_json::to_vec(&unsigned)?)
+    }
 }
 
 impl FreezeManager {
@@ -87,13 +400,34 @@ impl FreezeManager {
             info!("File freeze enabled, save directory: {:?}", config.save_dir);
         }
 
+        let manifest_signer = match &config.manifest_key {
+            Some(key) => Signer::new(key),
+            None => {
+                if config.enabled {
+                    info!("FREEZE_MANIFEST_KEY not set, manifests are signed with a random key");
+                }
+                Signer::random()
+            }
+        };
+
         Ok(Self {
             config,
             metadata_cache: parking_lot::RwLock::new(HashMap::new()),
+            manifest_signer,
         })
     }
 
-    /// Generate an owner token (UUID v4)
+    /// Largest document that can be frozen, in bytes
+    pub fn max_file_size(&self) -> u64 {
+        self.config.max_file_size
+    }
+
+    /// Whether documents frozen without a language get one inferred
+    pub fn infers_language(&self) -> bool {
+        self.config.infer_language
+    }
+
+        /// Generate an owner token (UUID v4)
     pub fn generate_owner_token() -> String {
         Uuid::new_v4().to_string()
     }
@@ -138,13 +472,24 @@ impl FreezeManager {
             bail!("File freeze feature is not enabled");
         }
 
+        if let Some(allowed) = &self.config.allowed_languages {
+            if !allowed.contains(&language.to_lowercase()) {
+                let permitted: Vec<_> = allowed.iter().map(String::as_str).collect();
+                bail!(ApiError::Forbidden(format!(
+                    "Documents in {} may not be frozen; permitted languages: {}",
+                    language,
+                    permitted.join(", ")
+                )));
+            }
+        }
+
         let content_bytes = content.as_bytes();
         if content_bytes.len() as u64 > self.config.max_file_size {
-            bail!(
+            bail!(ApiError::PayloadTooLarge(format!(
                 "Document size ({} bytes) exceeds maximum ({})",
                 content_bytes.len(),
                 self.config.max_file_size
-            );
+            )));
         }
 
         // Create directory structure: {SAVE_DIR}/frozen/{username}/
@@ -153,11 +498,22 @@ impl FreezeManager {
             .context("Failed to create owner directory")?;
 
         let file_extension = Self::get_extension(language);
-        let filename = format!("{}.{}", document_id, file_extension);
+        let filename = match self.config.compression {
+            Some(compression) => {
+                format!("{}.{}.{}", document_id, file_extension, compression.suffix())
+            }
+            None => format!("{}.{}", document_id, file_extension),
+        };
         let file_path = owner_dir.join(&filename);
 
         // Write the file
-        fs::write(&file_path, content_bytes)
+        let stored = match self.config.compression {
+            Some(compression) => compression
+                .compress(content_bytes)
+                .context("Failed to compress frozen document")?,
+            None => content_bytes.to_vec(),
+        };
+        fs::write(&file_path, &stored)
             .context("Failed to write frozen document")?;
 
         let frozen_at = Utc::now();
@@ -172,6 +528,8 @@ impl FreezeManager {
             expires_at,
             file_path: file_path.clone(),
             file_size: content_bytes.len() as u64,
+            content_hash: Some(content_hash(content)),
+            compressed: self.config.compression.is_some(),
         };
 
         // Save metadata
@@ -185,13 +543,66 @@ impl FreezeManager {
             .push(frozen_doc.clone());
 
         info!(
-            "Frozen document: id={}, username={}, size={} bytes",
-            document_id, username, content_bytes.len()
+            "Frozen document: id={}, username={}, size={} bytes, stored={} bytes",
+            document_id, username, content_bytes.len(), stored.len()
         );
 
         Ok(frozen_doc)
     }
 
+    /// Push back the expiry of a frozen document
+    ///
+    /// The new expiry is capped at `max_lifetime_days` after the document was
+    /// frozen, and an extension that gains nothing is refused.
+    pub fn extend_expiry(
+        &self,
+        username: &str,
+        document_id: &str,
+        additional_days: u32,
+    ) -> Result<FrozenDocument> {
+        if !self.config.enabled {
+            bail!("File freeze feature is not enabled");
+        }
+        if additional_days == 0 {
+            bail!(ApiError::BadRequest("Extension must be at least one day".into()));
+        }
+
+        let mut doc = self
+            .list_frozen_documents(username)?
+            .into_iter()
+            .find(|d| d.document_id == document_id)
+            .ok_or_else(|| ApiError::NotFound("Document not found".into()))?;
+        if doc.expires_at < Utc::now() {
+            bail!(ApiError::Conflict("Frozen document has already expired".into()));
+        }
+
+        let limit = doc.frozen_at + Duration::days(self.config.max_lifetime_days.into());
+        let expires_at = (doc.expires_at + Duration::days(additional_days.into())).min(limit);
+        if expires_at <= doc.expires_at {
+            bail!(ApiError::Conflict(format!(
+                "Frozen documents may be kept for at most {} days",
+                self.config.max_lifetime_days
+            )));
+        }
+        doc.expires_at = expires_at;
+
+        self.save_metadata(&doc)?;
+        let mut cache = self.metadata_cache.write();
+        if let Some(cached) = cache
+            .get_mut(username)
+            .and_then(|docs| docs.iter_mut().find(|d| d.document_id == document_id))
+        {
+            *cached = doc.clone();
+        }
+
+        info!(
+            "Extended frozen document: id={}, username={}, expires_at={}",
+            document_id, username, expires_at
+        );
+
+        Ok(doc)
+    }
+
     /// List frozen documents for a user
     pub fn list_frozen_documents(&self, username: &str) -> Result<Vec<FrozenDocument>> {
         if !self.config.enabled {
@@ -229,26 +640,174 @@ impl FreezeManager {
         Ok(documents)
     }
 
+    /// List one page of a user's frozen documents, newest first
+    ///
+    /// Documents frozen at the same moment are ordered by ID, so pages never
+    /// overlap. The limit defaults to, and is capped at, the page size.
+    pub fn list_frozen_page(
+        &self,
+        username: &str,
+        offset: usize,
+        limit: Option<usize>,
+    ) -> Result<FrozenPage> {
+        let limit = limit
+            .unwrap_or(self.config.page_size)
+            .min(self.config.page_size);
+        let mut documents = self.list_frozen_documents(username)?;
+        let total = documents.len();
+        documents.sort_by(|a, b| {
+            b.frozen_at
+                .cmp(&a.frozen_at)
+                .then_with(|| a.document_id.cmp(&b.document_id))
+        });
+        let documents = documents.into_iter().skip(offset).take(limit).collect();
+        Ok(FrozenPage {
+            documents,
+            total,
+            offset,
+            limit,
+        })
+    }
+
     /// Get a specific frozen document content
     pub fn get_frozen_document(&self, username: &str, document_id: &str) -> Result<String> {
+        let (_, content) = self.get_frozen_document_with_metadata(username, document_id)?;
+        Ok(content)
+    }
+
+    /// Get a specific frozen document along with its metadata
+    ///
+    /// The content hash is filled in for documents frozen before it was recorded.
+    pub fn get_frozen_document_with_metadata(
+        &self,
+        username: &str,
+        document_id: &str,
+    ) -> Result<(FrozenDocument, String)> {
         if !self.config.enabled {
             bail!("File freeze feature is not enabled");
         }
 
         let documents = self.list_frozen_documents(username)?;
-        let doc = documents
-            .iter()
+        let mut doc = documents
+            .into_iter()
             .find(|d| d.document_id == document_id)
-            .context("Document not found")?;
+            .ok_or_else(|| ApiError::NotFound("Document not found".into()))?;
 
         if !doc.file_path.exists() {
-            bail!("Frozen document file not found");
+            bail!(ApiError::NotFound("Frozen document file not found".into()));
         }
 
-        let content = fs::read_to_string(&doc.file_path)
-            .context("Failed to read frozen document")?;
+        let content = self.read_content(&doc)?;
 
-        Ok(content)
+        if doc.content_hash.is_none() {
+            doc.content_hash = Some(content_hash(&content));
+        }
+
+        Ok((doc, content))
+    }
+
+    /// Read a frozen file, decompressing it if needed
+    fn read_content(&self, doc: &FrozenDocument) -> Result<String> {
+        let bytes = fs::read(&doc.file_path).context("Failed to read frozen document")?;
+        let bytes = if doc.compressed {
+            Compression::from_path(&doc.file_path)
+                .context("Unknown compression for frozen document")?
+                .decompress(&bytes, self.config.max_file_size)
+                .context("Failed to decompress frozen document")?
+        } else {
+            bytes
+        };
+        String::from_utf8(bytes).context("Frozen document is not valid UTF-8")
+    }
+
+    /// Open a frozen file for reading line by line, decompressing it if needed
+    fn open_content(&self, doc: &FrozenDocument) -> Result<impl BufRead> {
+        let file = fs::File::open(&doc.file_path).context("Failed to open frozen document")?;
+        let reader: Box<dyn Read> = if doc.compressed {
+            Compression::from_path(&doc.file_path)
+                .context("Unknown compression for frozen document")?
+                .decoder(file)?
+        } else {
+            Box::new(file)
+        };
+        Ok(BufReader::new(reader.take(self.config.max_file_size)))
+    }
+
+    /// Search the content of a user's frozen documents, ignoring case
+    ///
+    /// Files are read a line at a time rather than loaded whole, and only the
+    /// first few matching lines of each are reported. Documents are returned
+    /// newest first; any that can't be read are skipped.
+    pub fn search(
+        &self,
+        username: &str,
+        query: &str,
+    ) -> Result<Vec<(FrozenDocument, Vec<SearchHit>)>> {
+        let needle = query.to_lowercase();
+        let mut documents = self.list_frozen_documents(username)?;
+        documents.sort_by(|a, b| b.frozen_at.cmp(&a.frozen_at));
+
+        let mut results = Vec::new();
+        for doc in documents {
+            match self.search_document(&doc, &needle) {
+                Ok(hits) if hits.is_empty() => {}
+                Ok(hits) => results.push((doc, hits)),
+                Err(e) => warn!(
+                    "Failed to search frozen document: id={}, username={}: {:#}",
+                    doc.document_id, username, e
+                ),
+            }
+        }
+        Ok(results)
+    }
+
+    /// Find the lines of one frozen document containing a lowercase needle
+    fn search_document(&self, doc: &FrozenDocument, needle: &str) -> Result<Vec<SearchHit>> {
+        let mut hits = Vec::new();
+        for (index, line) in self.open_content(doc)?.lines().enumerate() {
+            let line = line?;
+            if line.to_lowercase().contains(needle) {
+                hits.push(SearchHit::new(index + 1, &line));
+                if hits.len() == MAX_HITS_PER_DOCUMENT {
+                    break;
+                }
+            }
+        }
+        Ok(hits)
+    }
+
+    /// Build a signed manifest of a user's frozen documents
+    pub fn manifest(&self, username: &str) -> Result<Manifest> {
+        let mut documents = Vec::new();
+        for metadata in self.list_frozen_documents(username)? {
+            let (metadata, _) =
+                self.get_frozen_document_with_metadata(username, &metadata.document_id)?;
+            documents.push(ManifestEntry {
+                file_name: format!("{}.{}", metadata.document_id, metadata.file_extension),
+                sha256: metadata.content_hash.unwrap_or_default(),
+                document_id: metadata.document_id,
+                language: metadata.language,
+                file_size: metadata.file_size,
+                frozen_at: metadata.frozen_at,
+                expires_at: metadata.expires_at,
+            });
+        }
+        documents.sort_by_key(|entry| entry.frozen_at);
+
+        let mut manifest = Manifest {
+            username: username.to_string(),
+            generated_at: Utc::now(),
+            documents,
+            signature: String::new(),
+        };
+        manifest.signature = self.manifest_signer.sign_hex(&manifest.signed_bytes()?);
+        Ok(manifest)
+    }
+
+    /// Check that a manifest was signed by this server and is unmodified
+    pub fn verify_manifest(&self, manifest: &Manifest) -> Result<bool> {
+        let (signed, signer) = (manifest.signed_bytes()?, &self.manifest_signer);
+        Ok(signer.verify_hex(&signed, &manifest.signature))
     }
 
     /// Delete a frozen document
@@ -261,7 +820,7 @@ impl FreezeManager {
         let metadata_file = owner_dir.join("metadata.json");
 
         if !metadata_file.exists() {
-            bail!("No frozen documents found for this user");
+            bail!(ApiError::NotFound("No frozen documents found for this user".into()));
         }
 
         // Load existing metadata
@@ -274,7 +833,7 @@ impl FreezeManager {
         let doc_index = documents
             .iter()
             .position(|d| d.document_id == document_id)
-            .context("Document not found")?;
+            .ok_or_else(|| ApiError::NotFound("Document not found".into()))?;
 
         let doc = documents.remove(doc_index);
 
@@ -343,18 +902,47 @@ impl FreezeManager {
         Ok(())
     }
 
-    /// Clean up expired frozen documents
-    pub fn cleanup_expired(&self) -> Result<usize> {
+    /// Count frozen documents per language, across all owners
+    pub fn language_counts(&self) -> Result<BTreeMap<String, usize>> {
+        let mut counts = BTreeMap::new();
+        if !self.config.enabled {
+            return Ok(counts);
+        }
+
+        let frozen_dir = self.config.save_dir.join("frozen");
+        if !frozen_dir.exists() {
+            return Ok(counts);
+        }
+
+        for entry in fs::read_dir(&frozen_dir)? {
+            let entry = entry?;
+            if !entry.file_type()?.is_dir() {
+                continue;
+            }
+            let owner = entry.file_name().to_string_lossy().to_string();
+            for doc in self.list_frozen_documents(&owner)? {
+                *counts.entry(doc.language).or_insert(0) += 1;
+            }
+        }
+
+        Ok(counts)
+    }
+
+    /// Clean up expired frozen documents, returning those removed
+    ///
+    /// With `dry_run`, nothing is deleted and the metadata and cache are left
+    /// untouched, so the result only reports what a real pass would remove.
+    pub fn cleanup_expired(&self, dry_run: bool) -> Result<Vec<FrozenDocument>> {
         if !self.config.enabled {
-            return Ok(0);
+            return Ok(Vec::new());
         }
 
         let frozen_dir = self.config.save_dir.join("frozen");
         if !frozen_dir.exists() {
-            return Ok(0);
+            return Ok(Vec::new());
         }
 
-        let mut cleaned_count = 0;
+        let mut cleaned = Vec::new();
         let now = Utc::now();
 
         for entry in fs::read_dir(&frozen_dir)? {
@@ -371,24 +959,23 @@ impl FreezeManager {
             }
 
             let content = fs::read_to_string(&metadata_file)?;
-            let mut documents: Vec<FrozenDocument> = serde_json::from_str(&content)?;
-
-            let original_len = documents.len();
-            documents.retain(|doc| {
-                if doc.expires_at < now {
-                    // Delete the file
-                    if doc.file_path.exists() {
-                        if let Err(e) = fs::remove_file(&doc.file_path) {
-                            warn!("Failed to delete expired file: {}", e);
-                        }
+            let documents: Vec<FrozenDocument> = serde_json::from_str(&content)?;
+
+            let (expired, documents): (Vec<_>, Vec<_>) =
+                documents.into_iter().partition(|doc| doc.expires_at < now);
+            if dry_run {
+                cleaned.extend(expired);
+                continue;
+            }
+            for doc in &expired {
+                // Delete the file
+                if doc.file_path.exists() {
+                    if let Err(e) = fs::remove_file(&doc.file_path) {
+                        warn!("Failed to delete expired file: {}", e);
                     }
-                    false
-                } else {
-                    true
                 }
-            });
-
-            cleaned_count += original_len - documents.len();
+            }
+            cleaned.extend(expired);
 
             if documents.is_empty() {
                 // Remove the entire owner directory if empty
@@ -410,10 +997,10 @@ impl FreezeManager {
             }
         }
 
-        if cleaned_count > 0 {
-            info!("Cleaned up {} expired frozen documents", cleaned_count);
+        if !dry_run && !cleaned.is_empty() {
+            info!("Cleaned up {} expired frozen documents", cleaned.len());
         }
 
-        Ok(cleaned_count)
+        Ok(cleaned)
     }
 }
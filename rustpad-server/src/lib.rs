@@ -3,25 +3,49 @@
 #![forbid(unsafe_code)]
 #![warn(missing_docs)]
 
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 use std::time::{Duration, SystemTime};
 
-use dashmap::DashMap;
+use dashmap::{mapref::one::RefMut, DashMap};
 use log::{error, info};
 use rand::Rng;
 use serde::Serialize;
 use tokio::time::{self, Instant};
+use uuid::Uuid;
 use warp::{filters::BoxedFilter, ws::Ws, Filter, Rejection, Reply};
 
-use crate::{ai::AiManager, artifacts::ArtifactManager, auth::AuthManager, database::Database, freeze::FreezeManager, rustpad::Rustpad};
+use crate::{acl::{Access, DocumentAcl}, ai::AiManager, artifacts::ArtifactManager, audit::{AuditEvent, AuditLog}, auth::{AuthManager, SessionClient, User}, backup::BackupConfig, database::{hash_share_token, password_matches, Database, DocumentSummary, PersistedDocument}, error::ApiError, freeze::{FreezeManager, FrozenDocument, SearchHit}, lint::Linter, load::ServerLoad, metadata::{DocumentMetadata, MetadataUpdate}, names::AnonymousNames, persistence::{is_not_found, FileStore, LoadFailurePolicy, PersistenceRoutes, PersistenceTarget, Sink}, rustpad::{ConnectionAccess, DocumentConfig, Participant, Rustpad}, stats::{StatsHistory, StatsSample}, templates::LanguageTemplates, webhooks::{WebhookDispatcher, WebhookEvent}};
 
+pub use load::{IoLimiter, JoinRate, UserLimitPolicy};
+
+pub mod acl;
 pub mod ai;
+pub mod ai_log;
 pub mod artifacts;
+pub mod audit;
 pub mod auth;
+pub mod backup;
 pub mod database;
+pub mod error;
+mod export;
 pub mod freeze;
+pub mod lint;
+mod load;
+pub mod metadata;
+mod metrics;
+pub mod names;
 mod ot;
+pub mod persistence;
 mod rustpad;
+mod signer;
+mod stats;
+pub mod templates;
+pub mod webhooks;
 
 /// An entry stored in the global server map.
 ///
@@ -30,37 +54,100 @@ mod rustpad;
 /// growing without bound.
 struct Document {
     last_accessed: Instant,
+    /// Whether clients were warned of eviction since the last access.
+    expiry_warned: bool,
+    /// How long this document may stay idle, overriding the server default.
+    expiry: DocumentExpiry,
+    /// When a client last forced a snapshot, for rate limiting.
+    last_snapshot: Option<Instant>,
+    /// Where the document is persisted, chosen when it was created.
+    persistence: PersistenceTarget,
+    /// User who created the document, if it was created while logged in.
+    owner: Option<String>,
+    /// Whether the document is being evicted, so the cleaner skips it.
+    draining: bool,
+    /// When the document's allowance of joins would be spent, if limited.
+    join_schedule: Option<Instant>,
     rustpad: Arc<Rustpad>,
 }
 
 impl Document {
-    fn new(rustpad: Arc<Rustpad>) -> Self {
+    fn new(rustpad: Arc<Rustpad>, persistence: PersistenceTarget, owner: Option<String>) -> Self {
         Self {
             last_accessed: Instant::now(),
+            expiry_warned: false,
+            expiry: DocumentExpiry::Default,
+            last_snapshot: None,
+            persistence,
+            owner,
+            draining: false,
+            join_schedule: None,
             rustpad,
         }
     }
 }
 
+/// How long a document may stay idle before it is removed from memory.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum DocumentExpiry {
+    /// Use the server's `expiry_days`.
+    Default,
+    /// Remove the document after this much idle time.
+    After(Duration),
+    /// Keep the document in memory until the server stops.
+    Never,
+}
+
+impl DocumentExpiry {
+    /// Idle time before eviction, given the server default, or `None` if the
+    /// document is never evicted.
+    fn resolve(self, default: Duration) -> Option<Duration> {
+        match self {
+            DocumentExpiry::Default => Some(default),
+            DocumentExpiry::After(expiry) => Some(expiry),
+            DocumentExpiry::Never => None,
+        }
+    }
+}
+
 impl Drop for Document {
     fn drop(&mut self) {
         self.rustpad.kill();
     }
 }
 
-#[allow(dead_code)]
+/// Rejection for a failed handler, logged under the request's id and answered
+/// by [`with_request_log`] with the status of the [`ApiError`] behind it.
 #[derive(Debug)]
 struct CustomReject(anyhow::Error);
 
 impl warp::reject::Reject for CustomReject {}
 
+impl From<ApiError> for Rejection {
+    fn from(error: ApiError) -> Self {
+        warp::reject::custom(CustomReject(error.into()))
+    }
+}
+
 /// The shared state of the server, accessible from within request handlers.
 #[derive(Clone)]
 struct ServerState {
     /// Concurrent map storing in-memory documents.
     documents: Arc<DashMap<String, Document>>,
+    /// Guards held while a document is loaded, so that concurrent connections
+    /// to the same cold id share one load.
+    loading: Arc<DashMap<String, Arc<tokio::sync::Mutex<()>>>>,
+    /// Held while a new document is counted against its owner's limit and
+    /// inserted, so that concurrent creations can't both fit under it.
+    creating: Arc<parking_lot::Mutex<()>>,
+    /// Number of documents read from persistence since the server started.
+    persisted_loads: Arc<AtomicU64>,
     /// Connection to the database pool, if persistence is enabled.
     database: Option<Database>,
+    /// Directory of persisted documents, if file persistence is enabled.
+    file_store: Option<FileStore>,
+    /// Rules choosing where each document is persisted.
+    persistence_routes: PersistenceRoutes,
     /// File freeze manager for 30-day persistence.
     freeze_manager: Option<Arc<FreezeManager>>,
     /// Authentication manager for user accounts.
@@ -69,6 +156,42 @@ struct ServerState {
     ai_manager: Option<Arc<AiManager>>,
     /// Artifact manager for multi-file AI outputs.
     artifact_manager: Option<Arc<ArtifactManager>>,
+    /// Linter run against documents after they change.
+    linter: Option<Arc<Linter>>,
+    /// Limits applied to newly created documents.
+    document_config: DocumentConfig,
+    /// Live connection count and shutdown state.
+    load: Arc<ServerLoad>,
+    /// Schedule of the background cleaner.
+    cleaner: Arc<CleanerSchedule>,
+    /// Recent samples of server statistics.
+    stats_history: Arc<StatsHistory>,
+    /// Content that seeds brand-new documents, if configured.
+    default_content: Option<String>,
+    /// Content that seeds documents created with a language, if configured.
+    language_templates: Option<Arc<LanguageTemplates>>,
+    /// Public URL of the frontend, if configured.
+    public_url: Option<String>,
+    /// Maximum number of live documents a non-admin user may own.
+    max_documents_per_user: Option<usize>,
+    /// Origins allowed to open WebSocket connections, or `None` for any.
+    allowed_origins: Option<Arc<[String]>>,
+    /// What to do with persisted documents that fail to load.
+    load_failure_policy: LoadFailurePolicy,
+    /// Whether clients may create end-to-end encrypted documents.
+    encrypted_documents: bool,
+    /// How long clients of an evicted document have to finish, if at all.
+    drain_grace_period: Option<Duration>,
+    /// How fast connections may join any one document, if limited.
+    join_rate: Option<JoinRate>,
+    /// Bounds concurrent filesystem writes by the freeze and artifact managers.
+    io_limiter: IoLimiter,
+    /// Public branding shown by the frontend.
+    branding: Arc<Branding>,
+    /// Log of connections and authenticated requests, if enabled.
+    audit_log: Option<Arc<AuditLog>>,
+    /// Sender of event notifications, if any URLs are configured.
+    webhooks: Option<Arc<WebhookDispatcher>>,
 }
 
 /// Statistics about the server, returned from an API endpoint.
@@ -80,6 +203,10 @@ struct Stats {
     num_documents: usize,
     /// Number of documents persisted in the database.
     database_size: usize,
+    /// Number of documents read from persistence since the server started.
+    persisted_loads: u64,
+    /// Number of open documents with each tag.
+    tags: BTreeMap<String, usize>,
 }
 
 /// Server configuration.
@@ -87,8 +214,19 @@ struct Stats {
 pub struct ServerConfig {
     /// Number of days to clean up documents after inactivity.
     pub expiry_days: u32,
+    /// How long before eviction to warn a document's clients, if at all.
+    pub expiry_warning: Option<Duration>,
+    /// How long clients of an evicted document are given to finish before
+    /// being told to reload it, or `None` to unload it immediately.
+    pub drain_grace_period: Option<Duration>,
     /// Database object, for persistence if desired.
     pub database: Option<Database>,
+    /// Schedule for backing up the database, if desired.
+    pub backup: Option<BackupConfig>,
+    /// File store, for persistence to a directory if desired.
+    pub file_store: Option<FileStore>,
+    /// Rules routing documents to the database, file store, or neither.
+    pub persistence_routes: PersistenceRoutes,
     /// Freeze manager for 30-day document persistence.
     pub freeze_manager: Option<Arc<FreezeManager>>,
     /// Authentication manager for user accounts.
@@ -97,27 +235,498 @@ pub struct ServerConfig {
     pub ai_manager: Option<Arc<AiManager>>,
     /// Artifact manager for multi-file AI outputs.
     pub artifact_manager: Option<Arc<ArtifactManager>>,
+    /// Linter run against documents after they change.
+    pub linter: Option<Arc<Linter>>,
+    /// Number of operations a document retains before compacting its history.
+    pub max_revisions: Option<usize>,
+    /// Number of recent revisions whose edits users may undo on the server,
+    /// or `None` to disable undo.
+    pub undo_limit: Option<usize>,
+    /// Longest line, in characters, that edits may leave in a document, or
+    /// `None` for no limit.
+    pub max_line_length: Option<usize>,
+    /// Largest size, in bytes, that edits may grow a document's text to, or
+    /// `None` for no limit beyond the fixed 256 KiB character cap.
+    pub max_document_bytes: Option<usize>,
+    /// Refuse edits that insert null bytes or control characters other than
+    /// tabs and line breaks.
+    pub reject_binary_content: bool,
+    /// How long a closed connection's name and cursor are kept for its
+    /// client to reconnect, or `None` to drop them at once.
+    pub cursor_grace: Option<Duration>,
+    /// Window for batching other users' operations into one message, if any.
+    pub coalesce_window: Option<Duration>,
+    /// Honor the `X-Disable-Feature` header, only effective in debug builds.
+    pub debug_headers: bool,
+    /// Maximum number of simultaneous WebSocket connections, if limited.
+    pub max_connections: Option<usize>,
+    /// Welcome or template content for newly created documents.
+    pub default_content: Option<String>,
+    /// Starter content for documents created with a language.
+    pub language_templates: Option<Arc<LanguageTemplates>>,
+    /// Public URL of the frontend, used to build shareable document links.
+    pub public_url: Option<String>,
+    /// Names given to anonymous collaborators, if enabled.
+    pub anonymous_names: Option<Arc<AnonymousNames>>,
+    /// Tell clients when their edits have been durably persisted.
+    pub persistence_status: bool,
+    /// Convert text inserted into documents to Unicode NFC.
+    pub normalize_unicode: bool,
+    /// Size in bytes past which operations are compressed before broadcast.
+    pub compression_threshold: Option<usize>,
+    /// Allow clients to create end-to-end encrypted documents, whose content
+    /// the server relays without reading.
+    pub encrypted_documents: bool,
+    /// Maximum number of simultaneous connections per authenticated user.
+    pub max_user_connections: Option<usize>,
+    /// Whether a user over their limit is refused or has old connections closed.
+    pub user_limit_policy: UserLimitPolicy,
+    /// How fast connections may join any one document, if limited.
+    pub join_rate: Option<JoinRate>,
+    /// Most freeze and artifact filesystem operations that may run at once.
+    pub max_io_operations: usize,
+    /// How long an HTTP handler may run before it answers `504 Gateway Timeout`.
+    pub request_timeout: Duration,
+    /// Maximum number of live documents a non-admin user may create.
+    pub max_documents_per_user: Option<usize>,
+    /// Origins allowed to open WebSocket connections, or `None` for any.
+    pub allowed_origins: Option<Vec<String>>,
+    /// Origins allowed to make cross-origin API requests, or empty for none.
+    pub cors_origins: Vec<String>,
+    /// Whether a corrupt persisted document is refused, or replaced by an
+    /// empty one with a warning.
+    pub load_failure_policy: LoadFailurePolicy,
+    /// Time between samples of server statistics.
+    pub stats_interval: Duration,
+    /// How long samples of server statistics are kept.
+    pub stats_retention: Duration,
+    /// Branding and defaults that the frontend reads from `/api/config`.
+    pub branding: Branding,
+    /// Log of connections and authenticated requests, if enabled.
+    pub audit_log: Option<Arc<AuditLog>>,
+    /// Sender of notifications when documents are frozen or AI chats
+    /// complete, if any.
+    pub webhooks: Option<Arc<WebhookDispatcher>>,
+}
+
+/// Per-deployment branding, all of it safe to show to anyone.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct Branding {
+    /// Name of this instance, shown in place of "Rustpad".
+    pub instance_name: Option<String>,
+    /// CSS color for the frontend's title bar.
+    pub theme_color: Option<String>,
+    /// Language given to new documents created without one.
+    pub default_language: Option<String>,
 }
 
 impl Default for ServerConfig {
     fn default() -> Self {
         Self {
             expiry_days: 1,
+            expiry_warning: None,
+            drain_grace_period: None,
             database: None,
+            backup: None,
+            file_store: None,
+            persistence_routes: PersistenceRoutes::default(),
             freeze_manager: None,
             auth_manager: None,
             ai_manager: None,
             artifact_manager: None,
+            linter: None,
+            max_revisions: None,
+            undo_limit: None,
+            max_line_length: None,
+            max_document_bytes: None,
+            reject_binary_content: false,
+            cursor_grace: None,
+            coalesce_window: None,
+            debug_headers: false,
+            max_connections: None,
+            default_content: None,
+            language_templates: None,
+            public_url: None,
+            anonymous_names: None,
+            persistence_status: false,
+            normalize_unicode: false,
+            compression_threshold: None,
+            encrypted_documents: false,
+            max_user_connections: None,
+            user_limit_policy: UserLimitPolicy::default(),
+            join_rate: None,
+            max_io_operations: 8,
+            request_timeout: Duration::from_secs(60),
+            max_documents_per_user: None,
+            allowed_origins: None,
+            cors_origins: Vec::new(),
+            load_failure_policy: LoadFailurePolicy::default(),
+            stats_interval: Duration::from_secs(60),
+            stats_retention: HOUR * 24,
+            branding: Branding::default(),
+            audit_log: None,
+            webhooks: None,
+        }
+    }
+}
+
+impl ServerState {
+    /// Create the in-memory state for a brand-new document.
+    ///
+    /// A document created with a language starts from that language's
+    /// template when there is one, in place of the default content. An
+    /// encrypted document always starts empty, since the server can't write
+    /// to it.
+    fn new_rustpad(&self, language: Option<&str>, owner: Option<&str>, encrypted: bool) -> Rustpad {
+        let language = language.or(self.branding.default_language.as_deref());
+        let template = language.and_then(|language| self.language_templates.as_ref()?.get(language));
+        let text = template.or(self.default_content.as_deref());
+        let rustpad = match (text, language) {
+            _ if encrypted => Rustpad::from(PersistedDocument {
+                language: language.map(String::from),
+                encrypted: true,
+                ..PersistedDocument::default()
+            }),
+            (None, None) => Rustpad::default(),
+            (text, language) => Rustpad::from(PersistedDocument {
+                text: text.unwrap_or_default().to_string(),
+                language: language.map(String::from),
+                ..PersistedDocument::default()
+            }),
+        };
+        rustpad.set_creator(owner.map(String::from), chrono::Utc::now());
+        rustpad.with_config(self.document_config.clone())
+    }
+
+    /// Choose where a new document is persisted, honoring an explicit request
+    /// over the routing rules. Without either, any available sink is used.
+    fn persistence_target(&self, id: &str, requested: Option<PersistenceTarget>) -> PersistenceTarget {
+        requested
+            .or_else(|| self.persistence_routes.route(id))
+            .unwrap_or(if self.database.is_some() {
+                PersistenceTarget::Database
+            } else if self.file_store.is_some() {
+                PersistenceTarget::File
+            } else {
+                PersistenceTarget::None
+            })
+    }
+
+    /// The configured sink for a persistence target, if there is one.
+    fn sink(&self, target: PersistenceTarget) -> Option<Sink> {
+        match target {
+            PersistenceTarget::Database => self.database.clone().map(Sink::Database),
+            PersistenceTarget::File => self.file_store.clone().map(Sink::File),
+            PersistenceTarget::None => None,
+        }
+    }
+
+    /// The most documents `user` may own, unless they are an admin.
+    fn document_limit(&self, user: &User) -> Option<usize> {
+        self.max_documents_per_user.filter(|_| !user.is_admin)
+    }
+
+    /// Number of documents in memory that were created by a user.
+    fn owned_documents(&self, username: &str) -> usize {
+        self.documents
+            .iter()
+            .filter(|entry| entry.owner.as_deref() == Some(username))
+            .count()
+    }
+
+    /// Whether any persistence sink is configured.
+    fn has_persistence(&self) -> bool {
+        self.database.is_some() || self.file_store.is_some()
+    }
+
+    /// Store a document's current snapshot right away, rather than waiting
+    /// for the next persister tick.
+    async fn persist_now(
+        &self,
+        id: &str,
+        rustpad: &Rustpad,
+        persistence: PersistenceTarget,
+    ) -> anyhow::Result<()> {
+        let revision = rustpad.revision();
+        if let Some(sink) = self.sink(persistence) {
+            sink.store(id, &rustpad.snapshot()).await?;
+            rustpad.mark_persisted(revision);
+        }
+        Ok(())
+    }
+
+    /// Check a caller's credentials before serving a document over HTTP.
+    ///
+    /// Returns the reply to send instead if they may not read it.
+    async fn deny_read(
+        &self,
+        document: &PersistedDocument,
+        reader: Reader,
+    ) -> Result<Option<warp::reply::Response>, Rejection> {
+        self.deny_access(document, reader, Access::Read).await
+    }
+
+    /// Check a caller's credentials before changing a document over HTTP.
+    ///
+    /// Returns the reply to send instead if they may not edit it.
+    async fn deny_write(
+        &self,
+        document: &PersistedDocument,
+        reader: Reader,
+    ) -> Result<Option<warp::reply::Response>, Rejection> {
+        self.deny_access(document, reader, Access::Write).await
+    }
+
+    /// Check that a caller has `needed` access to a document, returning the
+    /// reply to send instead if they don't.
+    async fn deny_access(
+        &self,
+        document: &PersistedDocument,
+        reader: Reader,
+        needed: Access,
+    ) -> Result<Option<warp::reply::Response>, Rejection> {
+        let hash = document.password_hash.clone();
+        if !check_password(hash, reader.password.clone()).await? {
+            let error = ApiError::Unauthorized("Document password required".into());
+            return Ok(Some(error_reply(&error.into())));
+        }
+        // Only a restricted list can deny access, so open documents skip
+        // authentication entirely.
+        let restricted = match needed {
+            Access::Write => !document.acl.is_open(),
+            _ => document.acl.read.is_some(),
+        };
+        if restricted {
+            let username = match &self.auth_manager {
+                Some(auth_manager) if reader.auth.header.is_some() => {
+                    Some(authenticate(reader.auth, auth_manager).await?.username)
+                }
+                _ => None,
+            };
+            let message = match (document.acl.access(username.as_deref()), needed) {
+                (Access::Denied, _) => "Not allowed to view this document",
+                (Access::Read, Access::Write) => "Not allowed to edit this document",
+                _ => return Ok(None),
+            };
+            let error = ApiError::Forbidden(message.into());
+            return Ok(Some(error_reply(&error.into())));
+        }
+        Ok(None)
+    }
+
+    /// Reply for endpoints that need a document's text, which the server
+    /// can't read for an end-to-end encrypted document.
+    fn encrypted_reply() -> warp::reply::Response {
+        let error = ApiError::Conflict("Document is end-to-end encrypted".into());
+        error_reply(&error.into())
+    }
+
+    /// Get a document from memory, loading or creating it if needed, for a
+    /// caller that `admit` first checks may open it.
+    ///
+    /// Concurrent callers for the same cold id wait on one load rather than
+    /// each reading the document from persistence. A cold document is checked
+    /// against its persisted copy, so a refused caller never brings it into
+    /// memory.
+    async fn open_document<T, F>(
+        &self,
+        id: &str,
+        admit: impl Fn(DocumentGate) -> F,
+    ) -> Result<(RefMut<'_, String, Document>, T), Rejection>
+    where
+        F: Future<Output = Result<T, Rejection>>,
+    {
+        loop {
+            let gate = self
+                .documents
+                .get(id)
+                .map(|document| DocumentGate::from(&*document.rustpad));
+            let admitted = match gate {
+                Some(gate) => admit(gate).await?,
+                None => {
+                    let guard = Arc::clone(self.loading.entry(id.to_string()).or_default().value());
+                    let _held = guard.lock().await;
+                    if self.documents.contains_key(id) {
+                        continue;
+                    }
+                    let result = self.load_document(id, &admit).await;
+                    self.loading
+                        .remove_if(id, |_, lock| Arc::ptr_eq(lock, &guard));
+                    result?
+                }
+            };
+            // The document may have been evicted while it was being checked.
+            if let Some(document) = self.documents.get_mut(id) {
+                return Ok((document, admitted));
+            }
+        }
+    }
+
+    /// Bring a document into memory from persistence, or start a new one, if
+    /// `admit` lets the caller open it.
+    async fn load_document<T, F>(
+        &self,
+        id: &str,
+        admit: impl Fn(DocumentGate) -> F,
+    ) -> Result<T, Rejection>
+    where
+        F: Future<Output = Result<T, Rejection>>,
+    {
+        let persisted = self
+            .load_persisted(id)
+            .await
+            .map_err(|e| warp::reject::custom(CustomReject(e)))?;
+        let admitted = match &persisted {
+            Some((document, _)) => admit(DocumentGate::from(document)).await?,
+            None => admit(DocumentGate::default()).await?,
+        };
+        let (rustpad, persistence) = match persisted {
+            Some((document, persistence)) => (
+                Rustpad::from(document).with_config(self.document_config.clone()),
+                persistence,
+            ),
+            None => (
+                self.new_rustpad(None, None, false),
+                self.persistence_target(id, None),
+            ),
+        };
+        let rustpad = Arc::new(rustpad);
+        self.spawn_tasks(id, &rustpad, persistence);
+        self.documents
+            .insert(id.to_string(), Document::new(rustpad, persistence, None));
+        Ok(admitted)
+    }
+
+    /// Load a document from whichever sink it was persisted to.
+    ///
+    /// Returns `Ok(None)` if the document was never persisted. A document
+    /// that exists but can't be read is handled by the load failure policy.
+    async fn load_persisted(
+        &self,
+        id: &str,
+    ) -> anyhow::Result<Option<(PersistedDocument, PersistenceTarget)>> {
+        self.persisted_loads.fetch_add(1, Ordering::Relaxed);
+        if let Some(db) = &self.database {
+            match db.load(id).await {
+                Ok(document) => return Ok(Some((document, PersistenceTarget::Database))),
+                Err(e) if is_not_found(&e) => {}
+                Err(e) => return self.load_failed(id, PersistenceTarget::Database, e).await,
+            }
+        }
+        if let Some(store) = &self.file_store {
+            match store.load(id).await {
+                Ok(document) => return Ok(Some((document, PersistenceTarget::File))),
+                Err(e) if is_not_found(&e) => {}
+                Err(e) => return self.load_failed(id, PersistenceTarget::File, e).await,
+            }
+        }
+        Ok(None)
+    }
+
+    /// Apply the load failure policy to a document that could not be read.
+    async fn load_failed<T>(
+        &self,
+        id: &str,
+        target: PersistenceTarget,
+        err: anyhow::Error,
+    ) -> anyhow::Result<Option<T>> {
+        if self.load_failure_policy == LoadFailurePolicy::Strict {
+            error!("failed to load document {}: {:#}", id, err);
+            return Err(err.context(format!("Document {} could not be loaded", id)));
+        }
+        log::warn!(
+            "failed to load document {}, starting it empty and discarding the stored copy: {:#}",
+            id,
+            err
+        );
+        if self.load_failure_policy == LoadFailurePolicy::Quarantine {
+            let result = match target {
+                PersistenceTarget::Database => match &self.database {
+                    Some(db) => db.quarantine(id, &format!("{:#}", err)).await,
+                    None => Ok(()),
+                },
+                PersistenceTarget::File => match &self.file_store {
+                    Some(store) => store.quarantine(id).await,
+                    None => Ok(()),
+                },
+                PersistenceTarget::None => Ok(()),
+            };
+            match result {
+                Ok(()) => log::warn!("quarantined corrupt document {}", id),
+                Err(e) => error!("failed to quarantine document {}: {:#}", id, e),
+            }
+        }
+        Ok(None)
+    }
+
+    /// Start the background tasks that accompany an in-memory document.
+    fn spawn_tasks(&self, id: &str, rustpad: &Arc<Rustpad>, persistence: PersistenceTarget) {
+        if let Some(sink) = self.sink(persistence) {
+            tokio::spawn(persister(id.to_string(), Arc::clone(rustpad), sink));
+        }
+        if let Some(linter) = self.linter.as_ref().filter(|_| !rustpad.is_encrypted()) {
+            tokio::spawn(lint_runner(id.to_string(), Arc::clone(rustpad), Arc::clone(linter)));
+        }
+    }
+
+    /// Returns a copy of the state with the named features switched off, so
+    /// that handlers respond exactly as if they were not configured.
+    fn without_features(&self, features: &str) -> Self {
+        let mut state = self.clone();
+        for feature in features.split(',').map(str::trim) {
+            match feature {
+                "ai" => state.ai_manager = None,
+                "artifacts" => state.artifact_manager = None,
+                "auth" => state.auth_manager = None,
+                "freeze" => state.freeze_manager = None,
+                _ => {}
+            }
         }
+        state
     }
 }
 
 /// A combined filter handling all server routes.
 pub fn server(config: ServerConfig) -> BoxedFilter<(impl Reply,)> {
-    warp::path("api")
-        .and(backend(config))
-        .or(frontend())
-        .boxed()
+    let (backend, _) = backend(config);
+    warp::path("api").and(backend).or(frontend()).boxed()
+}
+
+/// A server, along with a future that resolves once it has shut down cleanly.
+///
+/// When `signal` resolves, new WebSocket connections are refused, every
+/// document with unsaved changes is written to its persistence target, and
+/// open connections are closed. Pass the returned future to
+/// [`warp::Server::bind_with_graceful_shutdown`] to also stop accepting HTTP
+/// connections.
+pub fn server_with_shutdown(
+    config: ServerConfig,
+    signal: impl Future<Output = ()> + Send + 'static,
+) -> (BoxedFilter<(impl Reply,)>, impl Future<Output = ()> + Send + 'static) {
+    let (backend, state) = backend(config);
+    let shutdown = async move {
+        signal.await;
+        shutdown(&state).await;
+    };
+    let filter = warp::path("api").and(backend).or(frontend()).boxed();
+    (filter, shutdown)
+}
+
+/// Flush unsaved documents and disconnect everyone, before the server exits.
+async fn shutdown(state: &ServerState) {
+    info!("shutting down, flushing documents");
+    state.load.begin_shutdown();
+    let documents: Vec<_> = state
+        .documents
+        .iter()
+        .map(|entry| (entry.key().clone(), Arc::clone(&entry.rustpad), entry.persistence))
+        .collect();
+    for (id, rustpad, persistence) in documents {
+        if let Some(sink) = state.sink(persistence) {
+            persist_if_dirty(&id, &rustpad, &sink).await;
+        }
+        rustpad.kill();
+    }
 }
 
 /// Construct routes for static files from React.
@@ -125,33 +734,151 @@ fn frontend() -> BoxedFilter<(impl Reply,)> {
     warp::fs::dir("dist").boxed()
 }
 
-/// Construct backend routes, including WebSocket handlers.
-fn backend(config: ServerConfig) -> BoxedFilter<(impl Reply,)> {
+/// Construct backend routes, including WebSocket handlers, and the state they share.
+fn backend(config: ServerConfig) -> (BoxedFilter<(impl Reply,)>, ServerState) {
+    let load = Arc::new(
+        ServerLoad::new(config.max_connections)
+            .with_user_limit(config.max_user_connections, config.user_limit_policy),
+    );
+    // Only needed when the frontend is served from another origin.
+    let cors = (!config.cors_origins.is_empty()).then(|| {
+        let origins = config
+            .cors_origins
+            .iter()
+            .map(|origin| origin.trim_end_matches('/'));
+        warp::cors()
+            .allow_origins(origins)
+            .allow_credentials(true)
+            .allow_headers(["authorization", "content-type", "x-document-password"])
+            .allow_methods(["GET", "POST", "PUT", "DELETE"])
+    });
+
     let state = ServerState {
         documents: Default::default(),
+        loading: Default::default(),
+        creating: Default::default(),
+        persisted_loads: Default::default(),
         database: config.database,
+        file_store: config.file_store,
+        persistence_routes: config.persistence_routes,
         freeze_manager: config.freeze_manager.clone(),
         auth_manager: config.auth_manager.clone(),
         ai_manager: config.ai_manager.clone(),
         artifact_manager: config.artifact_manager.clone(),
+        linter: config.linter,
+        document_config: DocumentConfig {
+            max_revisions: config.max_revisions,
+            undo_limit: config.undo_limit,
+            max_line_length: config.max_line_length,
+            max_document_bytes: config.max_document_bytes,
+            reject_binary: config.reject_binary_content,
+            cursor_grace: config.cursor_grace,
+            coalesce_window: config.coalesce_window,
+            anonymous_names: config.anonymous_names,
+            persistence_status: config.persistence_status,
+            normalize_unicode: config.normalize_unicode,
+            compression_threshold: config.compression_threshold,
+            load: Arc::clone(&load),
+        },
+        load,
+        cleaner: Arc::new(CleanerSchedule::new(HOUR * 24 * config.expiry_days)),
+        stats_history: Arc::new(StatsHistory::new(config.stats_interval, config.stats_retention)),
+        default_content: config.default_content,
+        language_templates: config.language_templates,
+        public_url: config.public_url,
+        max_documents_per_user: config.max_documents_per_user,
+        allowed_origins: config.allowed_origins.map(|origins| {
+            origins
+                .iter()
+                .map(|origin| origin.trim_end_matches('/').to_string())
+                .collect()
+        }),
+        load_failure_policy: config.load_failure_policy,
+        encrypted_documents: config.encrypted_documents,
+        drain_grace_period: config.drain_grace_period,
+        join_rate: config.join_rate,
+        io_limiter: IoLimiter::new(config.max_io_operations),
+        branding: Arc::new(config.branding),
+        audit_log: config.audit_log.clone(),
+        webhooks: config.webhooks,
     };
-    tokio::spawn(cleaner(state.clone(), config.expiry_days));
+    tokio::spawn(cleaner(state.clone()));
+    if let Some(threshold) = config.expiry_warning {
+        tokio::spawn(expiry_warner(state.clone(), threshold));
+    }
+    tokio::spawn(stats_sampler(state.clone()));
     
     // Spawn freeze cleanup task if enabled
     if let Some(ref freeze_manager) = config.freeze_manager {
         tokio::spawn(freeze_cleaner(Arc::clone(freeze_manager)));
     }
 
-    let state_filter = warp::any().map(move || state.clone());
+    // Spawn session cleanup task if enabled
+    if let Some(ref auth_manager) = config.auth_manager {
+        tokio::spawn(session_cleaner(Arc::clone(auth_manager)));
+    }
+
+    if let (Some(database), Some(backup)) = (&state.database, config.backup) {
+        tokio::spawn(backup::backup_task(database.clone(), backup));
+    }
+
+    let debug_headers = config.debug_headers && cfg!(debug_assertions);
+    if config.debug_headers && !debug_headers {
+        log::warn!("DEBUG_HEADERS is ignored in release builds");
+    }
+    // WebSocket connections are long-lived by design, so only the other
+    // handlers are bounded by this.
+    let request_timeout = config.request_timeout;
+
+    let shared = state.clone();
+    let state_filter = warp::any()
+        .and(warp::header::optional::<String>("x-disable-feature"))
+        .map(move |disabled: Option<String>| match disabled {
+            Some(features) if debug_headers => state.without_features(&features),
+            _ => state.clone(),
+        });
 
+    // Frames are sent uncompressed: the WebSocket implementation under warp
+    // 0.3 cannot negotiate permessage-deflate, so the extension is never
+    // accepted and browsers that offer it fall back to plain frames. Large
+    // operations can be compressed by the application instead, as configured
+    // by `compression_threshold`.
+    let client_ip = client_ip(config.audit_log.clone());
     let socket = warp::path!("socket" / String)
         .and(warp::ws())
+        .and(warp::query::<SocketQuery>())
+        .and(warp::header::optional("Origin"))
+        .and(warp::header::optional("X-Document-Password"))
+        .and(client_ip.clone())
         .and(state_filter.clone())
         .and_then(socket_handler);
 
+    let remote_addr = remote_addr(shared.auth_manager.clone());
+    let credentials = warp::header::optional("Authorization")
+        .and(remote_addr.clone())
+        .map(|header, remote_addr| Credentials {
+            header,
+            remote_addr,
+        });
+
+    // Browsers can't set headers on a plain link, so the password may also
+    // be given in the query string.
+    let reader = credentials
+        .clone()
+        .and(warp::header::optional::<String>("X-Document-Password"))
+        .and(warp::query::<PasswordQuery>())
+        .map(|auth, password: Option<String>, query: PasswordQuery| Reader {
+            auth,
+            password: password.or(query.password),
+        });
+
     let text = warp::path!("text" / String)
+        .and(reader.clone())
+        .and(warp::header::optional::<String>("Accept"))
         .and(state_filter.clone())
-        .and_then(text_handler);
+        .and_then(move |id, reader, accept, state| {
+            with_timeout(request_timeout, text_handler(id, reader, accept, state))
+        });
 
     let start_time = SystemTime::now()
         .duration_since(SystemTime::UNIX_EPOCH)
@@ -160,792 +887,4603 @@ fn backend(config: ServerConfig) -> BoxedFilter<(impl Reply,)> {
     let stats = warp::path!("stats")
         .and(warp::any().map(move || start_time))
         .and(state_filter.clone())
-        .and_then(stats_handler);
+        .and_then(move |start_time, state| {
+            with_timeout(request_timeout, stats_handler(start_time, state))
+        });
+
+    let metrics = warp::path!("metrics")
+        .and(warp::get())
+        .map(metrics_handler);
+
+    let health = warp::path!("health")
+        .and(warp::get())
+        .and(warp::any().map(move || start_time))
+        .and(state_filter.clone())
+        .and_then(move |start_time, state| {
+            with_timeout(request_timeout, health_handler(start_time, state))
+        });
+
+    let new_document = warp::path!("documents" / "new")
+        .and(warp::post())
+        .and(warp::query::<NewDocumentQuery>())
+        .and(reader.clone())
+        .and(state_filter.clone())
+        .and_then(move |query, reader, state| {
+            with_timeout(request_timeout, new_document_handler(query, reader, state))
+        });
+
+    let snapshot = warp::path!("documents" / String / "snapshot")
+        .and(warp::post())
+        .and(state_filter.clone())
+        .and_then(move |id, state| with_timeout(request_timeout, snapshot_handler(id, state)));
+
+    let acl = warp::path!("documents" / String / "acl")
+        .and(warp::put())
+        .and(warp::body::json())
+        .and(credentials.clone())
+        .and(state_filter.clone())
+        .and_then(move |id, acl, auth, state| {
+            with_timeout(request_timeout, acl_handler(id, acl, auth, state))
+        });
+
+    let password = warp::path!("documents" / String / "password")
+        .and(warp::put())
+        .and(warp::body::json())
+        .and(credentials.clone())
+        .and(warp::header::optional("X-Document-Password"))
+        .and(state_filter.clone())
+        .and_then(move |id, body, auth, current, state| {
+            with_timeout(
+                request_timeout,
+                password_handler(id, body, auth, current, state),
+            )
+        });
+
+    let share = warp::path!("documents" / String / "share")
+        .and(warp::post())
+        .and(credentials.clone())
+        .and(warp::header::optional("X-Document-Password"))
+        .and(state_filter.clone())
+        .and_then(move |id, auth, password, state| {
+            with_timeout(
+                request_timeout,
+                share_handler(id, true, auth, password, state),
+            )
+        });
+
+    let unshare = warp::path!("documents" / String / "share")
+        .and(warp::delete())
+        .and(credentials.clone())
+        .and(warp::header::optional("X-Document-Password"))
+        .and(state_filter.clone())
+        .and_then(move |id, auth, password, state| {
+            with_timeout(
+                request_timeout,
+                share_handler(id, false, auth, password, state),
+            )
+        });
+
+    let ttl = warp::path!("documents" / String / "ttl")
+        .and(warp::put())
+        .and(warp::body::json())
+        .and(reader.clone())
+        .and(state_filter.clone())
+        .and_then(move |id, body, reader, state| {
+            with_timeout(request_timeout, ttl_handler(id, body, reader, state))
+        });
+
+    let metadata = warp::path!("documents" / String / "metadata")
+        .and(warp::put())
+        .and(warp::body::json())
+        .and(credentials.clone())
+        .and(state_filter.clone())
+        .and_then(move |id, body, auth, state| {
+            with_timeout(request_timeout, metadata_handler(id, body, auth, state))
+        });
+
+    let participants = warp::path!("documents" / String / "participants")
+        .and(warp::get())
+        .and(reader.clone())
+        .and(state_filter.clone())
+        .and_then(move |id, reader, state| {
+            with_timeout(request_timeout, participants_handler(id, reader, state))
+        });
+
+    let qr = warp::path!("documents" / String / "qr")
+        .and(warp::get())
+        .and(state_filter.clone())
+        .and_then(move |id, state| with_timeout(request_timeout, qr_handler(id, state)));
 
     let freeze = warp::path("documents")
         .and(warp::path!(String / "freeze"))
         .and(warp::post())
         .and(warp::body::json())
-        .and(warp::header::optional("Authorization"))
+        .and(reader.clone())
         .and(state_filter.clone())
-        .and_then(freeze_handler);
+        .and_then(move |id, req, reader, state| {
+            with_timeout(request_timeout, freeze_handler(id, req, reader, state))
+        });
 
     let download = warp::path("documents")
         .and(warp::path!(String / "download"))
         .and(warp::get())
+        .and(reader)
+        .and(state_filter.clone())
+        .and_then(move |id, reader, state| {
+            with_timeout(request_timeout, download_handler(id, reader, state))
+        });
+
+    let import = warp::path!("documents" / String / "import")
+        .and(warp::post())
+        .and(
+            warp::multipart::form()
+                .max_length(DOCUMENT_IMPORT_MAX_UPLOAD)
+                .map(ImportBody::Form)
+                .or(warp::body::content_length_limit(DOCUMENT_IMPORT_MAX_UPLOAD)
+                    .and(warp::query::<ImportQuery>())
+                    .and(warp::body::bytes())
+                    .map(|query: ImportQuery, data| ImportBody::Raw(query.filename, data)))
+                .unify(),
+        )
+        .and(state_filter.clone())
+        .and_then(move |id, body, state| {
+            with_timeout(request_timeout, import_handler(id, body, state))
+        });
+
+    let clone = warp::path!("documents" / String / "clone")
+        .and(warp::post())
+        .and(warp::query::<CloneQuery>())
+        .and(reader.clone())
+        .and(state_filter.clone())
+        .and_then(move |id, query, reader, state| {
+            with_timeout(request_timeout, clone_handler(id, query, reader, state))
+        });
+
+    let download_frozen = warp::path!("documents" / String / "frozen")
+        .and(warp::get())
+        .and(credentials.clone())
+        .and(warp::header::optional("If-None-Match"))
         .and(state_filter.clone())
-        .and_then(download_handler);
+        .and_then(move |id, auth, if_none_match, state| {
+            with_timeout(
+                request_timeout,
+                download_frozen_handler(id, auth, if_none_match, state),
+            )
+        });
 
     let list_frozen = warp::path!("documents" / "list")
         .and(warp::get())
-        .and(warp::header::optional("Authorization"))
+        .and(warp::query())
+        .and(credentials.clone())
+        .and(state_filter.clone())
+        .and_then(move |query, auth, state| {
+            with_timeout(request_timeout, list_frozen_handler(query, auth, state))
+        });
+
+    let list_database = warp::path!("documents" / "db" / "list")
+        .and(warp::get())
+        .and(warp::query())
+        .and(credentials.clone())
+        .and(state_filter.clone())
+        .and_then(move |query, auth, state| {
+            with_timeout(request_timeout, list_database_handler(query, auth, state))
+        });
+
+    let search_frozen = warp::path!("documents" / "search")
+        .and(warp::get())
+        .and(warp::query())
+        .and(credentials.clone())
+        .and(state_filter.clone())
+        .and_then(move |query, auth, state| {
+            with_timeout(request_timeout, search_frozen_handler(query, auth, state))
+        });
+
+    let frozen_manifest = warp::path!("documents" / "manifest")
+        .and(warp::get())
+        .and(credentials.clone())
+        .and(state_filter.clone())
+        .and_then(move |auth, state| {
+            with_timeout(request_timeout, frozen_manifest_handler(auth, state))
+        });
+
+    let extend_frozen = warp::path!("documents" / String / "extend")
+        .and(warp::post())
+        .and(warp::body::json())
+        .and(credentials.clone())
         .and(state_filter.clone())
-        .and_then(list_frozen_handler);
+        .and_then(move |id, req, auth, state| {
+            with_timeout(request_timeout, extend_frozen_handler(id, req, auth, state))
+        });
 
     let delete_frozen = warp::path("documents")
         .and(warp::path!(String / "delete"))
         .and(warp::delete())
-        .and(warp::header::optional("Authorization"))
+        .and(credentials.clone())
         .and(state_filter.clone())
-        .and_then(delete_frozen_handler);
+        .and_then(move |id, auth, state| {
+            with_timeout(request_timeout, delete_frozen_handler(id, auth, state))
+        });
 
     let register = warp::path!("auth" / "register")
         .and(warp::post())
         .and(warp::body::json())
         .and(state_filter.clone())
-        .and_then(register_handler);
+        .and_then(move |req, state| with_timeout(request_timeout, register_handler(req, state)));
 
     let login = warp::path!("auth" / "login")
         .and(warp::post())
         .and(warp::body::json())
+        .and(warp::header::optional("User-Agent"))
+        .and(remote_addr.clone())
+        .and(state_filter.clone())
+        .and_then(move |req, user_agent, remote_addr, state| {
+            with_timeout(
+                request_timeout,
+                login_handler(req, user_agent, remote_addr, state),
+            )
+        });
+
+    let change_password = warp::path!("auth" / "change-password")
+        .and(warp::post())
+        .and(warp::body::json())
+        .and(state_filter.clone())
+        .and_then(move |req, state| {
+            with_timeout(request_timeout, change_password_handler(req, state))
+        });
+
+    let list_sessions = warp::path!("auth" / "sessions")
+        .and(warp::get())
+        .and(credentials.clone())
+        .and(state_filter.clone())
+        .and_then(move |auth, state| {
+            with_timeout(request_timeout, list_sessions_handler(auth, state))
+        });
+
+    let revoke_session = warp::path!("auth" / "sessions" / String)
+        .and(warp::delete())
+        .and(credentials.clone())
+        .and(state_filter.clone())
+        .and_then(move |id, auth, state| {
+            with_timeout(request_timeout, revoke_session_handler(id, auth, state))
+        });
+
+    let revoke_all_sessions = warp::path!("auth" / "sessions")
+        .and(warp::delete())
+        .and(credentials.clone())
+        .and(state_filter.clone())
+        .and_then(move |auth, state| {
+            with_timeout(request_timeout, revoke_all_sessions_handler(auth, state))
+        });
+
+    let export_data = warp::path!("auth" / "export-data")
+        .and(warp::get())
+        .and(credentials.clone())
+        .and(state_filter.clone())
+        .and_then(move |auth, state| {
+            with_timeout(request_timeout, export_data_handler(auth, state))
+        });
+
+    let public_config = warp::path!("config")
+        .and(warp::get())
+        .and(state_filter.clone())
+        .and_then(move |state| with_timeout(request_timeout, public_config_handler(state)));
+
+    let capabilities = warp::path!("auth" / "capabilities")
+        .and(warp::get())
+        .and(credentials.clone())
+        .and(state_filter.clone())
+        .and_then(move |auth, state| {
+            with_timeout(request_timeout, capabilities_handler(auth, state))
+        });
+
+    let ai_prompts = warp::path!("ai" / "prompts")
+        .and(warp::get())
         .and(state_filter.clone())
-        .and_then(login_handler);
+        .and_then(move |state| with_timeout(request_timeout, ai_prompts_handler(state)));
 
     let ai_models = warp::path!("ai" / "models")
         .and(warp::get())
         .and(state_filter.clone())
-        .and_then(ai_models_handler);
+        .and_then(move |state| with_timeout(request_timeout, ai_models_handler(state)));
 
     let ai_chat = warp::path!("ai" / "chat")
         .and(warp::post())
         .and(warp::body::json())
-        .and(warp::header::optional("Authorization"))
+        .and(credentials.clone())
+        .and(state_filter.clone())
+        .and_then(move |req, auth, state| {
+            with_timeout(request_timeout, ai_chat_handler(req, auth, state))
+        });
+
+    let ai_chat_stream = warp::path!("ai" / "chat" / "stream")
+        .and(warp::post())
+        .and(warp::body::json())
+        .and(credentials.clone())
+        .and(state_filter.clone())
+        .and_then(move |req, auth, state| {
+            with_timeout(request_timeout, ai_chat_stream_handler(req, auth, state))
+        });
+
+    let ai_cancel = warp::path!("ai" / "jobs" / String / "cancel")
+        .and(warp::post())
+        .and(credentials.clone())
         .and(state_filter.clone())
-        .and_then(ai_chat_handler);
+        .and_then(move |job_id, auth, state| {
+            with_timeout(request_timeout, ai_cancel_handler(job_id, auth, state))
+        });
 
     let artifacts_list = warp::path!("artifacts" / "list")
         .and(warp::get())
-        .and(warp::header::optional("Authorization"))
+        .and(credentials.clone())
         .and(state_filter.clone())
-        .and_then(artifacts_list_handler);
+        .and_then(move |auth, state| {
+            with_timeout(request_timeout, artifacts_list_handler(auth, state))
+        });
 
     let artifacts_get = warp::path!("artifacts" / String)
         .and(warp::get())
-        .and(warp::header::optional("Authorization"))
+        .and(warp::query())
+        .and(credentials.clone())
         .and(state_filter.clone())
-        .and_then(artifacts_get_handler);
+        .and_then(move |artifact_id, query, auth, state| {
+            with_timeout(request_timeout, artifacts_get_handler(artifact_id, query, auth, state))
+        });
 
     let artifacts_store = warp::path!("artifacts" / "store")
         .and(warp::post())
         .and(warp::body::json())
-        .and(warp::header::optional("Authorization"))
+        .and(credentials.clone())
+        .and(state_filter.clone())
+        .and_then(move |req, auth, state| {
+            with_timeout(request_timeout, artifacts_store_handler(req, auth, state))
+        });
+
+    let artifacts_import = warp::path!("artifacts" / "import")
+        .and(warp::post())
+        .and(warp::multipart::form().max_length(ARTIFACT_IMPORT_MAX_UPLOAD))
+        .and(credentials.clone())
         .and(state_filter.clone())
-        .and_then(artifacts_store_handler);
+        .and_then(move |form, auth, state| {
+            with_timeout(request_timeout, artifacts_import_handler(form, auth, state))
+        });
 
     let artifacts_delete = warp::path!("artifacts" / String)
         .and(warp::delete())
-        .and(warp::header::optional("Authorization"))
+        .and(credentials.clone())
+        .and(state_filter.clone())
+        .and_then(move |artifact_id, auth, state| {
+            with_timeout(request_timeout, artifacts_delete_handler(artifact_id, auth, state))
+        });
+
+    let artifacts_download = warp::path!("artifacts" / String / "download")
+        .and(warp::get())
+        .and(credentials.clone())
+        .and(state_filter.clone())
+        .and_then(move |artifact_id, auth, state| {
+            with_timeout(request_timeout, artifacts_download_handler(artifact_id, auth, state))
+        });
+
+    let artifacts_share = warp::path!("artifacts" / String / "share")
+        .and(warp::post())
+        .and(credentials.clone())
+        .and(state_filter.clone())
+        .and_then(move |artifact_id, auth, state| {
+            with_timeout(request_timeout, artifacts_share_handler(artifact_id, auth, state))
+        });
+
+    let artifacts_shared = warp::path!("artifacts" / "shared" / String)
+        .and(warp::get())
+        .and(warp::query())
         .and(state_filter.clone())
-        .and_then(artifacts_delete_handler);
+        .and_then(move |artifact_id, query, state| {
+            with_timeout(request_timeout, artifacts_shared_handler(artifact_id, query, state))
+        });
 
     let admin_users = warp::path!("admin" / "users")
         .and(warp::get())
-        .and(warp::header::optional("Authorization"))
+        .and(warp::query())
+        .and(credentials.clone())
         .and(state_filter.clone())
-        .and_then(admin_users_handler);
+        .and_then(move |query, auth, state| {
+            with_timeout(request_timeout, admin_users_handler(query, auth, state))
+        });
 
     let admin_update_ai = warp::path!("admin" / "users" / String / "ai")
         .and(warp::put())
         .and(warp::body::json())
-        .and(warp::header::optional("Authorization"))
+        .and(credentials.clone())
         .and(state_filter.clone())
-        .and_then(admin_update_ai_handler);
+        .and_then(move |username, req, auth, state| {
+            with_timeout(request_timeout, admin_update_ai_handler(username, req, auth, state))
+        });
+
+    let admin_update_rate_limit = warp::path!("admin" / "users" / String / "ai-rate-limit")
+        .and(warp::put())
+        .and(warp::body::json())
+        .and(credentials.clone())
+        .and(state_filter.clone())
+        .and_then(move |username, limit, auth, state| {
+            with_timeout(
+                request_timeout,
+                admin_update_rate_limit_handler(username, limit, auth, state),
+            )
+        });
+
+    let admin_get_usage = warp::path!("admin" / "users" / String / "usage")
+        .and(warp::get())
+        .and(credentials.clone())
+        .and(state_filter.clone())
+        .and_then(move |username, auth, state| {
+            with_timeout(request_timeout, admin_get_usage_handler(username, auth, state))
+        });
+
+    let admin_reset_usage = warp::path!("admin" / "users" / String / "usage")
+        .and(warp::delete())
+        .and(credentials.clone())
+        .and(state_filter.clone())
+        .and_then(move |username, auth, state| {
+            with_timeout(request_timeout, admin_reset_usage_handler(username, auth, state))
+        });
 
     let admin_delete_user = warp::path!("admin" / "users" / String)
         .and(warp::delete())
-        .and(warp::header::optional("Authorization"))
+        .and(credentials.clone())
         .and(state_filter.clone())
-        .and_then(admin_delete_user_handler);
+        .and_then(move |username, auth, state| {
+            with_timeout(request_timeout, admin_delete_user_handler(username, auth, state))
+        });
 
     let admin_get_settings = warp::path!("admin" / "settings")
         .and(warp::get())
-        .and(warp::header::optional("Authorization"))
+        .and(credentials.clone())
         .and(state_filter.clone())
-        .and_then(admin_get_settings_handler);
+        .and_then(move |auth, state| {
+            with_timeout(request_timeout, admin_get_settings_handler(auth, state))
+        });
+
+    let admin_ai_test = warp::path!("admin" / "ai" / "test")
+        .and(warp::post())
+        .and(credentials.clone())
+        .and(state_filter.clone())
+        .and_then(move |auth, state| {
+            with_timeout(request_timeout, admin_ai_test_handler(auth, state))
+        });
+
+    let admin_ai_replay = warp::path!("admin" / "ai" / "replay")
+        .and(warp::post())
+        .and(warp::body::json())
+        .and(credentials.clone())
+        .and(state_filter.clone())
+        .and_then(move |req, auth, state| {
+            with_timeout(request_timeout, admin_ai_replay_handler(req, auth, state))
+        });
+
+    let admin_language_stats = warp::path!("admin" / "languages" / "stats")
+        .and(warp::get())
+        .and(credentials.clone())
+        .and(state_filter.clone())
+        .and_then(move |auth, state| {
+            with_timeout(request_timeout, admin_language_stats_handler(auth, state))
+        });
+
+    let admin_stats_history = warp::path!("admin" / "stats" / "history")
+        .and(warp::get())
+        .and(credentials.clone())
+        .and(state_filter.clone())
+        .and_then(move |auth, state| {
+            with_timeout(request_timeout, admin_stats_history_handler(auth, state))
+        });
+
+    let admin_cleaner_status = warp::path!("admin" / "cleaner" / "status")
+        .and(warp::get())
+        .and(credentials.clone())
+        .and(state_filter.clone())
+        .and_then(move |auth, state| {
+            with_timeout(request_timeout, admin_cleaner_status_handler(auth, state))
+        });
+
+    let admin_cleaner_run = warp::path!("admin" / "cleaner" / "run")
+        .and(warp::post())
+        .and(credentials.clone())
+        .and(state_filter.clone())
+        .and_then(move |auth, state| {
+            with_timeout(request_timeout, admin_cleaner_run_handler(auth, state))
+        });
+
+    let admin_cleanup_preview = warp::path!("admin" / "cleanup" / "preview")
+        .and(warp::get())
+        .and(credentials.clone())
+        .and(state_filter.clone())
+        .and_then(move |auth, state| {
+            with_timeout(request_timeout, admin_cleanup_preview_handler(auth, state))
+        });
+
+    let admin_evict_document = warp::path!("admin" / "documents" / String)
+        .and(warp::delete())
+        .and(credentials.clone())
+        .and(state_filter.clone())
+        .and_then(move |id, auth, state| {
+            with_timeout(request_timeout, admin_evict_document_handler(id, auth, state))
+        });
 
     let admin_update_api_key = warp::path!("admin" / "settings" / "api-key")
         .and(warp::put())
         .and(warp::body::json())
-        .and(warp::header::optional("Authorization"))
+        .and(credentials.clone())
         .and(state_filter.clone())
-        .and_then(admin_update_api_key_handler);
+        .and_then(move |req, auth, state| {
+            with_timeout(request_timeout, admin_update_api_key_handler(req, auth, state))
+        });
 
-    socket
+    let routes = socket
         .or(text)
         .or(stats)
+        .or(health)
+        .or(metrics)
+        .or(new_document)
+        .or(qr)
+        .or(participants)
+        .or(snapshot)
+        .or(acl)
+        .or(password)
+        .or(share)
+        .or(unshare)
+        .or(ttl)
+        .or(metadata)
         .or(freeze)
         .or(download)
+        .or(import)
+        .or(clone)
+        .or(download_frozen)
         .or(list_frozen)
+        .or(list_database)
+        .or(search_frozen)
+        .or(frozen_manifest)
         .or(delete_frozen)
+        .or(extend_frozen)
         .or(register)
         .or(login)
+        .or(change_password)
+        .or(list_sessions)
+        .or(revoke_session)
+        .or(revoke_all_sessions)
+        .or(export_data)
+        .or(capabilities)
+        .or(public_config)
         .or(ai_models)
+        .or(ai_prompts)
         .or(ai_chat)
+        .or(ai_chat_stream)
+        .or(ai_cancel)
         .or(artifacts_list)
         .or(artifacts_get)
         .or(artifacts_store)
+        .or(artifacts_import)
         .or(artifacts_delete)
+        .or(artifacts_download)
+        .or(artifacts_share)
+        .or(artifacts_shared)
         .or(admin_users)
         .or(admin_update_ai)
+        .or(admin_update_rate_limit)
+        .or(admin_get_usage)
+        .or(admin_reset_usage)
         .or(admin_delete_user)
         .or(admin_get_settings)
         .or(admin_update_api_key)
-        .boxed()
+        .or(admin_language_stats)
+        .or(admin_ai_test)
+        .or(admin_ai_replay)
+        .or(admin_stats_history)
+        .or(admin_cleaner_status)
+        .or(admin_cleaner_run)
+        .or(admin_cleanup_preview)
+        .or(admin_evict_document)
+        .map(Reply::into_response)
+        .boxed();
+    let routes = with_request_log(routes);
+    let routes = match config.audit_log {
+        Some(audit_log) => with_audit_log(routes, audit_log, client_ip),
+        None => routes,
+    };
+    let routes = match cors {
+        Some(cors) => routes.with(cors).map(Reply::into_response).boxed(),
+        None => routes.map(Reply::into_response).boxed(),
+    };
+    (routes, shared)
+}
+
+/// Query parameters for connecting to a document.
+#[derive(serde::Deserialize)]
+struct SocketQuery {
+    /// Session of the authenticated user making the connection, if any.
+    session: Option<String>,
+    /// Password for the document, if it is protected.
+    password: Option<String>,
+    /// Token from a read-only share link, if the document was opened by one.
+    share: Option<String>,
+    /// Id the client keeps across reconnects, to resume its presence.
+    client: Option<String>,
 }
 
 /// Handler for the `/api/socket/{id}` endpoint.
-async fn socket_handler(id: String, ws: Ws, state: ServerState) -> Result<impl Reply, Rejection> {
-    use dashmap::mapref::entry::Entry;
+async fn socket_handler(
+    id: String,
+    ws: Ws,
+    query: SocketQuery,
+    origin: Option<String>,
+    password: Option<String>,
+    ip: Option<IpAddr>,
+    state: ServerState,
+) -> Result<impl Reply, Rejection> {
+    // Browsers always send an Origin on WebSocket upgrades, which CORS does
+    // not cover, so this stops other sites from driving the editor.
+    if let (Some(allowed), Some(origin)) = (&state.allowed_origins, &origin) {
+        let origin = origin.trim_end_matches('/');
+        if !allowed.iter().any(|allowed| allowed.eq_ignore_ascii_case(origin)) {
+            log::warn!("rejected WebSocket connection from origin {}", origin);
+            return Err(ApiError::Forbidden("Origin not allowed".into()).into());
+        }
+    }
 
-    let mut entry = match state.documents.entry(id.clone()) {
-        Entry::Occupied(e) => e.into_ref(),
-        Entry::Vacant(e) => {
-            let rustpad = Arc::new(match &state.database {
-                Some(db) => db.load(&id).await.map(Rustpad::from).unwrap_or_default(),
-                None => Rustpad::default(),
-            });
-            if let Some(db) = &state.database {
-                tokio::spawn(persister(id, Arc::clone(&rustpad), db.clone()));
-            }
-            e.insert(Document::new(rustpad))
+    let guard = match state.load.try_connect() {
+        Some(guard) => guard,
+        None => {
+            let retry_after = state.load.backoff().as_secs().max(1);
+            let error = ApiError::ServiceUnavailable("Server is at capacity".into());
+            let reply = warp::reply::with_header(
+                error_reply(&error.into()),
+                "Retry-After",
+                retry_after.to_string(),
+            );
+            return Ok(reply.into_response());
         }
     };
 
-    let value = entry.value_mut();
-    value.last_accessed = Instant::now();
-    let rustpad = Arc::clone(&value.rustpad);
-    Ok(ws.on_upgrade(|socket| async move { rustpad.on_connection(socket).await }))
-}
+    let session = match (&query.session, &state.auth_manager) {
+        (Some(session_id), Some(auth_manager)) => match auth_manager.get_session(session_id) {
+            Ok(session) => Some(session),
+            Err(e) => {
+                return Err(ApiError::Unauthorized(e.to_string().into()).into());
+            }
+        },
+        _ => None,
+    };
+    let username = session.map(|session| session.username);
+
+    // Anonymous connections are not subject to per-user limits.
+    let user_guard = match &username {
+        Some(username) => match state.load.try_connect_user(username) {
+            Some(guard) => Some(guard),
+            None => {
+                return Err(
+                    ApiError::TooManyRequests("Too many connections for this user".into()).into(),
+                );
+            }
+        },
+        None => None,
+    };
 
-/// Handler for the `/api/text/{id}` endpoint.
-async fn text_handler(id: String, state: ServerState) -> Result<impl Reply, Rejection> {
-    Ok(match state.documents.get(&id) {
-        Some(value) => value.rustpad.text(),
-        None => {
-            if let Some(db) = &state.database {
-                db.load(&id)
-                    .await
-                    .map(|document| document.text)
-                    .unwrap_or_default()
-            } else {
-                String::new()
+    let password = password.or(query.password);
+    let (share, reader) = (query.share.as_deref(), username.as_deref());
+    let admit = |gate: DocumentGate| {
+        let password = password.clone();
+        async move {
+            // A share link stands in for the password and access list, but
+            // only ever for viewing.
+            let shared = match share {
+                Some(token) if gate.check_share_token(token) => true,
+                Some(_) => {
+                    let message = "Share link is invalid or revoked".into();
+                    return Err(ApiError::Forbidden(message).into());
+                }
+                None => false,
+            };
+            if !shared && !check_password(gate.password_hash, password).await? {
+                return Err(ApiError::Unauthorized("Document password required".into()).into());
             }
+            let access = match gate.acl.access(reader) {
+                _ if shared => ConnectionAccess::Shared,
+                Access::Write => ConnectionAccess::Write,
+                Access::Read => ConnectionAccess::ReadOnly,
+                Access::Denied => {
+                    let message = "Not allowed to view this document".into();
+                    return Err(ApiError::Forbidden(message).into());
+                }
+            };
+            Ok::<_, Rejection>(access)
         }
-    })
-}
+    };
+    let (rustpad, access) = {
+        let (mut document, access) = state.open_document(&id, admit).await?;
+        document.last_accessed = Instant::now();
+        document.expiry_warned = false;
+        (Arc::clone(&document.rustpad), access)
+    };
 
-/// Handler for the `/api/stats` endpoint.
-async fn stats_handler(start_time: u64, state: ServerState) -> Result<impl Reply, Rejection> {
-    let num_documents = state.documents.len();
-    let database_size = match state.database {
-        None => 0,
-        Some(db) => match db.count().await {
-            Ok(size) => size,
-            Err(e) => return Err(warp::reject::custom(CustomReject(e))),
-        },
+    // Clients pick their own ids, so ones that couldn't be random are ignored.
+    let client = query
+        .client
+        .filter(|client| (16..=64).contains(&client.len()));
+
+    // Each join is sent the document's full state, so a stampede of joins to
+    // one document is queued briefly, and then refused.
+    let delay = match state.join_rate {
+        Some(rate) => {
+            let admitted = match state.documents.get_mut(&id) {
+                Some(mut document) => rate.admit(&mut document.join_schedule, Instant::now()),
+                None => Ok(Duration::ZERO),
+            };
+            match admitted {
+                Ok(delay) => delay,
+                Err(retry_after) => {
+                    let error = ApiError::TooManyRequests(
+                        "Too many connections joining this document".into(),
+                    );
+                    let retry_after = (retry_after.as_secs_f64().ceil() as u64).max(1);
+                    let reply = warp::reply::with_header(
+                        error_reply(&error.into()),
+                        "Retry-After",
+                        retry_after.to_string(),
+                    );
+                    return Ok(reply.into_response());
+                }
+            }
+        }
+        None => Duration::ZERO,
     };
-    Ok(warp::reply::json(&Stats {
-        start_time,
-        num_documents,
-        database_size,
-    }))
+    if let Some(audit_log) = &state.audit_log {
+        let event = AuditEvent::Connect {
+            document: id,
+            username,
+        };
+        audit_log.record(ip, event);
+    }
+    Ok(ws
+        .on_upgrade(move |socket| async move {
+            let _guard = guard;
+            let _connection = metrics::ConnectionGuard::new();
+            if !delay.is_zero() {
+                time::sleep(delay).await;
+            }
+            let evicted = async {
+                match &user_guard {
+                    Some(user_guard) => user_guard.evicted().await,
+                    None => futures::future::pending().await,
+                }
+            };
+            rustpad.on_connection(socket, evicted, access, client).await
+        })
+        .into_response())
 }
 
-const HOUR: Duration = Duration::from_secs(3600);
+/// Give each request an id, returned in the `X-Request-Id` header, and log
+/// its method, path, status and latency when it completes.
+///
+/// Errors from [`CustomReject`] are logged under the same id and answered by
+/// [`error_reply`]. Other rejections, like unmatched routes, are passed on for
+/// the next filter.
+fn with_request_log(
+    routes: BoxedFilter<(warp::reply::Response,)>,
+) -> BoxedFilter<(warp::reply::Response,)> {
+    let outcome = routes
+        .map(Ok::<_, Rejection>)
+        .recover(|rejection: Rejection| async move { Ok::<_, Infallible>(Err(rejection)) })
+        .unify();
+    warp::any()
+        .map(|| (Uuid::new_v4(), Instant::now()))
+        .and(warp::method())
+        .and(warp::path::full())
+        .and(outcome)
+        .and_then(
+            |(id, start): (Uuid, Instant),
+             method: warp::http::Method,
+             path: warp::path::FullPath,
+             outcome: Result<warp::reply::Response, Rejection>| async move {
+                let mut response = match outcome {
+                    Ok(response) => response,
+                    Err(rejection) => match rejection.find::<CustomReject>() {
+                        Some(CustomReject(e)) => {
+                            let path = path.as_str();
+                            match ApiError::find(e) {
+                                Some(_) => info!("[{}] {} {} refused: {:#}", id, method, path, e),
+                                None => error!("[{}] {} {} failed: {:#}", id, method, path, e),
+                            }
+                            error_reply(e)
+                        }
+                        None => return Err(rejection),
+                    },
+                };
+                info!(
+                    "[{}] {} {} {} {:?}",
+                    id,
+                    method,
+                    path.as_str(),
+                    response.status().as_u16(),
+                    start.elapsed()
+                );
+                let header = warp::http::HeaderValue::from_str(&id.to_string())
+                    .expect("UUIDs are valid header values");
+                response.headers_mut().insert("x-request-id", header);
+                Ok(response)
+            },
+        )
+        .boxed()
+}
 
-/// Reclaims memory for documents.
-async fn cleaner(state: ServerState, expiry_days: u32) {
-    loop {
-        time::sleep(HOUR).await;
-        let mut keys = Vec::new();
-        for entry in &*state.documents {
-            if entry.last_accessed.elapsed() > HOUR * 24 * expiry_days {
-                keys.push(entry.key().clone());
-            }
-        }
-        info!("cleaner removing keys: {:?}", keys);
-        for key in keys {
-            state.documents.remove(&key);
+/// The address of the client making a request, for the audit log, or `None`
+/// if there is no audit log.
+fn client_ip(
+    audit_log: Option<Arc<AuditLog>>,
+) -> impl Filter<Extract = (Option<IpAddr>,), Error = Infallible> + Clone {
+    warp::addr::remote()
+        .and(warp::header::headers_cloned())
+        .map(
+            move |peer: Option<SocketAddr>, headers: warp::http::HeaderMap| {
+                let forwarded_for = headers
+                    .get("x-forwarded-for")
+                    .and_then(|value| value.to_str().ok());
+                audit_log
+                    .as_ref()
+                    .and_then(|audit_log| audit_log.client_ip(peer, forwarded_for))
+            },
+        )
+}
+
+/// The address of the client making a request, for locking out repeated
+/// failed logins, or `None` if auth is disabled.
+fn remote_addr(
+    auth_manager: Option<Arc<AuthManager>>,
+) -> impl Filter<Extract = (Option<String>,), Error = Infallible> + Clone {
+    warp::addr::remote()
+        .and(warp::header::optional::<String>("X-Forwarded-For"))
+        .map(
+            move |peer: Option<SocketAddr>, forwarded_for: Option<String>| {
+                auth_manager
+                    .as_ref()
+                    .and_then(|auth_manager| {
+                        auth_manager.client_addr(peer, forwarded_for.as_deref())
+                    })
+                    .map(|ip| ip.to_string())
+            },
+        )
+}
+
+/// Write each request that carries credentials to the audit log, with its
+/// client's address and final status.
+fn with_audit_log(
+    routes: BoxedFilter<(warp::reply::Response,)>,
+    audit_log: Arc<AuditLog>,
+    client_ip: impl Filter<Extract = (Option<IpAddr>,), Error = Infallible>
+        + Clone
+        + Send
+        + Sync
+        + 'static,
+) -> BoxedFilter<(warp::reply::Response,)> {
+    warp::method()
+        .and(warp::path::full())
+        .and(warp::header::headers_cloned())
+        .and(client_ip)
+        .and(routes)
+        .map(
+            move |method: warp::http::Method,
+                  path: warp::path::FullPath,
+                  headers: warp::http::HeaderMap,
+                  ip: Option<IpAddr>,
+                  response: warp::reply::Response| {
+                if headers.contains_key("authorization") {
+                    let event = AuditEvent::Request {
+                        method: method.to_string(),
+                        path: path.as_str().to_string(),
+                        status: response.status().as_u16(),
+                    };
+                    audit_log.record(ip, event);
+                }
+                response
+            },
+        )
+        .boxed()
+}
+
+/// Answer an error with the status of the [`ApiError`] behind it, or else
+/// `500 Internal Server Error`, and a JSON body like `{ "error": "..." }`.
+fn error_reply(error: &anyhow::Error) -> warp::reply::Response {
+    let (status, message) = match ApiError::find(error) {
+        Some(e) => (e.status(), e.message()),
+        None => (warp::http::StatusCode::INTERNAL_SERVER_ERROR, "Internal server error"),
+    };
+    let body = warp::reply::json(&serde_json::json!({ "error": message }));
+    warp::reply::with_status(body, status).into_response()
+}
+
+/// Run a handler to completion, or answer `504 Gateway Timeout` if it takes
+/// longer than `timeout`, e.g. because of a slow database or upstream API.
+async fn with_timeout<R: Reply>(
+    timeout: Duration,
+    handler: impl Future<Output = Result<R, Rejection>>,
+) -> Result<warp::reply::Response, Rejection> {
+    match time::timeout(timeout, handler).await {
+        Ok(result) => result.map(Reply::into_response),
+        Err(_) => {
+            log::warn!("request timed out after {:?}", timeout);
+            let error = ApiError::GatewayTimeout("Request timed out".into());
+            Ok(error_reply(&error.into()))
         }
     }
 }
 
-const PERSIST_INTERVAL: Duration = Duration::from_secs(3);
-const PERSIST_INTERVAL_JITTER: Duration = Duration::from_secs(1);
-
-/// Persists changed documents after a fixed time interval.
-async fn persister(id: String, rustpad: Arc<Rustpad>, db: Database) {
-    let mut last_revision = 0;
-    while !rustpad.killed() {
-        let interval = PERSIST_INTERVAL
-            + rand::thread_rng().gen_range(Duration::ZERO..=PERSIST_INTERVAL_JITTER);
-        time::sleep(interval).await;
-        let revision = rustpad.revision();
-        if revision > last_revision {
-            info!("persisting revision {} for id = {}", revision, id);
-            if let Err(e) = db.store(&id, &rustpad.snapshot()).await {
-                error!("when persisting document {}: {}", id, e);
-            } else {
-                last_revision = revision;
-            }
+/// Handler for the `/api/text/{id}` endpoint.
+///
+/// Clients that accept JSON also get the document's language and metadata.
+async fn text_handler(
+    id: String,
+    reader: Reader,
+    accept: Option<String>,
+    state: ServerState,
+) -> Result<impl Reply, Rejection> {
+    let json = accept.map_or(false, |accept| accept.contains("application/json"));
+    let document = match state.documents.get(&id) {
+        Some(value) => Some(value.rustpad.snapshot()),
+        None => state
+            .load_persisted(&id)
+            .await
+            .map_err(|e| warp::reject::custom(CustomReject(e)))?
+            .map(|(document, _)| document),
+    };
+    let Some(document) = document else {
+        if json {
+            return Ok(warp::reply::json(&TextResponse::default()).into_response());
         }
+        return Ok(String::new().into_response());
+    };
+    if let Some(denied) = state.deny_read(&document, reader).await? {
+        return Ok(denied);
+    }
+    if document.encrypted {
+        return Ok(ServerState::encrypted_reply());
     }
+    if json {
+        let response = TextResponse {
+            text: document.text,
+            language: document.language,
+            metadata: document.metadata,
+        };
+        return Ok(warp::reply::json(&response).into_response());
+    }
+    Ok(document.text.into_response())
 }
 
-/// Request body for freezing a document
-#[derive(serde::Deserialize)]
-struct FreezeRequest {
+/// A document's text along with its metadata, for clients that accept JSON.
+#[derive(Default, Serialize)]
+struct TextResponse {
+    text: String,
     language: Option<String>,
+    metadata: DocumentMetadata,
 }
 
-/// Request body for authentication
-#[derive(serde::Deserialize)]
-struct AuthRequest {
-    username: String,
-    password: String,
-    #[serde(default)]
-    ai_enabled: bool,
-    #[serde(default)]
-    is_admin: bool,
+/// The `Authorization` header of a request, with the address it came from so
+/// that failed logins count towards that address's lockout too.
+#[derive(Clone, Debug, Default)]
+struct Credentials {
+    header: Option<String>,
+    remote_addr: Option<String>,
 }
 
-/// Response for authentication
-#[derive(Serialize)]
-struct AuthResponse {
-    username: String,
-    created_at: String,
-    ai_enabled: bool,
-    is_admin: bool,
+/// What decides whether a connection may open a document, whether the
+/// document is in memory or only persisted.
+#[derive(Default)]
+struct DocumentGate {
+    password_hash: Option<String>,
+    acl: DocumentAcl,
+    /// Hashes of the read-only share tokens.
+    share_tokens: Vec<String>,
 }
 
-/// Response for freezing a document
-#[derive(Serialize)]
-struct FreezeResponse {
-    owner_token: String,
-    document_id: String,
-    frozen_at: String,
-    expires_at: String,
-    file_extension: String,
+impl DocumentGate {
+    /// Returns whether a token opens the document read-only.
+    fn check_share_token(&self, token: &str) -> bool {
+        self.share_tokens.contains(&hash_share_token(token))
+    }
 }
 
-/// Extract username from Basic Auth header
-fn extract_basic_auth(auth_header: Option<String>) -> Result<(String, String), warp::Rejection> {
-    let auth_header = auth_header.ok_or_else(|| {
-        warp::reject::custom(CustomReject(anyhow::anyhow!("Missing Authorization header")))
-    })?;
-
-    let basic = auth_header.strip_prefix("Basic ").ok_or_else(|| {
-        warp::reject::custom(CustomReject(anyhow::anyhow!("Invalid Authorization format")))
-    })?;
+impl From<&Rustpad> for DocumentGate {
+    fn from(rustpad: &Rustpad) -> Self {
+        Self {
+            password_hash: rustpad.password_hash(),
+            acl: rustpad.acl(),
+            share_tokens: rustpad.share_tokens(),
+        }
+    }
+}
 
-    use base64::Engine;
-    let decoded = base64::engine::general_purpose::STANDARD.decode(basic).map_err(|_| {
-        warp::reject::custom(CustomReject(anyhow::anyhow!("Invalid Base64 encoding")))
-    })?;
+impl From<&PersistedDocument> for DocumentGate {
+    fn from(document: &PersistedDocument) -> Self {
+        Self {
+            password_hash: document.password_hash.clone(),
+            acl: document.acl.clone(),
+            share_tokens: document.share_tokens.clone(),
+        }
+    }
+}
 
-    let credentials = String::from_utf8(decoded).map_err(|_| {
-        warp::reject::custom(CustomReject(anyhow::anyhow!("Invalid UTF-8 in credentials")))
-    })?;
+/// Credentials offered when reading a document over HTTP.
+struct Reader {
+    /// The `Authorization` header, for documents with a read list.
+    auth: Credentials,
+    /// Password for protected documents.
+    password: Option<String>,
+}
 
-    let parts: Vec<&str> = credentials.splitn(2, ':').collect();
-    if parts.len() != 2 {
-        return Err(warp::reject::custom(CustomReject(anyhow::anyhow!(
-            "Invalid credentials format"
-        ))));
-    }
+/// Query parameters for reading a protected document.
+#[derive(serde::Deserialize)]
+struct PasswordQuery {
+    /// Password for the document, if it is protected.
+    password: Option<String>,
+}
 
-    Ok((parts[0].to_string(), parts[1].to_string()))
+/// Response for GET /api/admin/stats/history
+#[derive(Serialize)]
+struct StatsHistoryResponse {
+    /// Seconds between samples.
+    interval_secs: u64,
+    /// Retained samples, oldest first.
+    samples: Vec<StatsSample>,
 }
 
-/// Handler for POST /api/documents/{id}/freeze
-async fn freeze_handler(
-    id: String,
-    req: FreezeRequest,
-    auth: Option<String>,
+/// Handler for GET /api/admin/stats/history
+async fn admin_stats_history_handler(
+    auth: Credentials,
     state: ServerState,
 ) -> Result<impl Reply, Rejection> {
-    let freeze_manager = state
-        .freeze_manager
-        .as_ref()
-        .ok_or_else(|| warp::reject::custom(CustomReject(anyhow::anyhow!("Freeze feature not enabled"))))?;
-
     let auth_manager = state
         .auth_manager
         .as_ref()
-        .ok_or_else(|| warp::reject::custom(CustomReject(anyhow::anyhow!("Auth not enabled"))))?;
+        .ok_or_else(|| ApiError::NotFound("Auth not enabled".into()))?;
 
-    // Extract and validate credentials
-    let (username, password) = extract_basic_auth(auth)?;
-    auth_manager
-        .login(&username, &password)
-        .map_err(|e| warp::reject::custom(CustomReject(e)))?;
+    // Check admin access
+    check_admin_access(auth, auth_manager).await?;
 
-    // Get the current document content
-    let content = match state.documents.get(&id) {
-        Some(doc) => doc.rustpad.text(),
-        None => {
-            // Try loading from database
-            if let Some(db) = &state.database {
-                db.load(&id)
-                    .await
-                    .map(|doc| doc.text)
-                    .unwrap_or_default()
-            } else {
-                return Err(warp::reject::custom(CustomReject(anyhow::anyhow!(
-                    "Document not found"
-                ))));
-            }
+    Ok(warp::reply::json(&StatsHistoryResponse {
+        interval_secs: state.stats_history.interval().as_secs(),
+        samples: state.stats_history.samples(),
+    }))
+}
+
+/// Handler for the `/api/stats` endpoint.
+async fn stats_handler(start_time: u64, state: ServerState) -> Result<impl Reply, Rejection> {
+    let num_documents = state.documents.len();
+    let mut tags = BTreeMap::new();
+    for entry in state.documents.iter() {
+        for tag in entry.rustpad.metadata().tags {
+            *tags.entry(tag).or_default() += 1;
         }
+    }
+    let database_size = match state.database {
+        None => 0,
+        Some(db) => match db.count().await {
+            Ok(size) => size,
+            Err(e) => return Err(warp::reject::custom(CustomReject(e))),
+        },
     };
+    Ok(warp::reply::json(&Stats {
+        start_time,
+        num_documents,
+        database_size,
+        persisted_loads: state.persisted_loads.load(Ordering::Relaxed),
+        tags,
+    }))
+}
 
-    // Get language
-    let language = req.language.or_else(|| {
-        state.documents.get(&id).and_then(|doc| {
-            let snapshot = doc.rustpad.snapshot();
-            snapshot.language
-        })
-    }).unwrap_or_else(|| "plaintext".to_string());
+/// Handler for the `/api/metrics` endpoint, in the Prometheus text format.
+fn metrics_handler() -> warp::reply::Response {
+    match metrics::render() {
+        Some(body) => warp::reply::with_header(
+            body,
+            "Content-Type",
+            "text/plain; version=0.0.4; charset=utf-8",
+        )
+        .into_response(),
+        None => error_reply(&ApiError::NotFound("Metrics are not enabled".into()).into()),
+    }
+}
 
-    // Freeze the document
-    let frozen_doc = freeze_manager
-        .freeze_document(&id, &username, &language, &content)
-        .map_err(|e| warp::reject::custom(CustomReject(e)))?;
+/// Readiness of the server and its dependencies, returned from an API endpoint.
+#[derive(Serialize)]
+struct Health {
+    /// Always `"ok"`, since the process answered at all.
+    status: &'static str,
+    /// Whether the database is `"up"`, `"down"`, or `"disabled"`.
+    database: &'static str,
+    /// Seconds since the server started.
+    uptime_secs: u64,
+}
 
-    Ok(warp::reply::json(&FreezeResponse {
-        owner_token: frozen_doc.owner_token,
-        document_id: frozen_doc.document_id,
-        frozen_at: frozen_doc.frozen_at.to_rfc3339(),
-        expires_at: frozen_doc.expires_at.to_rfc3339(),
-        file_extension: frozen_doc.file_extension,
+/// How long the database may take to answer a health check.
+const HEALTH_PING_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Handler for the `/api/health` endpoint.
+///
+/// Unlike `/api/stats`, this never counts rows, so probes can call it often.
+/// A failing database is reported in the body rather than as an error.
+async fn health_handler(start_time: u64, state: ServerState) -> Result<impl Reply, Rejection> {
+    let database = match &state.database {
+        None => "disabled",
+        Some(db) => match time::timeout(HEALTH_PING_TIMEOUT, db.ping()).await {
+            Ok(Ok(())) => "up",
+            Ok(Err(e)) => {
+                error!("health check failed to reach database: {}", e);
+                "down"
+            }
+            Err(_) => {
+                error!("health check timed out reaching database");
+                "down"
+            }
+        },
+    };
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .expect("SystemTime returned before UNIX_EPOCH")
+        .as_secs();
+    Ok(warp::reply::json(&Health {
+        status: "ok",
+        database,
+        uptime_secs: now.saturating_sub(start_time),
     }))
 }
 
-/// Handler for GET /api/documents/{id}/download
-async fn download_handler(id: String, state: ServerState) -> Result<impl Reply, Rejection> {
-    let content = match state.documents.get(&id) {
-        Some(doc) => doc.rustpad.text(),
-        None => {
-            if let Some(db) = &state.database {
-                db.load(&id)
-                    .await
-                    .map(|doc| doc.text)
-                    .unwrap_or_default()
-            } else {
-                return Err(warp::reject::custom(CustomReject(anyhow::anyhow!(
-                    "Document not found"
-                ))));
+/// Handler for PUT /api/documents/{id}/acl
+///
+/// Only the user who created the document, or an admin, may change who can
+/// read and write it. The new lists are persisted immediately.
+async fn acl_handler(
+    id: String,
+    acl: DocumentAcl,
+    auth: Credentials,
+    state: ServerState,
+) -> Result<impl Reply, Rejection> {
+    let auth_manager = state
+        .auth_manager
+        .as_ref()
+        .ok_or_else(|| ApiError::NotFound("Auth not enabled".into()))?;
+    let user = authenticate(auth, auth_manager).await?;
+
+    let (rustpad, persistence) = match state.documents.get(&id) {
+        Some(document) => {
+            if !user.is_admin && document.owner.as_deref() != Some(user.username.as_str()) {
+                return Err(ApiError::Forbidden(
+                    "Only the document's owner can change its access".into(),
+                )
+                .into());
             }
+            (Arc::clone(&document.rustpad), document.persistence)
+        }
+        None => {
+            return Err(ApiError::NotFound("Document is not open".into()).into());
         }
     };
 
-    Ok(warp::reply::with_header(
-        content,
-        "Content-Disposition",
+    rustpad.set_acl(acl.clone());
+    state
+        .persist_now(&id, &rustpad, persistence)
+        .await
+        .map_err(|e| warp::reject::custom(CustomReject(e)))?;
+    info!("updated access control for id = {}", id);
+    Ok(warp::reply::json(&acl).into_response())
+}
+
+/// Request body for setting or clearing a document's password.
+#[derive(serde::Deserialize)]
+struct PasswordRequest {
+    /// The new password, or `None` to remove protection.
+    password: Option<String>,
+}
+
+/// Response for changing a document's password.
+#[derive(Serialize)]
+struct PasswordResponse {
+    protected: bool,
+}
+
+/// Handler for the `/api/documents/{id}/password` endpoint.
+///
+/// Changing the password of a protected document requires the current one,
+/// and documents with an owner can only be changed by the owner or an admin.
+async fn password_handler(
+    id: String,
+    body: PasswordRequest,
+    auth: Credentials,
+    current: Option<String>,
+    state: ServerState,
+) -> Result<impl Reply, Rejection> {
+    if body.password.as_deref() == Some("") {
+        return Err(ApiError::BadRequest("Password must not be empty".into()).into());
+    }
+
+    let (rustpad, persistence, owner) = match state.documents.get(&id) {
+        Some(document) => (
+            Arc::clone(&document.rustpad),
+            document.persistence,
+            document.owner.clone(),
+        ),
+        None => {
+            return Err(ApiError::NotFound("Document is not open".into()).into());
+        }
+    };
+    if let (Some(owner), Some(auth_manager)) = (owner, &state.auth_manager) {
+        let user = authenticate(auth, auth_manager).await?;
+        if !user.is_admin && user.username != owner {
+            return Err(ApiError::Forbidden(
+                "Only the document's owner can change its password".into(),
+            )
+            .into());
+        }
+    }
+    if !check_password(rustpad.password_hash(), current).await? {
+        return Err(ApiError::Unauthorized("Document password required".into()).into());
+    }
+
+    let protected = body.password.is_some();
+    let password = body.password;
+    blocking(&rustpad, move |rustpad| {
+        rustpad.set_password(password.as_deref())
+    })
+    .await?;
+    state
+        .persist_now(&id, &rustpad, persistence)
+        .await
+        .map_err(|e| warp::reject::custom(CustomReject(e)))?;
+    info!("updated password for id = {}", id);
+    Ok(warp::reply::json(&PasswordResponse { protected }).into_response())
+}
+
+/// Response for creating a read-only share link.
+#[derive(Serialize)]
+struct ShareResponse {
+    /// Token that opens the document read-only, shown only this once.
+    token: String,
+    /// Link that opens the document with the token, if `PUBLIC_URL` is set.
+    url: Option<String>,
+}
+
+/// Response for revoking a document's share links.
+#[derive(Serialize)]
+struct RevokeSharesResponse {
+    revoked: usize,
+}
+
+/// Handler for POST and DELETE /api/documents/{id}/share
+///
+/// POST creates a token that opens the document read-only, and DELETE
+/// revokes every token created so far. Like changing the password, this
+/// requires the document's password if it has one, and documents with an
+/// owner can only be shared by the owner or an admin. The owner's own access
+/// is unchanged.
+async fn share_handler(
+    id: String,
+    create: bool,
+    auth: Credentials,
+    password: Option<String>,
+    state: ServerState,
+) -> Result<impl Reply, Rejection> {
+    let (rustpad, persistence, owner) = match state.documents.get(&id) {
+        Some(document) => (
+            Arc::clone(&document.rustpad),
+            document.persistence,
+            document.owner.clone(),
+        ),
+        None => {
+            return Err(ApiError::NotFound("Document is not open".into()).into());
+        }
+    };
+    if let (Some(owner), Some(auth_manager)) = (owner, &state.auth_manager) {
+        let user = authenticate(auth, auth_manager).await?;
+        if !user.is_admin && user.username != owner {
+            return Err(ApiError::Forbidden("Only the document's owner can share it".into()).into());
+        }
+    }
+    if !check_password(rustpad.password_hash(), password).await? {
+        return Err(ApiError::Unauthorized("Document password required".into()).into());
+    }
+
+    if !create {
+        let revoked = rustpad.revoke_share_tokens();
+        state
+            .persist_now(&id, &rustpad, persistence)
+            .await
+            .map_err(|e| warp::reject::custom(CustomReject(e)))?;
+        info!("revoked {} share links for id = {}", revoked, id);
+        return Ok(warp::reply::json(&RevokeSharesResponse { revoked }).into_response());
+    }
+
+    let token = rustpad.create_share_token();
+    state
+        .persist_now(&id, &rustpad, persistence)
+        .await
+        .map_err(|e| warp::reject::custom(CustomReject(e)))?;
+    info!("created share link for id = {}", id);
+    let url = state
+        .public_url
+        .as_deref()
+        .map(|base_url| format!("{}/?share={}#{}", base_url.trim_end_matches('/'), token, id));
+    Ok(warp::reply::json(&ShareResponse { token, url }).into_response())
+}
+
+/// Request body for changing how long a document stays in memory.
+#[derive(serde::Deserialize)]
+struct TtlRequest {
+    /// Days of inactivity before eviction, or a keyword.
+    days: TtlDays,
+}
+
+/// A number of days, `"never"`, or `"default"` for the server's expiry.
+#[derive(serde::Deserialize)]
+#[serde(untagged)]
+enum TtlDays {
+    Days(u32),
+    Keyword(TtlKeyword),
+}
+
+#[derive(serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum TtlKeyword {
+    Never,
+    Default,
+}
+
+/// Response for changing how long a document stays in memory.
+#[derive(Serialize)]
+struct TtlResponse {
+    /// Idle time before eviction, or `None` if the document is never evicted.
+    expiry_secs: Option<u64>,
+}
+
+/// Handler for the `/api/documents/{id}/ttl` endpoint.
+///
+/// Callers need the same access as to edit the document, and documents with
+/// an owner can only be changed by the owner or an admin. Only they may keep
+/// a document in memory forever, so it can't be used to pin memory.
+async fn ttl_handler(
+    id: String,
+    body: TtlRequest,
+    reader: Reader,
+    state: ServerState,
+) -> Result<impl Reply, Rejection> {
+    let expiry = match body.days {
+        TtlDays::Days(days) => DocumentExpiry::After(HOUR * 24 * days),
+        TtlDays::Keyword(TtlKeyword::Never) => DocumentExpiry::Never,
+        TtlDays::Keyword(TtlKeyword::Default) => DocumentExpiry::Default,
+    };
+
+    let (rustpad, owner) = match state.documents.get(&id) {
+        Some(document) => (Arc::clone(&document.rustpad), document.owner.clone()),
+        None => {
+            return Err(ApiError::NotFound("Document is not open".into()).into());
+        }
+    };
+    let auth = reader.auth.clone();
+    if let Some(denied) = state.deny_write(&rustpad.snapshot(), reader).await? {
+        return Ok(denied);
+    }
+    let user = match &state.auth_manager {
+        Some(auth_manager) if owner.is_some() || auth.header.is_some() => {
+            Some(authenticate(auth, auth_manager).await?)
+        }
+        _ => None,
+    };
+    let privileged = user.as_ref().map_or(false, |user| {
+        user.is_admin || owner.as_deref() == Some(user.username.as_str())
+    });
+    if owner.is_some() && user.is_some() && !privileged {
+        return Err(ApiError::Forbidden(
+            "Only the document's owner can change its expiry".into(),
+        )
+        .into());
+    }
+    if expiry == DocumentExpiry::Never && !privileged {
+        return Err(ApiError::Forbidden(
+            "Only the document's owner or an admin can keep it in memory forever".into(),
+        )
+        .into());
+    }
+
+    // The document may have been evicted while authenticating.
+    let Some(mut document) = state.documents.get_mut(&id) else {
+        return Err(ApiError::NotFound("Document is not open".into()).into());
+    };
+    document.expiry = expiry;
+    document.expiry_warned = false;
+    info!("updated expiry for id = {} to {:?}", id, expiry);
+    let expiry_secs = expiry
+        .resolve(state.cleaner.expiry)
+        .map(|expiry| expiry.as_secs());
+    Ok(warp::reply::json(&TtlResponse { expiry_secs }).into_response())
+}
+
+/// Handler for the `/api/documents/{id}/metadata` endpoint.
+///
+/// Documents with an owner can only be changed by the owner or an admin. The
+/// metadata is persisted right away, without waiting for an edit.
+async fn metadata_handler(
+    id: String,
+    body: MetadataUpdate,
+    auth: Credentials,
+    state: ServerState,
+) -> Result<impl Reply, Rejection> {
+    let (rustpad, persistence, owner) = match state.documents.get(&id) {
+        Some(document) => (
+            Arc::clone(&document.rustpad),
+            document.persistence,
+            document.owner.clone(),
+        ),
+        None => {
+            return Err(ApiError::NotFound("Document is not open".into()).into());
+        }
+    };
+    if let (Some(owner), Some(auth_manager)) = (owner, &state.auth_manager) {
+        let user = authenticate(auth, auth_manager).await?;
+        if !user.is_admin && user.username != owner {
+            return Err(ApiError::Forbidden(
+                "Only the document's owner can change its metadata".into(),
+            )
+            .into());
+        }
+    }
+
+    let metadata = match rustpad.update_metadata(body) {
+        Ok(metadata) => metadata,
+        Err(e) => {
+            return Err(ApiError::PayloadTooLarge(format!("{:#}", e).into()).into());
+        }
+    };
+    state
+        .persist_now(&id, &rustpad, persistence)
+        .await
+        .map_err(|e| warp::reject::custom(CustomReject(e)))?;
+    info!("updated metadata for id = {}", id);
+    Ok(warp::reply::json(&metadata).into_response())
+}
+
+/// Query parameters for creating a new document.
+#[derive(serde::Deserialize)]
+struct NewDocumentQuery {
+    /// A specific id to claim instead of generating one.
+    id: Option<String>,
+    /// Where to persist the document, overriding the routing rules.
+    persistence: Option<PersistenceTarget>,
+    /// Editor language to start the document in, applying its template.
+    language: Option<String>,
+    /// Make the document end-to-end encrypted, so the server only relays it.
+    #[serde(default)]
+    encrypted: bool,
+    /// Id of a document to start from, copying its text and language.
+    seed: Option<String>,
+    /// Also take over the seed's operation history, so revisions carry on
+    /// from the seed's rather than starting again.
+    #[serde(default)]
+    history: bool,
+}
+
+/// Largest number of operations a new document takes over from its seed.
+const SEED_MAX_OPERATIONS: usize = 10_000;
+
+/// Response for creating a new document.
+#[derive(Serialize)]
+struct NewDocumentResponse {
+    id: String,
+}
+
+const DOCUMENT_ID_CHARS: &[u8] = b"abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789";
+const DOCUMENT_ID_LEN: usize = 6;
+const NEW_DOCUMENT_ATTEMPTS: usize = 5;
+
+/// Generate a random document id, in the same format as the frontend.
+fn generate_document_id() -> String {
+    let mut rng = rand::thread_rng();
+    (0..DOCUMENT_ID_LEN)
+        .map(|_| DOCUMENT_ID_CHARS[rng.gen_range(0..DOCUMENT_ID_CHARS.len())] as char)
+        .collect()
+}
+
+/// Check whether a document exists in memory or in persistent storage.
+async fn document_exists(state: &ServerState, id: &str) -> anyhow::Result<bool> {
+    if state.documents.contains_key(id) {
+        return Ok(true);
+    }
+    if let Some(db) = &state.database {
+        if db.exists(id).await? {
+            return Ok(true);
+        }
+    }
+    match &state.file_store {
+        Some(store) => store.exists(id).await,
+        None => Ok(false),
+    }
+}
+
+/// Whether a requested document id is non-empty and made of safe characters.
+fn valid_document_id(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= 64
+        && id.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+/// Claim a document id by inserting a new document, unless it is taken.
+///
+/// With a `limit`, the document is refused if `owner` already has that many.
+async fn try_create_document(
+    state: &ServerState,
+    id: &str,
+    persistence: Option<PersistenceTarget>,
+    owner: Option<&str>,
+    limit: Option<usize>,
+    rustpad: impl FnOnce() -> Rustpad,
+) -> anyhow::Result<bool> {
+    use dashmap::mapref::entry::Entry;
+
+    if document_exists(state, id).await? {
+        return Ok(false);
+    }
+    let _creating = limit.map(|_| state.creating.lock());
+    if let (Some(owner), Some(limit)) = (owner, limit) {
+        if state.owned_documents(owner) >= limit {
+            return Err(ApiError::Forbidden(format!(
+                "Document limit reached: each user may have at most {} active documents",
+                limit
+            ))
+            .into());
+        }
+    }
+    match state.documents.entry(id.to_string()) {
+        Entry::Occupied(_) => Ok(false),
+        Entry::Vacant(e) => {
+            let rustpad = Arc::new(rustpad());
+            let persistence = state.persistence_target(id, persistence);
+            state.spawn_tasks(id, &rustpad, persistence);
+            e.insert(Document::new(rustpad, persistence, owner.map(String::from)));
+            Ok(true)
+        }
+    }
+}
+
+/// Response for GET /api/documents/{id}/participants
+#[derive(Serialize)]
+struct ParticipantsResponse {
+    /// Connections with a display name, which clients joining would see.
+    participants: Vec<Participant>,
+    /// Number of open connections, including any without a name yet.
+    connections: usize,
+}
+
+/// Handler for GET /api/documents/{id}/participants
+///
+/// Callers need the same access as to read the document, and see the same
+/// names that joining it would show them. A document that isn't loaded has
+/// nobody connected.
+async fn participants_handler(
+    id: String,
+    reader: Reader,
+    state: ServerState,
+) -> Result<impl Reply, Rejection> {
+    let Some(rustpad) = state.documents.get(&id).map(|doc| Arc::clone(&doc.rustpad)) else {
+        let reply = ParticipantsResponse {
+            participants: Vec::new(),
+            connections: 0,
+        };
+        return Ok(warp::reply::json(&reply).into_response());
+    };
+    if let Some(denied) = state.deny_read(&rustpad.snapshot(), reader).await? {
+        return Ok(denied);
+    }
+    Ok(warp::reply::json(&ParticipantsResponse {
+        participants: rustpad.participants(),
+        connections: rustpad.connections(),
+    })
+    .into_response())
+}
+
+/// Handler for GET /api/documents/{id}/qr
+async fn qr_handler(id: String, state: ServerState) -> Result<impl Reply, Rejection> {
+    let base_url = match &state.public_url {
+        Some(base_url) => base_url,
+        None => {
+            return Err(ApiError::NotImplemented(
+                "QR codes require PUBLIC_URL to be configured".into(),
+            )
+            .into());
+        }
+    };
+
+    let link = format!("{}/#{}", base_url.trim_end_matches('/'), id);
+    let svg = qrcode::QrCode::new(link.as_bytes())
+        .map_err(|e| warp::reject::custom(CustomReject(anyhow::anyhow!("QR encoding failed: {}", e))))?
+        .render::<qrcode::render::svg::Color>()
+        .min_dimensions(256, 256)
+        .build();
+
+    let reply = warp::reply::with_header(svg, "Content-Type", "image/svg+xml");
+    Ok(reply.into_response())
+}
+
+/// Load the document a new one is seeded from, from memory or persistence.
+///
+/// Callers need the same access as to read the seed, whose text must be
+/// within the import limit, and whose history must be short enough to copy
+/// if `history` is asked for.
+async fn load_seed(
+    state: &ServerState,
+    id: &str,
+    history: bool,
+    reader: Reader,
+) -> Result<Result<Arc<Rustpad>, warp::reply::Response>, Rejection> {
+    let loaded = state.documents.get(id).map(|document| Arc::clone(&document.rustpad));
+    let seed = match loaded {
+        Some(rustpad) => rustpad,
+        None => match state
+            .load_persisted(id)
+            .await
+            .map_err(|e| warp::reject::custom(CustomReject(e)))?
+        {
+            Some((document, _)) => Arc::new(Rustpad::from(document)),
+            None => return Err(ApiError::NotFound("Seed document not found".into()).into()),
+        },
+    };
+    if let Some(denied) = state.deny_read(&seed.snapshot(), reader).await? {
+        return Ok(Err(denied));
+    }
+    if seed.is_encrypted() {
+        return Ok(Err(ServerState::encrypted_reply()));
+    }
+    if seed.text().len() as u64 > DOCUMENT_IMPORT_MAX_UPLOAD {
+        return Err(ApiError::PayloadTooLarge("Seed document is too large".into()).into());
+    }
+    if history && seed.retained_operations() > SEED_MAX_OPERATIONS {
+        let message = format!(
+            "Seed document has more than {} operations of history to copy",
+            SEED_MAX_OPERATIONS
+        );
+        return Err(ApiError::PayloadTooLarge(message).into());
+    }
+    Ok(Ok(seed))
+}
+
+/// Handler for POST /api/documents/new
+///
+/// With `seed`, the new document starts from another document's text and
+/// language instead of the template, and with `history` also from its
+/// operation history.
+async fn new_document_handler(
+    query: NewDocumentQuery,
+    reader: Reader,
+    state: ServerState,
+) -> Result<impl Reply, Rejection> {
+    // Documents created while logged in are owned by the user, and count
+    // towards their limit unless they are an admin.
+    let (owner, limit) = match &reader.auth.header {
+        Some(_) => {
+            let auth_manager = state
+                .auth_manager
+                .as_ref()
+                .ok_or_else(|| ApiError::NotFound("Auth not enabled".into()))?;
+            let user = authenticate(reader.auth.clone(), auth_manager).await?;
+            (Some(user.username.clone()), state.document_limit(&user))
+        }
+        None => (None, None),
+    };
+
+    if let Some(target) = query.persistence {
+        if target != PersistenceTarget::None && state.sink(target).is_none() {
+            let message = format!("Persistence target {:?} is not configured", target);
+            return Err(ApiError::BadRequest(message).into());
+        }
+    }
+
+    if query.encrypted && !state.encrypted_documents {
+        return Err(
+            ApiError::Forbidden("End-to-end encrypted documents are not enabled".into()).into(),
+        );
+    }
+
+    let language = query.language.as_deref().filter(|language| !language.is_empty());
+
+    let seed = match &query.seed {
+        Some(_) if language.is_some() || query.encrypted => {
+            let message = "A seeded document takes its seed's language and encryption";
+            return Err(ApiError::BadRequest(message.into()).into());
+        }
+        Some(seed) => match load_seed(&state, seed, query.history, reader).await? {
+            Ok(seed) => Some(seed),
+            Err(denied) => return Ok(denied),
+        },
+        None => None,
+    };
+    let new_rustpad = || {
+        let Some(seed) = &seed else {
+            return state.new_rustpad(language, owner.as_deref(), query.encrypted);
+        };
+        let rustpad = if query.history {
+            seed.fork_history()
+        } else {
+            Rustpad::from(PersistedDocument {
+                text: seed.text(),
+                language: seed.language(),
+                ..PersistedDocument::default()
+            })
+        };
+        rustpad.set_creator(owner.clone(), chrono::Utc::now());
+        rustpad.with_config(state.document_config.clone())
+    };
+
+    if let Some(id) = query.id {
+        if !valid_document_id(&id) {
+            return Err(ApiError::BadRequest("Invalid document id".into()).into());
+        }
+        let created = try_create_document(
+            &state,
+            &id,
+            query.persistence,
+            owner.as_deref(),
+            limit,
+            &new_rustpad,
+        )
+        .await
+        .map_err(|e| warp::reject::custom(CustomReject(e)))?;
+        if !created {
+            return Err(ApiError::Conflict("Document already exists".into()).into());
+        }
+        return Ok(warp::reply::with_status(
+            warp::reply::json(&NewDocumentResponse { id }),
+            warp::http::StatusCode::OK,
+        )
+        .into_response());
+    }
+
+    for _ in 0..NEW_DOCUMENT_ATTEMPTS {
+        let id = generate_document_id();
+        let created = try_create_document(
+            &state,
+            &id,
+            query.persistence,
+            owner.as_deref(),
+            limit,
+            &new_rustpad,
+        )
+        .await
+        .map_err(|e| warp::reject::custom(CustomReject(e)))?;
+        if created {
+            return Ok(warp::reply::with_status(
+                warp::reply::json(&NewDocumentResponse { id }),
+                warp::http::StatusCode::OK,
+            )
+            .into_response());
+        }
+        log::warn!("generated document id {} collided, retrying", id);
+    }
+
+    Err(warp::reject::custom(CustomReject(anyhow::anyhow!(
+        "Unable to generate a unique document id"
+    ))))
+}
+
+const HOUR: Duration = Duration::from_secs(3600);
+
+/// When the cleaner runs, and how long documents may stay idle.
+struct CleanerSchedule {
+    /// Idle time after which a document is removed from memory.
+    expiry: Duration,
+    /// When the last pass finished, if there has been one.
+    last_run: parking_lot::Mutex<Option<SystemTime>>,
+    /// When the next scheduled pass is due.
+    next_run: parking_lot::Mutex<SystemTime>,
+}
+
+impl CleanerSchedule {
+    fn new(expiry: Duration) -> Self {
+        Self {
+            expiry,
+            last_run: Default::default(),
+            next_run: parking_lot::Mutex::new(SystemTime::now() + HOUR),
+        }
+    }
+}
+
+/// Documents that have been idle for longer than the expiry, oldest first.
+fn expired_documents(state: &ServerState) -> Vec<(String, Duration)> {
+    let mut expired: Vec<(String, Duration)> = state
+        .documents
+        .iter()
+        .filter(|entry| !entry.draining)
+        .filter_map(|entry| {
+            let expiry = entry.expiry.resolve(state.cleaner.expiry)?;
+            let idle = entry.last_accessed.elapsed();
+            (idle > expiry).then(|| (entry.key().clone(), idle))
+        })
+        .collect();
+    expired.sort_by(|a, b| b.1.cmp(&a.1));
+    expired
+}
+
+/// Remove expired documents from memory, returning their ids.
+///
+/// Documents with open connections are drained in the background if a grace
+/// period is configured, and the rest are evicted before this returns.
+async fn clean_documents(state: &ServerState) -> Vec<String> {
+    let keys: Vec<String> = expired_documents(state)
+        .into_iter()
+        .map(|(key, _)| key)
+        .collect();
+    info!("cleaner removing keys: {:?}", keys);
+    for key in &keys {
+        let Some(rustpad) = state.documents.get_mut(key).map(|mut entry| {
+            entry.draining = true;
+            Arc::clone(&entry.rustpad)
+        }) else {
+            continue;
+        };
+        match state.drain_grace_period {
+            Some(grace) if rustpad.connections() > 0 => {
+                tokio::spawn(drain_document(state.clone(), key.clone(), rustpad, grace));
+            }
+            _ => evict_document(state, key, &rustpad).await,
+        }
+    }
+    *state.cleaner.last_run.lock() = Some(SystemTime::now());
+    keys
+}
+
+/// Warn a document's clients that it is being unloaded, then evict it once
+/// they have had the grace period to finish.
+async fn drain_document(state: ServerState, id: String, rustpad: Arc<Rustpad>, grace: Duration) {
+    info!("draining id = {} for {:?}", id, grace);
+    rustpad.warn_drain(grace);
+    time::sleep(grace).await;
+    evict_document(&state, &id, &rustpad).await;
+}
+
+/// Persist a document's final state, then remove it from memory.
+///
+/// With a grace period configured, clients are disconnected with a reason
+/// telling them to reconnect, which loads the document afresh from storage.
+async fn evict_document(state: &ServerState, id: &str, rustpad: &Arc<Rustpad>) {
+    rustpad.stop_edits();
+    let persistence = state.documents.get(id).map(|entry| entry.persistence);
+    if let Some(sink) = persistence.and_then(|target| state.sink(target)) {
+        persist_if_dirty(id, rustpad, &sink).await;
+    }
+    if state.drain_grace_period.is_some() {
+        rustpad.drain();
+    } else {
+        rustpad.kill();
+    }
+    state
+        .documents
+        .remove_if(id, |_, entry| Arc::ptr_eq(&entry.rustpad, rustpad));
+}
+
+/// Reclaims memory for documents.
+async fn cleaner(state: ServerState) {
+    loop {
+        *state.cleaner.next_run.lock() = SystemTime::now() + HOUR;
+        time::sleep(HOUR).await;
+        clean_documents(&state).await;
+    }
+}
+
+/// Longest time between checks for documents that are about to expire.
+const EXPIRY_WARNING_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Warn the clients of documents that will be evicted within the threshold,
+/// once per period of inactivity.
+fn warn_expiring(state: &ServerState, threshold: Duration) {
+    for mut entry in state.documents.iter_mut() {
+        if entry.expiry_warned {
+            continue;
+        }
+        let Some(expiry) = entry.expiry.resolve(state.cleaner.expiry) else {
+            continue;
+        };
+        let remaining = expiry.saturating_sub(entry.last_accessed.elapsed());
+        if remaining <= threshold {
+            entry.rustpad.warn_expiry(remaining);
+            entry.expiry_warned = true;
+        }
+    }
+}
+
+/// Tells clients when their documents are about to be evicted.
+async fn expiry_warner(state: ServerState, threshold: Duration) {
+    let mut interval = time::interval(threshold.min(EXPIRY_WARNING_INTERVAL));
+    loop {
+        interval.tick().await;
+        warn_expiring(&state, threshold);
+    }
+}
+
+/// Record a sample of server statistics at every interval.
+async fn stats_sampler(state: ServerState) {
+    let mut interval = time::interval(state.stats_history.interval());
+    loop {
+        interval.tick().await;
+        let database_size = match &state.database {
+            Some(db) => db.count().await.unwrap_or_else(|e| {
+                log::warn!("failed to count documents for stats: {}", e);
+                0
+            }),
+            None => 0,
+        };
+        state.stats_history.record(StatsSample {
+            timestamp: SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .expect("SystemTime returned before UNIX_EPOCH")
+                .as_secs(),
+            num_documents: state.documents.len(),
+            connections: state.load.connections(),
+            database_size,
+            memory_bytes: state
+                .documents
+                .iter()
+                .map(|entry| entry.rustpad.text_len())
+                .sum(),
+        });
+    }
+}
+
+const PERSIST_INTERVAL: Duration = Duration::from_secs(3);
+const PERSIST_INTERVAL_JITTER: Duration = Duration::from_secs(1);
+
+/// Minimum time between snapshots forced by clients for the same document.
+const SNAPSHOT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Response for forcing a snapshot of a document
+#[derive(Serialize)]
+struct SnapshotResponse {
+    /// Revision that is now durable, or current if persistence is disabled
+    revision: usize,
+    /// Whether the document was written to its persistence target
+    persisted: bool,
+}
+
+/// Handler for POST /api/documents/{id}/snapshot
+async fn snapshot_handler(id: String, state: ServerState) -> Result<impl Reply, Rejection> {
+    let (rustpad, persistence) = match state.documents.get_mut(&id) {
+        Some(mut document) => {
+            if let Some(last) = document.last_snapshot {
+                let elapsed = last.elapsed();
+                if elapsed < SNAPSHOT_INTERVAL {
+                    let retry_after = (SNAPSHOT_INTERVAL - elapsed).as_secs().max(1);
+                    let error = ApiError::TooManyRequests("Snapshots are rate limited".into());
+                    let reply = warp::reply::with_header(
+                        error_reply(&error.into()),
+                        "Retry-After",
+                        retry_after.to_string(),
+                    );
+                    return Ok(reply.into_response());
+                }
+            }
+            document.last_snapshot = Some(Instant::now());
+            (Arc::clone(&document.rustpad), document.persistence)
+        }
+        None => {
+            return Err(ApiError::NotFound("Document is not open".into()).into());
+        }
+    };
+
+    // Read the revision first, so the stored text is at least that new.
+    let revision = rustpad.revision();
+    let persisted = match state.sink(persistence) {
+        Some(sink) => {
+            sink.store(&id, &rustpad.snapshot())
+                .await
+                .map_err(|e| warp::reject::custom(CustomReject(e)))?;
+            info!("persisted revision {} for id = {} on request", revision, id);
+            rustpad.mark_persisted(revision);
+            true
+        }
+        None => false,
+    };
+
+    Ok(warp::reply::json(&SnapshotResponse {
+        revision,
+        persisted,
+    })
+    .into_response())
+}
+
+/// Persists changed documents after a fixed time interval.
+async fn persister(id: String, rustpad: Arc<Rustpad>, sink: Sink) {
+    while !rustpad.killed() {
+        let interval = PERSIST_INTERVAL
+            + rand::thread_rng().gen_range(Duration::ZERO..=PERSIST_INTERVAL_JITTER);
+        time::sleep(interval).await;
+        persist_if_dirty(&id, &rustpad, &sink).await;
+    }
+}
+
+/// Write a document to its sink if it has changed since it was last persisted.
+///
+/// The persisted revision lives on the document, so the persister and a
+/// shutdown flush never write the same revision twice.
+async fn persist_if_dirty(id: &str, rustpad: &Rustpad, sink: &Sink) {
+    let revision = rustpad.revision();
+    if revision > rustpad.persisted_revision().unwrap_or(0) {
+        info!("persisting revision {} for id = {}", revision, id);
+        let start = Instant::now();
+        if let Err(e) = sink.store(id, &rustpad.snapshot()).await {
+            error!("when persisting document {}: {}", id, e);
+        } else {
+            metrics::persisted(start.elapsed());
+            rustpad.mark_persisted(revision);
+        }
+    }
+}
+
+/// Lint a document whenever it changes and then stays idle for a while.
+async fn lint_runner(id: String, rustpad: Arc<Rustpad>, linter: Arc<Linter>) {
+    let mut last_linted = None;
+    let mut last_seen = None;
+    while !rustpad.killed() {
+        time::sleep(linter.debounce()).await;
+        // Read the revision first, so the text is at least as new as it.
+        let revision = rustpad.revision();
+        let document = rustpad.snapshot();
+        let current = Some((revision, document.language));
+        // Only lint once the document has been idle for a full interval.
+        if current != last_seen {
+            last_seen = current;
+            continue;
+        }
+        if current == last_linted {
+            continue;
+        }
+        last_linted = current.clone();
+        if let Some((revision, Some(language))) = current {
+            match linter.lint(&language, &document.text).await {
+                Ok(Some(diagnostics)) => rustpad.set_diagnostics(revision, diagnostics),
+                Ok(None) => {}
+                Err(e) => log::warn!("when linting document {}: {}", id, e),
+            }
+        }
+    }
+}
+
+/// Request body for freezing a document
+#[derive(serde::Deserialize)]
+struct FreezeRequest {
+    language: Option<String>,
+}
+
+/// Request body for authentication
+#[derive(serde::Deserialize)]
+struct AuthRequest {
+    username: String,
+    password: String,
+    #[serde(default)]
+    ai_enabled: bool,
+    #[serde(default)]
+    is_admin: bool,
+}
+
+/// Request body for changing a password
+#[derive(serde::Deserialize)]
+struct ChangePasswordRequest {
+    username: String,
+    old_password: String,
+    new_password: String,
+}
+
+/// Features enabled on the server
+#[derive(Serialize)]
+struct EnabledFeatures {
+    ai: bool,
+    artifacts: bool,
+    freeze: bool,
+    lint: bool,
+}
+
+impl EnabledFeatures {
+    /// Which optional features this server was started with.
+    fn of(state: &ServerState) -> Self {
+        Self {
+            ai: state.ai_manager.is_some(),
+            artifacts: state.artifact_manager.is_some(),
+            freeze: state.freeze_manager.is_some(),
+            lint: state.linter.is_some(),
+        }
+    }
+}
+
+/// Limits that apply to a user
+#[derive(Serialize)]
+struct UserQuotas {
+    /// Simultaneous WebSocket connections allowed, if limited
+    max_connections: Option<usize>,
+    /// AI tokens left this month, if limited and AI is available
+    ai_tokens_remaining: Option<u32>,
+    /// AI chat requests allowed right now, if limited and AI is available
+    ai_requests_remaining: Option<u32>,
+}
+
+/// Response describing what an authenticated user can do
+#[derive(Serialize)]
+struct CapabilitiesResponse {
+    username: String,
+    is_admin: bool,
+    can_use_ai: bool,
+    can_freeze: bool,
+    can_store_artifacts: bool,
+    features: EnabledFeatures,
+    quotas: UserQuotas,
+}
+
+/// Response for authentication
+#[derive(Serialize)]
+struct AuthResponse {
+    username: String,
+    /// Username in the case it was registered with.
+    display_name: String,
+    created_at: String,
+    ai_enabled: bool,
+    is_admin: bool,
+    /// Session identifying the user's WebSocket connections, set on login.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    session_id: Option<String>,
+    /// Signed access token for the same session, set on login.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    token: Option<String>,
+}
+
+/// Response for revoking login sessions
+#[derive(Serialize)]
+struct RevokeSessionsResponse {
+    /// Number of sessions that were revoked.
+    revoked: usize,
+}
+
+/// Response for freezing a document
+#[derive(Serialize)]
+struct FreezeResponse {
+    owner_token: String,
+    document_id: String,
+    frozen_at: String,
+    expires_at: String,
+    file_extension: String,
+}
+
+/// Extract username from Basic Auth header
+fn extract_basic_auth(auth_header: Option<String>) -> Result<(String, String), warp::Rejection> {
+    let auth_header = auth_header.ok_or_else(|| {
+        ApiError::Unauthorized("Missing Authorization header".into())
+    })?;
+
+    let basic = auth_header.strip_prefix("Basic ").ok_or_else(|| {
+        ApiError::Unauthorized("Invalid Authorization format".into())
+    })?;
+
+    use base64::Engine;
+    let decoded = base64::engine::general_purpose::STANDARD.decode(basic).map_err(|_| {
+        ApiError::Unauthorized("Invalid Base64 encoding".into())
+    })?;
+
+    let credentials = String::from_utf8(decoded).map_err(|_| {
+        ApiError::Unauthorized("Invalid UTF-8 in credentials".into())
+    })?;
+
+    let parts: Vec<&str> = credentials.splitn(2, ':').collect();
+    if parts.len() != 2 {
+        return Err(ApiError::Unauthorized("Invalid credentials format".into()).into());
+    }
+
+    Ok((parts[0].to_string(), parts[1].to_string()))
+}
+
+/// Extract a bearer token from an Authorization header, if it holds one.
+fn extract_bearer_auth(auth_header: Option<&str>) -> Option<&str> {
+    auth_header?.strip_prefix("Bearer ").map(str::trim)
+}
+
+/// Authenticate a request with either a bearer token or Basic credentials.
+///
+/// Bearer tokens are verified by signature alone, which avoids checking the
+/// password hash on every request.
+async fn authenticate(
+    credentials: Credentials,
+    auth_manager: &Arc<AuthManager>,
+) -> Result<User, Rejection> {
+    if let Some(token) = extract_bearer_auth(credentials.header.as_deref()) {
+        let token = token.to_string();
+        return blocking(auth_manager, move |auth_manager| {
+            let message = "Invalid or expired token".to_string();
+            auth_manager
+                .verify_token(&token)
+                .map_err(|e| e.context(ApiError::Unauthorized(message)))
+        })
+        .await;
+    }
+    let (username, password) = extract_basic_auth(credentials.header)?;
+    let remote_addr = credentials.remote_addr;
+    blocking(auth_manager, move |auth_manager| {
+        auth_manager.login(&username, &password, remote_addr.as_deref())
+    })
+    .await
+}
+
+/// Call a manager on the blocking thread pool.
+///
+/// Managers read and write files with `std::fs`, and hash passwords, so on
+/// slow storage or under load they would otherwise stall the async workers.
+async fn blocking<M, T>(
+    manager: &Arc<M>,
+    call: impl FnOnce(&M) -> anyhow::Result<T> + Send + 'static,
+) -> Result<T, Rejection>
+where
+    M: Send + Sync + 'static,
+    T: Send + 'static,
+{
+    let manager = Arc::clone(manager);
+    tokio::task::spawn_blocking(move || call(&manager))
+        .await
+        .map_err(|e| warp::reject::custom(CustomReject(e.into())))?
+        .map_err(|e| warp::reject::custom(CustomReject(e)))
+}
+
+/// Check a document password on the blocking thread pool, since bcrypt is
+/// slow by design.
+async fn check_password(hash: Option<String>, password: Option<String>) -> Result<bool, Rejection> {
+    if hash.is_none() {
+        return Ok(true);
+    }
+    tokio::task::spawn_blocking(move || password_matches(hash.as_deref(), password.as_deref()))
+        .await
+        .map_err(|e| warp::reject::custom(CustomReject(e.into())))
+}
+
+/// Handler for POST /api/documents/{id}/freeze
+async fn freeze_handler(
+    id: String,
+    req: FreezeRequest,
+    reader: Reader,
+    state: ServerState,
+) -> Result<impl Reply, Rejection> {
+    let freeze_manager = state
+        .freeze_manager
+        .as_ref()
+        .ok_or_else(|| ApiError::NotFound("Freeze feature not enabled".into()))?;
+
+    let auth_manager = state
+        .auth_manager
+        .as_ref()
+        .ok_or_else(|| ApiError::NotFound("Auth not enabled".into()))?;
+
+    // Extract and validate credentials
+    let username = authenticate(reader.auth.clone(), auth_manager).await?.username;
+
+    // Get the current document content
+    let document = match state.documents.get(&id) {
+        Some(doc) => doc.rustpad.snapshot(),
+        None => {
+            // Try loading from persistent storage
+            if state.has_persistence() {
+                state
+                    .load_persisted(&id)
+                    .await
+                    .map_err(|e| warp::reject::custom(CustomReject(e)))?
+                    .map(|(doc, _)| doc)
+                    .unwrap_or_default()
+            } else {
+                return Err(ApiError::NotFound("Document not found".into()).into());
+            }
+        }
+    };
+    // Freezing copies the text out of the document, so it is a read like any
+    // other.
+    if let Some(denied) = state.deny_read(&document, reader).await? {
+        return Ok(denied);
+    }
+    if document.encrypted {
+        return Ok(ServerState::encrypted_reply());
+    }
+    let content = document.text;
+
+    // Get language, inferring it if neither the request nor the document has one
+    let language = req
+        .language
+        .or(document.language)
+        .or_else(|| {
+            freeze_manager
+                .infers_language()
+                .then(|| freeze::infer_language(&id, &content))
+                .flatten()
+                .map(String::from)
+        })
+        .unwrap_or_else(|| "plaintext".to_string());
+
+    // Freeze the document
+    let freeze_manager = Arc::clone(freeze_manager);
+    let frozen_doc = state
+        .io_limiter
+        .run(move || freeze_manager.freeze_document(&id, &username, &language, &content))
+        .await;
+    metrics::freeze(frozen_doc.is_ok());
+    let frozen_doc = frozen_doc.map_err(|e| warp::reject::custom(CustomReject(e)))?;
+
+    if let Some(webhooks) = &state.webhooks {
+        webhooks.dispatch(WebhookEvent::DocumentFrozen {
+            document_id: frozen_doc.document_id.clone(),
+            username: frozen_doc.owner_token.clone(),
+            language: frozen_doc.language.clone(),
+            file_size: frozen_doc.file_size,
+            expires_at: frozen_doc.expires_at,
+        });
+    }
+
+    Ok(warp::reply::json(&FreezeResponse {
+        owner_token: frozen_doc.owner_token,
+        document_id: frozen_doc.document_id,
+        frozen_at: frozen_doc.frozen_at.to_rfc3339(),
+        expires_at: frozen_doc.expires_at.to_rfc3339(),
+        file_extension: frozen_doc.file_extension,
+    })
+    .into_response())
+}
+
+/// Handler for GET /api/documents/{id}/download
+async fn download_handler(
+    id: String,
+    reader: Reader,
+    state: ServerState,
+) -> Result<impl Reply, Rejection> {
+    let document = match state.documents.get(&id) {
+        Some(doc) => doc.rustpad.snapshot(),
+        None => {
+            if state.has_persistence() {
+                state
+                    .load_persisted(&id)
+                    .await
+                    .map_err(|e| warp::reject::custom(CustomReject(e)))?
+                    .map(|(doc, _)| doc)
+                    .unwrap_or_default()
+            } else {
+                return Err(ApiError::NotFound("Document not found".into()).into());
+            }
+        }
+    };
+    if let Some(denied) = state.deny_read(&document, reader).await? {
+        return Ok(denied);
+    }
+    if document.encrypted {
+        return Ok(ServerState::encrypted_reply());
+    }
+
+    Ok(warp::reply::with_header(
+        document.text,
+        "Content-Disposition",
         format!("attachment; filename=\"{}.txt\"", id),
+    )
+    .into_response())
+}
+
+/// Largest file accepted when importing it as a new document.
+const DOCUMENT_IMPORT_MAX_UPLOAD: u64 = 4 * 1024 * 1024;
+
+/// Query parameters for importing a file sent as the raw request body.
+#[derive(serde::Deserialize)]
+struct ImportQuery {
+    /// Name of the uploaded file, whose extension sets the language.
+    filename: Option<String>,
+}
+
+/// A file uploaded to start a new document.
+enum ImportBody {
+    /// A multipart form with the file in a `file` part.
+    Form(warp::multipart::FormData),
+    /// The file as the request body, with its name from the query string.
+    Raw(Option<String>, warp::hyper::body::Bytes),
+}
+
+/// Response for importing a file as a new document.
+#[derive(Serialize)]
+struct ImportResponse {
+    id: String,
+    language: Option<String>,
+}
+
+/// Handler for POST /api/documents/{id}/import
+///
+/// Starts a document from an uploaded file, in the language its extension or
+/// content suggests. A document that already exists is left alone, so live
+/// edits are never overwritten.
+async fn import_handler(
+    id: String,
+    body: ImportBody,
+    state: ServerState,
+) -> Result<impl Reply, Rejection> {
+    use futures::TryStreamExt;
+    use warp::Buf;
+
+    if !valid_document_id(&id) {
+        return Err(ApiError::BadRequest("Invalid document id".into()).into());
+    }
+
+    let (filename, data) = match body {
+        ImportBody::Raw(filename, data) => (filename, data.to_vec()),
+        ImportBody::Form(form) => {
+            let parts: Vec<(String, Option<String>, Vec<u8>)> = form
+                .and_then(|part| async move {
+                    let name = part.name().to_string();
+                    let filename = part.filename().map(String::from);
+                    let data = part
+                        .stream()
+                        .try_fold(Vec::new(), |mut data, buf| async move {
+                            data.extend_from_slice(buf.chunk());
+                            Ok(data)
+                        })
+                        .await?;
+                    Ok((name, filename, data))
+                })
+                .try_collect()
+                .await
+                .map_err(|e| ApiError::BadRequest(format!("Invalid upload: {}", e)))?;
+            parts
+                .into_iter()
+                .find(|(name, _, _)| name == "file")
+                .map(|(_, filename, data)| (filename, data))
+                .ok_or_else(|| ApiError::BadRequest("Missing file part with the document".into()))?
+        }
+    };
+    let text = String::from_utf8(data)
+        .map_err(|_| ApiError::BadRequest("Imported file is not UTF-8 text".into()))?;
+    let language = freeze::infer_language(filename.as_deref().unwrap_or_default(), &text);
+
+    let length = text.len();
+    let created = try_create_document(&state, &id, None, None, None, || {
+        let rustpad = Rustpad::from(PersistedDocument {
+            text,
+            language: language.map(String::from),
+            ..PersistedDocument::default()
+        });
+        rustpad.set_creator(None, chrono::Utc::now());
+        rustpad.with_config(state.document_config.clone())
+    })
+    .await
+    .map_err(|e| warp::reject::custom(CustomReject(e)))?;
+    if !created {
+        return Err(ApiError::Conflict("Document already exists".into()).into());
+    }
+
+    info!("imported {} bytes into new document id = {}", length, id);
+    Ok(warp::reply::json(&ImportResponse {
+        id,
+        language: language.map(String::from),
+    }))
+}
+
+/// Query parameters for POST /api/documents/{id}/clone
+#[derive(serde::Deserialize)]
+struct CloneQuery {
+    /// Id of the new document, which must not be in use.
+    to: String,
+    /// Move the document to the new id, rather than copying it.
+    #[serde(default)]
+    rename: bool,
+}
+
+/// Handler for POST /api/documents/{id}/clone
+///
+/// Copies a document's text and language into a new id, from memory or from
+/// persistence if it isn't loaded. The copy keeps the document's access rules
+/// and password, and belongs to the caller if they are logged in, counting
+/// towards their limit. With `rename`, the whole document moves instead,
+/// metadata included: it is stored under the new id, and removed from
+/// persistence and from memory under the old one.
+///
+/// Callers need the same access as to read the document, or to edit it for
+/// a rename, and an owned document can only be renamed by its owner or an
+/// admin. A document with live connections is never renamed, and nothing is
+/// written to an id that is already in use.
+async fn clone_handler(
+    id: String,
+    query: CloneQuery,
+    reader: Reader,
+    state: ServerState,
+) -> Result<impl Reply, Rejection> {
+    let target = query.to;
+    if !valid_document_id(&target) {
+        return Err(ApiError::BadRequest("Invalid document id".into()).into());
+    }
+    if target == id {
+        return Err(ApiError::BadRequest("Document is already at that id".into()).into());
+    }
+
+    if query.rename {
+        // The old id can't be loaded from persistence while it moves.
+        let guard = Arc::clone(state.loading.entry(id.clone()).or_default().value());
+        let renamed = {
+            let _held = guard.lock().await;
+            rename_document(&state, &id, &target, reader).await
+        };
+        state
+            .loading
+            .remove_if(&id, |_, lock| Arc::ptr_eq(lock, &guard));
+        return renamed;
+    }
+
+    let loaded = state
+        .documents
+        .get(&id)
+        .map(|document| Arc::clone(&document.rustpad));
+    let document = match loaded {
+        Some(rustpad) => rustpad.snapshot(),
+        None => match state
+            .load_persisted(&id)
+            .await
+            .map_err(|e| warp::reject::custom(CustomReject(e)))?
+        {
+            Some((document, _)) => document,
+            None => return Err(ApiError::NotFound("Document not found".into()).into()),
+        },
+    };
+
+    let auth = reader.auth.clone();
+    if let Some(denied) = state.deny_read(&document, reader).await? {
+        return Ok(denied);
+    }
+    if document.encrypted {
+        return Ok(ServerState::encrypted_reply());
+    }
+
+    let caller = match (&state.auth_manager, &auth.header) {
+        (Some(auth_manager), Some(_)) => Some(authenticate(auth, auth_manager).await?),
+        _ => None,
+    };
+    let owner = caller.as_ref().map(|user| user.username.clone());
+    let limit = caller.as_ref().and_then(|user| state.document_limit(user));
+    let created = try_create_document(&state, &target, None, owner.as_deref(), limit, || {
+        let rustpad = Rustpad::from(PersistedDocument {
+            text: document.text,
+            language: document.language,
+            acl: document.acl,
+            password_hash: document.password_hash,
+            ..PersistedDocument::default()
+        });
+        rustpad.set_creator(owner.clone(), chrono::Utc::now());
+        rustpad.with_config(state.document_config.clone())
+    })
+    .await
+    .map_err(|e| warp::reject::custom(CustomReject(e)))?;
+    if !created {
+        return Err(ApiError::Conflict("Document already exists".into()).into());
+    }
+    info!("cloned document id = {} to id = {}", id, target);
+    Ok(warp::reply::json(&NewDocumentResponse { id: target }).into_response())
+}
+
+/// Move a document to a new id, while the caller holds the loading guard of
+/// the old one.
+async fn rename_document(
+    state: &ServerState,
+    id: &str,
+    target: &str,
+    reader: Reader,
+) -> Result<warp::reply::Response, Rejection> {
+    let loaded = state.documents.get(id).map(|document| {
+        (
+            Arc::clone(&document.rustpad),
+            document.persistence,
+            document.owner.clone(),
+        )
+    });
+    let (document, persistence, owner) = match &loaded {
+        Some((rustpad, persistence, owner)) => (rustpad.snapshot(), *persistence, owner.clone()),
+        None => match state
+            .load_persisted(id)
+            .await
+            .map_err(|e| warp::reject::custom(CustomReject(e)))?
+        {
+            Some((document, persistence)) => (document, persistence, None),
+            None => return Err(ApiError::NotFound("Document not found".into()).into()),
+        },
+    };
+    let owner = owner.or_else(|| document.metadata.created_by.clone());
+
+    let auth = reader.auth.clone();
+    if let Some(denied) = state.deny_write(&document, reader).await? {
+        return Ok(denied);
+    }
+    if let (Some(owner), Some(auth_manager)) = (&owner, &state.auth_manager) {
+        let user = authenticate(auth, auth_manager).await?;
+        if !user.is_admin && &user.username != owner {
+            return Err(ApiError::Forbidden(
+                "Only the document's owner can rename it".into(),
+            )
+            .into());
+        }
+    }
+
+    // A loaded document stops taking edits, so that what is copied is final
+    // even if a connection slips in before it is removed.
+    let document = match &loaded {
+        Some((rustpad, _, _)) => {
+            if rustpad.connections() > 0 {
+                return Err(ApiError::Conflict("Document has live connections".into()).into());
+            }
+            rustpad.stop_edits();
+            rustpad.snapshot()
+        }
+        None => document,
+    };
+    let created = try_create_document(
+        state,
+        target,
+        Some(persistence),
+        owner.as_deref(),
+        None,
+        || Rustpad::from(document).with_config(state.document_config.clone()),
+    )
+    .await;
+    if !matches!(created, Ok(true)) {
+        if let Some((rustpad, _, _)) = &loaded {
+            rustpad.resume_edits();
+        }
+    }
+    let created = created.map_err(|e| warp::reject::custom(CustomReject(e)))?;
+    if !created {
+        return Err(ApiError::Conflict("Document already exists".into()).into());
+    }
+
+    // Store the new copy before removing the old one, so a failure part way
+    // leaves the document under both ids rather than neither.
+    let renamed = state
+        .documents
+        .get(target)
+        .map(|document| Arc::clone(&document.rustpad));
+    if let Some(renamed) = renamed {
+        state
+            .persist_now(target, &renamed, persistence)
+            .await
+            .map_err(|e| warp::reject::custom(CustomReject(e)))?;
+    }
+    if let Some((rustpad, _, _)) = &loaded {
+        // Nothing is left for the old copy's persister to write back.
+        rustpad.mark_persisted(rustpad.revision());
+        rustpad.kill();
+        state
+            .documents
+            .remove_if(id, |_, document| Arc::ptr_eq(&document.rustpad, rustpad));
+    }
+    if let Some(sink) = state.sink(persistence) {
+        sink.delete(id)
+            .await
+            .map_err(|e| warp::reject::custom(CustomReject(e)))?;
+    }
+
+    info!("renamed document id = {} to id = {}", id, target);
+    Ok(warp::reply::json(&NewDocumentResponse {
+        id: target.to_string(),
+    })
+    .into_response())
+}
+
+/// Handler for GET /api/documents/{id}/frozen
+///
+/// Responds with an `ETag` derived from the content hash, and with `304 Not
+/// Modified` when the client already holds the same content.
+async fn download_frozen_handler(
+    id: String,
+    auth: Credentials,
+    if_none_match: Option<String>,
+    state: ServerState,
+) -> Result<impl Reply, Rejection> {
+    let freeze_manager = state
+        .freeze_manager
+        .as_ref()
+        .ok_or_else(|| ApiError::NotFound("Freeze feature not enabled".into()))?;
+
+    let auth_manager = state
+        .auth_manager
+        .as_ref()
+        .ok_or_else(|| ApiError::NotFound("Auth not enabled".into()))?;
+
+    let username = authenticate(auth, auth_manager).await?.username;
+
+    let (document, content) = blocking(freeze_manager, move |freeze_manager| {
+        freeze_manager.get_frozen_document_with_metadata(&username, &id)
+    })
+    .await?;
+    let etag = format!("\"{}\"", document.content_hash.unwrap_or_default());
+
+    let mut builder = warp::http::Response::builder()
+        .header("ETag", &etag)
+        .header("Cache-Control", "private, no-cache");
+    let body = if matches!(&if_none_match, Some(tags) if etag_matches(tags, &etag)) {
+        builder = builder.status(warp::http::StatusCode::NOT_MODIFIED);
+        String::new()
+    } else {
+        builder = builder.header(
+            "Content-Disposition",
+            format!(
+                "attachment; filename=\"{}.{}\"",
+                document.document_id, document.file_extension
+            ),
+        );
+        content
+    };
+    builder
+        .body(body)
+        .map_err(|e| warp::reject::custom(CustomReject(e.into())))
+}
+
+/// Whether an `If-None-Match` header value matches the given entity tag.
+fn etag_matches(if_none_match: &str, etag: &str) -> bool {
+    if_none_match.split(',').map(str::trim).any(|tag| {
+        // Weak comparison, as required for `If-None-Match`.
+        tag == "*" || tag.trim_start_matches("W/") == etag
+    })
+}
+
+/// Query parameters for listing frozen documents
+#[derive(serde::Deserialize)]
+struct ListFrozenQuery {
+    /// Number of documents to skip, newest first
+    #[serde(default)]
+    offset: usize,
+    /// Most documents to return, capped at the configured page size
+    limit: Option<usize>,
+}
+
+/// Query parameters for listing documents in the database.
+#[derive(serde::Deserialize)]
+struct ListDatabaseQuery {
+    /// Number of documents to skip, in order of id
+    #[serde(default)]
+    offset: usize,
+    /// Most documents to return, capped at [`DATABASE_PAGE_LIMIT`]
+    limit: Option<usize>,
+}
+
+/// Default number of documents listed from the database at once.
+const DATABASE_PAGE_SIZE: usize = 50;
+
+/// Most documents listed from the database at once.
+const DATABASE_PAGE_LIMIT: usize = 500;
+
+/// One page of the documents persisted in the database.
+#[derive(Serialize)]
+struct DatabasePage {
+    documents: Vec<DocumentSummary>,
+    total: usize,
+    offset: usize,
+    limit: usize,
+}
+
+/// Handler for GET /api/documents/db/list (admin only)
+async fn list_database_handler(
+    query: ListDatabaseQuery,
+    auth: Credentials,
+    state: ServerState,
+) -> Result<impl Reply, Rejection> {
+    let auth_manager = state
+        .auth_manager
+        .as_ref()
+        .ok_or_else(|| ApiError::NotFound("Auth not enabled".into()))?;
+
+    // Check admin access
+    check_admin_access(auth, auth_manager).await?;
+
+    let database = state
+        .database
+        .as_ref()
+        .ok_or_else(|| ApiError::NotFound("Database not enabled".into()))?;
+    let limit = query.limit.unwrap_or(DATABASE_PAGE_SIZE).min(DATABASE_PAGE_LIMIT);
+    let documents = database
+        .list(limit, query.offset)
+        .await
+        .map_err(|e| warp::reject::custom(CustomReject(e)))?;
+    let total = database
+        .count()
+        .await
+        .map_err(|e| warp::reject::custom(CustomReject(e)))?;
+
+    Ok(warp::reply::json(&DatabasePage {
+        documents,
+        total,
+        offset: query.offset,
+        limit,
+    }))
+}
+
+/// Handler for GET /api/documents/list
+async fn list_frozen_handler(
+    query: ListFrozenQuery,
+    auth: Credentials,
+    state: ServerState,
+) -> Result<impl Reply, Rejection> {
+    let freeze_manager = state
+        .freeze_manager
+        .as_ref()
+        .ok_or_else(|| ApiError::NotFound("Freeze feature not enabled".into()))?;
+
+    let auth_manager = state
+        .auth_manager
+        .as_ref()
+        .ok_or_else(|| ApiError::NotFound("Auth not enabled".into()))?;
+
+    // Extract and validate credentials
+    let username = authenticate(auth, auth_manager).await?.username;
+
+    let page = blocking(freeze_manager, move |freeze_manager| {
+        freeze_manager.list_frozen_page(&username, query.offset, query.limit)
+    })
+    .await?;
+
+    Ok(warp::reply::json(&page))
+}
+
+/// Query parameters for searching frozen documents
+#[derive(serde::Deserialize)]
+struct SearchFrozenQuery {
+    /// Text to look for, ignoring case
+    q: String,
+}
+
+/// A frozen document with the lines that matched a search
+#[derive(Serialize)]
+struct SearchResult {
+    #[serde(flatten)]
+    document: FrozenDocument,
+    hits: Vec<SearchHit>,
+}
+
+/// Handler for GET /api/documents/search
+async fn search_frozen_handler(
+    query: SearchFrozenQuery,
+    auth: Credentials,
+    state: ServerState,
+) -> Result<warp::reply::Response, Rejection> {
+    let freeze_manager = state
+        .freeze_manager
+        .as_ref()
+        .ok_or_else(|| ApiError::NotFound("Freeze feature not enabled".into()))?;
+
+    let auth_manager = state
+        .auth_manager
+        .as_ref()
+        .ok_or_else(|| ApiError::NotFound("Auth not enabled".into()))?;
+
+    let username = authenticate(auth, auth_manager).await?.username;
+
+    if query.q.trim().is_empty() {
+        return Err(ApiError::BadRequest("Search query must not be empty".into()).into());
+    }
+
+    let results = blocking(freeze_manager, move |freeze_manager| {
+        freeze_manager.search(&username, &query.q)
+    })
+    .await?;
+
+    let results: Vec<_> = results
+        .into_iter()
+        .map(|(document, hits)| SearchResult { document, hits })
+        .collect();
+    Ok(warp::reply::json(&results).into_response())
+}
+
+/// Handler for GET /api/documents/manifest
+async fn frozen_manifest_handler(
+    auth: Credentials,
+    state: ServerState,
+) -> Result<impl Reply, Rejection> {
+    let freeze_manager = state
+        .freeze_manager
+        .as_ref()
+        .ok_or_else(|| ApiError::NotFound("Freeze feature not enabled".into()))?;
+
+    let auth_manager = state
+        .auth_manager
+        .as_ref()
+        .ok_or_else(|| ApiError::NotFound("Auth not enabled".into()))?;
+
+    // Extract and validate credentials
+    let username = authenticate(auth, auth_manager).await?.username;
+
+    let manifest =
+        blocking(freeze_manager, move |freeze_manager| freeze_manager.manifest(&username)).await?;
+
+    Ok(warp::reply::json(&manifest))
+}
+
+/// Request body for extending a frozen document
+#[derive(serde::Deserialize)]
+struct ExtendRequest {
+    /// Days to add to the current expiry
+    days: u32,
+}
+
+/// Response for extending a frozen document
+#[derive(Serialize)]
+struct ExtendResponse {
+    expires_at: chrono::DateTime<chrono::Utc>,
+}
+
+/// Handler for POST /api/documents/{id}/extend
+async fn extend_frozen_handler(
+    id: String,
+    req: ExtendRequest,
+    auth: Credentials,
+    state: ServerState,
+) -> Result<impl Reply, Rejection> {
+    let freeze_manager = state
+        .freeze_manager
+        .as_ref()
+        .ok_or_else(|| ApiError::NotFound("Freeze feature not enabled".into()))?;
+
+    let auth_manager = state
+        .auth_manager
+        .as_ref()
+        .ok_or_else(|| ApiError::NotFound("Auth not enabled".into()))?;
+
+    // Extract and validate credentials
+    let username = authenticate(auth, auth_manager).await?.username;
+
+    let freeze_manager = Arc::clone(freeze_manager);
+    let frozen = state
+        .io_limiter
+        .run(move || freeze_manager.extend_expiry(&username, &id, req.days))
+        .await
+        .map_err(|e| warp::reject::custom(CustomReject(e)))?;
+
+    Ok(warp::reply::json(&ExtendResponse {
+        expires_at: frozen.expires_at,
+    }))
+}
+
+/// Handler for DELETE /api/documents/{id}/delete
+async fn delete_frozen_handler(
+    id: String,
+    auth: Credentials,
+    state: ServerState,
+) -> Result<impl Reply, Rejection> {
+    let freeze_manager = state
+        .freeze_manager
+        .as_ref()
+        .ok_or_else(|| ApiError::NotFound("Freeze feature not enabled".into()))?;
+
+    let auth_manager = state
+        .auth_manager
+        .as_ref()
+        .ok_or_else(|| ApiError::NotFound("Auth not enabled".into()))?;
+
+    // Extract and validate credentials
+    let username = authenticate(auth, auth_manager).await?.username;
+
+    let freeze_manager = Arc::clone(freeze_manager);
+    state
+        .io_limiter
+        .run(move || freeze_manager.delete_frozen_document(&username, &id))
+        .await
+        .map_err(|e| warp::reject::custom(CustomReject(e)))?;
+
+    Ok(warp::reply::with_status(
+        "Document deleted",
+        warp::http::StatusCode::OK,
+    ))
+}
+
+/// Handler for POST /api/auth/register
+async fn register_handler(
+    req: AuthRequest,
+    state: ServerState,
+) -> Result<impl Reply, Rejection> {
+    let auth_manager = state
+        .auth_manager
+        .as_ref()
+        .ok_or_else(|| ApiError::NotFound("Auth not enabled".into()))?;
+
+    let user = blocking(auth_manager, move |auth_manager| {
+        auth_manager.register(&req.username, &req.password, req.ai_enabled, req.is_admin)
+    })
+    .await?;
+
+    Ok(warp::reply::json(&AuthResponse {
+        display_name: user.display_name().to_string(),
+        username: user.username,
+        created_at: user.created_at,
+        ai_enabled: user.ai_enabled,
+        is_admin: user.is_admin,
+        session_id: None,
+        token: None,
+    }))
+}
+
+/// Handler for POST /api/auth/change-password
+async fn change_password_handler(
+    req: ChangePasswordRequest,
+    state: ServerState,
+) -> Result<impl Reply, Rejection> {
+    let auth_manager = state
+        .auth_manager
+        .as_ref()
+        .ok_or_else(|| ApiError::NotFound("Auth not enabled".into()))?;
+
+    blocking(auth_manager, move |auth_manager| {
+        auth_manager.change_password(&req.username, &req.old_password, &req.new_password)
+    })
+    .await?;
+
+    Ok(warp::reply())
+}
+
+/// Handler for POST /api/auth/login
+async fn login_handler(
+    req: AuthRequest,
+    user_agent: Option<String>,
+    remote_addr: Option<String>,
+    state: ServerState,
+) -> Result<impl Reply, Rejection> {
+    let auth_manager = state
+        .auth_manager
+        .as_ref()
+        .ok_or_else(|| ApiError::NotFound("Auth not enabled".into()))?;
+
+    let addr = remote_addr.clone();
+    let user = blocking(auth_manager, move |auth_manager| {
+        auth_manager.login(&req.username, &req.password, addr.as_deref())
+    })
+    .await?;
+    let client = SessionClient {
+        user_agent,
+        remote_addr,
+    };
+    let name = user.username.clone();
+    let session = blocking(auth_manager, move |auth_manager| {
+        auth_manager.create_session_for(&name, client)
+    })
+    .await?;
+    let token = auth_manager
+        .session_token(&user, &session)
+        .map_err(|e| warp::reject::custom(CustomReject(e)))?;
+
+    Ok(warp::reply::json(&AuthResponse {
+        display_name: user.display_name().to_string(),
+        username: user.username,
+        created_at: user.created_at,
+        ai_enabled: user.ai_enabled,
+        is_admin: user.is_admin,
+        session_id: Some(session.id),
+        token: Some(token),
+    }))
+}
+
+/// Handler for GET /api/auth/sessions
+async fn list_sessions_handler(
+    auth: Credentials,
+    state: ServerState,
+) -> Result<impl Reply, Rejection> {
+    let auth_manager = state
+        .auth_manager
+        .as_ref()
+        .ok_or_else(|| ApiError::NotFound("Auth not enabled".into()))?;
+
+    let username = authenticate(auth, auth_manager).await?.username;
+
+    Ok(warp::reply::json(&auth_manager.list_sessions(&username)))
+}
+
+/// Handler for DELETE /api/auth/sessions/{id}
+async fn revoke_session_handler(
+    session_id: String,
+    auth: Credentials,
+    state: ServerState,
+) -> Result<impl Reply, Rejection> {
+    let auth_manager = state
+        .auth_manager
+        .as_ref()
+        .ok_or_else(|| ApiError::NotFound("Auth not enabled".into()))?;
+
+    let username = authenticate(auth, auth_manager).await?.username;
+
+    blocking(auth_manager, move |auth_manager| {
+        auth_manager.revoke_session(&username, &session_id)
+    })
+    .await?;
+    Ok(warp::reply::json(&RevokeSessionsResponse { revoked: 1 }))
+}
+
+/// Handler for DELETE /api/auth/sessions
+async fn revoke_all_sessions_handler(
+    auth: Credentials,
+    state: ServerState,
+) -> Result<impl Reply, Rejection> {
+    let auth_manager = state
+        .auth_manager
+        .as_ref()
+        .ok_or_else(|| ApiError::NotFound("Auth not enabled".into()))?;
+
+    let username = authenticate(auth, auth_manager).await?.username;
+
+    let revoked = blocking(auth_manager, move |auth_manager| {
+        auth_manager.revoke_all_sessions(&username)
+    })
+    .await?;
+    Ok(warp::reply::json(&RevokeSessionsResponse { revoked }))
+}
+
+/// Limits that apply to everyone using the server
+#[derive(Serialize)]
+struct PublicLimits {
+    /// Largest document that can be frozen, in bytes, if freezing is enabled
+    max_frozen_size: Option<u64>,
+    /// Live documents a non-admin user may create, if limited
+    max_documents_per_user: Option<usize>,
+}
+
+/// Public configuration that the frontend reads at load
+#[derive(Serialize)]
+struct PublicConfigResponse {
+    #[serde(flatten)]
+    branding: Branding,
+    auth_enabled: bool,
+    features: EnabledFeatures,
+    limits: PublicLimits,
+}
+
+/// Handler for GET /api/config
+///
+/// Unauthenticated, so this must only ever contain values that are safe to
+/// show to any visitor.
+async fn public_config_handler(state: ServerState) -> Result<impl Reply, Rejection> {
+    let mut branding = (*state.branding).clone();
+    branding
+        .instance_name
+        .get_or_insert_with(|| String::from("Rustpad"));
+    let response = PublicConfigResponse {
+        branding,
+        auth_enabled: state.auth_manager.is_some(),
+        features: EnabledFeatures::of(&state),
+        limits: PublicLimits {
+            max_frozen_size: state.freeze_manager.as_ref().map(|fm| fm.max_file_size()),
+            max_documents_per_user: state.max_documents_per_user,
+        },
+    };
+    let reply = warp::reply::json(&response);
+    Ok(warp::reply::with_header(reply, "Cache-Control", "public, max-age=60"))
+}
+
+/// Handler for GET /api/auth/capabilities
+async fn capabilities_handler(
+    auth: Credentials,
+    state: ServerState,
+) -> Result<impl Reply, Rejection> {
+    let auth_manager = state
+        .auth_manager
+        .as_ref()
+        .ok_or_else(|| ApiError::NotFound("Auth not enabled".into()))?;
+
+    // Extract and validate credentials
+    let user = authenticate(auth, auth_manager).await?;
+
+    let features = EnabledFeatures::of(&state);
+    let can_use_ai = features.ai && user.ai_enabled;
+    let (ai_tokens_remaining, ai_requests_remaining) = match &state.ai_manager {
+        Some(ai_manager) if can_use_ai => {
+            let tokens = auth_manager
+                .monthly_token_limit()
+                .map(|limit| u64::from(limit).saturating_sub(user.tokens_used()) as u32);
+            let requests = ai_manager.rate_limit_remaining(&user.username, user.ai_rate_limit);
+            (tokens, requests)
+        }
+        _ => (None, None),
+    };
+    let response = CapabilitiesResponse {
+        username: user.username,
+        is_admin: user.is_admin,
+        can_use_ai,
+        can_freeze: features.freeze,
+        can_store_artifacts: can_use_ai && features.artifacts,
+        features,
+        quotas: UserQuotas {
+            max_connections: state.load.max_user_connections(),
+            ai_tokens_remaining,
+            ai_requests_remaining,
+        },
+    };
+
+    // The remaining quotas go down with every AI request, so clients may
+    // only reuse the answer for a few seconds, and never share it with
+    // another user.
+    let reply = warp::reply::json(&response);
+    let reply = warp::reply::with_header(reply, "Cache-Control", "private, max-age=5");
+    Ok(warp::reply::with_header(reply, "Vary", "Authorization"))
+}
+
+/// Handler for GET /api/auth/export-data
+async fn export_data_handler(
+    auth: Credentials,
+    state: ServerState,
+) -> Result<impl Reply, Rejection> {
+    let auth_manager = state
+        .auth_manager
+        .as_ref()
+        .ok_or_else(|| ApiError::NotFound("Auth not enabled".into()))?;
+
+    // Extract and validate credentials
+    let user = authenticate(auth, auth_manager).await?;
+    let username = user.username.clone();
+
+    let ai_usage = export::AiUsage {
+        period: user.usage_period.clone(),
+        tokens: user.token_usage_this_period,
+    };
+    let account = AuthResponse {
+        display_name: user.display_name().to_string(),
+        username: user.username,
+        created_at: user.created_at,
+        ai_enabled: user.ai_enabled,
+        is_admin: user.is_admin,
+        session_id: None,
+        token: None,
+    };
+    let name = username.clone();
+    let (freeze_manager, artifact_manager) =
+        (state.freeze_manager.clone(), state.artifact_manager.clone());
+    let export = tokio::task::spawn_blocking(move || {
+        export::UserExport::new(&account, &ai_usage, &name, freeze_manager, artifact_manager)
+    })
+    .await
+    .map_err(|e| warp::reject::custom(CustomReject(e.into())))?
+    .map_err(|e| warp::reject::custom(CustomReject(e)))?;
+
+    warp::http::Response::builder()
+        .header("Content-Type", "application/json")
+        .header(
+            "Content-Disposition",
+            format!("attachment; filename=\"{}-export.json\"", username),
+        )
+        .body(export.into_body(state.io_limiter.clone()))
+        .map_err(|e| warp::reject::custom(CustomReject(e.into())))
+}
+
+/// Cleanup task for expired frozen documents
+async fn freeze_cleaner(freeze_manager: Arc<FreezeManager>) {
+    loop {
+        time::sleep(HOUR * 6).await; // Run every 6 hours
+        let freeze_manager = Arc::clone(&freeze_manager);
+        match tokio::task::spawn_blocking(move || freeze_manager.cleanup_expired(false)).await {
+            Ok(Ok(_)) => {}
+            Ok(Err(e)) => error!("Error during freeze cleanup: {}", e),
+            Err(e) => error!("Freeze cleanup panicked: {}", e),
+        }
+    }
+}
+
+/// Cleanup task for expired login sessions
+async fn session_cleaner(auth_manager: Arc<AuthManager>) {
+    let interval = auth_manager.session_cleanup_interval();
+    loop {
+        time::sleep(interval).await;
+        let count = auth_manager.prune_expired_sessions();
+        if count > 0 {
+            info!("Cleaned up {} expired sessions", count);
+        }
+    }
+}
+
+/// Request body for AI chat
+#[derive(serde::Deserialize)]
+struct AiChatRequest {
+    model: String,
+    messages: Vec<ai::ChatMessage>,
+    #[serde(default)]
+    max_tokens: Option<u32>,
+    #[serde(default)]
+    temperature: Option<f32>,
+    /// Client-chosen id that allows the request to be cancelled.
+    #[serde(default)]
+    job_id: Option<String>,
+    /// Name of a server-configured system prompt to start the conversation
+    /// with, unless the messages include a system message already.
+    #[serde(default)]
+    system_prompt_id: Option<String>,
+    /// Document whose current text is sent along as context, truncated to
+    /// fit the model.
+    #[serde(default)]
+    document_id: Option<String>,
+    /// Password for the context document, if it is protected.
+    #[serde(default)]
+    document_password: Option<String>,
+}
+
+/// Load the text a chat request asks for as context, checking that the caller
+/// may read it.
+///
+/// Returns the reply to send instead if they may not.
+async fn load_chat_context(
+    state: &ServerState,
+    id: &str,
+    reader: Reader,
+) -> Result<Result<PersistedDocument, warp::reply::Response>, Rejection> {
+    let loaded = state.documents.get(id).map(|document| document.rustpad.snapshot());
+    let document = match loaded {
+        Some(document) => document,
+        None => match state
+            .load_persisted(id)
+            .await
+            .map_err(|e| warp::reject::custom(CustomReject(e)))?
+        {
+            Some((document, _)) => document,
+            None => return Err(ApiError::NotFound("Document not found".into()).into()),
+        },
+    };
+    if let Some(denied) = state.deny_read(&document, reader).await? {
+        return Ok(Err(denied));
+    }
+    if document.encrypted {
+        return Ok(Err(ServerState::encrypted_reply()));
+    }
+    Ok(Ok(document))
+}
+
+/// Apply the parts of a chat request that add messages: its named system
+/// prompt, then its document context.
+///
+/// Returns the reply to send instead if the context can't be read.
+async fn prepare_chat_messages(
+    state: &ServerState,
+    ai_manager: &AiManager,
+    req: &mut AiChatRequest,
+    auth: Credentials,
+) -> Result<Option<warp::reply::Response>, Rejection> {
+    if let Some(name) = &req.system_prompt_id {
+        ai_manager
+            .apply_system_prompt(name, &mut req.messages)
+            .map_err(|e| warp::reject::custom(CustomReject(e)))?;
+    }
+    if let Some(id) = &req.document_id {
+        let reader = Reader {
+            auth,
+            password: req.document_password.clone(),
+        };
+        let document = match load_chat_context(state, id, reader).await? {
+            Ok(document) => document,
+            Err(denied) => return Ok(Some(denied)),
+        };
+        ai_manager.add_document_context(
+            &req.model,
+            id,
+            document.language.as_deref(),
+            &document.text,
+            &mut req.messages,
+            req.max_tokens,
+        );
+    }
+    Ok(None)
+}
+
+/// Handler for GET /api/ai/models
+async fn ai_models_handler(state: ServerState) -> Result<impl Reply, Rejection> {
+    let ai_manager = state
+        .ai_manager
+        .as_ref()
+        .ok_or_else(|| ApiError::NotFound("AI features not enabled".into()))?;
+
+    if !ai_manager.is_enabled() {
+        return Err(ApiError::NotFound("AI features not configured".into()).into());
+    }
+
+    // Served from a cache of the OpenRouter catalog, falling back to the
+    // static list only if it has never been fetched
+    let models = ai_manager.get_models_cached().await;
+
+    Ok(warp::reply::json(&models))
+}
+
+/// Handler for GET /api/ai/prompts
+async fn ai_prompts_handler(state: ServerState) -> Result<impl Reply, Rejection> {
+    let ai_manager = state
+        .ai_manager
+        .as_ref()
+        .ok_or_else(|| ApiError::NotFound("AI features not enabled".into()))?;
+
+    Ok(warp::reply::json(&ai_manager.system_prompt_names()))
+}
+
+/// Handler for POST /api/ai/chat
+async fn ai_chat_handler(
+    mut req: AiChatRequest,
+    auth: Credentials,
+    state: ServerState,
+) -> Result<impl Reply, Rejection> {
+    let ai_manager = state
+        .ai_manager
+        .as_ref()
+        .ok_or_else(|| ApiError::NotFound("AI features not enabled".into()))?;
+
+    let auth_manager = state
+        .auth_manager
+        .as_ref()
+        .ok_or_else(|| ApiError::NotFound("Auth not enabled".into()))?;
+
+    // Extract and validate credentials
+    let user = authenticate(auth.clone(), auth_manager).await?;
+    let username = user.username.clone();
+
+    // Check if user has AI access
+    if !user.ai_enabled {
+        return Err(ApiError::Forbidden("AI features not enabled for this user".into()).into());
+    }
+
+    if let Some(denied) = prepare_chat_messages(&state, ai_manager, &mut req, auth).await? {
+        return Ok(denied);
+    }
+
+    let name = username.clone();
+    let remaining = blocking(auth_manager, move |auth_manager| {
+        Ok(auth_manager.remaining_quota(&name))
+    })
+    .await?;
+    if remaining == Some(0) {
+        return Ok(quota_exceeded_response());
+    }
+
+    let remaining = match ai_manager.check_rate_limit(&username, user.ai_rate_limit) {
+        Ok(remaining) => remaining,
+        Err(limited) => return Ok(rate_limited_response(limited)),
+    };
+
+    // Make the API call
+    let completion =
+        ai_manager.chat_completion(&req.model, req.messages, req.max_tokens, req.temperature);
+    let response = match &req.job_id {
+        Some(job_id) => ai_manager.run_job(job_id, &username, completion).await,
+        None => completion.await,
+    };
+    metrics::ai_request(&req.model, response.is_ok());
+    let mut response = response.map_err(|e| warp::reject::custom(CustomReject(e)))?;
+
+    if let Some(usage) = &response.usage {
+        let (auth_manager, name) = (Arc::clone(auth_manager), username.clone());
+        let tokens = usage.total_tokens;
+        let recorded =
+            tokio::task::spawn_blocking(move || auth_manager.record_usage(&name, tokens)).await;
+        if let Err(e) = recorded.map_err(anyhow::Error::from).and_then(|result| result) {
+            log::warn!("failed to record AI token usage for {}: {}", username, e);
+        }
+    }
+
+    let redacted = ai_manager.redact(&mut response);
+    if redacted > 0 {
+        info!("redacted {} matches from an AI response to {}", redacted, username);
+    }
+
+    if let Some(webhooks) = &state.webhooks {
+        webhooks.dispatch(WebhookEvent::AiChatCompleted {
+            document_id: req.document_id.clone(),
+            username: username.clone(),
+            model: req.model.clone(),
+            total_tokens: response.usage.as_ref().map(|usage| usage.total_tokens),
+        });
+    }
+
+    Ok(with_rate_limit_remaining(warp::reply::json(&response), remaining))
+}
+
+/// Answer `429 Too Many Requests` for a user who has used up this month's tokens.
+fn quota_exceeded_response() -> warp::reply::Response {
+    error_reply(&ApiError::TooManyRequests("Monthly AI token quota exceeded".into()).into())
+}
+
+/// Answer `429 Too Many Requests` for a user over their AI rate limit.
+fn rate_limited_response(limited: ai::RateLimited) -> warp::reply::Response {
+    let retry_after = limited.retry_after.as_secs_f64().ceil().max(1.0) as u64;
+    let error = ApiError::TooManyRequests(format!(
+        "AI rate limit exceeded, try again in {} seconds",
+        retry_after
+    ));
+    let reply = warp::reply::with_header(
+        error_reply(&error.into()),
+        "Retry-After",
+        retry_after.to_string(),
+    );
+    warp::reply::with_header(reply, "X-RateLimit-Remaining", "0").into_response()
+}
+
+/// Tell the client how many more AI requests it may make right now, if limited.
+fn with_rate_limit_remaining(reply: impl Reply, remaining: Option<u32>) -> warp::reply::Response {
+    let mut response = reply.into_response();
+    if let Some(remaining) = remaining {
+        response
+            .headers_mut()
+            .insert("X-RateLimit-Remaining", remaining.into());
+    }
+    response
+}
+
+/// Handler for POST /api/ai/chat/stream
+///
+/// Each content delta is sent as an SSE event holding `{"content": ...}`,
+/// followed by a final `[DONE]` event. Closing the connection cancels the
+/// upstream request.
+async fn ai_chat_stream_handler(
+    mut req: AiChatRequest,
+    auth: Credentials,
+    state: ServerState,
+) -> Result<impl Reply, Rejection> {
+    use futures::StreamExt;
+    use warp::sse::Event;
+
+    let ai_manager = state
+        .ai_manager
+        .as_ref()
+        .ok_or_else(|| ApiError::NotFound("AI features not enabled".into()))?;
+
+    let auth_manager = state
+        .auth_manager
+        .as_ref()
+        .ok_or_else(|| ApiError::NotFound("Auth not enabled".into()))?;
+
+    // Extract and validate credentials
+    let user = authenticate(auth.clone(), auth_manager).await?;
+
+    // Check if user has AI access
+    if !user.ai_enabled {
+        return Err(ApiError::Forbidden("AI features not enabled for this user".into()).into());
+    }
+
+    if let Some(denied) = prepare_chat_messages(&state, ai_manager, &mut req, auth).await? {
+        return Ok(denied);
+    }
+
+    let name = user.username.clone();
+    let remaining = blocking(auth_manager, move |auth_manager| {
+        Ok(auth_manager.remaining_quota(&name))
+    })
+    .await?;
+    if remaining == Some(0) {
+        return Ok(quota_exceeded_response());
+    }
+
+    let remaining = match ai_manager.check_rate_limit(&user.username, user.ai_rate_limit) {
+        Ok(remaining) => remaining,
+        Err(limited) => return Ok(rate_limited_response(limited)),
+    };
+
+    let usage = StreamUsage {
+        auth_manager: Arc::clone(auth_manager),
+        username: user.username.clone(),
+        prompt_chars: req.messages.iter().map(|message| message.content.len()).sum(),
+        generated_chars: 0,
+        reported: None,
+    };
+    let deltas = ai_manager
+        .chat_completion_stream(&req.model, req.messages, req.max_tokens, req.temperature)
+        .await
+        .map_err(|e| warp::reject::custom(CustomReject(e)))?;
+
+    // Stop at the first error, reporting it to the client in place of `[DONE]`.
+    // The usage travels with the stream, so it is recorded when the stream is
+    // dropped, whether it finished or the client went away. Content held back
+    // for redaction is released before the stream ends either way.
+    let state = (false, usage, ai_manager.stream_redactor());
+    let username = user.username.clone();
+    let events = deltas
+        .map(Some)
+        .chain(futures::stream::once(async { None }))
+        .scan(state, move |(failed, usage, redactor), event| {
+            let content_event = |content: String| {
+                (!content.is_empty())
+                    .then(|| Event::default().json_data(serde_json::json!({ "content": content })))
+            };
+            let mut finish = || {
+                let redactor = redactor.as_mut()?;
+                if redactor.redacted() > 0 {
+                    info!(
+                        "redacted {} matches from an AI stream to {}",
+                        redactor.redacted(),
+                        username
+                    );
+                }
+                content_event(redactor.finish())
+            };
+            let events: Vec<_> = match event {
+                _ if *failed => return futures::future::ready(None),
+                Some(Ok(ai::StreamEvent::Usage(reported))) => {
+                    usage.reported = Some(reported.total_tokens);
+                    Vec::new()
+                }
+                Some(Ok(ai::StreamEvent::Delta(delta))) => {
+                    usage.generated_chars += delta.len();
+                    let delta = match redactor {
+                        Some(redactor) => redactor.push(&delta),
+                        None => delta,
+                    };
+                    content_event(delta).into_iter().collect()
+                }
+                Some(Err(e)) => {
+                    *failed = true;
+                    let error = Event::default().event("error").data(format!("{:#}", e));
+                    finish().into_iter().chain([Ok(error)]).collect()
+                }
+                None => {
+                    let done = Event::default().data("[DONE]");
+                    finish().into_iter().chain([Ok(done)]).collect()
+                }
+            };
+            futures::future::ready(Some(events))
+        })
+        .flat_map(futures::stream::iter);
+
+    let reply = warp::sse::reply(warp::sse::keep_alive().stream(events));
+    Ok(with_rate_limit_remaining(reply, remaining))
+}
+
+/// Tokens used by a streamed chat, counted against the user's monthly quota
+/// when dropped.
+struct StreamUsage {
+    auth_manager: Arc<AuthManager>,
+    username: String,
+    /// Characters in the prompt, for estimating usage that was never reported
+    prompt_chars: usize,
+    /// Characters generated so far
+    generated_chars: usize,
+    /// Tokens used as reported by the upstream, once the generation ended
+    reported: Option<u32>,
+}
+
+impl Drop for StreamUsage {
+    fn drop(&mut self) {
+        // A stream cut short never gets its usage reported, so what was sent
+        // and generated so far is estimated instead. Nothing is counted for a
+        // request that failed before generating anything.
+        let tokens = match self.reported {
+            Some(tokens) => tokens,
+            None if self.generated_chars == 0 => return,
+            None => ai::estimate_tokens(self.prompt_chars + self.generated_chars),
+        };
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            log::warn!("failed to record AI token usage for {}: no runtime", self.username);
+            return;
+        };
+        let (auth_manager, username) = (Arc::clone(&self.auth_manager), self.username.clone());
+        runtime.spawn_blocking(move || {
+            if let Err(e) = auth_manager.record_usage(&username, tokens) {
+                log::warn!("failed to record AI token usage for {}: {}", username, e);
+            }
+        });
+    }
+}
+
+/// Handler for POST /api/ai/jobs/{id}/cancel
+async fn ai_cancel_handler(
+    job_id: String,
+    auth: Credentials,
+    state: ServerState,
+) -> Result<impl Reply, Rejection> {
+    let ai_manager = state
+        .ai_manager
+        .as_ref()
+        .ok_or_else(|| ApiError::NotFound("AI features not enabled".into()))?;
+
+    let auth_manager = state
+        .auth_manager
+        .as_ref()
+        .ok_or_else(|| ApiError::NotFound("Auth not enabled".into()))?;
+
+    // Extract and validate credentials
+    let username = authenticate(auth, auth_manager).await?.username;
+
+    ai_manager
+        .cancel_job(&job_id, &username)
+        .map_err(|e| warp::reject::custom(CustomReject(e)))?;
+
+    Ok(warp::reply::with_status(
+        "Job cancelled",
+        warp::http::StatusCode::OK,
+    ))
+}
+
+/// Request body for storing artifacts
+#[derive(serde::Deserialize)]
+struct ArtifactStoreRequest {
+    document_id: String,
+    model: String,
+    prompt: String,
+    files: Vec<artifacts::ArtifactFile>,
+}
+
+/// Handler for GET /api/artifacts/list
+async fn artifacts_list_handler(
+    auth: Credentials,
+    state: ServerState,
+) -> Result<impl Reply, Rejection> {
+    let artifact_manager = state
+        .artifact_manager
+        .as_ref()
+        .ok_or_else(|| ApiError::NotFound("Artifact storage not enabled".into()))?;
+
+    let auth_manager = state
+        .auth_manager
+        .as_ref()
+        .ok_or_else(|| ApiError::NotFound("Auth not enabled".into()))?;
+
+    // Extract and validate credentials
+    let username = authenticate(auth, auth_manager).await?.username;
+
+    let artifacts = blocking(artifact_manager, move |artifact_manager| {
+        artifact_manager.list_artifacts(&username)
+    })
+    .await?;
+
+    Ok(warp::reply::json(&artifacts))
+}
+
+/// Query parameters for retrieving an artifact.
+#[derive(serde::Deserialize)]
+struct ArtifactQuery {
+    /// Glob pattern restricting which files are returned, such as `*.md`.
+    files: Option<String>,
+}
+
+/// Handler for GET /api/artifacts/{id}
+async fn artifacts_get_handler(
+    artifact_id: String,
+    query: ArtifactQuery,
+    auth: Credentials,
+    state: ServerState,
+) -> Result<impl Reply, Rejection> {
+    let artifact_manager = state
+        .artifact_manager
+        .as_ref()
+        .ok_or_else(|| ApiError::NotFound("Artifact storage not enabled".into()))?;
+
+    let auth_manager = state
+        .auth_manager
+        .as_ref()
+        .ok_or_else(|| ApiError::NotFound("Auth not enabled".into()))?;
+
+    // Extract and validate credentials
+    let username = authenticate(auth, auth_manager).await?.username;
+
+    let artifact = blocking(artifact_manager, move |artifact_manager| {
+        artifact_manager.get_artifact_matching(&username, &artifact_id, query.files.as_deref())
+    })
+    .await?;
+
+    Ok(warp::reply::json(&artifact))
+}
+
+/// Size of the chunks that artifact archives are streamed in.
+const ZIP_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Handler for GET /api/artifacts/{id}/download
+async fn artifacts_download_handler(
+    artifact_id: String,
+    auth: Credentials,
+    state: ServerState,
+) -> Result<impl Reply, Rejection> {
+    use std::io::{Seek, SeekFrom};
+    use tokio::io::AsyncReadExt;
+
+    let artifact_manager = state
+        .artifact_manager
+        .as_ref()
+        .ok_or_else(|| ApiError::NotFound("Artifact storage not enabled".into()))?;
+
+    let auth_manager = state
+        .auth_manager
+        .as_ref()
+        .ok_or_else(|| ApiError::NotFound("Auth not enabled".into()))?;
+
+    // Extract and validate credentials
+    let username = authenticate(auth, auth_manager).await?.username;
+
+    let (name, id) = (username.clone(), artifact_id.clone());
+    let exists = blocking(artifact_manager, move |artifact_manager| {
+        Ok(artifact_manager.has_artifact(&name, &id))
+    })
+    .await?;
+    if !exists {
+        return Err(ApiError::NotFound("Artifact not found".into()).into());
+    }
+
+    // The archive is assembled in an unnamed temporary file, so neither it
+    // nor the files in it are held in memory, and it vanishes once closed.
+    let artifact_manager = Arc::clone(artifact_manager);
+    let id = artifact_id.clone();
+    let archive = state
+        .io_limiter
+        .run(move || {
+            let mut file = artifact_manager.write_zip(&username, &id, tempfile::tempfile()?)?;
+            file.seek(SeekFrom::Start(0))?;
+            Ok(file)
+        })
+        .await
+        .map_err(|e| warp::reject::custom(CustomReject(e)))?;
+
+    let chunks = futures::stream::try_unfold(
+        tokio::fs::File::from_std(archive),
+        |mut file| async move {
+            let mut chunk = vec![0; ZIP_CHUNK_SIZE];
+            let len = file.read(&mut chunk).await?;
+            if len == 0 {
+                return Ok::<_, std::io::Error>(None);
+            }
+            chunk.truncate(len);
+            Ok(Some((chunk, file)))
+        },
+    );
+    let response = warp::http::Response::builder()
+        .header("Content-Type", "application/zip")
+        .header(
+            "Content-Disposition",
+            format!("attachment; filename=\"{}.zip\"", artifact_id),
+        )
+        .body(warp::hyper::Body::wrap_stream(chunks))
+        .map_err(|e| warp::reject::custom(CustomReject(e.into())))?;
+    Ok(response)
+}
+
+/// Handler for POST /api/artifacts/store
+async fn artifacts_store_handler(
+    req: ArtifactStoreRequest,
+    auth: Credentials,
+    state: ServerState,
+) -> Result<impl Reply, Rejection> {
+    let artifact_manager = state
+        .artifact_manager
+        .as_ref()
+        .ok_or_else(|| ApiError::NotFound("Artifact storage not enabled".into()))?;
+
+    let auth_manager = state
+        .auth_manager
+        .as_ref()
+        .ok_or_else(|| ApiError::NotFound("Auth not enabled".into()))?;
+
+    // Extract and validate credentials
+    let username = authenticate(auth, auth_manager).await?.username;
+
+    let artifact_manager = Arc::clone(artifact_manager);
+    let metadata = state
+        .io_limiter
+        .run(move || {
+            artifact_manager.store_artifact(
+                &username,
+                &req.document_id,
+                &req.model,
+                &req.prompt,
+                req.files,
+            )
+        })
+        .await
+        .map_err(|e| warp::reject::custom(CustomReject(e)))?;
+
+    Ok(warp::reply::json(&metadata))
+}
+
+/// Largest multipart upload accepted when importing a ZIP as an artifact.
+const ARTIFACT_IMPORT_MAX_UPLOAD: u64 = 16 * 1024 * 1024;
+
+/// Handler for POST /api/artifacts/import
+///
+/// Expects a multipart form with the archive in a `file` part, and optional
+/// `document_id`, `model`, and `prompt` text parts describing the artifact.
+async fn artifacts_import_handler(
+    form: warp::multipart::FormData,
+    auth: Credentials,
+    state: ServerState,
+) -> Result<impl Reply, Rejection> {
+    use futures::TryStreamExt;
+    use warp::Buf;
+
+    let artifact_manager = state
+        .artifact_manager
+        .as_ref()
+        .ok_or_else(|| ApiError::NotFound("Artifact storage not enabled".into()))?;
+
+    let auth_manager = state
+        .auth_manager
+        .as_ref()
+        .ok_or_else(|| ApiError::NotFound("Auth not enabled".into()))?;
+
+    // Extract and validate credentials
+    let username = authenticate(auth, auth_manager).await?.username;
+
+    let parts: Vec<(String, Vec<u8>)> = form
+        .and_then(|part| async move {
+            let name = part.name().to_string();
+            let data = part
+                .stream()
+                .try_fold(Vec::new(), |mut data, buf| async move {
+                    data.extend_from_slice(buf.chunk());
+                    Ok(data)
+                })
+                .await?;
+            Ok((name, data))
+        })
+        .try_collect()
+        .await
+        .map_err(|e| ApiError::BadRequest(format!("Invalid upload: {}", e)))?;
+
+    let field = |name: &str| {
+        parts
+            .iter()
+            .find(|(part, _)| part == name)
+            .map(|(_, data)| String::from_utf8_lossy(data).into_owned())
+            .unwrap_or_default()
+    };
+    let (document_id, model, prompt) = (field("document_id"), field("model"), field("prompt"));
+    let Some((_, archive)) = parts.into_iter().find(|(name, _)| name == "file") else {
+        return Err(ApiError::BadRequest("Missing file part with the ZIP archive".into()).into());
+    };
+
+    let artifact_manager = Arc::clone(artifact_manager);
+    let imported = state
+        .io_limiter
+        .run(move || {
+            artifact_manager.import_zip(&username, &document_id, &model, &prompt, &archive)
+        })
+        .await;
+    match imported {
+        Ok(metadata) => Ok(warp::reply::json(&metadata).into_response()),
+        Err(e) => Err(ApiError::BadRequest(format!("{:#}", e)).into()),
+    }
+}
+
+/// Handler for DELETE /api/artifacts/{id}
+async fn artifacts_delete_handler(
+    artifact_id: String,
+    auth: Credentials,
+    state: ServerState,
+) -> Result<impl Reply, Rejection> {
+    let artifact_manager = state
+        .artifact_manager
+        .as_ref()
+        .ok_or_else(|| ApiError::NotFound("Artifact storage not enabled".into()))?;
+
+    let auth_manager = state
+        .auth_manager
+        .as_ref()
+        .ok_or_else(|| ApiError::NotFound("Auth not enabled".into()))?;
+
+    // Extract and validate credentials
+    let username = authenticate(auth, auth_manager).await?.username;
+
+    let artifact_manager = Arc::clone(artifact_manager);
+    state
+        .io_limiter
+        .run(move || artifact_manager.delete_artifact(&username, &artifact_id))
+        .await
+        .map_err(|e| warp::reject::custom(CustomReject(e)))?;
+
+    Ok(warp::reply::with_status(
+        "Artifact deleted",
+        warp::http::StatusCode::OK,
     ))
 }
 
-/// Handler for GET /api/documents/list
-async fn list_frozen_handler(
-    auth: Option<String>,
+/// A share link for an artifact, returned when it is created
+#[derive(Serialize)]
+struct ArtifactShareResponse {
+    /// Link serving the artifact to anyone who has it
+    url: String,
+    /// When the link stops working
+    expires_at: chrono::DateTime<chrono::Utc>,
+}
+
+/// Handler for POST /api/artifacts/{id}/share
+async fn artifacts_share_handler(
+    artifact_id: String,
+    auth: Credentials,
+    state: ServerState,
+) -> Result<impl Reply, Rejection> {
+    let artifact_manager = state
+        .artifact_manager
+        .as_ref()
+        .ok_or_else(|| ApiError::NotFound("Artifact storage not enabled".into()))?;
+
+    let auth_manager = state
+        .auth_manager
+        .as_ref()
+        .ok_or_else(|| ApiError::NotFound("Auth not enabled".into()))?;
+
+    // Extract and validate credentials
+    let username = authenticate(auth, auth_manager).await?.username;
+
+    let link = blocking(artifact_manager, move |artifact_manager| {
+        artifact_manager.share_artifact(&username, &artifact_id)
+    })
+    .await?;
+
+    let base_url = state.public_url.as_deref().unwrap_or("").trim_end_matches('/');
+    Ok(warp::reply::json(&ArtifactShareResponse {
+        url: format!(
+            "{}/api/artifacts/shared/{}?exp={}&sig={}",
+            base_url, link.artifact_id, link.exp, link.sig
+        ),
+        expires_at: chrono::DateTime::from_timestamp(link.exp, 0).unwrap_or_default(),
+    }))
+}
+
+/// Query parameters of an artifact share link.
+#[derive(serde::Deserialize)]
+struct SharedArtifactQuery {
+    /// Expiry time, in seconds since the Unix epoch.
+    exp: i64,
+    /// Signature over the artifact ID and expiry.
+    sig: String,
+    /// Name of a single file to serve as plain text, instead of the artifact.
+    file: Option<String>,
+}
+
+/// Handler for GET /api/artifacts/shared/{id}
+async fn artifacts_shared_handler(
+    artifact_id: String,
+    query: SharedArtifactQuery,
+    state: ServerState,
+) -> Result<impl Reply, Rejection> {
+    let artifact_manager = state
+        .artifact_manager
+        .as_ref()
+        .ok_or_else(|| ApiError::NotFound("Artifact storage not enabled".into()))?;
+
+    let link = artifacts::ShareLink {
+        artifact_id,
+        exp: query.exp,
+        sig: query.sig,
+    };
+    if let Err(e) = artifact_manager.verify_share_link(&link) {
+        log::warn!("rejected share link for artifact {}: {}", link.artifact_id, e);
+        return Err(ApiError::Forbidden("Invalid or expired share link".into()).into());
+    }
+
+    let not_found = || error_reply(&ApiError::NotFound("Artifact not found".into()).into());
+    let artifact_id = link.artifact_id.clone();
+    let shared = blocking(artifact_manager, move |artifact_manager| {
+        artifact_manager.get_shared_artifact(&artifact_id)
+    });
+    let Ok(artifact) = shared.await else {
+        return Ok(not_found());
+    };
+    match query.file {
+        Some(name) => match artifact.files.into_iter().find(|file| file.name == name) {
+            Some(file) => Ok(file.content.into_response()),
+            None => Ok(not_found()),
+        },
+        None => Ok(warp::reply::json(&artifact).into_response()),
+    }
+}
+
+/// User info for admin panel (without password hash)
+#[derive(Serialize)]
+struct AdminUserInfo {
+    username: String,
+    display_name: String,
+    created_at: String,
+    ai_enabled: bool,
+    is_admin: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ai_rate_limit: Option<ai::RateLimit>,
+}
+
+/// Query parameters for listing users in the admin panel.
+#[derive(serde::Deserialize)]
+struct AdminUsersQuery {
+    /// Number of users to skip, in the chosen order
+    #[serde(default)]
+    offset: usize,
+    /// Most users to return, capped at [`ADMIN_USERS_PAGE_LIMIT`]
+    limit: Option<usize>,
+    /// Order to list users in, `username` or `created_at`
+    #[serde(default)]
+    sort: auth::UserSort,
+}
+
+/// Default number of users listed at once.
+const ADMIN_USERS_PAGE_SIZE: usize = 50;
+
+/// Most users listed at once.
+const ADMIN_USERS_PAGE_LIMIT: usize = 500;
+
+/// One page of the registered users.
+#[derive(Serialize)]
+struct AdminUserPage {
+    users: Vec<AdminUserInfo>,
+    total: usize,
+    offset: usize,
+    limit: usize,
+}
+
+/// Number of documents using each language
+#[derive(Serialize)]
+struct LanguageStats {
+    /// Documents currently held in memory
+    documents: BTreeMap<String, usize>,
+    /// Frozen documents, if file freeze is enabled
+    frozen_documents: Option<BTreeMap<String, usize>>,
+}
+
+/// What the background cleaner has done and will do next
+#[derive(Serialize)]
+struct CleanerStatus {
+    /// When the cleaner last ran, if it has run yet
+    last_run: Option<chrono::DateTime<chrono::Utc>>,
+    /// When the next scheduled pass is due
+    next_run: chrono::DateTime<chrono::Utc>,
+    /// Idle time after which documents are evicted
+    expiry_secs: u64,
+    /// Documents that the next pass would evict, longest idle first
+    eligible: Vec<EligibleDocument>,
+}
+
+/// A document past the expiry threshold
+#[derive(Serialize)]
+struct EligibleDocument {
+    id: String,
+    /// Time since the document was last accessed
+    idle_secs: u64,
+}
+
+/// A frozen document past its expiry
+#[derive(Serialize)]
+struct ExpiredFrozenDocument {
+    username: String,
+    document_id: String,
+    expires_at: chrono::DateTime<chrono::Utc>,
+    /// Size of the content in bytes
+    file_size: u64,
+}
+
+/// Everything that the cleaners would remove if they ran now
+#[derive(Serialize)]
+struct CleanupPreview {
+    /// In-memory documents past the idle expiry, longest idle first
+    documents: Vec<EligibleDocument>,
+    /// Frozen documents past their expiry
+    frozen: Vec<ExpiredFrozenDocument>,
+}
+
+/// Result of running the cleaner on demand
+#[derive(Serialize)]
+struct CleanerRunResponse {
+    /// Ids of the documents that were evicted
+    evicted: Vec<String>,
+}
+
+/// Bucket for documents without a language set
+const UNKNOWN_LANGUAGE: &str = "plaintext";
+
+/// Request to update AI access
+#[derive(serde::Deserialize)]
+struct UpdateAiAccessRequest {
+    ai_enabled: bool,
+}
+
+/// Helper function to check admin access
+async fn check_admin_access(
+    auth: Credentials,
+    auth_manager: &Arc<AuthManager>,
+) -> Result<(), Rejection> {
+    let user = authenticate(auth, auth_manager).await?;
+
+    if !user.is_admin {
+        return Err(ApiError::Forbidden("Admin access required".into()).into());
+    }
+
+    Ok(())
+}
+
+/// Handler for GET /api/admin/users
+async fn admin_users_handler(
+    query: AdminUsersQuery,
+    auth: Credentials,
     state: ServerState,
 ) -> Result<impl Reply, Rejection> {
-    let freeze_manager = state
-        .freeze_manager
-        .as_ref()
-        .ok_or_else(|| warp::reject::custom(CustomReject(anyhow::anyhow!("Freeze feature not enabled"))))?;
-
     let auth_manager = state
         .auth_manager
         .as_ref()
-        .ok_or_else(|| warp::reject::custom(CustomReject(anyhow::anyhow!("Auth not enabled"))))?;
+        .ok_or_else(|| ApiError::NotFound("Auth not enabled".into()))?;
 
-    // Extract and validate credentials
-    let (username, password) = extract_basic_auth(auth)?;
-    auth_manager
-        .login(&username, &password)
-        .map_err(|e| warp::reject::custom(CustomReject(e)))?;
+    // Check admin access
+    check_admin_access(auth, auth_manager).await?;
+
+    let limit = query
+        .limit
+        .unwrap_or(ADMIN_USERS_PAGE_SIZE)
+        .min(ADMIN_USERS_PAGE_LIMIT);
+    let page = blocking(auth_manager, move |auth_manager| {
+        auth_manager.list_users(query.sort, query.offset, limit)
+    })
+    .await?;
 
-    let documents = freeze_manager
-        .list_frozen_documents(&username)
-        .map_err(|e| warp::reject::custom(CustomReject(e)))?;
+    // Convert to admin user info (remove password hash)
+    let users = page
+        .users
+        .into_iter()
+        .map(|u| AdminUserInfo {
+            display_name: u.display_name().to_string(),
+            username: u.username,
+            created_at: u.created_at,
+            ai_enabled: u.ai_enabled,
+            is_admin: u.is_admin,
+            ai_rate_limit: u.ai_rate_limit,
+        })
+        .collect();
 
-    Ok(warp::reply::json(&documents))
+    Ok(warp::reply::json(&AdminUserPage {
+        users,
+        total: page.total,
+        offset: query.offset,
+        limit,
+    }))
 }
 
-/// Handler for DELETE /api/documents/{id}/delete
-async fn delete_frozen_handler(
-    id: String,
-    auth: Option<String>,
+/// Handler for POST /api/admin/ai/test
+async fn admin_ai_test_handler(
+    auth: Credentials,
     state: ServerState,
 ) -> Result<impl Reply, Rejection> {
-    let freeze_manager = state
-        .freeze_manager
-        .as_ref()
-        .ok_or_else(|| warp::reject::custom(CustomReject(anyhow::anyhow!("Freeze feature not enabled"))))?;
-
     let auth_manager = state
         .auth_manager
         .as_ref()
-        .ok_or_else(|| warp::reject::custom(CustomReject(anyhow::anyhow!("Auth not enabled"))))?;
+        .ok_or_else(|| ApiError::NotFound("Auth not enabled".into()))?;
 
-    // Extract and validate credentials
-    let (username, password) = extract_basic_auth(auth)?;
-    auth_manager
-        .login(&username, &password)
-        .map_err(|e| warp::reject::custom(CustomReject(e)))?;
+    // Check admin access
+    check_admin_access(auth, auth_manager).await?;
 
-    freeze_manager
-        .delete_frozen_document(&username, &id)
-        .map_err(|e| warp::reject::custom(CustomReject(e)))?;
+    let ai_manager = state
+        .ai_manager
+        .as_ref()
+        .ok_or_else(|| ApiError::NotFound("AI features not enabled".into()))?;
 
-    Ok(warp::reply::with_status(
-        "Document deleted",
-        warp::http::StatusCode::OK,
-    ))
+    Ok(warp::reply::json(&ai_manager.test_connection().await))
 }
 
-/// Handler for POST /api/auth/register
-async fn register_handler(
-    req: AuthRequest,
+/// Request body for replaying a logged AI request
+#[derive(serde::Deserialize)]
+struct AiReplayRequest {
+    /// Id of the request log entry.
+    id: String,
+    /// Model to send the request to, if not the one it was logged with.
+    #[serde(default)]
+    model: Option<String>,
+}
+
+/// A logged AI request, alongside the completion it gets now
+#[derive(Serialize)]
+struct AiReplayResponse {
+    original: ai_log::LoggedRequest,
+    completion: ai::ChatCompletionResponse,
+}
+
+/// Handler for POST /api/admin/ai/replay
+async fn admin_ai_replay_handler(
+    req: AiReplayRequest,
+    auth: Credentials,
     state: ServerState,
 ) -> Result<impl Reply, Rejection> {
     let auth_manager = state
         .auth_manager
         .as_ref()
-        .ok_or_else(|| warp::reject::custom(CustomReject(anyhow::anyhow!("Auth not enabled"))))?;
+        .ok_or_else(|| ApiError::NotFound("Auth not enabled".into()))?;
 
-    let user = auth_manager
-        .register(&req.username, &req.password, req.ai_enabled, req.is_admin)
-        .map_err(|e| warp::reject::custom(CustomReject(e)))?;
+    check_admin_access(auth, auth_manager).await?;
 
-    Ok(warp::reply::json(&AuthResponse {
-        username: user.username,
-        created_at: user.created_at,
-        ai_enabled: user.ai_enabled,
-        is_admin: user.is_admin,
+    let ai_manager = state
+        .ai_manager
+        .as_ref()
+        .ok_or_else(|| ApiError::NotFound("AI features not enabled".into()))?;
+    let request_log = ai_manager
+        .request_log()
+        .ok_or_else(|| ApiError::NotFound("AI request logging not enabled".into()))?;
+
+    let id = req.id;
+    let original = blocking(request_log, move |request_log| request_log.find(&id))
+        .await?
+        .ok_or_else(|| ApiError::NotFound("Logged AI request not found".into()))?;
+    let Some(messages) = original.replay_messages() else {
+        return Err(ApiError::BadRequest(
+            "Message content was not logged for this request".into(),
+        )
+        .into());
+    };
+
+    let model = req.model.unwrap_or_else(|| original.model.clone());
+    let completion = ai_manager
+        .chat_completion(&model, messages, original.max_tokens, original.temperature)
+        .await;
+    metrics::ai_request(&model, completion.is_ok());
+    let mut completion = completion.map_err(|e| warp::reject::custom(CustomReject(e)))?;
+    ai_manager.redact(&mut completion);
+
+    Ok(warp::reply::json(&AiReplayResponse {
+        original,
+        completion,
     }))
 }
 
-/// Handler for POST /api/auth/login
-async fn login_handler(
-    req: AuthRequest,
+/// Handler for GET /api/admin/languages/stats
+async fn admin_language_stats_handler(
+    auth: Credentials,
     state: ServerState,
 ) -> Result<impl Reply, Rejection> {
     let auth_manager = state
         .auth_manager
         .as_ref()
-        .ok_or_else(|| warp::reject::custom(CustomReject(anyhow::anyhow!("Auth not enabled"))))?;
+        .ok_or_else(|| ApiError::NotFound("Auth not enabled".into()))?;
 
-    let user = auth_manager
-        .login(&req.username, &req.password)
-        .map_err(|e| warp::reject::custom(CustomReject(e)))?;
+    // Check admin access
+    check_admin_access(auth, auth_manager).await?;
 
-    Ok(warp::reply::json(&AuthResponse {
-        username: user.username,
-        created_at: user.created_at,
-        ai_enabled: user.ai_enabled,
-        is_admin: user.is_admin,
-    }))
-}
+    let mut documents = BTreeMap::new();
+    for entry in state.documents.iter() {
+        let language = entry.rustpad.language().filter(|l| !l.is_empty());
+        let language = language.unwrap_or_else(|| UNKNOWN_LANGUAGE.to_string());
+        *documents.entry(language).or_insert(0) += 1;
+    }
 
-/// Cleanup task for expired frozen documents
-async fn freeze_cleaner(freeze_manager: Arc<FreezeManager>) {
-    loop {
-        time::sleep(HOUR * 6).await; // Run every 6 hours
-        match freeze_manager.cleanup_expired() {
-            Ok(count) if count > 0 => info!("Cleaned up {} expired frozen documents", count),
-            Err(e) => error!("Error during freeze cleanup: {}", e),
-            _ => {}
+    let frozen_documents = match &state.freeze_manager {
+        Some(freeze_manager) => {
+            let mut counts = BTreeMap::new();
+            let frozen =
+                blocking(freeze_manager, |freeze_manager| freeze_manager.language_counts()).await?;
+            for (language, count) in frozen {
+                let language = if language.is_empty() {
+                    UNKNOWN_LANGUAGE.to_string()
+                } else {
+                    language
+                };
+                *counts.entry(language).or_insert(0) += count;
+            }
+            Some(counts)
         }
-    }
-}
+        None => None,
+    };
 
-/// Request body for AI chat
-#[derive(serde::Deserialize)]
-struct AiChatRequest {
-    model: String,
-    messages: Vec<ai::ChatMessage>,
-    #[serde(default)]
-    max_tokens: Option<u32>,
-    #[serde(default)]
-    temperature: Option<f32>,
+    Ok(warp::reply::json(&LanguageStats {
+        documents,
+        frozen_documents,
+    }))
 }
 
-/// Handler for GET /api/ai/models
-async fn ai_models_handler(state: ServerState) -> Result<impl Reply, Rejection> {
-    let ai_manager = state
-        .ai_manager
+/// Handler for GET /api/admin/cleaner/status
+async fn admin_cleaner_status_handler(
+    auth: Credentials,
+    state: ServerState,
+) -> Result<impl Reply, Rejection> {
+    let auth_manager = state
+        .auth_manager
         .as_ref()
-        .ok_or_else(|| warp::reject::custom(CustomReject(anyhow::anyhow!("AI features not enabled"))))?;
+        .ok_or_else(|| ApiError::NotFound("Auth not enabled".into()))?;
 
-    if !ai_manager.is_enabled() {
-        return Err(warp::reject::custom(CustomReject(anyhow::anyhow!(
-            "AI features not configured"
-        ))));
-    }
+    // Check admin access
+    check_admin_access(auth, auth_manager).await?;
 
-    // Fetch models dynamically from OpenRouter API
-    // Falls back to static list if API call fails
-    let models = ai_manager
-        .get_available_models_async()
-        .await
-        .unwrap_or_else(|e| {
-            log::warn!("Failed to fetch models dynamically, using fallback: {}", e);
-            ai_manager.get_available_models()
-        });
-    
-    Ok(warp::reply::json(&models))
+    let eligible = expired_documents(&state)
+        .into_iter()
+        .map(|(id, idle)| EligibleDocument {
+            id,
+            idle_secs: idle.as_secs(),
+        })
+        .collect();
+
+    Ok(warp::reply::json(&CleanerStatus {
+        last_run: state.cleaner.last_run.lock().map(Into::into),
+        next_run: (*state.cleaner.next_run.lock()).into(),
+        expiry_secs: state.cleaner.expiry.as_secs(),
+        eligible,
+    }))
 }
 
-/// Handler for POST /api/ai/chat
-async fn ai_chat_handler(
-    req: AiChatRequest,
-    auth: Option<String>,
+/// Handler for GET /api/admin/cleanup/preview
+async fn admin_cleanup_preview_handler(
+    auth: Credentials,
     state: ServerState,
 ) -> Result<impl Reply, Rejection> {
-    let ai_manager = state
-        .ai_manager
-        .as_ref()
-        .ok_or_else(|| warp::reject::custom(CustomReject(anyhow::anyhow!("AI features not enabled"))))?;
-
     let auth_manager = state
         .auth_manager
         .as_ref()
-        .ok_or_else(|| warp::reject::custom(CustomReject(anyhow::anyhow!("Auth not enabled"))))?;
+        .ok_or_else(|| ApiError::NotFound("Auth not enabled".into()))?;
 
-    // Extract and validate credentials
-    let (username, password) = extract_basic_auth(auth)?;
-    let user = auth_manager
-        .login(&username, &password)
-        .map_err(|e| warp::reject::custom(CustomReject(e)))?;
-
-    // Check if user has AI access
-    if !user.ai_enabled {
-        return Err(warp::reject::custom(CustomReject(anyhow::anyhow!(
-            "AI features not enabled for this user"
-        ))));
-    }
-
-    // Make the API call
-    let response = ai_manager
-        .chat_completion(&req.model, req.messages, req.max_tokens, req.temperature)
-        .await
-        .map_err(|e| warp::reject::custom(CustomReject(e)))?;
+    // Check admin access
+    check_admin_access(auth, auth_manager).await?;
 
-    Ok(warp::reply::json(&response))
-}
+    let documents = expired_documents(&state)
+        .into_iter()
+        .map(|(id, idle)| EligibleDocument {
+            id,
+            idle_secs: idle.as_secs(),
+        })
+        .collect();
+    let frozen = match &state.freeze_manager {
+        Some(freeze_manager) => blocking(freeze_manager, |freeze_manager| {
+            freeze_manager.cleanup_expired(true)
+        })
+        .await?
+        .into_iter()
+        .map(|doc| ExpiredFrozenDocument {
+            username: doc.owner_token,
+            document_id: doc.document_id,
+            expires_at: doc.expires_at,
+            file_size: doc.file_size,
+        })
+        .collect(),
+        None => Vec::new(),
+    };
 
-/// Request body for storing artifacts
-#[derive(serde::Deserialize)]
-struct ArtifactStoreRequest {
-    document_id: String,
-    model: String,
-    prompt: String,
-    files: Vec<artifacts::ArtifactFile>,
+    Ok(warp::reply::json(&CleanupPreview { documents, frozen }))
 }
 
-/// Handler for GET /api/artifacts/list
-async fn artifacts_list_handler(
-    auth: Option<String>,
+/// Handler for POST /api/admin/cleaner/run
+async fn admin_cleaner_run_handler(
+    auth: Credentials,
     state: ServerState,
 ) -> Result<impl Reply, Rejection> {
-    let artifact_manager = state
-        .artifact_manager
-        .as_ref()
-        .ok_or_else(|| warp::reject::custom(CustomReject(anyhow::anyhow!("Artifact storage not enabled"))))?;
-
     let auth_manager = state
         .auth_manager
         .as_ref()
-        .ok_or_else(|| warp::reject::custom(CustomReject(anyhow::anyhow!("Auth not enabled"))))?;
+        .ok_or_else(|| ApiError::NotFound("Auth not enabled".into()))?;
 
-    // Extract and validate credentials
-    let (username, password) = extract_basic_auth(auth)?;
-    auth_manager
-        .login(&username, &password)
-        .map_err(|e| warp::reject::custom(CustomReject(e)))?;
+    // Check admin access
+    check_admin_access(auth, auth_manager).await?;
 
-    let artifacts = artifact_manager
-        .list_artifacts(&username)
-        .map_err(|e| warp::reject::custom(CustomReject(e)))?;
+    let evicted = clean_documents(&state).await;
+    Ok(warp::reply::json(&CleanerRunResponse { evicted }))
+}
 
-    Ok(warp::reply::json(&artifacts))
+/// Result of evicting a document on demand
+#[derive(Serialize)]
+struct EvictDocumentResponse {
+    /// Whether the document was loaded, and so was evicted
+    existed: bool,
 }
 
-/// Handler for GET /api/artifacts/{id}
-async fn artifacts_get_handler(
-    artifact_id: String,
-    auth: Option<String>,
+/// Handler for DELETE /api/admin/documents/{id}
+///
+/// Unloads a document at once, disconnecting everyone from it. Its final
+/// state is persisted first, so clients that reconnect load it afresh.
+async fn admin_evict_document_handler(
+    id: String,
+    auth: Credentials,
     state: ServerState,
 ) -> Result<impl Reply, Rejection> {
-    let artifact_manager = state
-        .artifact_manager
-        .as_ref()
-        .ok_or_else(|| warp::reject::custom(CustomReject(anyhow::anyhow!("Artifact storage not enabled"))))?;
-
     let auth_manager = state
         .auth_manager
         .as_ref()
-        .ok_or_else(|| warp::reject::custom(CustomReject(anyhow::anyhow!("Auth not enabled"))))?;
+        .ok_or_else(|| ApiError::NotFound("Auth not enabled".into()))?;
 
-    // Extract and validate credentials
-    let (username, password) = extract_basic_auth(auth)?;
-    auth_manager
-        .login(&username, &password)
-        .map_err(|e| warp::reject::custom(CustomReject(e)))?;
-
-    let artifact = artifact_manager
-        .get_artifact(&username, &artifact_id)
-        .map_err(|e| warp::reject::custom(CustomReject(e)))?;
-
-    Ok(warp::reply::json(&artifact))
+    // Check admin access
+    check_admin_access(auth, auth_manager).await?;
+
+    let loaded = state
+        .documents
+        .get(&id)
+        .map(|entry| (Arc::clone(&entry.rustpad), entry.persistence));
+    let Some((rustpad, persistence)) = loaded else {
+        return Ok(warp::reply::json(&EvictDocumentResponse { existed: false }));
+    };
+    info!("evicting id = {} on admin request", id);
+    rustpad.stop_edits();
+    if let Some(sink) = state.sink(persistence) {
+        persist_if_dirty(&id, &rustpad, &sink).await;
+    }
+    // Dropping the entry kills the document, closing every connection to it.
+    state
+        .documents
+        .remove_if(&id, |_, entry| Arc::ptr_eq(&entry.rustpad, &rustpad));
+    Ok(warp::reply::json(&EvictDocumentResponse { existed: true }))
 }
 
-/// Handler for POST /api/artifacts/store
-async fn artifacts_store_handler(
-    req: ArtifactStoreRequest,
-    auth: Option<String>,
+/// Handler for PUT /api/admin/users/{username}/ai
+async fn admin_update_ai_handler(
+    username: String,
+    req: UpdateAiAccessRequest,
+    auth: Credentials,
     state: ServerState,
 ) -> Result<impl Reply, Rejection> {
-    let artifact_manager = state
-        .artifact_manager
-        .as_ref()
-        .ok_or_else(|| warp::reject::custom(CustomReject(anyhow::anyhow!("Artifact storage not enabled"))))?;
-
     let auth_manager = state
         .auth_manager
         .as_ref()
-        .ok_or_else(|| warp::reject::custom(CustomReject(anyhow::anyhow!("Auth not enabled"))))?;
+        .ok_or_else(|| ApiError::NotFound("Auth not enabled".into()))?;
 
-    // Extract and validate credentials
-    let (username, password) = extract_basic_auth(auth)?;
-    auth_manager
-        .login(&username, &password)
-        .map_err(|e| warp::reject::custom(CustomReject(e)))?;
+    // Check admin access
+    check_admin_access(auth, auth_manager).await?;
 
-    let metadata = artifact_manager
-        .store_artifact(&username, &req.document_id, &req.model, &req.prompt, req.files)
-        .map_err(|e| warp::reject::custom(CustomReject(e)))?;
+    blocking(auth_manager, move |auth_manager| {
+        auth_manager.update_ai_access(&username, req.ai_enabled)
+    })
+    .await?;
 
-    Ok(warp::reply::json(&metadata))
+    Ok(warp::reply::with_status(
+        "AI access updated",
+        warp::http::StatusCode::OK,
+    ))
 }
 
-/// Handler for DELETE /api/artifacts/{id}
-async fn artifacts_delete_handler(
-    artifact_id: String,
-    auth: Option<String>,
+/// Handler for PUT /api/admin/users/{username}/ai-rate-limit
+///
+/// A `null` body restores the server's default limits for the user.
+async fn admin_update_rate_limit_handler(
+    username: String,
+    limit: Option<ai::RateLimit>,
+    auth: Credentials,
     state: ServerState,
 ) -> Result<impl Reply, Rejection> {
-    let artifact_manager = state
-        .artifact_manager
-        .as_ref()
-        .ok_or_else(|| warp::reject::custom(CustomReject(anyhow::anyhow!("Artifact storage not enabled"))))?;
-
     let auth_manager = state
         .auth_manager
         .as_ref()
-        .ok_or_else(|| warp::reject::custom(CustomReject(anyhow::anyhow!("Auth not enabled"))))?;
+        .ok_or_else(|| ApiError::NotFound("Auth not enabled".into()))?;
 
-    // Extract and validate credentials
-    let (username, password) = extract_basic_auth(auth)?;
-    auth_manager
-        .login(&username, &password)
-        .map_err(|e| warp::reject::custom(CustomReject(e)))?;
+    // Check admin access
+    check_admin_access(auth, auth_manager).await?;
 
-    artifact_manager
-        .delete_artifact(&username, &artifact_id)
-        .map_err(|e| warp::reject::custom(CustomReject(e)))?;
+    blocking(auth_manager, move |auth_manager| {
+        auth_manager.update_ai_rate_limit(&username, limit)
+    })
+    .await?;
 
     Ok(warp::reply::with_status(
-        "Artifact deleted",
+        "AI rate limit updated",
         warp::http::StatusCode::OK,
     ))
 }
 
-/// User info for admin panel (without password hash)
+/// A user's AI token usage for the current month
 #[derive(Serialize)]
-struct AdminUserInfo {
+struct TokenUsageInfo {
     username: String,
-    created_at: String,
-    ai_enabled: bool,
-    is_admin: bool,
-}
-
-/// Request to update AI access
-#[derive(serde::Deserialize)]
-struct UpdateAiAccessRequest {
-    ai_enabled: bool,
+    /// Month being counted, as `YYYY-MM`
+    period: String,
+    tokens_used: u64,
+    /// Monthly limit, if usage is limited
+    monthly_limit: Option<u32>,
+    /// Tokens left this month, if usage is limited
+    remaining: Option<u32>,
 }
 
-/// Helper function to check admin access
-fn check_admin_access(auth: Option<String>, auth_manager: &AuthManager) -> Result<(), Rejection> {
-    let (username, password) = extract_basic_auth(auth)?;
-    let user = auth_manager
-        .login(&username, &password)
-        .map_err(|e| warp::reject::custom(CustomReject(e)))?;
-
-    if !user.is_admin {
-        return Err(warp::reject::custom(CustomReject(anyhow::anyhow!(
-            "Admin access required"
-        ))));
-    }
-
-    Ok(())
-}
-
-/// Handler for GET /api/admin/users
-async fn admin_users_handler(
-    auth: Option<String>,
+/// Handler for GET /api/admin/users/{username}/usage
+async fn admin_get_usage_handler(
+    username: String,
+    auth: Credentials,
     state: ServerState,
 ) -> Result<impl Reply, Rejection> {
     let auth_manager = state
         .auth_manager
         .as_ref()
-        .ok_or_else(|| warp::reject::custom(CustomReject(anyhow::anyhow!("Auth not enabled"))))?;
+        .ok_or_else(|| ApiError::NotFound("Auth not enabled".into()))?;
 
     // Check admin access
-    check_admin_access(auth, auth_manager)?;
-
-    let users = auth_manager
-        .list_users()
-        .map_err(|e| warp::reject::custom(CustomReject(e)))?;
-
-    // Convert to admin user info (remove password hash)
-    let admin_users: Vec<AdminUserInfo> = users
-        .into_iter()
-        .map(|u| AdminUserInfo {
-            username: u.username,
-            created_at: u.created_at,
-            ai_enabled: u.ai_enabled,
-            is_admin: u.is_admin,
-        })
-        .collect();
-
-    Ok(warp::reply::json(&admin_users))
+    check_admin_access(auth, auth_manager).await?;
+
+    let name = username.clone();
+    let tokens_used =
+        blocking(auth_manager, move |auth_manager| auth_manager.token_usage(&name)).await?;
+
+    Ok(warp::reply::json(&TokenUsageInfo {
+        period: auth::current_usage_period(),
+        tokens_used,
+        monthly_limit: auth_manager.monthly_token_limit(),
+        remaining: auth_manager
+            .monthly_token_limit()
+            .map(|limit| u64::from(limit).saturating_sub(tokens_used) as u32),
+        username,
+    }))
 }
 
-/// Handler for PUT /api/admin/users/{username}/ai
-async fn admin_update_ai_handler(
+/// Handler for DELETE /api/admin/users/{username}/usage
+async fn admin_reset_usage_handler(
     username: String,
-    req: UpdateAiAccessRequest,
-    auth: Option<String>,
+    auth: Credentials,
     state: ServerState,
 ) -> Result<impl Reply, Rejection> {
     let auth_manager = state
         .auth_manager
         .as_ref()
-        .ok_or_else(|| warp::reject::custom(CustomReject(anyhow::anyhow!("Auth not enabled"))))?;
+        .ok_or_else(|| ApiError::NotFound("Auth not enabled".into()))?;
 
     // Check admin access
-    check_admin_access(auth, auth_manager)?;
+    check_admin_access(auth, auth_manager).await?;
 
-    auth_manager
-        .update_ai_access(&username, req.ai_enabled)
-        .map_err(|e| warp::reject::custom(CustomReject(e)))?;
+    blocking(auth_manager, move |auth_manager| auth_manager.reset_usage(&username)).await?;
 
     Ok(warp::reply::with_status(
-        "AI access updated",
+        "AI token usage reset",
         warp::http::StatusCode::OK,
     ))
 }
@@ -953,20 +5491,18 @@ async fn admin_update_ai_handler(
 /// Handler for DELETE /api/admin/users/{username}
 async fn admin_delete_user_handler(
     username: String,
-    auth: Option<String>,
+    auth: Credentials,
     state: ServerState,
 ) -> Result<impl Reply, Rejection> {
     let auth_manager = state
         .auth_manager
         .as_ref()
-        .ok_or_else(|| warp::reject::custom(CustomReject(anyhow::anyhow!("Auth not enabled"))))?;
+        .ok_or_else(|| ApiError::NotFound("Auth not enabled".into()))?;
 
     // Check admin access
-    check_admin_access(auth, auth_manager)?;
+    check_admin_access(auth, auth_manager).await?;
 
-    auth_manager
-        .delete_user(&username)
-        .map_err(|e| warp::reject::custom(CustomReject(e)))?;
+    blocking(auth_manager, move |auth_manager| auth_manager.delete_user(&username)).await?;
 
     Ok(warp::reply::with_status(
         "User deleted",
@@ -990,16 +5526,16 @@ struct UpdateApiKeyRequest {
 
 /// Handler for GET /api/admin/settings
 async fn admin_get_settings_handler(
-    auth: Option<String>,
+    auth: Credentials,
     state: ServerState,
 ) -> Result<impl Reply, Rejection> {
     let auth_manager = state
         .auth_manager
         .as_ref()
-        .ok_or_else(|| warp::reject::custom(CustomReject(anyhow::anyhow!("Auth not enabled"))))?;
+        .ok_or_else(|| ApiError::NotFound("Auth not enabled".into()))?;
 
     // Check admin access
-    check_admin_access(auth, auth_manager)?;
+    check_admin_access(auth, auth_manager).await?;
 
     let ai_manager = state.ai_manager.as_ref();
     let (ai_enabled, api_key_configured, api_key_preview) = if let Some(ai) = ai_manager {
@@ -1025,21 +5561,21 @@ async fn admin_get_settings_handler(
 /// Handler for PUT /api/admin/settings/api-key
 async fn admin_update_api_key_handler(
     req: UpdateApiKeyRequest,
-    auth: Option<String>,
+    auth: Credentials,
     state: ServerState,
 ) -> Result<impl Reply, Rejection> {
     let auth_manager = state
         .auth_manager
         .as_ref()
-        .ok_or_else(|| warp::reject::custom(CustomReject(anyhow::anyhow!("Auth not enabled"))))?;
+        .ok_or_else(|| ApiError::NotFound("Auth not enabled".into()))?;
 
     // Check admin access
-    check_admin_access(auth, auth_manager)?;
+    check_admin_access(auth, auth_manager).await?;
 
     let ai_manager = state
         .ai_manager
         .as_ref()
-        .ok_or_else(|| warp::reject::custom(CustomReject(anyhow::anyhow!("AI not enabled"))))?;
+        .ok_or_else(|| ApiError::NotFound("AI not enabled".into()))?;
 
     ai_manager
         .update_api_key(&req.api_key)
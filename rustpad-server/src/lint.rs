@@ -0,0 +1,200 @@
+//! Optional server-side linting of document contents.
+
+use std::collections::HashMap;
+use std::process::Stdio;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use log::info;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::process::Command;
+
+/// Maximum number of bytes read from a linter's output.
+const MAX_OUTPUT_BYTES: u64 = 1024 * 1024;
+
+/// Maximum number of diagnostics reported for a single run.
+const MAX_DIAGNOSTICS: usize = 200;
+
+/// Configuration for document linting
+#[derive(Debug, Clone)]
+pub struct LintConfig {
+    /// Whether linting is enabled
+    pub enabled: bool,
+    /// Linter command line for each language, e.g. `python` => `ruff check -`
+    pub commands: HashMap<String, Vec<String>>,
+    /// How long a linter may run before it is killed
+    pub timeout: Duration,
+    /// Documents larger than this many bytes are not linted
+    pub max_bytes: usize,
+    /// How long a document must be idle before it is linted
+    pub debounce: Duration,
+}
+
+impl Default for LintConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            commands: HashMap::new(),
+            timeout: Duration::from_secs(5),
+            max_bytes: 256 * 1024, // 256 KiB
+            debounce: Duration::from_secs(1),
+        }
+    }
+}
+
+impl LintConfig {
+    /// Create config from environment variables
+    ///
+    /// `LINT_COMMANDS` holds `language=command` pairs separated by `;`, with
+    /// the command split on whitespace and the document given on stdin.
+    pub fn from_env() -> Self {
+        let enabled = std::env::var("ENABLE_LINTER")
+            .map(|s| s == "true")
+            .unwrap_or(false);
+
+        let commands = std::env::var("LINT_COMMANDS")
+            .map(|s| parse_commands(&s))
+            .unwrap_or_default();
+
+        let timeout_secs: u64 = std::env::var("LINT_TIMEOUT_SECS")
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(5);
+
+        let max_bytes = std::env::var("LINT_MAX_BYTES")
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(256 * 1024);
+
+        let debounce_ms: u64 = std::env::var("LINT_DEBOUNCE_MS")
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(1000);
+
+        Self {
+            enabled: enabled && !commands.is_empty(),
+            commands,
+            timeout: Duration::from_secs(timeout_secs),
+            max_bytes,
+            debounce: Duration::from_millis(debounce_ms),
+        }
+    }
+}
+
+/// Parse `language=command` pairs separated by `;`.
+fn parse_commands(s: &str) -> HashMap<String, Vec<String>> {
+    s.split(';')
+        .filter_map(|entry| {
+            let (language, command) = entry.split_once('=')?;
+            let command: Vec<String> = command.split_whitespace().map(String::from).collect();
+            (!command.is_empty()).then(|| (language.trim().to_string(), command))
+        })
+        .collect()
+}
+
+/// A single problem reported by a linter
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Diagnostic {
+    /// Line number, starting from 1
+    pub line: u32,
+    /// Column number, starting from 1
+    pub column: u32,
+    /// Description of the problem
+    pub message: String,
+}
+
+/// Runs configured linters against document contents
+#[derive(Debug)]
+pub struct Linter {
+    config: LintConfig,
+}
+
+impl Linter {
+    /// Create a new linter
+    pub fn new(config: LintConfig) -> Self {
+        if config.enabled {
+            info!(
+                "Linting enabled for languages: {:?}",
+                config.commands.keys().collect::<Vec<_>>()
+            );
+        }
+        Self { config }
+    }
+
+    /// How long a document must be idle before it is linted
+    pub fn debounce(&self) -> Duration {
+        self.config.debounce
+    }
+
+    /// Lint a document, returning `None` if it is not eligible for linting.
+    ///
+    /// The linter runs without a shell and with an empty environment apart
+    /// from `PATH`, and is killed if it exceeds the configured timeout.
+    pub async fn lint(&self, language: &str, text: &str) -> Result<Option<Vec<Diagnostic>>> {
+        let command = match self.config.commands.get(language) {
+            Some(command) if text.len() <= self.config.max_bytes => command,
+            _ => return Ok(None),
+        };
+
+        let mut child = Command::new(&command[0])
+            .args(&command[1..])
+            .env_clear()
+            .envs(std::env::var_os("PATH").map(|path| ("PATH", path)))
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .kill_on_drop(true)
+            .spawn()
+            .with_context(|| format!("Failed to start linter {}", command[0]))?;
+
+        let mut stdin = child.stdin.take().context("Linter stdin unavailable")?;
+        let stdout = child.stdout.take().context("Linter stdout unavailable")?;
+
+        let run = async {
+            let write = async {
+                stdin.write_all(text.as_bytes()).await?;
+                drop(stdin);
+                Ok::<_, std::io::Error>(())
+            };
+            let read = async {
+                let mut output = String::new();
+                stdout.take(MAX_OUTPUT_BYTES).read_to_string(&mut output).await?;
+                Ok::<_, std::io::Error>(output)
+            };
+            let (_, output) = tokio::try_join!(write, read)?;
+            child.wait().await?;
+            Ok::<_, std::io::Error>(output)
+        };
+
+        let output = tokio::time::timeout(self.config.timeout, run)
+            .await
+            .context("Linter timed out")?
+            .context("Failed to run linter")?;
+
+        Ok(Some(parse_output(&output)))
+    }
+}
+
+/// Parse linter output of the common `file:line:column: message` form.
+///
+/// Lines that don't contain a line and column number are ignored.
+fn parse_output(output: &str) -> Vec<Diagnostic> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let parts: Vec<&str> = line.split(':').collect();
+            (0..parts.len().saturating_sub(2)).find_map(|i| {
+                let line = parts[i].trim().parse().ok()?;
+                let column = parts[i + 1].trim().parse().ok()?;
+                let message = parts[i + 2..].join(":").trim().to_string();
+                Some(Diagnostic {
+                    line,
+                    column,
+                    message,
+                })
+            })
+        })
+        .take(MAX_DIAGNOSTICS)
+        .collect()
+}
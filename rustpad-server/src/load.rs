@@ -0,0 +1,286 @@
+//! Tracking of server load, used to tell clients how long to back off.
+
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::bail;
+use parking_lot::Mutex;
+use rand::Rng;
+use tokio::sync::{Notify, Semaphore};
+use tokio::time::Instant;
+
+/// Backoff suggested to clients when the server is idle.
+const BASE_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Backoff suggested to clients while the server is shutting down.
+const SHUTDOWN_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Close code sent when a document is unloaded from memory.
+pub const CLOSE_GOING_AWAY: u16 = 1001;
+
+/// Close code sent when the server is restarting.
+pub const CLOSE_SERVICE_RESTART: u16 = 1012;
+
+/// What to do when a user opens more connections than they are allowed.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum UserLimitPolicy {
+    /// Refuse the new connection.
+    #[default]
+    RejectNewest,
+    /// Close the user's oldest connection to make room for the new one.
+    EvictOldest,
+}
+
+impl FromStr for UserLimitPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reject" => Ok(Self::RejectNewest),
+            "evict-oldest" => Ok(Self::EvictOldest),
+            _ => bail!("unknown user limit policy: {}", s),
+        }
+    }
+}
+
+/// How fast connections may join any one document.
+#[derive(Clone, Copy, Debug)]
+pub struct JoinRate {
+    /// Joins allowed per second, sustained.
+    pub per_second: f64,
+    /// Joins allowed at once before the rate applies.
+    pub burst: u32,
+    /// Longest a join is held back to fit the rate before it is refused.
+    pub max_queue: Duration,
+}
+
+impl JoinRate {
+    /// Admit a join to a document, returning how long it must wait first, or
+    /// if that is too long, how long until a join would be admitted at once.
+    ///
+    /// `schedule` is the document's own state: when its allowance would be
+    /// fully spent, as in the generic cell rate algorithm. Admitted joins
+    /// reserve their place, so queued joins are spaced out at the rate.
+    pub fn admit(&self, schedule: &mut Option<Instant>, now: Instant) -> Result<Duration, Duration> {
+        let interval = Duration::from_secs_f64(1.0 / self.per_second);
+        let tolerance = interval * self.burst.saturating_sub(1);
+        let spent = schedule.map_or(now, |spent| spent.max(now));
+        let wait = spent.duration_since(now).saturating_sub(tolerance);
+        if wait > self.max_queue {
+            return Err(wait);
+        }
+        *schedule = Some(spent + interval);
+        Ok(wait)
+    }
+}
+
+/// Bounds how many filesystem-heavy operations, like freezing documents and
+/// storing artifacts, run at once, so a burst can't saturate slow storage.
+#[derive(Clone, Debug)]
+pub struct IoLimiter {
+    permits: Arc<Semaphore>,
+    limit: usize,
+}
+
+impl IoLimiter {
+    /// Allow up to `limit` operations at once, and at least one.
+    pub fn new(limit: usize) -> Self {
+        let limit = limit.max(1);
+        Self {
+            permits: Arc::new(Semaphore::new(limit)),
+            limit,
+        }
+    }
+
+    /// Number of operations running right now.
+    pub fn in_flight(&self) -> usize {
+        self.limit - self.permits.available_permits()
+    }
+
+    /// Run a synchronous operation on the blocking thread pool, once fewer
+    /// than the limit are in flight. Waiting callers are served in order.
+    pub async fn run<T: Send + 'static>(
+        &self,
+        operation: impl FnOnce() -> anyhow::Result<T> + Send + 'static,
+    ) -> anyhow::Result<T> {
+        let _permit = self.permits.acquire().await?;
+        tokio::task::spawn_blocking(operation).await?
+    }
+}
+
+/// A connection held by an authenticated user.
+#[derive(Debug)]
+struct UserSlot {
+    id: u64,
+    evicted: Arc<Notify>,
+}
+
+/// Counts live WebSocket connections across all documents.
+#[derive(Debug, Default)]
+pub struct ServerLoad {
+    connections: AtomicUsize,
+    max_connections: Option<usize>,
+    /// Set once the server begins shutting down.
+    shutting_down: AtomicBool,
+    /// Open connections of each authenticated user, oldest first.
+    users: Mutex<HashMap<String, Vec<UserSlot>>>,
+    max_user_connections: Option<usize>,
+    user_limit_policy: UserLimitPolicy,
+    next_slot: AtomicU64,
+}
+
+/// Holds a connection slot for as long as the connection is open.
+pub struct ConnectionGuard(Arc<ServerLoad>);
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.0.connections.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Holds one of a user's connection slots for as long as the connection is open.
+pub struct UserGuard {
+    load: Arc<ServerLoad>,
+    username: String,
+    id: u64,
+    evicted: Arc<Notify>,
+}
+
+impl UserGuard {
+    /// Resolves once this connection has been evicted by a newer one.
+    pub async fn evicted(&self) {
+        self.evicted.notified().await
+    }
+}
+
+impl Drop for UserGuard {
+    fn drop(&mut self) {
+        let mut users = self.load.users.lock();
+        if let Some(slots) = users.get_mut(&self.username) {
+            slots.retain(|slot| slot.id != self.id);
+            if slots.is_empty() {
+                users.remove(&self.username);
+            }
+        }
+    }
+}
+
+impl ServerLoad {
+    /// Construct a new load tracker with an optional connection limit.
+    pub fn new(max_connections: Option<usize>) -> Self {
+        Self {
+            max_connections,
+            ..Default::default()
+        }
+    }
+
+    /// Limit the number of simultaneous connections of each user.
+    pub fn with_user_limit(mut self, max: Option<usize>, policy: UserLimitPolicy) -> Self {
+        self.max_user_connections = max;
+        self.user_limit_policy = policy;
+        self
+    }
+
+    /// Returns the number of open connections.
+    pub fn connections(&self) -> usize {
+        self.connections.load(Ordering::SeqCst)
+    }
+
+    /// Returns the per-user connection limit, if any.
+    pub fn max_user_connections(&self) -> Option<usize> {
+        self.max_user_connections
+    }
+
+    /// Returns if the server is shutting down.
+    pub fn is_shutting_down(&self) -> bool {
+        self.shutting_down.load(Ordering::SeqCst)
+    }
+
+    /// Stop accepting new connections, ahead of the server exiting.
+    pub fn begin_shutdown(&self) {
+        self.shutting_down.store(true, Ordering::SeqCst);
+    }
+
+    /// Reserve a slot for a new connection, unless the server is at capacity.
+    pub fn try_connect(self: &Arc<Self>) -> Option<ConnectionGuard> {
+        if self.is_shutting_down() {
+            return None;
+        }
+        let max = self.max_connections.unwrap_or(usize::MAX);
+        self.connections
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| {
+                (n < max).then_some(n + 1)
+            })
+            .ok()?;
+        Some(ConnectionGuard(Arc::clone(self)))
+    }
+
+    /// Reserve a slot for a connection by an authenticated user.
+    ///
+    /// When the user is already at their limit, this either refuses the
+    /// connection or evicts their oldest ones, depending on the policy.
+    pub fn try_connect_user(self: &Arc<Self>, username: &str) -> Option<UserGuard> {
+        let mut users = self.users.lock();
+        let count = users.get(username).map_or(0, Vec::len);
+        if let Some(max) = self.max_user_connections {
+            if count >= max {
+                if max == 0 || self.user_limit_policy == UserLimitPolicy::RejectNewest {
+                    return None;
+                }
+                let slots = users.get_mut(username)?;
+                for slot in slots.drain(..=count - max) {
+                    slot.evicted.notify_one();
+                }
+            }
+        }
+
+        let id = self.next_slot.fetch_add(1, Ordering::Relaxed);
+        let evicted = Arc::new(Notify::new());
+        users.entry(username.to_string()).or_default().push(UserSlot {
+            id,
+            evicted: Arc::clone(&evicted),
+        });
+        Some(UserGuard {
+            load: Arc::clone(self),
+            username: username.to_string(),
+            id,
+            evicted,
+        })
+    }
+
+    /// Suggested delay before a client reconnects, scaled by current load.
+    ///
+    /// Jitter is added so that clients disconnected at the same moment do
+    /// not all retry at once.
+    pub fn backoff(&self) -> Duration {
+        if self.is_shutting_down() {
+            return SHUTDOWN_BACKOFF;
+        }
+        let utilization = match self.max_connections {
+            Some(max) if max > 0 => (self.connections() as f64 / max as f64).min(1.0),
+            _ => 0.0,
+        };
+        let jitter = rand::thread_rng().gen_range(0.0..0.5);
+        BASE_BACKOFF.mul_f64((1.0 + 9.0 * utilization) * (1.0 + jitter))
+    }
+
+    /// Close code and reason to send when a connection is closed by the server.
+    ///
+    /// The reason is a small JSON object, `{"reason":...,"retry_ms":...}`,
+    /// which fits within the 123-byte limit on WebSocket close reasons.
+    pub fn close_frame(&self, reason: &str) -> (u16, String) {
+        let code = if self.is_shutting_down() {
+            CLOSE_SERVICE_RESTART
+        } else {
+            CLOSE_GOING_AWAY
+        };
+        let reason = serde_json::json!({
+            "reason": reason,
+            "retry_ms": self.backoff().as_millis() as u64,
+        });
+        (code, reason.to_string())
+    }
+}
@@ -1,4 +1,4 @@
-use rustpad_server::{ai::{AiConfig, AiManager}, artifacts::{ArtifactConfig, ArtifactManager}, auth::{AuthConfig, AuthManager}, database::Database, freeze::{FreezeConfig, FreezeManager}, server, ServerConfig};
+use rustpad_server::{ai::{AiConfig, AiManager}, artifacts::{ArtifactConfig, ArtifactManager}, audit::{AuditConfig, AuditLog, MaxMindLookup}, auth::{AuthConfig, AuthManager}, backup::BackupConfig, database::{Database, DEFAULT_MAX_CONCURRENT_WRITES}, freeze::{Compression, FreezeConfig, FreezeManager}, lint::{LintConfig, Linter}, names::AnonymousNames, persistence::{FileStore, LoadFailurePolicy, PersistenceRoutes, PersistenceTarget}, server_with_shutdown, templates::LanguageTemplates, webhooks::{WebhookConfig, WebhookDispatcher}, Branding, JoinRate, ServerConfig};
 
 #[tokio::main]
 async fn main() {
@@ -50,24 +50,246 @@ async fn main() {
         None
     };
 
+    let lint_config = LintConfig::from_env();
+    let linter = if lint_config.enabled {
+        Some(std::sync::Arc::new(Linter::new(lint_config)))
+    } else {
+        None
+    };
+
+    let file_store = std::env::var("PERSIST_DIR")
+        .ok()
+        .map(|dir| FileStore::new(dir).expect("Unable to initialize PERSIST_DIR"));
+    // `DATABASE_URI` may name a SQLite or PostgreSQL database, and
+    // `SQLITE_URI` is still honored for existing deployments.
+    let database_uri = std::env::var("DATABASE_URI")
+        .or_else(|_| std::env::var("SQLITE_URI"))
+        .ok();
+
+    let persistence_routes: PersistenceRoutes = std::env::var("PERSISTENCE_ROUTES")
+        .map(|s| s.parse().expect("Unable to parse PERSISTENCE_ROUTES"))
+        .unwrap_or_default();
+    for target in persistence_routes.targets() {
+        match target {
+            PersistenceTarget::Database if database_uri.is_none() => {
+                panic!("PERSISTENCE_ROUTES uses db, but DATABASE_URI is not set")
+            }
+            PersistenceTarget::File if file_store.is_none() => {
+                panic!("PERSISTENCE_ROUTES uses file, but PERSIST_DIR is not set")
+            }
+            _ => {}
+        }
+    }
+
     let config = ServerConfig {
         expiry_days: std::env::var("EXPIRY_DAYS")
             .unwrap_or_else(|_| String::from("1"))
             .parse()
             .expect("Unable to parse EXPIRY_DAYS"),
-        database: match std::env::var("SQLITE_URI") {
-            Ok(uri) => Some(
+        expiry_warning: std::env::var("EXPIRY_WARNING_MINUTES")
+            .ok()
+            .map(|s| s.parse().expect("Unable to parse EXPIRY_WARNING_MINUTES"))
+            .filter(|&minutes| minutes > 0)
+            .map(|minutes: u64| std::time::Duration::from_secs(60 * minutes)),
+        drain_grace_period: std::env::var("DRAIN_GRACE_SECONDS")
+            .ok()
+            .map(|s| s.parse().expect("Unable to parse DRAIN_GRACE_SECONDS"))
+            .filter(|&seconds| seconds > 0)
+            .map(std::time::Duration::from_secs),
+        database: match database_uri {
+            Some(uri) => Some(
                 Database::new(&uri)
                     .await
-                    .expect("Unable to connect to SQLITE_URI"),
+                    .expect("Unable to connect to DATABASE_URI")
+                    .with_max_concurrent_writes(
+                        std::env::var("PERSIST_CONCURRENCY")
+                            .map(|s| s.parse().expect("Unable to parse PERSIST_CONCURRENCY"))
+                            .unwrap_or(DEFAULT_MAX_CONCURRENT_WRITES),
+                    )
+                    .with_compression(
+                        std::env::var("DATABASE_COMPRESSION")
+                            .ok()
+                            .filter(|s| !s.is_empty() && s != "none")
+                            .map(|s| {
+                                s.parse::<Compression>()
+                                    .expect("Unable to parse DATABASE_COMPRESSION")
+                            }),
+                    ),
             ),
-            Err(_) => None,
+            None => None,
         },
+        backup: BackupConfig::from_env(),
+        file_store,
+        persistence_routes,
         freeze_manager,
         auth_manager,
         ai_manager,
         artifact_manager,
+        linter,
+        max_revisions: std::env::var("MAX_REVISIONS")
+            .ok()
+            .map(|s| s.parse().expect("Unable to parse MAX_REVISIONS")),
+        undo_limit: std::env::var("UNDO_LIMIT")
+            .ok()
+            .map(|s| s.parse().expect("Unable to parse UNDO_LIMIT")),
+        max_line_length: std::env::var("MAX_LINE_LENGTH")
+            .ok()
+            .map(|s| s.parse().expect("Unable to parse MAX_LINE_LENGTH")),
+        max_document_bytes: std::env::var("MAX_DOCUMENT_BYTES")
+            .ok()
+            .map(|s| s.parse().expect("Unable to parse MAX_DOCUMENT_BYTES")),
+        reject_binary_content: std::env::var("REJECT_BINARY_CONTENT")
+            .map(|s| s == "true")
+            .unwrap_or(false),
+        cursor_grace: Some(std::time::Duration::from_secs(
+            std::env::var("CURSOR_GRACE_SECONDS")
+                .map(|s| s.parse().expect("Unable to parse CURSOR_GRACE_SECONDS"))
+                .unwrap_or(10),
+        ))
+        .filter(|grace| !grace.is_zero()),
+        coalesce_window: std::env::var("COALESCE_WINDOW_MS")
+            .ok()
+            .map(|s| s.parse().expect("Unable to parse COALESCE_WINDOW_MS"))
+            .filter(|&ms| ms > 0)
+            .map(std::time::Duration::from_millis),
+        persistence_status: std::env::var("PERSISTENCE_STATUS")
+            .map(|s| s == "true")
+            .unwrap_or(false),
+        normalize_unicode: std::env::var("NORMALIZE_UNICODE")
+            .map(|s| s == "true")
+            .unwrap_or(false),
+        compression_threshold: std::env::var("COMPRESSION_THRESHOLD")
+            .ok()
+            .map(|s| s.parse().expect("Unable to parse COMPRESSION_THRESHOLD")),
+        encrypted_documents: std::env::var("ENCRYPTED_DOCUMENTS")
+            .map(|s| s == "true")
+            .unwrap_or(false),
+        debug_headers: std::env::var("DEBUG_HEADERS")
+            .map(|s| s == "true")
+            .unwrap_or(false),
+        max_connections: std::env::var("MAX_CONNECTIONS")
+            .ok()
+            .map(|s| s.parse().expect("Unable to parse MAX_CONNECTIONS")),
+        max_documents_per_user: std::env::var("MAX_DOCUMENTS_PER_USER")
+            .ok()
+            .map(|s| s.parse().expect("Unable to parse MAX_DOCUMENTS_PER_USER")),
+        allowed_origins: std::env::var("ALLOWED_ORIGINS")
+            .ok()
+            .filter(|s| !s.is_empty() && s != "*")
+            .map(|s| s.split(',').map(|origin| origin.trim().to_string()).collect()),
+        cors_origins: std::env::var("CORS_ORIGINS")
+            .map(|s| {
+                s.split(',')
+                    .map(str::trim)
+                    .filter(|origin| !origin.is_empty())
+                    .map(String::from)
+                    .collect()
+            })
+            .unwrap_or_default(),
+        default_content: match std::env::var("DEFAULT_DOCUMENT_CONTENT_FILE") {
+            Ok(path) => Some(
+                std::fs::read_to_string(path)
+                    .expect("Unable to read DEFAULT_DOCUMENT_CONTENT_FILE"),
+            ),
+            Err(_) => std::env::var("DEFAULT_DOCUMENT_CONTENT").ok(),
+        }
+        .filter(|content| !content.is_empty()),
+        language_templates: std::env::var("LANGUAGE_TEMPLATES_DIR").ok().map(|dir| {
+            std::sync::Arc::new(
+                LanguageTemplates::from_dir(dir).expect("Unable to load LANGUAGE_TEMPLATES_DIR"),
+            )
+        }),
+        public_url: std::env::var("PUBLIC_URL").ok(),
+        anonymous_names: match std::env::var("ANONYMOUS_NAMES_FILE") {
+            Ok(path) => Some(std::sync::Arc::new(
+                AnonymousNames::from_file(path).expect("Unable to load ANONYMOUS_NAMES_FILE"),
+            )),
+            Err(_) => std::env::var("ANONYMOUS_NAMES")
+                .map(|s| s == "true")
+                .unwrap_or(false)
+                .then(|| std::sync::Arc::new(AnonymousNames::default())),
+        },
+        max_user_connections: std::env::var("MAX_USER_CONNECTIONS")
+            .ok()
+            .map(|s| s.parse().expect("Unable to parse MAX_USER_CONNECTIONS")),
+        user_limit_policy: std::env::var("USER_LIMIT_POLICY")
+            .map(|s| s.parse().expect("Unable to parse USER_LIMIT_POLICY"))
+            .unwrap_or_default(),
+        join_rate: std::env::var("JOIN_RATE_PER_SECOND")
+            .ok()
+            .map(|s| s.parse().expect("Unable to parse JOIN_RATE_PER_SECOND"))
+            .filter(|&per_second: &f64| per_second > 0.0)
+            .map(|per_second| JoinRate {
+                per_second,
+                burst: std::env::var("JOIN_BURST")
+                    .map(|s| s.parse().expect("Unable to parse JOIN_BURST"))
+                    .unwrap_or_else(|_| per_second.ceil() as u32),
+                max_queue: std::time::Duration::from_millis(
+                    std::env::var("JOIN_QUEUE_MS")
+                        .map(|s| s.parse().expect("Unable to parse JOIN_QUEUE_MS"))
+                        .unwrap_or(1000),
+                ),
+            }),
+        max_io_operations: std::env::var("MAX_IO_OPERATIONS")
+            .map(|s| s.parse().expect("Unable to parse MAX_IO_OPERATIONS"))
+            .unwrap_or(8),
+        load_failure_policy: std::env::var("LOAD_FAILURE_POLICY")
+            .map(|s| s.parse::<LoadFailurePolicy>().expect("Unable to parse LOAD_FAILURE_POLICY"))
+            .unwrap_or_default(),
+        request_timeout: std::time::Duration::from_secs(
+            std::env::var("REQUEST_TIMEOUT_SECS")
+                .map(|s| s.parse().expect("Unable to parse REQUEST_TIMEOUT_SECS"))
+                .unwrap_or(60),
+        ),
+        stats_interval: std::time::Duration::from_secs(
+            std::env::var("STATS_INTERVAL_SECS")
+                .map(|s| s.parse().expect("Unable to parse STATS_INTERVAL_SECS"))
+                .unwrap_or(60),
+        ),
+        stats_retention: std::time::Duration::from_secs(
+            3600 * std::env::var("STATS_RETENTION_HOURS")
+                .map(|s| s.parse::<u64>().expect("Unable to parse STATS_RETENTION_HOURS"))
+                .unwrap_or(24),
+        ),
+        audit_log: AuditConfig::from_env().map(|audit_config| {
+            let audit_log = AuditLog::new(audit_config);
+            let audit_log = match std::env::var("GEOIP_DATABASE") {
+                Ok(path) => audit_log.with_geo(
+                    MaxMindLookup::open(path).expect("Unable to load GEOIP_DATABASE"),
+                ),
+                Err(_) => audit_log,
+            };
+            std::sync::Arc::new(audit_log)
+        }),
+        webhooks: WebhookConfig::from_env().map(|webhook_config| {
+            std::sync::Arc::new(
+                WebhookDispatcher::new(webhook_config).expect("Unable to initialize webhooks"),
+            )
+        }),
+        branding: Branding {
+            instance_name: std::env::var("INSTANCE_NAME").ok().filter(|s| !s.is_empty()),
+            theme_color: std::env::var("THEME_COLOR").ok().filter(|s| !s.is_empty()),
+            default_language: std::env::var("DEFAULT_LANGUAGE").ok().filter(|s| !s.is_empty()),
+        },
     };
 
-    warp::serve(server(config)).run(([0, 0, 0, 0], port)).await;
+    let (filter, shutdown) = server_with_shutdown(config, shutdown_signal());
+    let (_, server) =
+        warp::serve(filter).bind_with_graceful_shutdown(([0, 0, 0, 0], port), shutdown);
+    server.await;
+}
+
+/// Resolves when the process is asked to stop, by Ctrl+C or SIGTERM.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut terminate = signal(SignalKind::terminate()).expect("Unable to listen for SIGTERM");
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = terminate.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await.ok();
 }
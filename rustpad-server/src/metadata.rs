@@ -0,0 +1,66 @@
+//! Structured metadata stored alongside a document's content.
+
+use std::collections::BTreeMap;
+
+use anyhow::{bail, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+/// Largest metadata object a document may hold, in bytes of JSON.
+pub const MAX_METADATA_SIZE: usize = 8 * 1024;
+
+/// Application-level information about a document, kept apart from its text.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DocumentMetadata {
+    /// User who created the document, if it was created while logged in.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created_by: Option<String>,
+    /// When the document was created, if known.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created_at: Option<DateTime<Utc>>,
+    /// Free-form labels for the document.
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// Arbitrary key-value pairs set by clients.
+    #[serde(default)]
+    pub custom: BTreeMap<String, String>,
+}
+
+/// The parts of a document's metadata that clients may change.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct MetadataUpdate {
+    /// Replaces the document's tags, if given.
+    pub tags: Option<Vec<String>>,
+    /// Replaces the document's key-value pairs, if given.
+    pub custom: Option<BTreeMap<String, String>>,
+}
+
+impl DocumentMetadata {
+    /// Returns whether nothing has been recorded, as for an older document.
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Apply a client's update, leaving the creation details untouched.
+    ///
+    /// Fails without changing anything if the result would be too large.
+    pub fn update(&mut self, update: MetadataUpdate) -> Result<()> {
+        let mut updated = self.clone();
+        if let Some(tags) = update.tags {
+            updated.tags = tags;
+        }
+        if let Some(custom) = update.custom {
+            updated.custom = custom;
+        }
+        let size = serde_json::to_vec(&updated)?.len();
+        if size > MAX_METADATA_SIZE {
+            bail!(
+                "metadata is {} bytes, over the limit of {}",
+                size,
+                MAX_METADATA_SIZE
+            );
+        }
+        *self = updated;
+        Ok(())
+    }
+}
@@ -0,0 +1,144 @@
+//! Prometheus metrics, collected when built with the `metrics` feature.
+//!
+//! The recording functions are always available, and do nothing without the
+//! feature, so call sites don't need their own `cfg` attributes.
+
+use std::time::Duration;
+
+#[cfg(feature = "metrics")]
+use std::sync::OnceLock;
+
+#[cfg(feature = "metrics")]
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, IntCounter, IntCounterVec, IntGauge, Opts, Registry,
+    TextEncoder,
+};
+
+/// Every metric the server exports, registered in a registry of its own.
+#[cfg(feature = "metrics")]
+struct Metrics {
+    registry: Registry,
+    connections: IntGauge,
+    operations: IntCounter,
+    ai_requests: IntCounterVec,
+    freezes: IntCounterVec,
+    persist_seconds: Histogram,
+}
+
+#[cfg(feature = "metrics")]
+impl Metrics {
+    fn new() -> prometheus::Result<Self> {
+        let registry = Registry::new_custom(Some("rustpad".into()), None)?;
+        let connections = IntGauge::new("connections", "Open WebSocket connections")?;
+        let operations = IntCounter::new("operations_total", "Edits applied to documents")?;
+        let ai_requests = IntCounterVec::new(
+            Opts::new("ai_requests_total", "AI chat requests, by model and outcome"),
+            &["model", "outcome"],
+        )?;
+        let freezes = IntCounterVec::new(
+            Opts::new("freezes_total", "Requests to freeze documents, by outcome"),
+            &["outcome"],
+        )?;
+        let persist_seconds = Histogram::with_opts(HistogramOpts::new(
+            "persist_seconds",
+            "Time taken to write a document to storage",
+        ))?;
+        registry.register(Box::new(connections.clone()))?;
+        registry.register(Box::new(operations.clone()))?;
+        registry.register(Box::new(ai_requests.clone()))?;
+        registry.register(Box::new(freezes.clone()))?;
+        registry.register(Box::new(persist_seconds.clone()))?;
+        Ok(Self {
+            registry,
+            connections,
+            operations,
+            ai_requests,
+            freezes,
+            persist_seconds,
+        })
+    }
+}
+
+#[cfg(feature = "metrics")]
+fn metrics() -> &'static Metrics {
+    static METRICS: OnceLock<Metrics> = OnceLock::new();
+    METRICS.get_or_init(|| Metrics::new().expect("failed to register metrics"))
+}
+
+/// Label for whether a request succeeded.
+#[cfg_attr(not(feature = "metrics"), allow(dead_code))]
+fn outcome(success: bool) -> &'static str {
+    if success {
+        "success"
+    } else {
+        "failure"
+    }
+}
+
+/// Counts an open WebSocket connection for as long as it is held.
+pub struct ConnectionGuard(());
+
+impl ConnectionGuard {
+    pub fn new() -> Self {
+        #[cfg(feature = "metrics")]
+        metrics().connections.inc();
+        Self(())
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        #[cfg(feature = "metrics")]
+        metrics().connections.dec();
+    }
+}
+
+/// Record an edit applied to a document.
+pub fn operation_applied() {
+    #[cfg(feature = "metrics")]
+    metrics().operations.inc();
+}
+
+/// Record an AI chat request to a model.
+pub fn ai_request(model: &str, success: bool) {
+    #[cfg(feature = "metrics")]
+    metrics()
+        .ai_requests
+        .with_label_values(&[model, outcome(success)])
+        .inc();
+    #[cfg(not(feature = "metrics"))]
+    let _ = (model, success);
+}
+
+/// Record a request to freeze a document.
+pub fn freeze(success: bool) {
+    #[cfg(feature = "metrics")]
+    metrics().freezes.with_label_values(&[outcome(success)]).inc();
+    #[cfg(not(feature = "metrics"))]
+    let _ = success;
+}
+
+/// Record how long a document took to persist.
+pub fn persisted(elapsed: Duration) {
+    #[cfg(feature = "metrics")]
+    metrics().persist_seconds.observe(elapsed.as_secs_f64());
+    #[cfg(not(feature = "metrics"))]
+    let _ = elapsed;
+}
+
+/// Render every metric in the Prometheus text format, or `None` if the server
+/// was built without metrics.
+pub fn render() -> Option<String> {
+    #[cfg(feature = "metrics")]
+    {
+        let mut buffer = Vec::new();
+        let families = metrics().registry.gather();
+        if let Err(e) = TextEncoder::new().encode(&families, &mut buffer) {
+            log::error!("failed to encode metrics: {}", e);
+            return None;
+        }
+        Some(String::from_utf8_lossy(&buffer).into_owned())
+    }
+    #[cfg(not(feature = "metrics"))]
+    None
+}
@@ -0,0 +1,64 @@
+//! Friendly display names for anonymous collaborators.
+
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use rand::seq::SliceRandom;
+
+/// Words used when no custom list is configured, one per line.
+const DEFAULT_WORDS: &str = include_str!("../resources/animals.txt");
+
+/// Generates names like "Anonymous Otter" from a list of words.
+#[derive(Debug, Clone)]
+pub struct AnonymousNames {
+    words: Vec<String>,
+}
+
+impl Default for AnonymousNames {
+    fn default() -> Self {
+        Self::from_lines(DEFAULT_WORDS).expect("default word list is not empty")
+    }
+}
+
+impl AnonymousNames {
+    /// Read a word list from a file, with one word per line.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self> {
+        let contents = std::fs::read_to_string(path.as_ref())
+            .with_context(|| format!("Failed to read word list {:?}", path.as_ref()))?;
+        Self::from_lines(&contents)
+    }
+
+    /// Parse a word list with one word per line, ignoring blank lines.
+    pub fn from_lines(contents: &str) -> Result<Self> {
+        let words: Vec<String> = contents
+            .lines()
+            .map(str::trim)
+            .filter(|word| !word.is_empty())
+            .map(String::from)
+            .collect();
+        if words.is_empty() {
+            bail!("Word list for anonymous names is empty");
+        }
+        Ok(Self { words })
+    }
+
+    /// Pick a random name that is not already `taken`, if possible.
+    ///
+    /// Once every word is in use, a numeric suffix keeps names distinct.
+    pub fn pick(&self, taken: impl Fn(&str) -> bool) -> String {
+        let mut rng = rand::thread_rng();
+        let mut words: Vec<&String> = self.words.iter().collect();
+        words.shuffle(&mut rng);
+        for word in &words {
+            let name = format!("Anonymous {}", word);
+            if !taken(&name) {
+                return name;
+            }
+        }
+        let word = words[0];
+        (2..)
+            .map(|n| format!("Anonymous {} {}", word, n))
+            .find(|name| !taken(name))
+            .expect("some suffix is free")
+    }
+}
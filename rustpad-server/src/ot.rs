@@ -1,6 +1,7 @@
 //! Helper methods for working with operational transformation.
 
-use operational_transform::{Operation, OperationSeq};
+use operational_transform::{OTError, Operation, OperationSeq};
+use unicode_normalization::{is_nfc, UnicodeNormalization};
 
 /// Return the new index of a position in the string.
 pub fn transform_index(operation: &OperationSeq, position: u32) -> u32 {
@@ -21,3 +22,59 @@ pub fn transform_index(operation: &OperationSeq, position: u32) -> u32 {
     }
     new_index as u32
 }
+
+/// Return an operation converting the text inserted by `operation` to NFC.
+///
+/// The result applies on top of `operation`'s target and only touches the
+/// inserted ranges. Returns `None` if everything inserted is already NFC.
+pub fn normalize_inserts(operation: &OperationSeq) -> Option<OperationSeq> {
+    let mut normalized = OperationSeq::default();
+    let mut changed = false;
+    for op in operation.ops() {
+        match op {
+            &Operation::Retain(n) => normalized.retain(n),
+            Operation::Insert(s) if !is_nfc(s) => {
+                normalized.delete(bytecount::num_chars(s.as_bytes()) as u64);
+                normalized.insert(&s.nfc().collect::<String>());
+                changed = true;
+            }
+            Operation::Insert(s) => normalized.retain(bytecount::num_chars(s.as_bytes()) as u64),
+            Operation::Delete(_) => {}
+        }
+    }
+    changed.then_some(normalized)
+}
+
+/// Return whether `operation` inserts null bytes or other control characters
+/// that break text tooling, such as terminal escape sequences.
+///
+/// Tabs and line breaks are ordinary text, and are allowed.
+pub fn inserts_binary(operation: &OperationSeq) -> bool {
+    operation.ops().iter().any(|op| match op {
+        Operation::Insert(s) => s
+            .chars()
+            .any(|c| c.is_control() && !matches!(c, '\t' | '\n' | '\r')),
+        _ => false,
+    })
+}
+
+/// Transform an operation made at some past revision, so that it applies after
+/// the `history` of operations applied since then.
+///
+/// Undo and redo use this to treat the inverse of a user's earlier edit as if
+/// it were a concurrent edit made right after it. Only that user's edit is
+/// reversed, so under these rules:
+///
+/// - Text that others inserted since is kept, even inside the undone range.
+/// - Text that the edit inserted and others have since deleted stays deleted.
+/// - Text that the edit deleted is restored where it was. If someone else has
+///   inserted at that same position, the restored text goes first.
+pub fn rebase<'a>(
+    mut operation: OperationSeq,
+    history: impl IntoIterator<Item = &'a OperationSeq>,
+) -> Result<OperationSeq, OTError> {
+    for applied in history {
+        operation = operation.transform(applied)?.0;
+    }
+    Ok(operation)
+}
@@ -0,0 +1,253 @@
+//! Routing of documents to the sinks that persist them.
+
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::acl::DocumentAcl;
+use crate::metadata::DocumentMetadata;
+use crate::database::{Database, PersistedDocument, CURRENT_FORMAT_VERSION};
+
+/// Where a document is persisted, fixed when the document is created.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PersistenceTarget {
+    /// Snapshots are written to the SQLite database.
+    #[serde(alias = "db")]
+    Database,
+    /// Snapshots are written as files in a directory.
+    File,
+    /// The document only lives in memory.
+    None,
+}
+
+impl FromStr for PersistenceTarget {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "db" | "database" => Ok(Self::Database),
+            "file" => Ok(Self::File),
+            "none" => Ok(Self::None),
+            _ => bail!("unknown persistence target {:?}, expected db, file, or none", s),
+        }
+    }
+}
+
+/// What to do when a persisted document exists but cannot be loaded.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LoadFailurePolicy {
+    /// Refuse to open the document, surfacing the error to clients.
+    Strict,
+    /// Log a warning and start the document empty.
+    #[default]
+    Lenient,
+    /// Like [`LoadFailurePolicy::Lenient`], but first move the stored copy
+    /// aside so it is kept for inspection.
+    Quarantine,
+}
+
+impl FromStr for LoadFailurePolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "strict" => Ok(Self::Strict),
+            "lenient" => Ok(Self::Lenient),
+            "quarantine" => Ok(Self::Quarantine),
+            _ => bail!("unknown load failure policy {:?}, expected strict, lenient, or quarantine", s),
+        }
+    }
+}
+
+/// Whether a load error only means that nothing was stored under the id.
+pub(crate) fn is_not_found(err: &anyhow::Error) -> bool {
+    match (err.downcast_ref::<sqlx::Error>(), err.downcast_ref::<std::io::Error>()) {
+        (Some(sqlx::Error::RowNotFound), _) => true,
+        (_, Some(err)) => err.kind() == std::io::ErrorKind::NotFound,
+        _ => false,
+    }
+}
+
+/// Rules choosing a persistence target from a document's id.
+#[derive(Debug, Clone, Default)]
+pub struct PersistenceRoutes {
+    /// Id prefixes and their targets.
+    rules: Vec<(String, PersistenceTarget)>,
+    /// Target for ids that match no prefix, if set.
+    default: Option<PersistenceTarget>,
+}
+
+impl FromStr for PersistenceRoutes {
+    type Err = anyhow::Error;
+
+    /// Parse `prefix=target` pairs separated by `;`, where the prefix `*`
+    /// sets the target for all other documents.
+    fn from_str(s: &str) -> Result<Self> {
+        let mut routes = Self::default();
+        for entry in s.split(';').map(str::trim).filter(|entry| !entry.is_empty()) {
+            let (prefix, target) = entry
+                .split_once('=')
+                .with_context(|| format!("expected prefix=target, got {:?}", entry))?;
+            let target = target.trim().parse()?;
+            match prefix.trim() {
+                "*" => routes.default = Some(target),
+                prefix => routes.rules.push((prefix.to_string(), target)),
+            }
+        }
+        Ok(routes)
+    }
+}
+
+impl PersistenceRoutes {
+    /// The target for a document id, preferring the longest matching prefix.
+    pub fn route(&self, id: &str) -> Option<PersistenceTarget> {
+        self.rules
+            .iter()
+            .filter(|(prefix, _)| id.starts_with(prefix.as_str()))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map(|&(_, target)| target)
+            .or(self.default)
+    }
+
+    /// Every target that these rules can send documents to.
+    pub fn targets(&self) -> impl Iterator<Item = PersistenceTarget> + '_ {
+        self.rules
+            .iter()
+            .map(|&(_, target)| target)
+            .chain(self.default)
+    }
+}
+
+/// A document snapshot as written to disk by [`FileStore`].
+#[derive(Serialize, Deserialize)]
+struct StoredFile {
+    id: String,
+    text: String,
+    language: Option<String>,
+    #[serde(default, skip_serializing_if = "DocumentAcl::is_open")]
+    acl: DocumentAcl,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    password_hash: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    share_tokens: Vec<String>,
+    #[serde(default, skip_serializing_if = "DocumentMetadata::is_empty")]
+    metadata: DocumentMetadata,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    encrypted: bool,
+    format_version: i64,
+}
+
+/// Persists documents as JSON files in a directory.
+#[derive(Debug, Clone)]
+pub struct FileStore {
+    dir: PathBuf,
+}
+
+impl FileStore {
+    /// Create a store in the given directory, creating it if needed.
+    pub fn new(dir: impl Into<PathBuf>) -> Result<Self> {
+        let dir = dir.into();
+        std::fs::create_dir_all(&dir)
+            .with_context(|| format!("Failed to create persistence directory {:?}", dir))?;
+        Ok(Self { dir })
+    }
+
+    /// File holding a document, named by a hash so any id is a safe filename.
+    fn path(&self, document_id: &str) -> PathBuf {
+        let hash = Sha256::digest(document_id.as_bytes());
+        self.dir.join(format!("{:x}.json", hash))
+    }
+
+    /// Load the text of a single document from disk.
+    pub async fn load(&self, document_id: &str) -> Result<PersistedDocument> {
+        let contents = tokio::fs::read(self.path(document_id)).await?;
+        let file: StoredFile = serde_json::from_slice(&contents)?;
+        if file.format_version > CURRENT_FORMAT_VERSION {
+            bail!(
+                "document uses format version {}, but this server only supports up to {}",
+                file.format_version,
+                CURRENT_FORMAT_VERSION,
+            );
+        }
+        Ok(PersistedDocument {
+            text: file.text,
+            language: file.language,
+            acl: file.acl,
+            password_hash: file.password_hash,
+            share_tokens: file.share_tokens,
+            metadata: file.metadata,
+            encrypted: file.encrypted,
+        })
+    }
+
+    /// Store the text of a single document, replacing any previous version.
+    pub async fn store(&self, document_id: &str, document: &PersistedDocument) -> Result<()> {
+        let file = StoredFile {
+            id: document_id.to_string(),
+            text: document.text.clone(),
+            language: document.language.clone(),
+            acl: document.acl.clone(),
+            password_hash: document.password_hash.clone(),
+            share_tokens: document.share_tokens.clone(),
+            metadata: document.metadata.clone(),
+            encrypted: document.encrypted,
+            format_version: CURRENT_FORMAT_VERSION,
+        };
+        let path = self.path(document_id);
+        // Write to a temporary file first, so readers never see a partial one.
+        let tmp = path.with_extension("json.tmp");
+        tokio::fs::write(&tmp, serde_json::to_vec(&file)?).await?;
+        tokio::fs::rename(&tmp, &path).await?;
+        Ok(())
+    }
+
+    /// Rename a document that failed to load, keeping it for inspection.
+    pub async fn quarantine(&self, document_id: &str) -> Result<()> {
+        let path = self.path(document_id);
+        tokio::fs::rename(&path, path.with_extension("json.corrupt")).await?;
+        Ok(())
+    }
+
+    /// Remove a document from disk, returning whether it was there.
+    pub async fn delete(&self, document_id: &str) -> Result<bool> {
+        match tokio::fs::remove_file(self.path(document_id)).await {
+            Ok(()) => Ok(true),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Check whether a document is stored on disk.
+    pub async fn exists(&self, document_id: &str) -> Result<bool> {
+        Ok(tokio::fs::try_exists(self.path(document_id)).await?)
+    }
+}
+
+/// A configured destination for document snapshots.
+#[derive(Debug, Clone)]
+pub(crate) enum Sink {
+    Database(Database),
+    File(FileStore),
+}
+
+impl Sink {
+    /// Write a snapshot of a document to this sink.
+    pub(crate) async fn store(&self, document_id: &str, document: &PersistedDocument) -> Result<()> {
+        match self {
+            Sink::Database(db) => db.store(document_id, document).await,
+            Sink::File(store) => store.store(document_id, document).await,
+        }
+    }
+
+    /// Remove a document from this sink, returning whether it was there.
+    pub(crate) async fn delete(&self, document_id: &str) -> Result<bool> {
+        match self {
+            Sink::Database(db) => db.delete(document_id).await,
+            Sink::File(store) => store.delete(document_id).await,
+        }
+    }
+}
@@ -1,9 +1,16 @@
 //! Eventually consistent server-side logic for Rustpad.
 
 use std::collections::HashMap;
-use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use std::io::Write;
 
 use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Utc};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use flate2::write::GzEncoder;
 use futures::prelude::*;
 use log::{info, warn};
 use operational_transform::OperationSeq;
@@ -12,7 +19,15 @@ use serde::{Deserialize, Serialize};
 use tokio::sync::{broadcast, Notify};
 use warp::ws::{Message, WebSocket};
 
-use crate::{database::PersistedDocument, ot::transform_index};
+use rand::Rng;
+
+use crate::{
+    acl::DocumentAcl,
+    database::{hash_share_token, PersistedDocument}, lint::Diagnostic,
+    load::ServerLoad,
+    metadata::{DocumentMetadata, MetadataUpdate}, metrics, names::AnonymousNames,
+    ot::{inserts_binary, normalize_inserts, rebase, transform_index},
+};
 
 /// The main object representing a collaborative session.
 pub struct Rustpad {
@@ -26,30 +41,237 @@ pub struct Rustpad {
     update: broadcast::Sender<ServerMsg>,
     /// Set to true when the document is destroyed.
     killed: AtomicBool,
+    /// Set to true when the document is unloaded so that clients reload it.
+    drained: AtomicBool,
+    /// Number of open connections to the document.
+    connections: AtomicUsize,
+    /// Limits applied to this document.
+    config: DocumentConfig,
+}
+
+/// Limits and policies applied to every document on the server.
+#[derive(Clone, Debug, Default)]
+pub struct DocumentConfig {
+    /// Number of retained operations after which history is compacted.
+    pub max_revisions: Option<usize>,
+    /// How long to hold back other users' operations so that several can be
+    /// sent in a single message.
+    pub coalesce_window: Option<Duration>,
+    /// Server-wide load, used for reconnect hints when closing connections.
+    pub load: Arc<ServerLoad>,
+    /// Names given to connections that don't provide their own.
+    pub anonymous_names: Option<Arc<AnonymousNames>>,
+    /// Tell clients when their edits have been durably persisted.
+    pub persistence_status: bool,
+    /// Convert inserted text to Unicode NFC.
+    pub normalize_unicode: bool,
+    /// Size in bytes past which operations are sent to clients compressed.
+    pub compression_threshold: Option<usize>,
+    /// Number of recent revisions whose edits may be undone on the server,
+    /// or `None` to disable undo.
+    pub undo_limit: Option<usize>,
+    /// Longest line, in characters, that edits may leave behind.
+    pub max_line_length: Option<usize>,
+    /// Largest size, in bytes of UTF-8, that edits may grow the text to.
+    pub max_document_bytes: Option<usize>,
+    /// Refuse edits inserting null bytes or control characters other than
+    /// tabs and line breaks.
+    pub reject_binary: bool,
+    /// How long a closed connection's name and cursor are kept for its
+    /// client to reconnect, or `None` to drop them at once.
+    pub cursor_grace: Option<Duration>,
 }
 
 /// Shared state involving multiple users, protected by a lock.
 #[derive(Default)]
 struct State {
     operations: Vec<UserOperation>,
+    /// Number of revisions folded into the first retained operation.
+    compacted: usize,
     text: String,
     language: Option<String>,
     users: HashMap<u64, UserInfo>,
     cursors: HashMap<u64, CursorData>,
+    /// Stable ids that clients sent when connecting, by connection id.
+    clients: HashMap<u64, String>,
+    /// Closed connections whose name and cursor are kept for a reconnect,
+    /// by the stable id of their client.
+    departed: HashMap<String, u64>,
+    /// Latest linter results, with the revision they were computed at.
+    diagnostics: Option<(usize, Vec<Diagnostic>)>,
+    /// Latest revision known to be stored in the database.
+    persisted: Option<usize>,
+    /// Who may read and write the document.
+    acl: DocumentAcl,
+    /// Bcrypt hash of the password needed to open the document, if any.
+    password_hash: Option<String>,
+    /// SHA-256 hashes of the tokens that open the document read-only.
+    share_tokens: Vec<String>,
+    /// Application-level information about the document.
+    metadata: DocumentMetadata,
+    /// Whether the document is end-to-end encrypted, in which case its
+    /// history is kept in `sealed` and `operations` and `text` stay empty.
+    encrypted: bool,
+    /// Operations on an end-to-end encrypted document, in the order applied.
+    sealed: Vec<SealedOperation>,
+    /// Set when the document is about to be unloaded, after which edits are
+    /// dropped so that its persisted state is final.
+    edits_stopped: bool,
+    /// Each connection's edits that may be undone, oldest first.
+    undo: HashMap<u64, Vec<UndoEntry>>,
+    /// Each connection's undone edits that may be redone, oldest first.
+    redo: HashMap<u64, Vec<UndoEntry>>,
+    /// Oldest revision each connection may still send an edit against or be
+    /// sent operations after, which compaction must not fold away.
+    held: HashMap<u64, usize>,
+}
+
+/// An edit that a user may undo or redo, kept as the operation reversing it.
+#[derive(Clone, Debug)]
+struct UndoEntry {
+    /// Revision of the document that `operation` applies to.
+    revision: usize,
+    operation: OperationSeq,
 }
 
+/// What a connection may do with the document.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConnectionAccess {
+    /// View and edit the document.
+    Write,
+    /// View the document, and be disconnected for trying to change it.
+    ReadOnly,
+    /// View the document through a share link, with changes dropped and
+    /// answered by an error message instead.
+    Shared,
+}
+
+/// Length of a read-only share token, in alphanumeric characters.
+const SHARE_TOKEN_LEN: usize = 32;
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 struct UserOperation {
     id: u64,
     operation: OperationSeq,
 }
 
+/// An edit that was well-formed but refused by a content policy.
+///
+/// The author is told why and resynchronized, rather than the connection
+/// failing as it does for a malformed edit.
+#[derive(Debug)]
+struct Refused(String);
+
+impl std::fmt::Display for Refused {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for Refused {}
+
+/// Total number of characters by which the lines of a text exceed a maximum.
+fn line_excess(text: &str, max: usize) -> usize {
+    text.split('\n')
+        .map(|line| line.trim_end_matches('\r').chars().count().saturating_sub(max))
+        .sum()
+}
+
+/// Largest ciphertext accepted for a single sealed operation, in bytes.
+const MAX_SEALED_SIZE: usize = 512 * 1024;
+
+/// Largest key negotiation message relayed between clients, in bytes.
+const MAX_KEY_EXCHANGE_SIZE: usize = 16 * 1024;
+
+/// An operation on an end-to-end encrypted document, opaque to the server.
+///
+/// A snapshot holds the client's encryption of the entire document, and
+/// supersedes every operation before it.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct SealedOperation {
+    id: u64,
+    ciphertext: String,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    snapshot: bool,
+}
+
+/// An operation as sent to clients, with large ones compressed.
+///
+/// Compression only changes how an operation is encoded on the wire; clients
+/// decode it back to the same operation before applying or transforming it.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+enum WireOperation {
+    /// Consecutive operations by one author composed into one, which
+    /// advances the revision by `revisions`.
+    Composed {
+        id: u64,
+        operation: OperationSeq,
+        revisions: usize,
+    },
+    Plain(UserOperation),
+    /// The operation's JSON, gzip-compressed and base64-encoded.
+    Compressed {
+        id: u64,
+        compressed: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        revisions: Option<usize>,
+    },
+    /// An operation on an end-to-end encrypted document, relayed as is.
+    Sealed(SealedOperation),
+}
+
+impl WireOperation {
+    /// Encode an operation standing for `revisions` revisions, compressing it
+    /// if its JSON exceeds `threshold` bytes.
+    fn new(op: UserOperation, revisions: usize, threshold: Option<usize>) -> Self {
+        let uncompressed = |op: UserOperation| match revisions {
+            1 => Self::Plain(op),
+            _ => Self::Composed {
+                id: op.id,
+                operation: op.operation,
+                revisions,
+            },
+        };
+        let Some(threshold) = threshold else {
+            return uncompressed(op);
+        };
+        let json = serde_json::to_vec(&op.operation).expect("failed serialize");
+        if json.len() <= threshold {
+            return uncompressed(op);
+        }
+        let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::fast());
+        match encoder.write_all(&json).and_then(|_| encoder.finish()) {
+            Ok(bytes) => Self::Compressed {
+                id: op.id,
+                compressed: STANDARD.encode(bytes),
+                revisions: (revisions > 1).then_some(revisions),
+            },
+            Err(e) => {
+                warn!("failed to compress operation, sending it as is: {}", e);
+                uncompressed(op)
+            }
+        }
+    }
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 struct UserInfo {
     name: String,
     hue: u32,
 }
 
+/// A connection to a document, as other users see it.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Participant {
+    /// Id of the connection.
+    pub id: u64,
+    /// Display name chosen by the user, or assigned to them if anonymous.
+    pub name: String,
+    /// Hue of the user's cursor color.
+    pub hue: u32,
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 struct CursorData {
     cursors: Vec<u32>,
@@ -70,6 +292,20 @@ enum ClientMsg {
     ClientInfo(UserInfo),
     /// Sets the user's cursor and selection positions.
     CursorData(CursorData),
+    /// Appends an operation or snapshot to an end-to-end encrypted document.
+    Sealed {
+        revision: usize,
+        ciphertext: String,
+        #[serde(default)]
+        snapshot: bool,
+    },
+    /// Relays key negotiation data to the other clients of an encrypted
+    /// document, such as public keys or a document key wrapped for one peer.
+    KeyExchange(String),
+    /// Reverses the user's latest edit that hasn't been undone.
+    Undo,
+    /// Reapplies the user's latest undone edit.
+    Redo,
 }
 
 /// A message sent to the client over WebSocket.
@@ -80,7 +316,7 @@ enum ServerMsg {
     /// Broadcasts text operations to all clients.
     History {
         start: usize,
-        operations: Vec<UserOperation>,
+        operations: Vec<WireOperation>,
     },
     /// Broadcasts the current language, last writer wins.
     Language(String),
@@ -88,6 +324,30 @@ enum ServerMsg {
     UserInfo { id: u64, info: Option<UserInfo> },
     /// Broadcasts a user's cursor position.
     UserCursor { id: u64, data: CursorData },
+    /// Broadcasts linter results for the document at a given revision.
+    Diagnostics {
+        revision: usize,
+        diagnostics: Vec<Diagnostic>,
+    },
+    /// Broadcasts the latest revision that has been durably persisted.
+    Persisted(usize),
+    /// Informs a client that it may view the document but not edit it.
+    ReadOnly,
+    /// Informs a client that the document is end-to-end encrypted, so its
+    /// history holds sealed operations rather than plain ones.
+    Encrypted,
+    /// Broadcasts a client's key negotiation data, which the server neither
+    /// reads nor stores.
+    KeyExchange { id: u64, payload: String },
+    /// Warns that the document will be evicted from memory in this many
+    /// seconds, unless it is opened again.
+    Expiring(u64),
+    /// Warns that the document is being unloaded, and that connections will
+    /// be closed in this many seconds for clients to reconnect and reload it.
+    Draining(u64),
+    /// Informs a client that its message was refused, without closing the
+    /// connection.
+    Rejected(String),
 }
 
 impl From<ServerMsg> for Message {
@@ -106,64 +366,501 @@ impl Default for Rustpad {
             notify: Default::default(),
             update: tx,
             killed: AtomicBool::new(false),
+            drained: AtomicBool::new(false),
+            connections: AtomicUsize::new(0),
+            config: Default::default(),
         }
     }
 }
 
 impl From<PersistedDocument> for Rustpad {
     fn from(document: PersistedDocument) -> Self {
-        let mut operation = OperationSeq::default();
-        operation.insert(&document.text);
-
         let rustpad = Self::default();
         {
             let mut state = rustpad.state.write();
-            state.text = document.text;
             state.language = document.language;
-            state.operations.push(UserOperation {
-                id: u64::MAX,
-                operation,
-            })
+            state.acl = document.acl;
+            state.password_hash = document.password_hash;
+            state.share_tokens = document.share_tokens;
+            state.metadata = document.metadata;
+            state.encrypted = document.encrypted;
+            if document.encrypted {
+                // An encrypted document's text is its sealed history.
+                if !document.text.is_empty() {
+                    match serde_json::from_str(&document.text) {
+                        Ok(sealed) => state.sealed = sealed,
+                        Err(e) => warn!("discarding malformed sealed history: {}", e),
+                    }
+                }
+            } else {
+                let mut operation = OperationSeq::default();
+                operation.insert(&document.text);
+                state.text = document.text;
+                state.operations.push(UserOperation {
+                    id: u64::MAX,
+                    operation,
+                });
+            }
         }
         rustpad
     }
 }
 
+impl State {
+    /// Returns the current revision, including compacted history.
+    fn revision(&self) -> usize {
+        self.compacted + self.retained()
+    }
+
+    /// Returns the number of operations retained in the document's history.
+    fn retained(&self) -> usize {
+        if self.encrypted {
+            self.sealed.len()
+        } else {
+            self.operations.len()
+        }
+    }
+
+    /// Returns the retained operations from `index` onward, as sent to clients.
+    fn wire_operations(&self, index: usize) -> Vec<WireOperation> {
+        if self.encrypted {
+            let ops = self.sealed.get(index..).unwrap_or_default();
+            ops.iter().cloned().map(WireOperation::Sealed).collect()
+        } else {
+            let ops = self.operations.get(index..).unwrap_or_default();
+            ops.iter().cloned().map(WireOperation::Plain).collect()
+        }
+    }
+
+    /// Returns the index of the latest sealed snapshot, if there is one.
+    fn last_snapshot(&self) -> Option<usize> {
+        self.sealed.iter().rposition(|op| op.snapshot)
+    }
+
+    /// Returns the index of the first operation applied after `revision`.
+    fn index_of(&self, revision: usize) -> Result<usize> {
+        if self.compacted > 0 && revision <= self.compacted {
+            bail!(
+                "revision {} precedes compacted history at {}",
+                revision,
+                self.compacted
+            );
+        }
+        Ok(revision - self.compacted)
+    }
+
+    /// Append an operation whose result is `new_text`, moving cursors with it.
+    fn push(&mut self, id: u64, operation: OperationSeq, new_text: String) {
+        for (_, data) in self.cursors.iter_mut() {
+            for cursor in data.cursors.iter_mut() {
+                *cursor = transform_index(&operation, *cursor);
+            }
+            for (start, end) in data.selections.iter_mut() {
+                *start = transform_index(&operation, *start);
+                *end = transform_index(&operation, *end);
+            }
+        }
+        self.operations.push(UserOperation { id, operation });
+        self.text = new_text;
+    }
+
+    /// Remember how to undo an edit that `id` just applied, forgetting the
+    /// edits it had undone and its edits older than `limit` revisions.
+    fn record_undo(&mut self, id: u64, inverse: OperationSeq, limit: usize) {
+        let revision = self.revision();
+        self.redo.remove(&id);
+        let stack = self.undo.entry(id).or_default();
+        stack.retain(|entry| entry.revision + limit >= revision);
+        stack.push(UndoEntry {
+            revision,
+            operation: inverse,
+        });
+    }
+
+    /// Fold all but the last `keep` operations into a single base operation.
+    fn compact(&mut self, keep: usize) -> Result<()> {
+        let mut cut = self.operations.len().saturating_sub(keep);
+        if let Some(&oldest) = self.held.values().min() {
+            cut = cut.min(oldest.saturating_sub(self.compacted));
+        }
+        if cut < 2 {
+            return Ok(());
+        }
+        let mut base = OperationSeq::default();
+        for op in &self.operations[..cut] {
+            base = base.compose(&op.operation)?;
+        }
+        let retained = self.operations.split_off(cut);
+        self.operations = vec![UserOperation {
+            id: u64::MAX,
+            operation: base,
+        }];
+        self.operations.extend(retained);
+        self.compacted += cut - 1;
+        info!("compacted {} operations, now at {}", cut, self.revision());
+        Ok(())
+    }
+
+    /// Drop the sealed operations superseded by the latest snapshot that no
+    /// connection still needs the history before.
+    fn compact_sealed(&mut self) {
+        let mut limit = self.sealed.len();
+        if let Some(&oldest) = self.held.values().min() {
+            limit = limit.min(oldest.saturating_sub(self.compacted).max(1));
+        }
+        let cut = self.sealed[..limit]
+            .iter()
+            .rposition(|op| op.snapshot)
+            .unwrap_or(0);
+        if cut == 0 {
+            return;
+        }
+        self.sealed.drain(..cut);
+        self.compacted += cut;
+        info!("compacted {} sealed operations, now at {}", cut, self.revision());
+    }
+}
+
 impl Rustpad {
+    /// Apply the given limits to this document.
+    pub fn with_config(mut self, config: DocumentConfig) -> Self {
+        self.config = config;
+        self
+    }
+
     /// Handle a connection from a WebSocket.
-    pub async fn on_connection(&self, socket: WebSocket) {
+    ///
+    /// The connection is closed early if the `evicted` future resolves. Every
+    /// connection sees every change, but only one with `Write` access may
+    /// make them.
+    ///
+    /// A `client` id that stays the same across reconnects lets the name and
+    /// cursor of a connection that closed within the grace period carry over
+    /// to this one, so other users don't see them vanish and reappear.
+    pub async fn on_connection(
+        &self,
+        socket: WebSocket,
+        evicted: impl Future<Output = ()>,
+        access: ConnectionAccess,
+        client: Option<String>,
+    ) {
         let id = self.count.fetch_add(1, Ordering::Relaxed);
         info!("connection! id = {}", id);
-        if let Err(e) = self.handle_connection(id, socket).await {
+        self.connections.fetch_add(1, Ordering::Relaxed);
+        if let Some(client) = &client {
+            self.resume(id, client);
+        }
+        if let Err(e) = self.handle_connection(id, socket, evicted, access).await {
             warn!("connection terminated early: {}", e);
         }
+        self.connections.fetch_sub(1, Ordering::Relaxed);
         info!("disconnection, id = {}", id);
-        self.state.write().users.remove(&id);
-        self.state.write().cursors.remove(&id);
+
+        let grace = {
+            let mut state = self.state.write();
+            state.undo.remove(&id);
+            state.redo.remove(&id);
+            state.held.remove(&id);
+            match (client, self.config.cursor_grace) {
+                (Some(client), Some(grace)) if !self.killed() => {
+                    state.departed.insert(client, id);
+                    Some(grace)
+                }
+                _ => None,
+            }
+        };
+        if let Some(grace) = grace {
+            tokio::time::sleep(grace).await;
+        }
+        {
+            let mut state = self.state.write();
+            if let Some(client) = state.clients.remove(&id) {
+                if state.departed.get(&client) == Some(&id) {
+                    state.departed.remove(&client);
+                }
+            } else if grace.is_some() {
+                // A reconnecting client took over the name and cursor.
+                return;
+            }
+            state.users.remove(&id);
+            state.cursors.remove(&id);
+        }
         self.update
             .send(ServerMsg::UserInfo { id, info: None })
             .ok();
     }
 
+    /// Register the stable id of connection `id`'s client, moving over the
+    /// name and cursor of its previous connection if that is still kept.
+    fn resume(&self, id: u64, client: &str) {
+        let (previous, info, cursor) = {
+            let mut state = self.state.write();
+            state.clients.insert(id, client.to_string());
+            let Some(previous) = state.departed.remove(client) else {
+                return;
+            };
+            state.clients.remove(&previous);
+            let info = state.users.remove(&previous);
+            let cursor = state.cursors.remove(&previous);
+            if let Some(info) = &info {
+                state.users.insert(id, info.clone());
+            }
+            if let Some(cursor) = &cursor {
+                state.cursors.insert(id, cursor.clone());
+            }
+            (previous, info, cursor)
+        };
+        info!("connection id = {} resumed id = {}", id, previous);
+        self.update
+            .send(ServerMsg::UserInfo {
+                id: previous,
+                info: None,
+            })
+            .ok();
+        if let Some(info) = info {
+            self.update.send(ServerMsg::UserInfo { id, info: Some(info) }).ok();
+        }
+        if let Some(data) = cursor {
+            self.update.send(ServerMsg::UserCursor { id, data }).ok();
+        }
+    }
+
     /// Returns a snapshot of the latest text.
     pub fn text(&self) -> String {
         let state = self.state.read();
         state.text.clone()
     }
 
+    /// Returns the length of the latest text in bytes.
+    pub fn text_len(&self) -> usize {
+        self.state.read().text.len()
+    }
+
+    /// Returns the current language, if one has been set.
+    pub fn language(&self) -> Option<String> {
+        let state = self.state.read();
+        state.language.clone()
+    }
+
     /// Returns a snapshot of the current document for persistence.
+    ///
+    /// An encrypted document's text is its sealed history from the latest
+    /// snapshot onward, which is all that clients need to rebuild it.
     pub fn snapshot(&self) -> PersistedDocument {
         let state = self.state.read();
+        let text = if state.encrypted {
+            let sealed = &state.sealed[state.last_snapshot().unwrap_or(0)..];
+            serde_json::to_string(sealed).expect("failed serialize")
+        } else {
+            state.text.clone()
+        };
         PersistedDocument {
-            text: state.text.clone(),
+            text,
             language: state.language.clone(),
+            acl: state.acl.clone(),
+            password_hash: state.password_hash.clone(),
+            share_tokens: state.share_tokens.clone(),
+            metadata: state.metadata.clone(),
+            encrypted: state.encrypted,
         }
     }
 
+    /// Returns whether the document is end-to-end encrypted, so that the
+    /// server only relays its operations without reading them.
+    pub fn is_encrypted(&self) -> bool {
+        self.state.read().encrypted
+    }
+
+    /// Returns who may read and write the document.
+    pub fn acl(&self) -> DocumentAcl {
+        self.state.read().acl.clone()
+    }
+
+    /// Replace who may read and write the document.
+    ///
+    /// Only checked when a connection is opened, so existing connections keep
+    /// their access.
+    pub fn set_acl(&self, acl: DocumentAcl) {
+        self.state.write().acl = acl;
+    }
+
+    /// Returns the document's metadata.
+    pub fn metadata(&self) -> DocumentMetadata {
+        self.state.read().metadata.clone()
+    }
+
+    /// Record who created the document and when.
+    pub fn set_creator(&self, created_by: Option<String>, created_at: DateTime<Utc>) {
+        let mut state = self.state.write();
+        state.metadata.created_by = created_by;
+        state.metadata.created_at = Some(created_at);
+    }
+
+    /// Apply a client's change to the metadata, returning the result.
+    pub fn update_metadata(&self, update: MetadataUpdate) -> Result<DocumentMetadata> {
+        let mut state = self.state.write();
+        state.metadata.update(update)?;
+        Ok(state.metadata.clone())
+    }
+
+    /// Require a password to open the document, or remove it with `None`.
+    ///
+    /// Only a bcrypt hash of the password is kept.
+    pub fn set_password(&self, password: Option<&str>) -> Result<()> {
+        let hash = password
+            .map(|password| bcrypt::hash(password, bcrypt::DEFAULT_COST))
+            .transpose()?;
+        self.state.write().password_hash = hash;
+        Ok(())
+    }
+
+    /// The bcrypt hash of the document's password, if it has one.
+    ///
+    /// Checking a password against it is slow, so callers do that on the
+    /// blocking thread pool.
+    pub fn password_hash(&self) -> Option<String> {
+        self.state.read().password_hash.clone()
+    }
+
+    /// Create a token that opens the document read-only, returning it.
+    ///
+    /// Only a hash of the token is kept, so it can't be shown again later.
+    pub fn create_share_token(&self) -> String {
+        let token: String = rand::thread_rng()
+            .sample_iter(rand::distributions::Alphanumeric)
+            .take(SHARE_TOKEN_LEN)
+            .map(char::from)
+            .collect();
+        self.state.write().share_tokens.push(hash_share_token(&token));
+        token
+    }
+
+    /// Hashes of the tokens that open the document read-only.
+    pub fn share_tokens(&self) -> Vec<String> {
+        self.state.read().share_tokens.clone()
+    }
+
+    /// Revoke every read-only share token, returning how many there were.
+    ///
+    /// Only checked when a connection is opened, so existing connections stay
+    /// open.
+    pub fn revoke_share_tokens(&self) -> usize {
+        std::mem::take(&mut self.state.write().share_tokens).len()
+    }
+
+    /// Publish linter results computed at the given revision.
+    pub fn set_diagnostics(&self, revision: usize, diagnostics: Vec<Diagnostic>) {
+        let mut state = self.state.write();
+        state.diagnostics = Some((revision, diagnostics.clone()));
+        self.update
+            .send(ServerMsg::Diagnostics {
+                revision,
+                diagnostics,
+            })
+            .ok();
+    }
+
+    /// Record that the given revision has been stored in the database.
+    pub fn mark_persisted(&self, revision: usize) {
+        let mut state = self.state.write();
+        if matches!(state.persisted, Some(persisted) if persisted >= revision) {
+            return;
+        }
+        state.persisted = Some(revision);
+        if self.config.persistence_status {
+            self.update.send(ServerMsg::Persisted(revision)).ok();
+        }
+    }
+
+    /// Warn connected clients that the document is about to be evicted.
+    pub fn warn_expiry(&self, remaining: Duration) {
+        self.update.send(ServerMsg::Expiring(remaining.as_secs())).ok();
+    }
+
+    /// Returns the connections that have a display name, in order of id.
+    pub fn participants(&self) -> Vec<Participant> {
+        let state = self.state.read();
+        let mut participants: Vec<_> = state
+            .users
+            .iter()
+            .filter(|(id, _)| !state.departed.values().any(|departed| departed == *id))
+            .map(|(&id, info)| Participant {
+                id,
+                name: info.name.clone(),
+                hue: info.hue,
+            })
+            .collect();
+        participants.sort_by_key(|participant| participant.id);
+        participants
+    }
+
+    /// Returns the number of operations kept in memory, which a copy made by
+    /// [`Rustpad::fork_history`] would take over.
+    pub fn retained_operations(&self) -> usize {
+        self.state.read().retained()
+    }
+
+    /// Returns a new document with this one's text, language, and operation
+    /// history, so that its revisions carry on from this one's.
+    ///
+    /// Access rules, metadata, and the state of connections are not copied,
+    /// and neither is the history of an end-to-end encrypted document.
+    pub fn fork_history(&self) -> Self {
+        let rustpad = Self::default();
+        {
+            let state = self.state.read();
+            let mut forked = rustpad.state.write();
+            if !state.encrypted {
+                // Authors' connection ids mean nothing in the new document,
+                // and would be taken as acknowledgements by its clients.
+                forked.operations = state
+                    .operations
+                    .iter()
+                    .map(|op| UserOperation {
+                        id: u64::MAX,
+                        operation: op.operation.clone(),
+                    })
+                    .collect();
+                forked.compacted = state.compacted;
+                forked.text = state.text.clone();
+            }
+            forked.language = state.language.clone();
+        }
+        rustpad
+    }
+
+    /// Returns the latest revision stored in the database, if any.
+    pub fn persisted_revision(&self) -> Option<usize> {
+        self.state.read().persisted
+    }
+
     /// Returns the current revision.
     pub fn revision(&self) -> usize {
         let state = self.state.read();
-        state.operations.len()
+        state.revision()
+    }
+
+    /// Returns the number of open connections to the document.
+    pub fn connections(&self) -> usize {
+        self.connections.load(Ordering::Relaxed)
+    }
+
+    /// Warn connected clients that the document will be unloaded after a
+    /// grace period, and that they should reconnect to reload it.
+    pub fn warn_drain(&self, grace: Duration) {
+        self.update.send(ServerMsg::Draining(grace.as_secs())).ok();
+    }
+
+    /// Drop any further edits, so that the current state can be persisted as
+    /// final before the document is unloaded.
+    pub fn stop_edits(&self) {
+        self.state.write().edits_stopped = true;
+    }
+
+    /// Take edits again after [`Rustpad::stop_edits`], if the document is
+    /// kept after all.
+    pub fn resume_edits(&self) {
+        self.state.write().edits_stopped = false;
     }
 
     /// Kill this object immediately, dropping all current connections.
@@ -172,30 +869,86 @@ impl Rustpad {
         self.notify.notify_waiters();
     }
 
+    /// Kill this object, closing connections with a reason that tells
+    /// clients to reconnect and load the document afresh.
+    pub fn drain(&self) {
+        self.drained.store(true, Ordering::Relaxed);
+        self.kill();
+    }
+
     /// Returns if this Rustpad object has been killed.
     pub fn killed(&self) -> bool {
         self.killed.load(Ordering::Relaxed)
     }
 
-    async fn handle_connection(&self, id: u64, mut socket: WebSocket) -> Result<()> {
+    async fn handle_connection(
+        &self,
+        id: u64,
+        mut socket: WebSocket,
+        evicted: impl Future<Output = ()>,
+        access: ConnectionAccess,
+    ) -> Result<()> {
         let mut update_rx = self.update.subscribe();
+        tokio::pin!(evicted);
 
-        let mut revision: usize = self.send_initial(id, &mut socket).await?;
+        let mut revision: usize = self.send_initial(id, &mut socket, access).await?;
+        let resumed = self.state.read().users.contains_key(&id);
+        if let (Some(names), false) = (&self.config.anonymous_names, resumed) {
+            let name = self.unique_name(names);
+            let hue = rand::thread_rng().gen_range(0..360);
+            self.set_user_info(id, UserInfo { name, hue });
+        }
 
+        let mut close_reason = None;
+        // When operations held back by the coalescing window are due.
+        let mut flush_at = None;
         loop {
             // In order to avoid the "lost wakeup" problem, we first request a
             // notification, **then** check the current state for new revisions.
             // This is the same approach that `tokio::sync::watch` takes.
             let notified = self.notify.notified();
             if self.killed() {
+                close_reason = Some(if self.drained.load(Ordering::Relaxed) {
+                    "drained"
+                } else {
+                    "unloaded"
+                });
                 break;
             }
             if self.revision() > revision {
-                revision = self.send_history(revision, &mut socket).await?
+                // Others' operations are held back for the window, but our own
+                // are acknowledged without delay, so that the author's typing
+                // latency is unaffected.
+                match self.config.coalesce_window {
+                    Some(window) if !self.has_operation_from(id, revision) => {
+                        flush_at.get_or_insert_with(|| tokio::time::Instant::now() + window);
+                    }
+                    _ => {
+                        revision = self.send_history(id, revision, &mut socket).await?;
+                        self.release(id, revision, access);
+                        flush_at = None;
+                    }
+                }
             }
+            // Messages keep being handled while operations are held back.
+            let flush = async {
+                match flush_at {
+                    Some(at) => tokio::time::sleep_until(at).await,
+                    None => futures::future::pending().await,
+                }
+            };
 
             tokio::select! {
                 _ = notified => {}
+                _ = flush => {
+                    revision = self.send_history(id, revision, &mut socket).await?;
+                    self.release(id, revision, access);
+                    flush_at = None;
+                }
+                _ = &mut evicted => {
+                    close_reason = Some("evicted");
+                    break;
+                }
                 update = update_rx.recv() => {
                     socket.send(update?.into()).await?;
                 }
@@ -203,26 +956,52 @@ impl Rustpad {
                     match result {
                         None => break,
                         Some(message) => {
-                            self.handle_message(id, message?).await?;
+                            if let Some(reply) = self.handle_message(id, message?, access).await? {
+                                // A writer's edit was refused after they
+                                // applied it locally, so they must reload.
+                                let resync = access == ConnectionAccess::Write
+                                    && matches!(reply, ServerMsg::Rejected(_));
+                                socket.send(reply.into()).await?;
+                                if resync {
+                                    close_reason = Some("rejected");
+                                    break;
+                                }
+                            }
                         }
                     }
                 }
             }
         }
 
+        if let Some(reason) = close_reason {
+            let (code, reason) = self.config.load.close_frame(reason);
+            socket.send(Message::close_with(code, reason)).await.ok();
+        }
+
         Ok(())
     }
 
-    async fn send_initial(&self, id: u64, socket: &mut WebSocket) -> Result<usize> {
+    async fn send_initial(
+        &self,
+        id: u64,
+        socket: &mut WebSocket,
+        access: ConnectionAccess,
+    ) -> Result<usize> {
         socket.send(ServerMsg::Identity(id).into()).await?;
+        if access != ConnectionAccess::Write {
+            socket.send(ServerMsg::ReadOnly.into()).await?;
+        }
+        if self.is_encrypted() {
+            socket.send(ServerMsg::Encrypted.into()).await?;
+        }
         let mut messages = Vec::new();
+        let mut history = None;
         let revision = {
-            let state = self.state.read();
-            if !state.operations.is_empty() {
-                messages.push(ServerMsg::History {
-                    start: 0,
-                    operations: state.operations.clone(),
-                });
+            let mut state = self.state.write();
+            let revision = state.revision();
+            state.held.insert(id, revision);
+            if state.retained() > 0 {
+                history = Some((state.compacted, state.wire_operations(0)));
             }
             if let Some(language) = &state.language {
                 messages.push(ServerMsg::Language(language.clone()));
@@ -239,63 +1018,357 @@ impl Rustpad {
                     data: data.clone(),
                 });
             }
-            state.operations.len()
+            if let Some(revision) = state.persisted.filter(|_| self.config.persistence_status) {
+                messages.push(ServerMsg::Persisted(revision));
+            }
+            if let Some((revision, diagnostics)) = &state.diagnostics {
+                messages.push(ServerMsg::Diagnostics {
+                    revision: *revision,
+                    diagnostics: diagnostics.clone(),
+                });
+            }
+            revision
         };
+        if let Some((start, operations)) = history {
+            socket
+                .send(self.history(start, operations, None).into())
+                .await?;
+        }
         for msg in messages {
             socket.send(msg.into()).await?;
         }
         Ok(revision)
     }
 
-    async fn send_history(&self, start: usize, socket: &mut WebSocket) -> Result<usize> {
+    /// Returns if any operation after `start` was authored by connection `id`.
+    fn has_operation_from(&self, id: u64, start: usize) -> bool {
+        let state = self.state.read();
+        match state.index_of(start) {
+            Ok(index) if state.encrypted => state.sealed[index.min(state.sealed.len())..]
+                .iter()
+                .any(|op| op.id == id),
+            Ok(index) => state.operations[index.min(state.operations.len())..]
+                .iter()
+                .any(|op| op.id == id),
+            Err(_) => false,
+        }
+    }
+
+    /// Let compaction fold the history that connection `id` has been sent.
+    ///
+    /// Writers may still send edits against an older revision, so their
+    /// history is only released as their own edits are applied.
+    fn release(&self, id: u64, revision: usize, access: ConnectionAccess) {
+        if access != ConnectionAccess::Write {
+            self.state.write().held.insert(id, revision);
+        }
+    }
+
+    /// Pick an anonymous name not already used in this document.
+    fn unique_name(&self, names: &AnonymousNames) -> String {
+        let state = self.state.read();
+        names.pick(|name| state.users.values().any(|info| info.name == name))
+    }
+
+    fn set_user_info(&self, id: u64, info: UserInfo) {
+        self.state.write().users.insert(id, info.clone());
+        let msg = ServerMsg::UserInfo {
+            id,
+            info: Some(info),
+        };
+        self.update.send(msg).ok();
+    }
+
+    /// Send connection `id` the operations after `start`, returning the
+    /// revision it is now at.
+    ///
+    /// With a coalescing window, runs of operations by another author are
+    /// composed into one, while connection `id` still sees each of its own
+    /// operations to acknowledge them.
+    async fn send_history(&self, id: u64, start: usize, socket: &mut WebSocket) -> Result<usize> {
         let operations = {
             let state = self.state.read();
-            let len = state.operations.len();
-            if start < len {
-                state.operations[start..].to_owned()
-            } else {
-                Vec::new()
-            }
+            let index = state.index_of(start)?;
+            state.wire_operations(index)
         };
         let num_ops = operations.len();
         if num_ops > 0 {
-            let msg = ServerMsg::History { start, operations };
-            socket.send(msg.into()).await?;
+            let compose_for = self.config.coalesce_window.map(|_| id);
+            socket
+                .send(self.history(start, operations, compose_for).into())
+                .await?;
         }
         Ok(start + num_ops)
     }
 
-    async fn handle_message(&self, id: u64, message: Message) -> Result<()> {
+    /// Build a history message, compressing operations over the threshold.
+    ///
+    /// With `compose_for`, consecutive operations by the same author are
+    /// composed, except those by that connection.
+    fn history(
+        &self,
+        start: usize,
+        operations: Vec<WireOperation>,
+        compose_for: Option<u64>,
+    ) -> ServerMsg {
+        let threshold = self.config.compression_threshold;
+        let mut runs: Vec<(UserOperation, usize)> = Vec::new();
+        let mut wire = Vec::new();
+        for op in operations {
+            let WireOperation::Plain(op) = op else {
+                wire.push(op);
+                continue;
+            };
+            if let Some((last, revisions)) = runs.last_mut() {
+                if compose_for.is_some_and(|recipient| last.id == op.id && op.id != recipient) {
+                    // Consecutive operations in the history always compose.
+                    if let Ok(operation) = last.operation.compose(&op.operation) {
+                        last.operation = operation;
+                        *revisions += 1;
+                        continue;
+                    }
+                }
+            }
+            runs.push((op, 1));
+        }
+        wire.extend(
+            runs.into_iter()
+                .map(|(op, revisions)| WireOperation::new(op, revisions, threshold)),
+        );
+        ServerMsg::History {
+            start,
+            operations: wire,
+        }
+    }
+
+    /// Handle a message from a client, returning a reply for it alone, if any.
+    async fn handle_message(
+        &self,
+        id: u64,
+        message: Message,
+        access: ConnectionAccess,
+    ) -> Result<Option<ServerMsg>> {
         let msg: ClientMsg = match message.to_str() {
             Ok(text) => serde_json::from_str(text).context("failed to deserialize message")?,
-            Err(()) => return Ok(()), // Ignore non-text messages
+            Err(()) => return Ok(None), // Ignore non-text messages
         };
+        if access != ConnectionAccess::Write
+            && matches!(
+                msg,
+                ClientMsg::Edit { .. }
+                    | ClientMsg::SetLanguage(_)
+                    | ClientMsg::Sealed { .. }
+                    | ClientMsg::Undo
+                    | ClientMsg::Redo
+            )
+        {
+            if access == ConnectionAccess::Shared {
+                info!("dropping change from id = {} on a read-only share link", id);
+                let reason = "document is shared read-only".to_string();
+                return Ok(Some(ServerMsg::Rejected(reason)));
+            }
+            bail!("connection is read-only");
+        }
         match msg {
             ClientMsg::Edit {
                 revision,
                 operation,
             } => {
-                self.apply_edit(id, revision, operation)
-                    .context("invalid edit operation")?;
+                if let Err(e) = self.apply_edit(id, revision, operation) {
+                    if let Some(Refused(reason)) = e.downcast_ref() {
+                        info!("refused edit from id = {}: {}", id, reason);
+                        return Ok(Some(ServerMsg::Rejected(reason.clone())));
+                    }
+                    return Err(e.context("invalid edit operation"));
+                }
                 self.notify.notify_waiters();
             }
             ClientMsg::SetLanguage(language) => {
                 self.state.write().language = Some(language.clone());
                 self.update.send(ServerMsg::Language(language)).ok();
             }
-            ClientMsg::ClientInfo(info) => {
-                self.state.write().users.insert(id, info.clone());
-                let msg = ServerMsg::UserInfo {
-                    id,
-                    info: Some(info),
-                };
-                self.update.send(msg).ok();
+            ClientMsg::ClientInfo(mut info) => {
+                if info.name.trim().is_empty() {
+                    let assigned = self.state.read().users.get(&id).map(|u| u.name.clone());
+                    if let Some(name) = assigned {
+                        info.name = name;
+                    } else if let Some(names) = &self.config.anonymous_names {
+                        info.name = self.unique_name(names);
+                    }
+                }
+                self.set_user_info(id, info);
             }
             ClientMsg::CursorData(data) => {
                 self.state.write().cursors.insert(id, data.clone());
                 let msg = ServerMsg::UserCursor { id, data };
                 self.update.send(msg).ok();
             }
+            ClientMsg::Sealed {
+                revision,
+                ciphertext,
+                snapshot,
+            } => {
+                self.apply_sealed(id, revision, ciphertext, snapshot)
+                    .context("invalid sealed operation")?;
+                self.notify.notify_waiters();
+            }
+            ClientMsg::KeyExchange(payload) => {
+                if !self.is_encrypted() {
+                    bail!("document is not end-to-end encrypted");
+                }
+                if payload.len() > MAX_KEY_EXCHANGE_SIZE {
+                    bail!(
+                        "key exchange length {} is greater than 16 KiB maximum",
+                        payload.len()
+                    );
+                }
+                self.update.send(ServerMsg::KeyExchange { id, payload }).ok();
+            }
+            ClientMsg::Undo => {
+                if self.undo(id).context("invalid undo")? {
+                    self.notify.notify_waiters();
+                }
+            }
+            ClientMsg::Redo => {
+                if self.redo(id).context("invalid redo")? {
+                    self.notify.notify_waiters();
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// Returns if changing `text` to `new_text` would grow it past the size
+    /// limit. Documents that are already too large may still shrink.
+    fn exceeds_max_size(&self, text: &str, new_text: &str) -> bool {
+        match self.config.max_document_bytes {
+            Some(max) => new_text.len() > max && new_text.len() > text.len(),
+            None => false,
+        }
+    }
+
+    /// Undo the latest edit by connection `id` that hasn't been undone.
+    ///
+    /// The edit's inverse is transformed past every operation applied since,
+    /// following the rules of [`rebase`], so other users' work is untouched.
+    /// It is broadcast as an operation from the server, which clients apply
+    /// like any other user's. Returns whether there was an edit to undo.
+    pub fn undo(&self, id: u64) -> Result<bool> {
+        self.step_history(id, false)
+    }
+
+    /// Redo the latest edit by connection `id` that was undone, unless it has
+    /// made another edit since. Returns whether there was an edit to redo.
+    pub fn redo(&self, id: u64) -> Result<bool> {
+        self.step_history(id, true)
+    }
+
+    /// Apply the top entry of a connection's undo or redo stack, and push
+    /// what reverses it onto the other.
+    fn step_history(&self, id: u64, redo: bool) -> Result<bool> {
+        let Some(limit) = self.config.undo_limit else {
+            return Ok(false);
+        };
+        let mut state = self.state.write();
+        if state.edits_stopped {
+            info!("dropping undo from id = {} to a document being unloaded", id);
+            return Ok(false);
+        }
+        let revision = state.revision();
+        let stack = if redo { &mut state.redo } else { &mut state.undo };
+        let Some(entry) = stack.get_mut(&id).and_then(Vec::pop) else {
+            return Ok(false);
+        };
+        // Entries below this one are older, and so out of reach as well.
+        let index = match state.index_of(entry.revision) {
+            Ok(index) if entry.revision + limit >= revision => index,
+            _ => {
+                info!("history of id = {} is older than the undo limit", id);
+                let stack = if redo { &mut state.redo } else { &mut state.undo };
+                stack.remove(&id);
+                return Ok(false);
+            }
+        };
+        let history = state.operations[index..].iter().map(|op| &op.operation);
+        let operation = rebase(entry.operation, history)?;
+        let reverse = operation.invert(&state.text);
+        let new_text = operation.apply(&state.text)?;
+        if self.exceeds_max_size(&state.text, &new_text) {
+            info!("dropping undo or redo from id = {} past the size limit", id);
+            let stack = if redo { &mut state.redo } else { &mut state.undo };
+            stack.remove(&id);
+            return Ok(false);
+        }
+        state.push(u64::MAX, operation, new_text);
+        metrics::operation_applied();
+        let entry = UndoEntry {
+            revision: state.revision(),
+            operation: reverse,
+        };
+        let stack = if redo { &mut state.undo } else { &mut state.redo };
+        stack.entry(id).or_default().push(entry);
+        self.compact_if_needed(&mut state);
+        Ok(true)
+    }
+
+    /// Compact the history once it holds more than the configured maximum.
+    fn compact_if_needed(&self, state: &mut State) {
+        if let Some(max_revisions) = self.config.max_revisions {
+            if state.operations.len() > max_revisions {
+                if let Err(e) = state.compact(max_revisions / 2) {
+                    warn!("failed to compact history: {}", e);
+                }
+            }
+        }
+    }
+
+    /// Append an opaque operation to an end-to-end encrypted document.
+    ///
+    /// Sealed operations can't be transformed, so one that isn't based on the
+    /// latest revision is dropped. Its author then sees another client's
+    /// operation at that revision, and should rebase and resend their own.
+    fn apply_sealed(&self, id: u64, revision: usize, ciphertext: String, snapshot: bool) -> Result<()> {
+        info!(
+            "sealed edit: id = {}, revision = {}, len = {}, snapshot = {}",
+            id,
+            revision,
+            ciphertext.len(),
+            snapshot
+        );
+        if ciphertext.len() > MAX_SEALED_SIZE {
+            bail!(
+                "ciphertext length {} is greater than 512 KiB maximum",
+                ciphertext.len()
+            );
+        }
+        let mut state = self.state.write();
+        if !state.encrypted {
+            bail!("document is not end-to-end encrypted");
+        }
+        if state.edits_stopped {
+            info!("dropping sealed edit from id = {} to a document being unloaded", id);
+            return Ok(());
+        }
+        let len = state.revision();
+        if revision > len {
+            bail!("got revision {}, but current is {}", revision, len);
+        }
+        if revision < len {
+            info!("dropping sealed edit from id = {} at stale revision {}", id, revision);
+            return Ok(());
+        }
+        state.sealed.push(SealedOperation {
+            id,
+            ciphertext,
+            snapshot,
+        });
+        let revision = state.revision();
+        state.held.insert(id, revision);
+        metrics::operation_applied();
+        if let Some(max_revisions) = self.config.max_revisions {
+            if state.sealed.len() > max_revisions {
+                state.compact_sealed();
+            }
         }
         Ok(())
     }
@@ -309,11 +1382,18 @@ impl Rustpad {
             operation.target_len()
         );
         let state = self.state.upgradable_read();
-        let len = state.operations.len();
+        if state.encrypted {
+            bail!("document is end-to-end encrypted, so edits must be sealed");
+        }
+        if state.edits_stopped {
+            info!("dropping edit from id = {} to a document being unloaded", id);
+            return Ok(());
+        }
+        let len = state.revision();
         if revision > len {
             bail!("got revision {}, but current is {}", revision, len);
         }
-        for history_op in &state.operations[revision..] {
+        for history_op in &state.operations[state.index_of(revision)?..] {
             operation = operation.transform(&history_op.operation)?.0;
         }
         if operation.target_len() > 256 * 1024 {
@@ -322,19 +1402,44 @@ impl Rustpad {
                 operation.target_len()
             );
         }
+        if self.config.reject_binary && inserts_binary(&operation) {
+            bail!(Refused("edits may not insert binary or control characters".into()));
+        }
         let new_text = operation.apply(&state.text)?;
-        let mut state = RwLockUpgradableReadGuard::upgrade(state);
-        for (_, data) in state.cursors.iter_mut() {
-            for cursor in data.cursors.iter_mut() {
-                *cursor = transform_index(&operation, *cursor);
-            }
-            for (start, end) in data.selections.iter_mut() {
-                *start = transform_index(&operation, *start);
-                *end = transform_index(&operation, *end);
+        if self.exceeds_max_size(&state.text, &new_text) {
+            let max = self.config.max_document_bytes.unwrap_or_default();
+            bail!(Refused(format!("documents are limited to {} bytes", max)));
+        }
+        // Lines are only measured against the limit relative to before, so an
+        // edit may touch or shorten lines that were already too long, but not
+        // lengthen them, or make new ones by joining or pasting.
+        if let Some(max) = self.config.max_line_length {
+            if line_excess(&new_text, max) > line_excess(&state.text, max) {
+                bail!(Refused(format!("lines are limited to {} characters", max)));
             }
         }
-        state.operations.push(UserOperation { id, operation });
-        state.text = new_text;
+        // Normalization is a separate operation from the server, so that the
+        // author, who never re-applies their own acknowledged edit, sees it too.
+        let normalization = if self.config.normalize_unicode {
+            normalize_inserts(&operation)
+        } else {
+            None
+        };
+        let inverse = self.config.undo_limit.map(|_| operation.invert(&state.text));
+        let mut state = RwLockUpgradableReadGuard::upgrade(state);
+        state.push(id, operation, new_text);
+        // The author's next edit is based on at least this one.
+        let revision = state.revision();
+        state.held.insert(id, revision);
+        metrics::operation_applied();
+        if let (Some(inverse), Some(limit)) = (inverse, self.config.undo_limit) {
+            state.record_undo(id, inverse, limit);
+        }
+        if let Some(normalization) = normalization {
+            let normalized_text = normalization.apply(&state.text)?;
+            state.push(u64::MAX, normalization, normalized_text);
+        }
+        self.compact_if_needed(&mut state);
         Ok(())
     }
 }
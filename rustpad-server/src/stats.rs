@@ -0,0 +1,64 @@
+//! Recent history of server statistics, sampled periodically.
+
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use parking_lot::Mutex;
+use serde::Serialize;
+
+/// Server statistics at one point in time.
+#[derive(Clone, Debug, Serialize)]
+pub struct StatsSample {
+    /// When the sample was taken, in seconds since Unix epoch.
+    pub timestamp: u64,
+    /// Number of documents held in memory.
+    pub num_documents: usize,
+    /// Number of live WebSocket connections.
+    pub connections: usize,
+    /// Number of documents persisted in the database.
+    pub database_size: usize,
+    /// Bytes of document text held in memory.
+    pub memory_bytes: usize,
+}
+
+/// A bounded series of samples, dropping the oldest once full.
+#[derive(Debug)]
+pub struct StatsHistory {
+    /// Time between samples.
+    interval: Duration,
+    /// Maximum number of samples kept.
+    capacity: usize,
+    samples: Mutex<VecDeque<StatsSample>>,
+}
+
+impl StatsHistory {
+    /// Keep samples taken every `interval` for at most `retention`.
+    pub fn new(interval: Duration, retention: Duration) -> Self {
+        let interval = interval.max(Duration::from_millis(1));
+        let capacity = (retention.as_nanos() / interval.as_nanos()).max(1) as usize;
+        Self {
+            interval,
+            capacity,
+            samples: Mutex::new(VecDeque::with_capacity(capacity.min(1024))),
+        }
+    }
+
+    /// Time between samples.
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    /// Add a sample, evicting the oldest if the history is full.
+    pub fn record(&self, sample: StatsSample) {
+        let mut samples = self.samples.lock();
+        if samples.len() == self.capacity {
+            samples.pop_front();
+        }
+        samples.push_back(sample);
+    }
+
+    /// All retained samples, oldest first.
+    pub fn samples(&self) -> Vec<StatsSample> {
+        self.samples.lock().iter().cloned().collect()
+    }
+}
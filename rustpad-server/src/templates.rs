@@ -0,0 +1,42 @@
+//! Starter content for new documents, chosen by language.
+
+use std::collections::HashMap;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+
+/// Template content keyed by editor language, such as `rust` or `html`.
+#[derive(Debug, Clone, Default)]
+pub struct LanguageTemplates {
+    templates: HashMap<String, String>,
+}
+
+impl LanguageTemplates {
+    /// Read every file in a directory as the template for the language named
+    /// by its file stem, so `rust.rs` and `rust` both hold the Rust template.
+    pub fn from_dir(dir: impl AsRef<Path>) -> Result<Self> {
+        let dir = dir.as_ref();
+        let mut templates = HashMap::new();
+        let entries = std::fs::read_dir(dir)
+            .with_context(|| format!("Failed to read template directory {:?}", dir))?;
+        for entry in entries {
+            let path = entry?.path();
+            if !path.is_file() {
+                continue;
+            }
+            let language = match path.file_stem().and_then(|stem| stem.to_str()) {
+                Some(language) => language.to_string(),
+                None => continue,
+            };
+            let content = std::fs::read_to_string(&path)
+                .with_context(|| format!("Failed to read template {:?}", path))?;
+            templates.insert(language, content);
+        }
+        Ok(Self { templates })
+    }
+
+    /// The template for a language, if one is configured.
+    pub fn get(&self, language: &str) -> Option<&str> {
+        self.templates.get(language).map(String::as_str)
+    }
+}
@@ -0,0 +1,212 @@
+//! Notifications POSTed to configured URLs when documents are frozen or AI
+//! chats complete, so integrators can react without polling.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::signer::Signer;
+
+/// Delay before the one retry of a failed delivery
+const RETRY_DELAY: Duration = Duration::from_millis(500);
+
+/// Where events are sent, and how
+#[derive(Debug, Clone)]
+pub struct WebhookConfig {
+    /// URLs that every event is POSTed to
+    pub urls: Vec<String>,
+    /// Secret for the `X-Rustpad-Signature` header, if events are signed
+    pub secret: Option<String>,
+    /// How long each delivery attempt may take
+    pub timeout: Duration,
+}
+
+impl Default for WebhookConfig {
+    fn default() -> Self {
+        Self {
+            urls: Vec::new(),
+            secret: None,
+            timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+impl WebhookConfig {
+    /// Read the configuration from the environment, if `WEBHOOK_URLS` lists
+    /// any URLs
+    pub fn from_env() -> Option<Self> {
+        let urls: Vec<String> = std::env::var("WEBHOOK_URLS")
+            .unwrap_or_default()
+            .split(',')
+            .map(|url| url.trim().to_string())
+            .filter(|url| !url.is_empty())
+            .collect();
+        if urls.is_empty() {
+            return None;
+        }
+        let secret = std::env::var("WEBHOOK_SECRET")
+            .ok()
+            .filter(|s| !s.is_empty());
+        if secret.is_none() {
+            warn!("WEBHOOK_URLS is set without WEBHOOK_SECRET, so events are unsigned");
+        }
+        let timeout = std::env::var("WEBHOOK_TIMEOUT_SECS")
+            .map(|s| s.parse().expect("Unable to parse WEBHOOK_TIMEOUT_SECS"))
+            .map(Duration::from_secs)
+            .unwrap_or(Duration::from_secs(5));
+        Some(Self {
+            urls,
+            secret,
+            timeout,
+        })
+    }
+}
+
+/// Something that happened, as sent to webhooks
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum WebhookEvent {
+    /// A document was frozen
+    DocumentFrozen {
+        /// Id of the document
+        document_id: String,
+        /// User who froze it
+        username: String,
+        /// Language it was frozen as
+        language: String,
+        /// Size of the frozen text in bytes
+        file_size: u64,
+        /// When the frozen copy expires
+        expires_at: DateTime<Utc>,
+    },
+    /// An AI chat request succeeded
+    AiChatCompleted {
+        /// Document sent as context, if any
+        document_id: Option<String>,
+        /// User who made the request
+        username: String,
+        /// Model that answered
+        model: String,
+        /// Tokens used, if reported
+        total_tokens: Option<u32>,
+    },
+}
+
+impl WebhookEvent {
+    /// Name of the event's type, as in its `type` field
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::DocumentFrozen { .. } => "document_frozen",
+            Self::AiChatCompleted { .. } => "ai_chat_completed",
+        }
+    }
+}
+
+/// Body of a webhook request
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WebhookPayload {
+    /// What happened
+    #[serde(flatten)]
+    pub event: WebhookEvent,
+    /// When it happened
+    pub timestamp: DateTime<Utc>,
+}
+
+/// Sends events to the configured URLs in the background
+pub struct WebhookDispatcher {
+    config: WebhookConfig,
+    client: reqwest::Client,
+    signer: Option<Signer>,
+}
+
+impl std::fmt::Debug for WebhookDispatcher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("WebhookDispatcher")
+            .field("urls", &self.config.urls)
+            .field("signed", &self.config.secret.is_some())
+            .finish()
+    }
+}
+
+impl WebhookDispatcher {
+    /// Create a dispatcher for the configured URLs
+    pub fn new(config: WebhookConfig) -> Result<Self> {
+        let client = reqwest::Client::builder()
+            .timeout(config.timeout)
+            .build()
+            .context("Failed to create HTTP client")?;
+        let signer = config.secret.as_ref().map(Signer::new);
+        Ok(Self {
+            config,
+            client,
+            signer,
+        })
+    }
+
+    /// Hex-encoded HMAC-SHA256 of a body, if a secret is configured
+    pub fn sign(&self, body: &[u8]) -> Option<String> {
+        Some(self.signer.as_ref()?.sign_hex(body))
+    }
+
+    /// Send an event to every URL without waiting for it to be delivered
+    ///
+    /// Each delivery that fails is retried once, and then given up on with a
+    /// warning.
+    pub fn dispatch(self: &Arc<Self>, event: WebhookEvent) {
+        let kind = event.kind();
+        let payload = WebhookPayload {
+            event,
+            timestamp: Utc::now(),
+        };
+        let body = match serde_json::to_vec(&payload) {
+            Ok(body) => Arc::new(body),
+            Err(e) => {
+                warn!("failed to serialize {} webhook: {}", kind, e);
+                return;
+            }
+        };
+        for url in &self.config.urls {
+            let (dispatcher, url, body) = (Arc::clone(self), url.clone(), Arc::clone(&body));
+            tokio::spawn(async move {
+                if let Err(e) = dispatcher.deliver(&url, kind, &body).await {
+                    warn!("giving up on {} webhook to {}: {:#}", kind, url, e);
+                }
+            });
+        }
+    }
+
+    /// POST a body to one URL, retrying once if it fails
+    async fn deliver(&self, url: &str, kind: &str, body: &[u8]) -> Result<()> {
+        match self.post(url, kind, body).await {
+            Ok(()) => Ok(()),
+            Err(e) => {
+                warn!("{} webhook to {} failed, retrying: {:#}", kind, url, e);
+                tokio::time::sleep(RETRY_DELAY).await;
+                self.post(url, kind, body).await
+            }
+        }
+    }
+
+    /// POST a body to one URL once
+    async fn post(&self, url: &str, kind: &str, body: &[u8]) -> Result<()> {
+        let mut request = self
+            .client
+            .post(url)
+            .header("Content-Type", "application/json")
+            .header("X-Rustpad-Event", kind)
+            .body(body.to_vec());
+        if let Some(signature) = self.sign(body) {
+            request = request.header("X-Rustpad-Signature", format!("sha256={}", signature));
+        }
+        let response = request.send().await.context("Failed to send webhook")?;
+        let status = response.status();
+        if !status.is_success() {
+            anyhow::bail!("webhook answered {}", status);
+        }
+        Ok(())
+    }
+}
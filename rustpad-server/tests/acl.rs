@@ -0,0 +1,176 @@
+//! Tests for separate read and write access to documents.
+
+use std::sync::Arc;
+
+use anyhow::Result;
+use common::*;
+use rustpad_server::{
+    acl::{Access, DocumentAcl},
+    auth::{AuthConfig, AuthManager},
+    ServerConfig,
+};
+use serde_json::{json, Value};
+use warp::{filters::BoxedFilter, Reply};
+
+pub mod common;
+
+const ALICE: &str = "Basic YWxpY2U6aHVudGVyMjI="; // alice:hunter22
+const BOB: &str = "Basic Ym9iOmh1bnRlcjIy"; // bob:hunter22
+
+fn users(names: &[&str]) -> Option<Vec<String>> {
+    Some(names.iter().map(|name| name.to_string()).collect())
+}
+
+#[test]
+fn test_access_combinations() {
+    let open = DocumentAcl::default();
+    assert_eq!(open.access(None), Access::Write);
+    assert_eq!(open.access(Some("alice")), Access::Write);
+
+    // A public demo: anyone can watch, only alice can edit.
+    let demo = DocumentAcl {
+        read: None,
+        write: users(&["alice"]),
+    };
+    assert_eq!(demo.access(Some("alice")), Access::Write);
+    assert_eq!(demo.access(Some("bob")), Access::Read);
+    assert_eq!(demo.access(None), Access::Read);
+
+    // Everyone who can read can write, when no write list is given.
+    let private = DocumentAcl {
+        read: users(&["alice"]),
+        write: None,
+    };
+    assert_eq!(private.access(Some("alice")), Access::Write);
+    assert_eq!(private.access(Some("bob")), Access::Denied);
+    assert_eq!(private.access(None), Access::Denied);
+
+    // Writers can read without being listed as readers.
+    let split = DocumentAcl {
+        read: users(&["bob"]),
+        write: users(&["alice"]),
+    };
+    assert_eq!(split.access(Some("alice")), Access::Write);
+    assert_eq!(split.access(Some("bob")), Access::Read);
+    assert_eq!(split.access(Some("carol")), Access::Denied);
+    assert_eq!(split.access(None), Access::Denied);
+
+    // Usernames match whatever case they are listed or logged in with.
+    let mixed = DocumentAcl {
+        read: users(&["Bob"]),
+        write: users(&["alice"]),
+    };
+    assert_eq!(mixed.access(Some("ALICE")), Access::Write);
+    assert_eq!(mixed.access(Some("bob")), Access::Read);
+
+    let frozen = DocumentAcl {
+        read: None,
+        write: users(&[]),
+    };
+    assert_eq!(frozen.access(Some("alice")), Access::Read);
+    assert_eq!(frozen.access(None), Access::Read);
+}
+
+async fn set_acl(
+    filter: &BoxedFilter<(impl Reply + 'static,)>,
+    auth: &str,
+    acl: Value,
+) -> u16 {
+    warp::test::request()
+        .method("PUT")
+        .path("/api/documents/doc/acl")
+        .header("Authorization", auth)
+        .json(&acl)
+        .reply(filter)
+        .await
+        .status()
+        .as_u16()
+}
+
+#[tokio::test]
+async fn test_document_acl() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let dir = tempfile::tempdir()?;
+    let auth_manager = Arc::new(AuthManager::new(AuthConfig {
+        enabled: true,
+        data_dir: dir.path().join("users"),
+        ..AuthConfig::default()
+    })?);
+    auth_manager.register("alice", "hunter22", false, false)?;
+    auth_manager.register("bob", "hunter22", false, false)?;
+    let alice = format!("doc?session={}", auth_manager.create_session("alice")?.id);
+    let bob = format!("doc?session={}", auth_manager.create_session("bob")?.id);
+
+    let config = ServerConfig {
+        auth_manager: Some(auth_manager),
+        ..ServerConfig::default()
+    };
+    let (database, filter) = database_server(&dir, config).await?;
+
+    let resp = warp::test::request()
+        .method("POST")
+        .path("/api/documents/new?id=doc")
+        .header("Authorization", ALICE)
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+
+    // Only the owner can change who has access.
+    let demo = json!({ "write": ["alice"] });
+    assert_eq!(set_acl(&filter, BOB, demo.clone()).await, 403);
+    assert_eq!(set_acl(&filter, ALICE, demo).await, 200);
+
+    let mut writer = connect(&filter, &alice).await?;
+    assert_eq!(writer.recv().await?, json!({ "Identity": 0 }));
+    writer
+        .send(&json!({ "Edit": { "revision": 0, "operation": ["hello"] } }))
+        .await;
+    writer.recv().await?;
+
+    let mut viewer = connect(&filter, &bob).await?;
+    assert_eq!(viewer.recv().await?, json!({ "Identity": 1 }));
+    assert_eq!(viewer.recv().await?, json!("ReadOnly"));
+    viewer.recv().await?; // History
+    viewer
+        .send(&json!({ "Edit": { "revision": 1, "operation": [5, " world"] } }))
+        .await;
+    viewer.recv_closed().await?;
+    expect_text(&filter, "doc", "hello").await;
+
+    let mut anonymous = connect(&filter, "doc").await?;
+    assert_eq!(anonymous.recv().await?, json!({ "Identity": 2 }));
+    assert_eq!(anonymous.recv().await?, json!("ReadOnly"));
+
+    // Restricting readers keeps out everyone else, including from the text.
+    let split = json!({ "read": ["bob"], "write": ["alice"] });
+    assert_eq!(set_acl(&filter, ALICE, split.clone()).await, 200);
+    assert!(connect(&filter, "doc").await.is_err());
+    let resp = warp::test::request().path("/api/text/doc").reply(&filter).await;
+    assert_eq!(resp.status(), 403);
+    let resp = warp::test::request()
+        .path("/api/text/doc")
+        .header("Authorization", BOB)
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.body(), "hello");
+
+    let mut viewer = connect(&filter, &bob).await?;
+    viewer.recv().await?;
+    assert_eq!(viewer.recv().await?, json!("ReadOnly"));
+
+    // Both lists are persisted along with the text.
+    let stored = database.load("doc").await?;
+    assert_eq!(stored.text, "hello");
+    assert_eq!(serde_json::to_value(&stored.acl)?, split);
+
+    // A private document, which only its readers may see and edit.
+    assert_eq!(set_acl(&filter, ALICE, json!({ "read": ["bob"] })).await, 200);
+    assert!(connect(&filter, &alice).await.is_err());
+    let mut editor = connect(&filter, &bob).await?;
+    editor.recv().await?;
+    let msg = editor.recv().await?;
+    assert!(msg.get("History").is_some(), "expected history, got {}", msg);
+
+    Ok(())
+}
@@ -0,0 +1,51 @@
+//! Tests for refusing edits that insert binary content.
+
+use anyhow::Result;
+use common::*;
+use rustpad_server::{server, ServerConfig};
+use serde_json::json;
+
+pub mod common;
+
+#[tokio::test]
+async fn test_reject_binary_content() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig {
+        reject_binary_content: true,
+        ..ServerConfig::default()
+    });
+
+    assert_eq!(edit(&filter, "binary", 0, json!(["hello"])).await?, None);
+    let refused = edit(&filter, "binary", 1, json!([5, "\u{0}"])).await?;
+    assert_eq!(
+        refused.as_deref(),
+        Some("edits may not insert binary or control characters")
+    );
+    assert!(edit(&filter, "binary", 1, json!(["\u{1b}[31m", 5]))
+        .await?
+        .is_some());
+    assert!(edit(&filter, "binary", 1, json!([5, "\u{7f}"]))
+        .await?
+        .is_some());
+    expect_text(&filter, "binary", "hello").await;
+
+    // Tabs and line breaks are ordinary text.
+    assert_eq!(
+        edit(&filter, "binary", 1, json!([5, "\tworld\r\n"])).await?,
+        None
+    );
+    expect_text(&filter, "binary", "hello\tworld\r\n").await;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_binary_content_allowed() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig::default());
+
+    assert_eq!(edit(&filter, "binary", 0, json!(["a\u{0}b"])).await?, None);
+    expect_text(&filter, "binary", "a\u{0}b").await;
+
+    Ok(())
+}
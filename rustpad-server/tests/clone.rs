@@ -0,0 +1,204 @@
+//! Tests for cloning and renaming documents.
+
+use anyhow::Result;
+use common::*;
+use rustpad_server::{
+    acl::DocumentAcl, database::PersistedDocument, metadata::DocumentMetadata, server, ServerConfig,
+};
+use serde_json::{json, Value};
+use warp::{filters::BoxedFilter, Reply};
+
+pub mod common;
+
+const ALICE: &str = "Basic YWxpY2U6aHVudGVyMjI="; // alice:hunter22
+const BOB: &str = "Basic Ym9iOmh1bnRlcjIy"; // bob:hunter22
+
+async fn clone(filter: &BoxedFilter<(impl Reply + 'static,)>, path: &str) -> (u16, Value) {
+    clone_with(filter, path, &[]).await
+}
+
+async fn clone_with(
+    filter: &BoxedFilter<(impl Reply + 'static,)>,
+    path: &str,
+    headers: &[(&str, &str)],
+) -> (u16, Value) {
+    let mut request = warp::test::request()
+        .method("POST")
+        .path(&format!("/api/documents/{}", path));
+    for (name, value) in headers {
+        request = request.header(*name, *value);
+    }
+    let resp = request.reply(filter).await;
+    let body = serde_json::from_slice(resp.body()).unwrap_or(Value::Null);
+    (resp.status().as_u16(), body)
+}
+
+#[tokio::test]
+async fn test_clone_document() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig::default());
+
+    let mut client = connect(&filter, "source").await?;
+    assert_eq!(client.recv().await?, json!({ "Identity": 0 }));
+    client
+        .send(&json!({ "Edit": { "revision": 0, "operation": ["hello"] } }))
+        .await;
+    client.recv().await?;
+    client.send(&json!({ "SetLanguage": "rust" })).await;
+    client.recv().await?;
+
+    // The copy starts from the source's text and language, and the two are
+    // edited independently.
+    let (status, body) = clone(&filter, "source/clone?to=fork").await;
+    assert_eq!((status, body), (200, json!({ "id": "fork" })));
+    let mut forked = connect(&filter, "fork").await?;
+    assert_eq!(forked.recv().await?, json!({ "Identity": 0 }));
+    assert!(forked.recv().await?.get("History").is_some());
+    assert_eq!(forked.recv().await?, json!({ "Language": "rust" }));
+    forked
+        .send(&json!({ "Edit": { "revision": 1, "operation": [5, " world"] } }))
+        .await;
+    forked.recv().await?;
+    expect_text(&filter, "fork", "hello world").await;
+    expect_text(&filter, "source", "hello").await;
+
+    // Ids in use are never overwritten, live or not.
+    let mut busy = connect(&filter, "busy").await?;
+    assert_eq!(busy.recv().await?, json!({ "Identity": 0 }));
+    assert_eq!(clone(&filter, "source/clone?to=busy").await.0, 409);
+    assert_eq!(clone(&filter, "source/clone?to=fork").await.0, 409);
+    expect_text(&filter, "fork", "hello world").await;
+
+    assert_eq!(clone(&filter, "source/clone?to=source").await.0, 400);
+    assert_eq!(clone(&filter, "source/clone?to=bad%20id").await.0, 400);
+    assert_eq!(clone(&filter, "missing/clone?to=other").await.0, 404);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_rename_document() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let dir = tempfile::tempdir()?;
+    let (database, filter) = database_server(&dir, ServerConfig::default()).await?;
+    let document = PersistedDocument {
+        text: "hello".into(),
+        language: Some("python".into()),
+        metadata: DocumentMetadata {
+            tags: vec!["draft".into()],
+            ..DocumentMetadata::default()
+        },
+        ..PersistedDocument::default()
+    };
+    database.store("old", &document).await?;
+
+    // A document that isn't loaded moves in the database, metadata and all.
+    let (status, body) = clone(&filter, "old/clone?to=new&rename=true").await;
+    assert_eq!((status, body), (200, json!({ "id": "new" })));
+    assert_eq!(database.load("new").await?, document);
+    assert!(!database.exists("old").await?);
+    expect_text(&filter, "new", "hello").await;
+
+    // A loaded one is removed from memory under its old id.
+    let (status, _) = clone(&filter, "new/clone?to=newer&rename=true").await;
+    assert_eq!(status, 200);
+    assert!(!database.exists("new").await?);
+    expect_text(&filter, "newer", "hello").await;
+    expect_text(&filter, "new", "").await;
+
+    // Documents are only renamed while nobody is connected to them.
+    let mut client = connect(&filter, "newer").await?;
+    assert_eq!(client.recv().await?, json!({ "Identity": 0 }));
+    assert_eq!(clone(&filter, "newer/clone?to=newest&rename=true").await.0, 409);
+    expect_text(&filter, "newer", "hello").await;
+    assert!(!database.exists("newest").await?);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_clone_access() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let dir = tempfile::tempdir()?;
+    let auth_manager = auth_manager(&dir)?;
+    auth_manager.register("alice", "hunter22", false, false)?;
+    auth_manager.register("bob", "hunter22", false, false)?;
+    let (database, filter) = database_server(
+        &dir,
+        ServerConfig {
+            auth_manager: Some(auth_manager),
+            max_documents_per_user: Some(1),
+            ..ServerConfig::default()
+        },
+    )
+    .await?;
+    let demo = DocumentAcl {
+        read: None,
+        write: Some(vec!["alice".into()]),
+    };
+    let shared = PersistedDocument {
+        text: "hello".into(),
+        acl: demo.clone(),
+        ..PersistedDocument::default()
+    };
+    database.store("shared", &shared).await?;
+    let locked = PersistedDocument {
+        text: "secret".into(),
+        password_hash: Some(bcrypt::hash("s3cret", 4)?),
+        ..PersistedDocument::default()
+    };
+    database.store("locked", &locked).await?;
+
+    // Renaming needs edit access, not just read access.
+    let bob = [("Authorization", BOB)];
+    let (status, _) = clone_with(&filter, "shared/clone?to=moved&rename=true", &bob).await;
+    assert_eq!(status, 403);
+    assert!(database.exists("shared").await?);
+    assert!(!database.exists("moved").await?);
+
+    // Readers can still clone it, but the copy keeps its access rules and
+    // belongs to them.
+    let (status, body) = clone_with(&filter, "shared/clone?to=copy", &bob).await;
+    assert_eq!((status, body), (200, json!({ "id": "copy" })));
+    let (status, body) = clone_with(&filter, "copy/clone?to=moved&rename=true", &bob).await;
+    assert_eq!(status, 403);
+    assert_eq!(
+        body,
+        json!({ "error": "Not allowed to edit this document" })
+    );
+    let alice = [("Authorization", ALICE)];
+    let (status, body) = clone_with(&filter, "copy/clone?to=moved&rename=true", &alice).await;
+    assert_eq!(status, 403);
+    assert_eq!(
+        body,
+        json!({ "error": "Only the document's owner can rename it" })
+    );
+
+    // Copies count towards their owner's limit.
+    let (status, body) = clone_with(&filter, "shared/clone?to=another", &bob).await;
+    assert_eq!(status, 403);
+    assert!(body["error"]
+        .as_str()
+        .unwrap_or_default()
+        .contains("at most 1"));
+
+    // The copy of a password-protected document needs the same password.
+    let (status, _) = clone_with(&filter, "locked/clone?to=unlocked", &alice).await;
+    assert_eq!(status, 401);
+    let unlocked = [("Authorization", ALICE), ("X-Document-Password", "s3cret")];
+    let (status, _) = clone_with(&filter, "locked/clone?to=unlocked", &unlocked).await;
+    assert_eq!(status, 200);
+    let resp = warp::test::request()
+        .path("/api/text/unlocked")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 401);
+    let resp = warp::test::request()
+        .path("/api/text/unlocked")
+        .header("X-Document-Password", "s3cret")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.body(), "secret");
+
+    Ok(())
+}
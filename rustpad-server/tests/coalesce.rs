@@ -0,0 +1,115 @@
+//! Tests for coalescing operations into fewer broadcast messages.
+
+use std::time::Duration;
+
+use anyhow::Result;
+use common::*;
+use operational_transform::OperationSeq;
+use rustpad_server::{server, ServerConfig};
+use serde_json::json;
+
+pub mod common;
+
+fn insert(text: &str) -> OperationSeq {
+    let mut operation = OperationSeq::default();
+    operation.insert(text);
+    operation
+}
+
+#[tokio::test]
+async fn test_coalesce_window() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig {
+        coalesce_window: Some(Duration::from_millis(100)),
+        ..ServerConfig::default()
+    });
+
+    let mut alice = connect(&filter, "burst").await?;
+    assert_eq!(alice.recv().await?, json!({ "Identity": 0 }));
+    let mut bob = connect(&filter, "burst").await?;
+    assert_eq!(bob.recv().await?, json!({ "Identity": 1 }));
+    let mut observer = connect(&filter, "burst").await?;
+    assert_eq!(observer.recv().await?, json!({ "Identity": 2 }));
+
+    alice.send(&json!({ "Edit": { "revision": 0, "operation": insert("a") } })).await;
+
+    // The author is acknowledged immediately.
+    let ack = alice.recv().await?;
+    assert_eq!(ack["History"]["start"], json!(0));
+    assert_eq!(ack["History"]["operations"][0]["id"], json!(0));
+
+    let mut operation = OperationSeq::default();
+    operation.retain(1);
+    operation.insert("b");
+    bob.send(&json!({ "Edit": { "revision": 1, "operation": operation } })).await;
+
+    // The observer receives both operations in a single message.
+    let msg = observer.recv().await?;
+    assert_eq!(msg["History"]["start"], json!(0));
+    assert_eq!(
+        msg["History"]["operations"].as_array().map(Vec::len),
+        Some(2)
+    );
+
+    expect_text(&filter, "burst", "ab").await;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_compose_same_author() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig {
+        coalesce_window: Some(Duration::from_millis(250)),
+        ..ServerConfig::default()
+    });
+
+    let mut alice = connect(&filter, "typing").await?;
+    assert_eq!(alice.recv().await?, json!({ "Identity": 0 }));
+    let mut observers = Vec::new();
+    for id in 1..=3 {
+        let mut observer = connect(&filter, "typing").await?;
+        assert_eq!(observer.recv().await?, json!({ "Identity": id }));
+        observers.push(observer);
+    }
+
+    // Alice types five characters, each acknowledged on its own.
+    for (revision, c) in "hello".chars().enumerate() {
+        let mut operation = OperationSeq::default();
+        operation.retain(revision as u64);
+        operation.insert(&c.to_string());
+        alice
+            .send(&json!({ "Edit": { "revision": revision, "operation": operation } }))
+            .await;
+        let ack = alice.recv().await?;
+        assert_eq!(ack["History"]["start"], json!(revision));
+        assert_eq!(ack["History"]["operations"][0]["id"], json!(0));
+    }
+
+    // Every other client receives one operation for the whole burst rather
+    // than five, so the broadcast volume is a fifth of what it was.
+    for observer in &mut observers {
+        let msg = observer.recv().await?;
+        assert_eq!(
+            msg,
+            json!({ "History": {
+                "start": 0,
+                "operations": [{ "id": 0, "operation": insert("hello"), "revisions": 5 }]
+            } })
+        );
+    }
+
+    // Later edits continue from the composed revisions.
+    let mut operation = OperationSeq::default();
+    operation.retain(5);
+    operation.insert("!");
+    let observer = &mut observers[0];
+    observer
+        .send(&json!({ "Edit": { "revision": 5, "operation": operation } }))
+        .await;
+    let ack = observer.recv().await?;
+    assert_eq!(ack["History"]["start"], json!(5));
+    expect_text(&filter, "typing", "hello!").await;
+
+    Ok(())
+}
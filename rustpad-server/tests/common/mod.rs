@@ -1,6 +1,15 @@
+use std::sync::Arc;
+
 use anyhow::{anyhow, Result};
-use serde_json::Value;
-use warp::{filters::BoxedFilter, test::WsClient, Reply};
+use rustpad_server::{
+    auth::{AuthConfig, AuthManager},
+    database::Database,
+    freeze::{FreezeConfig, FreezeManager},
+    server, ServerConfig,
+};
+use serde_json::{json, Value};
+use tempfile::TempDir;
+use warp::{filters::BoxedFilter, reply::Response, test::WsClient, Filter, Reply};
 
 /// A test WebSocket client that sends and receives JSON messages.
 pub struct JsonSocket(WsClient);
@@ -16,6 +25,12 @@ impl JsonSocket {
         Ok(serde_json::from_str(msg)?)
     }
 
+    pub async fn recv_close_frame(&mut self) -> Result<(u16, Value)> {
+        let msg = self.0.recv().await?;
+        let (code, reason) = msg.close_frame().ok_or_else(|| anyhow!("not a close frame"))?;
+        Ok((code, serde_json::from_str(reason)?))
+    }
+
     pub async fn recv_closed(&mut self) -> Result<()> {
         self.0.recv_closed().await.map_err(|e| e.into())
     }
@@ -42,3 +57,79 @@ pub async fn expect_text(filter: &BoxedFilter<(impl Reply + 'static,)>, id: &str
     assert_eq!(resp.status(), 200);
     assert_eq!(resp.body(), text);
 }
+
+/// Send one edit to a document from a new connection, returning the reason it
+/// was refused, if it was.
+///
+/// A refused edit is answered with `Rejected`, and the connection is closed
+/// so the author reloads the document.
+pub async fn edit(
+    filter: &BoxedFilter<(impl Reply + 'static,)>,
+    id: &str,
+    revision: usize,
+    operation: Value,
+) -> Result<Option<String>> {
+    let mut client = connect(filter, id).await?;
+    client.recv().await?; // Identity
+    if revision > 0 {
+        client.recv().await?; // History
+    }
+    client
+        .send(&json!({ "Edit": { "revision": revision, "operation": operation } }))
+        .await;
+    let msg = client.recv().await?;
+    if let Some(reason) = msg.get("Rejected") {
+        let (_, close) = client.recv_close_frame().await?;
+        assert_eq!(close["reason"], "rejected");
+        return Ok(Some(reason.as_str().unwrap().to_string()));
+    }
+    assert!(
+        msg.get("History").is_some(),
+        "expected history, got {}",
+        msg
+    );
+    Ok(None)
+}
+
+/// An enabled authentication manager storing its users in `dir`, with the
+/// rest of its settings taken from `config`.
+pub fn auth_manager_with(dir: &TempDir, config: AuthConfig) -> Result<AuthManager> {
+    AuthManager::new(AuthConfig {
+        enabled: true,
+        data_dir: dir.path().join("users"),
+        ..config
+    })
+}
+
+/// An enabled authentication manager storing its users in `dir`.
+pub fn auth_manager(dir: &TempDir) -> Result<Arc<AuthManager>> {
+    Ok(Arc::new(auth_manager_with(dir, AuthConfig::default())?))
+}
+
+/// Enabled freeze and authentication managers storing their data in `dir`,
+/// with `alice` registered with the password `hunter22` and AI access.
+pub fn setup(dir: &TempDir) -> Result<(Arc<FreezeManager>, Arc<AuthManager>)> {
+    let freeze_manager = Arc::new(FreezeManager::new(FreezeConfig {
+        enabled: true,
+        save_dir: dir.path().to_path_buf(),
+        ..FreezeConfig::default()
+    })?);
+    let auth_manager = auth_manager(dir)?;
+    auth_manager.register("alice", "hunter22", true, false)?;
+    Ok((freeze_manager, auth_manager))
+}
+
+/// A server persisting to a new SQLite database in `dir`, with the rest of
+/// its settings taken from `config`, along with the database.
+pub async fn database_server(
+    dir: &TempDir,
+    config: ServerConfig,
+) -> Result<(Database, BoxedFilter<(Response,)>)> {
+    let uri = format!("sqlite://{}", dir.path().join("rustpad.db").display());
+    let database = Database::new(&uri).await?;
+    let filter = server(ServerConfig {
+        database: Some(database.clone()),
+        ..config
+    });
+    Ok((database, filter.map(Reply::into_response).boxed()))
+}
@@ -0,0 +1,118 @@
+//! Tests for compaction of long operation histories.
+
+use anyhow::Result;
+use common::*;
+use operational_transform::OperationSeq;
+use rustpad_server::{server, ServerConfig};
+use serde_json::json;
+
+pub mod common;
+
+#[tokio::test]
+async fn test_compaction() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig {
+        max_revisions: Some(4),
+        ..ServerConfig::default()
+    });
+
+    let mut client = connect(&filter, "compact").await?;
+    assert_eq!(client.recv().await?, json!({ "Identity": 0 }));
+
+    for revision in 0..10 {
+        let mut operation = OperationSeq::default();
+        operation.retain(revision);
+        operation.insert("a");
+        let msg = json!({
+            "Edit": {
+                "revision": revision,
+                "operation": operation
+            }
+        });
+        client.send(&msg).await;
+
+        let msg = client.recv().await?;
+        assert_eq!(msg["History"]["start"], json!(revision));
+    }
+    expect_text(&filter, "compact", "aaaaaaaaaa").await;
+
+    let mut client2 = connect(&filter, "compact").await?;
+    assert_eq!(client2.recv().await?, json!({ "Identity": 1 }));
+    let msg = client2.recv().await?;
+    let start = msg["History"]["start"].as_u64().expect("start is a number");
+    let operations = msg["History"]["operations"]
+        .as_array()
+        .expect("operations is an array");
+    assert!(start > 0, "history should have been compacted");
+    assert!(operations.len() <= 4);
+    assert_eq!(start + operations.len() as u64, 10);
+    assert_eq!(operations[0], json!({ "id": u64::MAX, "operation": ["aaaaaaa"] }));
+
+    // The original client keeps editing at its current revision.
+    let mut operation = OperationSeq::default();
+    operation.retain(10);
+    operation.insert("b");
+    let msg = json!({
+        "Edit": {
+            "revision": 10,
+            "operation": operation
+        }
+    });
+    client.send(&msg).await;
+    assert_eq!(client.recv().await?["History"]["start"], json!(10));
+    assert_eq!(client2.recv().await?["History"]["start"], json!(10));
+    expect_text(&filter, "compact", "aaaaaaaaaab").await;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_compaction_keeps_held_history() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig {
+        max_revisions: Some(4),
+        ..ServerConfig::default()
+    });
+
+    let mut client = connect(&filter, "held").await?;
+    assert_eq!(client.recv().await?, json!({ "Identity": 0 }));
+    let mut client2 = connect(&filter, "held").await?;
+    assert_eq!(client2.recv().await?, json!({ "Identity": 1 }));
+
+    for revision in 0..10 {
+        let mut operation = OperationSeq::default();
+        operation.retain(revision);
+        operation.insert("a");
+        let msg = json!({
+            "Edit": {
+                "revision": revision,
+                "operation": operation
+            }
+        });
+        client.send(&msg).await;
+        assert_eq!(client.recv().await?["History"]["start"], json!(revision));
+    }
+    expect_text(&filter, "held", "aaaaaaaaaa").await;
+
+    // The second client hasn't edited yet, so its edit against the empty
+    // document is still transformed rather than refused.
+    let mut operation = OperationSeq::default();
+    operation.insert("b");
+    let msg = json!({
+        "Edit": {
+            "revision": 0,
+            "operation": operation
+        }
+    });
+    client2.send(&msg).await;
+    expect_text(&filter, "held", "baaaaaaaaaa").await;
+
+    // Once it has, older history is compacted again.
+    let mut client3 = connect(&filter, "held").await?;
+    assert_eq!(client3.recv().await?, json!({ "Identity": 2 }));
+    let msg = client3.recv().await?;
+    let start = msg["History"]["start"].as_u64().expect("start is a number");
+    assert!(start > 0, "history should have been compacted");
+
+    Ok(())
+}
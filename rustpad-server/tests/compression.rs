@@ -0,0 +1,90 @@
+//! Tests for compressing large operations sent to clients.
+
+pub mod common;
+
+use std::io::Read;
+
+use anyhow::Result;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use common::*;
+use operational_transform::OperationSeq;
+use rustpad_server::{server, ServerConfig};
+use serde_json::{json, Value};
+
+/// Decode an operation as a client would, whether or not it was compressed.
+fn decode(op: &Value) -> Result<Value> {
+    match op["compressed"].as_str() {
+        Some(compressed) => {
+            let bytes = STANDARD.decode(compressed)?;
+            let mut json = String::new();
+            flate2::read::GzDecoder::new(&bytes[..]).read_to_string(&mut json)?;
+            Ok(serde_json::from_str(&json)?)
+        }
+        None => Ok(op["operation"].clone()),
+    }
+}
+
+#[tokio::test]
+async fn test_large_paste() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig {
+        compression_threshold: Some(1024),
+        ..ServerConfig::default()
+    });
+
+    let mut writer = connect(&filter, "paste").await?;
+    assert_eq!(writer.recv().await?, json!({ "Identity": 0 }));
+
+    let paste = "fn main() {\n    println!(\"Hello, world!\");\n}\n".repeat(2000);
+    let mut operation = OperationSeq::default();
+    operation.insert(&paste);
+    writer
+        .send(&json!({ "Edit": { "revision": 0, "operation": operation } }))
+        .await;
+
+    let msg = writer.recv().await?;
+    let op = &msg["History"]["operations"][0];
+    assert_eq!(op["id"], 0);
+    assert!(op.get("operation").is_none());
+    assert!(op["compressed"].as_str().unwrap().len() < paste.len() / 10);
+    assert_eq!(decode(op)?, json!([paste]));
+
+    // Small edits are sent as they are.
+    let mut operation = OperationSeq::default();
+    operation.retain(paste.len() as u64);
+    operation.insert("// end");
+    writer
+        .send(&json!({ "Edit": { "revision": 1, "operation": operation } }))
+        .await;
+    let msg = writer.recv().await?;
+    assert_eq!(
+        msg["History"]["operations"][0]["operation"],
+        json!([paste.len(), "// end"])
+    );
+
+    // A client joining later gets the same history, and its concurrent edit
+    // is transformed against the decoded operations as usual.
+    let mut reader = connect(&filter, "paste").await?;
+    assert_eq!(reader.recv().await?, json!({ "Identity": 1 }));
+    let msg = reader.recv().await?;
+    let operations = msg["History"]["operations"].as_array().unwrap();
+    assert_eq!(operations.len(), 2);
+    assert_eq!(decode(&operations[0])?, json!([paste]));
+    assert_eq!(decode(&operations[1])?, json!([paste.len(), "// end"]));
+
+    let mut operation = OperationSeq::default();
+    operation.insert("// start\n");
+    operation.retain(paste.len() as u64);
+    reader
+        .send(&json!({ "Edit": { "revision": 1, "operation": operation } }))
+        .await;
+    let msg = reader.recv().await?;
+    assert_eq!(
+        msg["History"]["operations"][0]["operation"],
+        json!(["// start\n", paste.len() + 6])
+    );
+
+    expect_text(&filter, "paste", &format!("// start\n{}// end", paste)).await;
+
+    Ok(())
+}
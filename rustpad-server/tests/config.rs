@@ -0,0 +1,75 @@
+//! Tests for the public configuration endpoint.
+
+use std::sync::Arc;
+
+use anyhow::Result;
+use common::*;
+use rustpad_server::{
+    auth::{AuthConfig, AuthManager},
+    server, Branding, ServerConfig,
+};
+use serde_json::{json, Value};
+
+pub mod common;
+
+#[tokio::test]
+async fn test_default_config() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig::default());
+
+    let resp = warp::test::request().path("/api/config").reply(&filter).await;
+    assert_eq!(resp.status(), 200);
+    let config: Value = serde_json::from_slice(resp.body())?;
+    assert_eq!(
+        config,
+        json!({
+            "instance_name": "Rustpad",
+            "theme_color": null,
+            "default_language": null,
+            "auth_enabled": false,
+            "features": { "ai": false, "artifacts": false, "freeze": false, "lint": false },
+            "limits": { "max_frozen_size": null, "max_documents_per_user": null },
+        })
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_branding() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let dir = tempfile::tempdir()?;
+    let auth_manager = Arc::new(AuthManager::new(AuthConfig {
+        enabled: true,
+        data_dir: dir.path().join("users"),
+        ..AuthConfig::default()
+    })?);
+    let filter = server(ServerConfig {
+        auth_manager: Some(auth_manager),
+        max_documents_per_user: Some(5),
+        branding: Branding {
+            instance_name: Some("Acme Pad".into()),
+            theme_color: Some("#ff6600".into()),
+            default_language: Some("rust".into()),
+        },
+        ..ServerConfig::default()
+    });
+
+    // No credentials are needed to read it.
+    let resp = warp::test::request().path("/api/config").reply(&filter).await;
+    assert_eq!(resp.status(), 200);
+    let config: Value = serde_json::from_slice(resp.body())?;
+    assert_eq!(config["instance_name"], "Acme Pad");
+    assert_eq!(config["theme_color"], "#ff6600");
+    assert_eq!(config["default_language"], "rust");
+    assert_eq!(config["auth_enabled"], true);
+    assert_eq!(config["limits"]["max_documents_per_user"], 5);
+
+    // New documents start in the default language.
+    let mut client = connect(&filter, "branded").await?;
+    assert_eq!(client.recv().await?, json!({ "Identity": 0 }));
+    client.recv().await?; // History
+    assert_eq!(client.recv().await?, json!({ "Language": "rust" }));
+
+    Ok(())
+}
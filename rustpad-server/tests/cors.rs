@@ -0,0 +1,81 @@
+//! Tests for cross-origin requests to the API.
+
+use anyhow::Result;
+use common::*;
+use rustpad_server::{server, ServerConfig};
+use serde_json::json;
+
+pub mod common;
+
+const ORIGIN: &str = "http://localhost:5173";
+
+#[tokio::test]
+async fn test_cors() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig {
+        cors_origins: vec![ORIGIN.into()],
+        ..ServerConfig::default()
+    });
+
+    // Preflight for an authenticated, streaming AI request.
+    let resp = warp::test::request()
+        .method("OPTIONS")
+        .path("/api/ai/chat/stream")
+        .header("origin", ORIGIN)
+        .header("access-control-request-method", "POST")
+        .header("access-control-request-headers", "authorization, content-type")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.headers()["access-control-allow-origin"], ORIGIN);
+    assert_eq!(resp.headers()["access-control-allow-credentials"], "true");
+
+    let resp = warp::test::request()
+        .path("/api/stats")
+        .header("origin", ORIGIN)
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.headers()["access-control-allow-origin"], ORIGIN);
+
+    let resp = warp::test::request()
+        .path("/api/stats")
+        .header("origin", "https://evil.example.com")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 403);
+
+    let resp = warp::test::request()
+        .path("/api/socket/foobar")
+        .header("connection", "upgrade")
+        .header("upgrade", "websocket")
+        .header("sec-websocket-version", "13")
+        .header("sec-websocket-key", "dGhlIHNhbXBsZSBub25jZQ==")
+        .header("origin", ORIGIN)
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 101);
+    assert_eq!(resp.headers()["access-control-allow-origin"], ORIGIN);
+
+    // Clients that send no origin are unaffected.
+    let mut client = connect(&filter, "foobar").await?;
+    assert_eq!(client.recv().await?, json!({ "Identity": 0 }));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_cors_disabled() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig::default());
+
+    let resp = warp::test::request()
+        .path("/api/stats")
+        .header("origin", ORIGIN)
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+    assert!(!resp.headers().contains_key("access-control-allow-origin"));
+
+    Ok(())
+}
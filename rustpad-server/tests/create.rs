@@ -0,0 +1,322 @@
+//! Tests for creating documents with collision-safe ids.
+
+use std::sync::Arc;
+
+use anyhow::Result;
+use common::*;
+use rustpad_server::{
+    auth::{AuthConfig, AuthManager},
+    server,
+    templates::LanguageTemplates,
+    ServerConfig,
+};
+use serde_json::{json, Value};
+
+pub mod common;
+
+#[tokio::test]
+async fn test_new_document() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig::default());
+
+    let resp = warp::test::request()
+        .method("POST")
+        .path("/api/documents/new")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+    let body: Value = serde_json::from_slice(resp.body())?;
+    let id = body["id"].as_str().expect("id is a string");
+    assert_eq!(id.len(), 6);
+    expect_text(&filter, id, "").await;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_requested_id_conflict() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig::default());
+
+    let resp = warp::test::request()
+        .method("POST")
+        .path("/api/documents/new?id=mine")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(serde_json::from_slice::<Value>(resp.body())?, json!({ "id": "mine" }));
+
+    let resp = warp::test::request()
+        .method("POST")
+        .path("/api/documents/new?id=mine")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 409);
+    let error = json!({ "error": "Document already exists" });
+    assert_eq!(serde_json::from_slice::<Value>(resp.body())?, error);
+
+    let mut client = connect(&filter, "taken").await?;
+    assert_eq!(client.recv().await?, json!({ "Identity": 0 }));
+    let resp = warp::test::request()
+        .method("POST")
+        .path("/api/documents/new?id=taken")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 409);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_default_content() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig {
+        default_content: Some("Welcome!\n".into()),
+        ..ServerConfig::default()
+    });
+
+    let mut client = connect(&filter, "fresh").await?;
+    assert_eq!(client.recv().await?, json!({ "Identity": 0 }));
+    assert_eq!(
+        client.recv().await?,
+        json!({
+            "History": {
+                "start": 0,
+                "operations": [
+                    { "id": u64::MAX, "operation": ["Welcome!\n"] }
+                ]
+            }
+        })
+    );
+    expect_text(&filter, "fresh", "Welcome!\n").await;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_user_document_limit() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let dir = tempfile::tempdir()?;
+    let auth_manager = Arc::new(AuthManager::new(AuthConfig {
+        enabled: true,
+        data_dir: dir.path().join("users"),
+        ..AuthConfig::default()
+    })?);
+    auth_manager.register("alice", "hunter22", false, false)?;
+    auth_manager.register("admin", "hunter22", false, true)?;
+    auth_manager.register("bob", "hunter22", false, false)?;
+
+    let filter = server(ServerConfig {
+        auth_manager: Some(auth_manager),
+        max_documents_per_user: Some(2),
+        ..ServerConfig::default()
+    });
+
+    let create = |auth: Option<&'static str>| {
+        let mut request = warp::test::request().method("POST").path("/api/documents/new");
+        if let Some(auth) = auth {
+            request = request.header("Authorization", auth);
+        }
+        request.reply(&filter)
+    };
+    let alice = Some("Basic YWxpY2U6aHVudGVyMjI="); // alice:hunter22
+    let admin = Some("Basic YWRtaW46aHVudGVyMjI="); // admin:hunter22
+
+    assert_eq!(create(alice).await.status(), 200);
+    assert_eq!(create(alice).await.status(), 200);
+    let resp = create(alice).await;
+    assert_eq!(resp.status(), 403);
+    assert!(std::str::from_utf8(resp.body())?.contains("at most 2"));
+
+    // Admins and anonymous users are not limited.
+    for _ in 0..3 {
+        assert_eq!(create(admin).await.status(), 200);
+        assert_eq!(create(None).await.status(), 200);
+    }
+
+    // Concurrent requests can't all slip in under the limit.
+    let bob = Some("Basic Ym9iOmh1bnRlcjIy"); // bob:hunter22
+    let responses = futures::future::join_all((0..5).map(|_| create(bob))).await;
+    let created = responses.iter().filter(|resp| resp.status() == 200).count();
+    assert_eq!(created, 2);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_language_templates() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let dir = tempfile::tempdir()?;
+    std::fs::write(dir.path().join("rust.rs"), "fn main() {}\n")?;
+    std::fs::write(dir.path().join("html"), "<!DOCTYPE html>\n")?;
+
+    let filter = server(ServerConfig {
+        default_content: Some("Welcome!\n".into()),
+        language_templates: Some(Arc::new(LanguageTemplates::from_dir(dir.path())?)),
+        ..ServerConfig::default()
+    });
+
+    let create = |query: &'static str| {
+        warp::test::request()
+            .method("POST")
+            .path(&format!("/api/documents/new?{}", query))
+            .reply(&filter)
+    };
+    for query in [
+        "id=rusty&language=rust",
+        "id=page&language=html",
+        "id=script&language=python",
+        "id=plain",
+    ] {
+        assert_eq!(create(query).await.status(), 200);
+    }
+
+    expect_text(&filter, "rusty", "fn main() {}\n").await;
+    expect_text(&filter, "page", "<!DOCTYPE html>\n").await;
+    // Without a template, the language is still set but the text is empty.
+    expect_text(&filter, "script", "").await;
+    expect_text(&filter, "plain", "Welcome!\n").await;
+
+    let mut client = connect(&filter, "script").await?;
+    assert_eq!(client.recv().await?, json!({ "Identity": 0 }));
+    assert!(client.recv().await?.get("History").is_some());
+    assert_eq!(client.recv().await?, json!({ "Language": "python" }));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_import_document() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig {
+        default_content: Some("Welcome!\n".into()),
+        ..ServerConfig::default()
+    });
+
+    // A raw body takes its language from the file name in the query.
+    let resp = warp::test::request()
+        .method("POST")
+        .path("/api/documents/script/import?filename=hello.py")
+        .body("print('hello')\n")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(
+        serde_json::from_slice::<Value>(resp.body())?,
+        json!({ "id": "script", "language": "python" })
+    );
+    expect_text(&filter, "script", "print('hello')\n").await;
+
+    let mut client = connect(&filter, "script").await?;
+    assert_eq!(client.recv().await?, json!({ "Identity": 0 }));
+    assert!(client.recv().await?.get("History").is_some());
+    assert_eq!(client.recv().await?, json!({ "Language": "python" }));
+
+    // Multipart forms carry the file name in the `file` part.
+    let boundary = "rustpad-boundary";
+    let form = format!(
+        "--{0}\r\nContent-Disposition: form-data; name=\"file\"; filename=\"main.rs\"\r\n\
+         Content-Type: text/plain\r\n\r\nfn main() {{}}\n\r\n--{0}--\r\n",
+        boundary
+    );
+    let resp = warp::test::request()
+        .method("POST")
+        .path("/api/documents/rusty/import")
+        .header("Content-Type", format!("multipart/form-data; boundary={}", boundary))
+        .body(form)
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(
+        serde_json::from_slice::<Value>(resp.body())?,
+        json!({ "id": "rusty", "language": "rust" })
+    );
+    expect_text(&filter, "rusty", "fn main() {}\n").await;
+
+    // Existing documents are never overwritten, even when just opened.
+    let resp = warp::test::request()
+        .method("POST")
+        .path("/api/documents/script/import")
+        .body("clobbered")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 409);
+    expect_text(&filter, "script", "print('hello')\n").await;
+    let mut client = connect(&filter, "fresh").await?;
+    assert_eq!(client.recv().await?, json!({ "Identity": 0 }));
+    let resp = warp::test::request()
+        .method("POST")
+        .path("/api/documents/fresh/import")
+        .body("clobbered")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 409);
+
+    let resp = warp::test::request()
+        .method("POST")
+        .path("/api/documents/binary/import")
+        .body(vec![0xff, 0xfe, 0x00])
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 400);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_seed_document() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig::default());
+    let create = |query: &'static str| {
+        warp::test::request()
+            .method("POST")
+            .path(&format!("/api/documents/new?{}", query))
+            .reply(&filter)
+    };
+
+    let mut client = connect(&filter, "source").await?;
+    assert_eq!(client.recv().await?, json!({ "Identity": 0 }));
+    client
+        .send(&json!({ "Edit": { "revision": 0, "operation": ["hello"] } }))
+        .await;
+    client.recv().await?;
+    client
+        .send(&json!({ "Edit": { "revision": 1, "operation": [5, " world"] } }))
+        .await;
+    client.recv().await?;
+    client.send(&json!({ "SetLanguage": "rust" })).await;
+    client.recv().await?;
+
+    // A seeded document starts from the seed's text and language.
+    let resp = create("id=copy&seed=source").await;
+    assert_eq!(resp.status(), 200);
+    let mut copy = connect(&filter, "copy").await?;
+    assert_eq!(copy.recv().await?, json!({ "Identity": 0 }));
+    let history = copy.recv().await?;
+    assert_eq!(history["History"]["start"], 0);
+    assert_eq!(history["History"]["operations"].as_array().unwrap().len(), 1);
+    assert_eq!(copy.recv().await?, json!({ "Language": "rust" }));
+    expect_text(&filter, "copy", "hello world").await;
+
+    // With its history, revisions carry on from the seed's.
+    let resp = create("id=continued&seed=source&history=true").await;
+    assert_eq!(resp.status(), 200);
+    let mut continued = connect(&filter, "continued").await?;
+    assert_eq!(continued.recv().await?, json!({ "Identity": 0 }));
+    let history = continued.recv().await?;
+    let operations = history["History"]["operations"].as_array().unwrap();
+    assert_eq!(operations.len(), 2);
+    assert!(operations.iter().all(|op| op["id"] == json!(u64::MAX)));
+    assert_eq!(continued.recv().await?, json!({ "Language": "rust" }));
+    continued
+        .send(&json!({ "Edit": { "revision": 2, "operation": [11, "!"] } }))
+        .await;
+    continued.recv().await?;
+    expect_text(&filter, "continued", "hello world!").await;
+    expect_text(&filter, "source", "hello world").await;
+
+    assert_eq!(create("seed=missing").await.status(), 404);
+    assert_eq!(create("seed=source&language=python").await.status(), 400);
+
+    Ok(())
+}
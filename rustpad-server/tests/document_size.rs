@@ -0,0 +1,61 @@
+//! Tests for the limit on how large edits may grow a document.
+
+use anyhow::Result;
+use common::*;
+use rustpad_server::{server, ServerConfig};
+use serde_json::json;
+
+pub mod common;
+
+#[tokio::test]
+async fn test_max_document_bytes() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig {
+        max_document_bytes: Some(10),
+        ..ServerConfig::default()
+    });
+
+    let mut viewer = connect(&filter, "sized").await?;
+    assert_eq!(viewer.recv().await?, json!({ "Identity": 0 }));
+
+    assert_eq!(edit(&filter, "sized", 0, json!(["hello"])).await?, None);
+    viewer.recv().await?;
+    let refused = edit(&filter, "sized", 1, json!([5, " world"])).await?;
+    assert_eq!(
+        refused.as_deref(),
+        Some("documents are limited to 10 bytes")
+    );
+    expect_text(&filter, "sized", "hello").await;
+
+    // Size is measured in bytes, not characters.
+    assert!(edit(&filter, "sized", 1, json!([5, "ééé"]))
+        .await?
+        .is_some());
+    assert_eq!(edit(&filter, "sized", 1, json!([5, "éé"])).await?, None);
+    expect_text(&filter, "sized", "helloéé").await;
+
+    // Other connections never see the refused edits.
+    let msg = viewer.recv().await?;
+    assert_eq!(msg["History"]["start"], 1);
+    assert_eq!(msg["History"]["operations"].as_array().unwrap().len(), 1);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_existing_large_document() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig {
+        default_content: Some("0123456789abcdef".into()),
+        max_document_bytes: Some(10),
+        ..ServerConfig::default()
+    });
+
+    // A document that is already too large may shrink, but not grow.
+    assert!(edit(&filter, "sized", 1, json!([16, "x"])).await?.is_some());
+    assert_eq!(edit(&filter, "sized", 1, json!([10, -6])).await?, None);
+    assert!(edit(&filter, "sized", 2, json!([10, "x"])).await?.is_some());
+    expect_text(&filter, "sized", "0123456789").await;
+
+    Ok(())
+}
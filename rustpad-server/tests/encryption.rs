@@ -0,0 +1,134 @@
+//! Tests for end-to-end encrypted documents, which the server only relays.
+
+use anyhow::Result;
+use common::*;
+use rustpad_server::{server, ServerConfig};
+use serde_json::{json, Value};
+
+pub mod common;
+
+#[tokio::test]
+async fn test_encrypted_disabled() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig::default());
+
+    let resp = warp::test::request()
+        .method("POST")
+        .path("/api/documents/new?id=secret&encrypted=true")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 403);
+
+    // Plain documents can't take sealed operations either.
+    let mut client = connect(&filter, "plain").await?;
+    assert_eq!(client.recv().await?, json!({ "Identity": 0 }));
+    client
+        .send(&json!({ "Sealed": { "revision": 0, "ciphertext": "AAAA" } }))
+        .await;
+    client.recv_closed().await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_encrypted_relay() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let dir = tempfile::tempdir()?;
+    let config = ServerConfig {
+        encrypted_documents: true,
+        default_content: Some("Welcome!".into()),
+        ..ServerConfig::default()
+    };
+    let (database, filter) = database_server(&dir, config).await?;
+
+    let resp = warp::test::request()
+        .method("POST")
+        .path("/api/documents/new?id=secret&encrypted=true")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+
+    // Encrypted documents start empty, without the default content.
+    let mut alice = connect(&filter, "secret").await?;
+    assert_eq!(alice.recv().await?, json!({ "Identity": 0 }));
+    assert_eq!(alice.recv().await?, json!("Encrypted"));
+    alice
+        .send(&json!({ "Sealed": { "revision": 0, "ciphertext": "c1" } }))
+        .await;
+    let msg = alice.recv().await?;
+    assert_eq!(
+        msg,
+        json!({ "History": {
+            "start": 0,
+            "operations": [{ "id": 0, "ciphertext": "c1" }]
+        }})
+    );
+
+    let mut bob = connect(&filter, "secret").await?;
+    assert_eq!(bob.recv().await?, json!({ "Identity": 1 }));
+    assert_eq!(bob.recv().await?, json!("Encrypted"));
+    assert_eq!(bob.recv().await?, msg);
+
+    // Key negotiation is relayed to everyone, the sender included.
+    bob.send(&json!({ "KeyExchange": "bob-public-key" })).await;
+    let msg = json!({ "KeyExchange": { "id": 1, "payload": "bob-public-key" } });
+    assert_eq!(alice.recv().await?, msg);
+    assert_eq!(bob.recv().await?, msg);
+
+    // The first operation at a revision wins, and later ones are dropped.
+    bob.send(&json!({ "Sealed": { "revision": 1, "ciphertext": "c2" } }))
+        .await;
+    let msg = bob.recv().await?;
+    assert_eq!(msg["History"]["operations"][0]["id"], 1);
+    assert_eq!(alice.recv().await?, msg);
+    alice
+        .send(&json!({ "Sealed": { "revision": 1, "ciphertext": "stale" } }))
+        .await;
+    alice
+        .send(&json!({
+            "Sealed": { "revision": 2, "ciphertext": "c3", "snapshot": true }
+        }))
+        .await;
+    let snapshot = json!({ "id": 0, "ciphertext": "c3", "snapshot": true });
+    let msg = json!({ "History": { "start": 2, "operations": [snapshot] } });
+    assert_eq!(alice.recv().await?, msg);
+    assert_eq!(bob.recv().await?, msg);
+
+    // Plain edits are refused, since the server can't transform them.
+    bob.send(&json!({ "Edit": { "revision": 3, "operation": ["x"] } }))
+        .await;
+    bob.recv_closed().await?;
+
+    // Endpoints that would need the text refuse, rather than serve ciphertext.
+    let resp = warp::test::request()
+        .path("/api/text/secret")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 409);
+
+    // Only the history since the latest snapshot is stored.
+    let resp = warp::test::request()
+        .method("POST")
+        .path("/api/documents/secret/snapshot")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+    let stored = database.load("secret").await?;
+    assert!(stored.encrypted);
+    assert_eq!(serde_json::from_str::<Value>(&stored.text)?, json!([snapshot]));
+
+    // A fresh server rebuilds the document from that history.
+    let filter = server(ServerConfig {
+        database: Some(database),
+        ..ServerConfig::default()
+    });
+    let mut carol = connect(&filter, "secret").await?;
+    assert_eq!(carol.recv().await?, json!({ "Identity": 0 }));
+    assert_eq!(carol.recv().await?, json!("Encrypted"));
+    assert_eq!(
+        carol.recv().await?,
+        json!({ "History": { "start": 0, "operations": [snapshot] } })
+    );
+
+    Ok(())
+}
@@ -0,0 +1,59 @@
+//! Tests for simulating disabled features with debug headers.
+
+use anyhow::Result;
+use common::*;
+use rustpad_server::{server, ServerConfig};
+use serde_json::json;
+
+pub mod common;
+
+#[tokio::test]
+async fn test_disable_feature_header() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let dir = tempfile::tempdir()?;
+    let filter = server(ServerConfig {
+        auth_manager: Some(auth_manager(&dir)?),
+        debug_headers: true,
+        ..ServerConfig::default()
+    });
+
+    let resp = warp::test::request()
+        .method("POST")
+        .path("/api/auth/register")
+        .header("X-Disable-Feature", "ai, auth")
+        .json(&json!({ "username": "alice", "password": "hunter22" }))
+        .reply(&filter)
+        .await;
+    assert_ne!(resp.status(), 200);
+
+    let resp = warp::test::request()
+        .method("POST")
+        .path("/api/auth/register")
+        .json(&json!({ "username": "alice", "password": "hunter22" }))
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_disable_feature_header_ignored() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let dir = tempfile::tempdir()?;
+    let filter = server(ServerConfig {
+        auth_manager: Some(auth_manager(&dir)?),
+        ..ServerConfig::default()
+    });
+
+    let resp = warp::test::request()
+        .method("POST")
+        .path("/api/auth/register")
+        .header("X-Disable-Feature", "auth")
+        .json(&json!({ "username": "bob", "password": "hunter22" }))
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+
+    Ok(())
+}
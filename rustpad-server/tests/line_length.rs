@@ -0,0 +1,101 @@
+//! Tests for the per-line length limit on edits.
+
+use anyhow::Result;
+use common::*;
+use rustpad_server::{server, ServerConfig};
+use serde_json::json;
+
+pub mod common;
+
+#[tokio::test]
+async fn test_line_length() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig {
+        max_line_length: Some(10),
+        ..ServerConfig::default()
+    });
+
+    assert_eq!(edit(&filter, "lines", 0, json!(["hello"])).await?, None);
+    let refused = edit(&filter, "lines", 1, json!([5, " world"])).await?;
+    assert_eq!(
+        refused.as_deref(),
+        Some("lines are limited to 10 characters")
+    );
+    expect_text(&filter, "lines", "hello").await;
+
+    // Inserted newlines start new lines, which are measured on their own.
+    assert_eq!(
+        edit(&filter, "lines", 1, json!([5, "\nworld12345"])).await?,
+        None
+    );
+    assert_eq!(
+        edit(&filter, "lines", 2, json!([2, "xy\nz", 14])).await?,
+        None
+    );
+    expect_text(&filter, "lines", "hexy\nzllo\nworld12345").await;
+
+    // Inserts lengthen the lines on either side of where they start and end.
+    assert!(edit(&filter, "lines", 3, json!([10, "ab", 10]))
+        .await?
+        .is_some());
+    assert!(edit(&filter, "lines", 3, json!([4, "abcdefg\nab", 16]))
+        .await?
+        .is_some());
+    assert!(edit(&filter, "lines", 3, json!([5, "ab\nabcdefg", 15]))
+        .await?
+        .is_some());
+    assert!(edit(&filter, "lines", 3, json!([20, "x"])).await?.is_some());
+    assert_eq!(
+        edit(&filter, "lines", 3, json!([4, "abcdef\nabcde", 16])).await?,
+        None
+    );
+    expect_text(&filter, "lines", "hexyabcdef\nabcde\nzllo\nworld12345").await;
+
+    // Deleting a newline joins two lines into one.
+    assert!(edit(&filter, "lines", 4, json!([21, -1, 10]))
+        .await?
+        .is_some());
+    expect_text(&filter, "lines", "hexyabcdef\nabcde\nzllo\nworld12345").await;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_existing_long_lines() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig {
+        default_content: Some("0123456789abcdef\nshort".into()),
+        max_line_length: Some(10),
+        ..ServerConfig::default()
+    });
+
+    // Lines that were already too long may be edited elsewhere or shortened,
+    // but not lengthened.
+    assert_eq!(edit(&filter, "lines", 1, json!([22, "er"])).await?, None);
+    assert_eq!(edit(&filter, "lines", 2, json!([10, -4, 10])).await?, None);
+    assert!(edit(&filter, "lines", 3, json!([12, "x", 8]))
+        .await?
+        .is_some());
+    assert_eq!(
+        edit(&filter, "lines", 3, json!([10, "\n", 10])).await?,
+        None
+    );
+    expect_text(&filter, "lines", "0123456789\nef\nshorter").await;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_line_length_disabled() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig::default());
+
+    let long = "x".repeat(10_000);
+    assert_eq!(
+        edit(&filter, "lines", 0, json!([long.clone()])).await?,
+        None
+    );
+    expect_text(&filter, "lines", &long).await;
+
+    Ok(())
+}
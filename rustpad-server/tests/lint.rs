@@ -0,0 +1,67 @@
+//! Tests for server-side linting of documents.
+
+use std::collections::HashMap;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+use common::*;
+use rustpad_server::lint::{LintConfig, Linter};
+use rustpad_server::{server, ServerConfig};
+use serde_json::json;
+
+pub mod common;
+
+#[tokio::test]
+async fn test_lint_diagnostics() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let dir = tempfile::tempdir()?;
+    let script = dir.path().join("lint.sh");
+    fs::write(
+        &script,
+        "#!/bin/sh\ncat > /dev/null\necho '-:1:6: unexpected token: foo'\necho 'summary line'\n",
+    )?;
+    fs::set_permissions(&script, fs::Permissions::from_mode(0o755))?;
+
+    let linter = Linter::new(LintConfig {
+        enabled: true,
+        commands: HashMap::from([(
+            "python".to_string(),
+            vec![script.to_string_lossy().into_owned()],
+        )]),
+        debounce: Duration::from_millis(10),
+        ..LintConfig::default()
+    });
+    let filter = server(ServerConfig {
+        linter: Some(Arc::new(linter)),
+        ..ServerConfig::default()
+    });
+
+    let mut client = connect(&filter, "linted").await?;
+    assert_eq!(client.recv().await?, json!({ "Identity": 0 }));
+
+    client.send(&json!({ "SetLanguage": "python" })).await;
+    assert_eq!(client.recv().await?, json!({ "Language": "python" }));
+
+    assert_eq!(
+        client.recv().await?,
+        json!({
+            "Diagnostics": {
+                "revision": 0,
+                "diagnostics": [
+                    { "line": 1, "column": 6, "message": "unexpected token: foo" }
+                ]
+            }
+        })
+    );
+
+    // Newly connected clients receive the latest diagnostics.
+    let mut client2 = connect(&filter, "linted").await?;
+    assert_eq!(client2.recv().await?, json!({ "Identity": 1 }));
+    assert_eq!(client2.recv().await?, json!({ "Language": "python" }));
+    assert_eq!(client2.recv().await?["Diagnostics"]["revision"], json!(0));
+
+    Ok(())
+}
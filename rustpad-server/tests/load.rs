@@ -0,0 +1,227 @@
+//! Tests for connection limits and reconnect backoff hints.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+use common::*;
+use rustpad_server::{server, IoLimiter, JoinRate, ServerConfig, UserLimitPolicy};
+use serde_json::json;
+use tokio::time;
+
+pub mod common;
+
+#[tokio::test]
+async fn test_max_connections() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig {
+        max_connections: Some(1),
+        ..ServerConfig::default()
+    });
+
+    let mut client = connect(&filter, "busy").await?;
+    assert_eq!(client.recv().await?, json!({ "Identity": 0 }));
+
+    assert!(connect(&filter, "busy").await.is_err());
+    let resp = warp::test::request()
+        .path("/api/socket/other")
+        .header("connection", "upgrade")
+        .header("upgrade", "websocket")
+        .header("sec-websocket-version", "13")
+        .header("sec-websocket-key", "dGhlIHNhbXBsZSBub25jZQ==")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 503);
+    let retry_after: u64 = resp.headers()["retry-after"].to_str()?.parse()?;
+    assert!(retry_after >= 1);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_join_rate() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig {
+        join_rate: Some(JoinRate {
+            per_second: 1.0,
+            burst: 3,
+            max_queue: Duration::ZERO,
+        }),
+        ..ServerConfig::default()
+    });
+
+    // A flood of joins to one document only lets the burst through.
+    let joins = (0..10).map(|_| connect(&filter, "hot"));
+    let joined = futures::future::join_all(joins)
+        .await
+        .into_iter()
+        .filter(Result::is_ok)
+        .count();
+    assert_eq!(joined, 3);
+
+    let resp = warp::test::request()
+        .path("/api/socket/hot")
+        .header("connection", "upgrade")
+        .header("upgrade", "websocket")
+        .header("sec-websocket-version", "13")
+        .header("sec-websocket-key", "dGhlIHNhbXBsZSBub25jZQ==")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 429);
+    let retry_after: u64 = resp.headers()["retry-after"].to_str()?.parse()?;
+    assert!(retry_after >= 1);
+
+    // Other documents have allowances of their own.
+    let mut client = connect(&filter, "cold").await?;
+    assert_eq!(client.recv().await?, json!({ "Identity": 0 }));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_join_queue() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig {
+        join_rate: Some(JoinRate {
+            per_second: 20.0,
+            burst: 1,
+            max_queue: Duration::from_secs(1),
+        }),
+        ..ServerConfig::default()
+    });
+
+    // Joins over the rate are held back rather than refused.
+    let start = std::time::Instant::now();
+    let mut clients = Vec::new();
+    for _ in 0..5 {
+        clients.push(connect(&filter, "busy").await?);
+    }
+    for client in &mut clients {
+        assert!(client.recv().await?.get("Identity").is_some());
+    }
+    assert!(start.elapsed() >= Duration::from_millis(150));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_user_limit_reject() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let dir = tempfile::tempdir()?;
+    let auth_manager = auth_manager(&dir)?;
+    let session = auth_manager.create_session("alice")?;
+    let filter = server(ServerConfig {
+        auth_manager: Some(auth_manager),
+        max_user_connections: Some(1),
+        ..ServerConfig::default()
+    });
+
+    let path = format!("doc?session={}", session.id);
+    let mut client = connect(&filter, &path).await?;
+    assert_eq!(client.recv().await?, json!({ "Identity": 0 }));
+
+    assert!(connect(&filter, &path).await.is_err());
+    assert!(connect(&filter, "doc?session=bogus").await.is_err());
+
+    // Anonymous connections are exempt from the limit.
+    let mut anonymous = connect(&filter, "doc").await?;
+    assert_eq!(anonymous.recv().await?, json!({ "Identity": 1 }));
+
+    drop(client);
+    time::sleep(Duration::from_millis(50)).await;
+    let mut client = connect(&filter, &path).await?;
+    assert_eq!(client.recv().await?, json!({ "Identity": 2 }));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_user_limit_evict_oldest() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let dir = tempfile::tempdir()?;
+    let auth_manager = auth_manager(&dir)?;
+    let session = auth_manager.create_session("alice")?;
+    let filter = server(ServerConfig {
+        auth_manager: Some(auth_manager),
+        max_user_connections: Some(1),
+        user_limit_policy: UserLimitPolicy::EvictOldest,
+        ..ServerConfig::default()
+    });
+
+    let path = format!("doc?session={}", session.id);
+    let mut first = connect(&filter, &path).await?;
+    assert_eq!(first.recv().await?, json!({ "Identity": 0 }));
+
+    let mut second = connect(&filter, &path).await?;
+    assert_eq!(second.recv().await?, json!({ "Identity": 1 }));
+
+    let (code, reason) = first.recv_close_frame().await?;
+    assert_eq!(code, 1001);
+    assert_eq!(reason["reason"], json!("evicted"));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_close_reason_on_unload() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig {
+        expiry_days: 1,
+        ..ServerConfig::default()
+    });
+
+    let mut client = connect(&filter, "old").await?;
+    assert_eq!(client.recv().await?, json!({ "Identity": 0 }));
+
+    time::pause();
+    time::advance(Duration::from_secs(25 * 3600)).await;
+    time::resume();
+
+    let (code, reason) = client.recv_close_frame().await?;
+    assert_eq!(code, 1001);
+    assert_eq!(reason["reason"], json!("unloaded"));
+    assert!(reason["retry_ms"].as_u64().expect("retry_ms is a number") >= 1000);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_io_limit() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let limiter = IoLimiter::new(2);
+    let running = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+    let peak = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+
+    let operations: Vec<_> = (0..8)
+        .map(|_| {
+            let limiter = limiter.clone();
+            let (running, peak) = (Arc::clone(&running), Arc::clone(&peak));
+            tokio::spawn(async move {
+                limiter
+                    .run(move || {
+                        use std::sync::atomic::Ordering::SeqCst;
+                        peak.fetch_max(running.fetch_add(1, SeqCst) + 1, SeqCst);
+                        std::thread::sleep(Duration::from_millis(50));
+                        running.fetch_sub(1, SeqCst);
+                        Ok(())
+                    })
+                    .await
+            })
+        })
+        .collect();
+    time::sleep(Duration::from_millis(20)).await;
+    assert_eq!(limiter.in_flight(), 2);
+    for operation in operations {
+        operation.await??;
+    }
+
+    // Operations beyond the limit waited their turn, rather than failing.
+    assert_eq!(peak.load(std::sync::atomic::Ordering::SeqCst), 2);
+    assert_eq!(limiter.in_flight(), 0);
+
+    // Errors from the operation come back to the caller.
+    let result: Result<()> = limiter.run(|| Err(anyhow::anyhow!("disk full"))).await;
+    assert!(result.is_err());
+
+    Ok(())
+}
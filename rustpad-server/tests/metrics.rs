@@ -0,0 +1,67 @@
+//! Tests for the Prometheus metrics endpoint.
+
+use anyhow::Result;
+use common::*;
+use operational_transform::OperationSeq;
+use rustpad_server::{server, ServerConfig};
+use serde_json::json;
+
+pub mod common;
+
+#[cfg(feature = "metrics")]
+#[tokio::test]
+async fn test_metrics() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig::default());
+
+    let mut client = connect(&filter, "metrics").await?;
+    assert_eq!(client.recv().await?, json!({ "Identity": 0 }));
+    let mut operation = OperationSeq::default();
+    operation.insert("hello");
+    client
+        .send(&json!({ "Edit": { "revision": 0, "operation": operation } }))
+        .await;
+    client.recv().await?;
+
+    let resp = warp::test::request().path("/api/metrics").reply(&filter).await;
+    assert_eq!(resp.status(), 200);
+    assert!(resp.headers()["content-type"]
+        .to_str()?
+        .starts_with("text/plain"));
+    let body = std::str::from_utf8(resp.body())?;
+    // Metrics are global to the process, so other tests may add to them.
+    assert!(body.contains("# TYPE rustpad_connections gauge"));
+    assert!(body.contains("# TYPE rustpad_operations_total counter"));
+    assert!(body.contains("# TYPE rustpad_persist_seconds histogram"));
+    let operations: u64 = body
+        .lines()
+        .find_map(|line| line.strip_prefix("rustpad_operations_total "))
+        .expect("operations counter")
+        .parse()?;
+    assert!(operations >= 1);
+
+    Ok(())
+}
+
+#[cfg(not(feature = "metrics"))]
+#[tokio::test]
+async fn test_metrics_disabled() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig::default());
+
+    // Collecting is a no-op, and the endpoint says so.
+    let mut client = connect(&filter, "metrics").await?;
+    assert_eq!(client.recv().await?, json!({ "Identity": 0 }));
+    let mut operation = OperationSeq::default();
+    operation.insert("hello");
+    client
+        .send(&json!({ "Edit": { "revision": 0, "operation": operation } }))
+        .await;
+    client.recv().await?;
+    expect_text(&filter, "metrics", "hello").await;
+
+    let resp = warp::test::request().path("/api/metrics").reply(&filter).await;
+    assert_eq!(resp.status(), 404);
+
+    Ok(())
+}
@@ -0,0 +1,119 @@
+//! Tests for password-protected documents.
+
+use anyhow::Result;
+use common::*;
+use rustpad_server::{database::PersistedDocument, ServerConfig};
+use serde_json::json;
+use warp::{filters::BoxedFilter, Reply};
+
+pub mod common;
+
+async fn set_password(
+    filter: &BoxedFilter<(impl Reply + 'static,)>,
+    current: Option<&str>,
+    password: Option<&str>,
+) -> u16 {
+    let mut request = warp::test::request()
+        .method("PUT")
+        .path("/api/documents/doc/password")
+        .json(&json!({ "password": password }));
+    if let Some(current) = current {
+        request = request.header("X-Document-Password", current);
+    }
+    request.reply(filter).await.status().as_u16()
+}
+
+#[tokio::test]
+async fn test_document_password() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let dir = tempfile::tempdir()?;
+    let (database, filter) = database_server(&dir, ServerConfig::default()).await?;
+
+    let mut client = connect(&filter, "doc").await?;
+    assert_eq!(client.recv().await?, json!({ "Identity": 0 }));
+    client
+        .send(&json!({ "Edit": { "revision": 0, "operation": ["hello"] } }))
+        .await;
+    client.recv().await?;
+
+    assert_eq!(set_password(&filter, None, Some("")).await, 400);
+    assert_eq!(set_password(&filter, None, Some("s3cret")).await, 200);
+    assert_eq!(set_password(&filter, None, Some("other")).await, 401);
+    assert_eq!(set_password(&filter, Some("wrong"), None).await, 401);
+
+    // Connections, text, and downloads all need the password now.
+    assert!(connect(&filter, "doc").await.is_err());
+    assert!(connect(&filter, "doc?password=wrong").await.is_err());
+    let mut client = connect(&filter, "doc?password=s3cret").await?;
+    assert_eq!(client.recv().await?, json!({ "Identity": 1 }));
+
+    let resp = warp::test::request().path("/api/text/doc").reply(&filter).await;
+    assert_eq!(resp.status(), 401);
+    let resp = warp::test::request()
+        .path("/api/text/doc")
+        .header("X-Document-Password", "s3cret")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.body(), "hello");
+
+    let resp = warp::test::request()
+        .path("/api/documents/doc/download")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 401);
+    let resp = warp::test::request()
+        .path("/api/documents/doc/download?password=s3cret")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.body(), "hello");
+
+    // Only a hash is stored, and it still guards the persisted copy.
+    let stored = database.load("doc").await?;
+    let hash = stored.password_hash.clone().expect("password should be stored");
+    assert_ne!(hash, "s3cret");
+    assert!(stored.check_password(Some("s3cret")));
+    assert!(!stored.check_password(None));
+
+    // Removing the password opens the document back up.
+    assert_eq!(set_password(&filter, Some("s3cret"), None).await, 200);
+    expect_text(&filter, "doc", "hello").await;
+    let mut client = connect(&filter, "doc").await?;
+    assert_eq!(client.recv().await?, json!({ "Identity": 2 }));
+    assert_eq!(database.load("doc").await?.password_hash, None);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_refused_connection_stays_cold() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let dir = tempfile::tempdir()?;
+    let (database, filter) = database_server(&dir, ServerConfig::default()).await?;
+    let document = PersistedDocument {
+        text: "hidden".into(),
+        password_hash: Some(bcrypt::hash("s3cret", 4)?),
+        ..Default::default()
+    };
+    database.store("doc", &document).await?;
+    let num_documents = || async {
+        let resp = warp::test::request()
+            .path("/api/stats")
+            .reply(&filter)
+            .await;
+        let stats: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+        stats["num_documents"].clone()
+    };
+
+    // A connection without the password is refused before the document is
+    // brought into memory.
+    assert!(connect(&filter, "doc").await.is_err());
+    assert_eq!(num_documents().await, 0);
+
+    let mut client = connect(&filter, "doc?password=s3cret").await?;
+    assert_eq!(client.recv().await?, json!({ "Identity": 0 }));
+    assert_eq!(num_documents().await, 1);
+
+    Ok(())
+}
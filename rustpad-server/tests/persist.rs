@@ -1,17 +1,22 @@
 //! Tests to ensure that documents are persisted with SQLite.
 
+use std::sync::Arc;
 use std::time::Duration;
 
 use anyhow::Result;
 use common::*;
 use operational_transform::OperationSeq;
 use rustpad_server::{
-    database::{Database, PersistedDocument},
-    server, ServerConfig,
+    auth::{AuthConfig, AuthManager},
+    backup::{run_backup, BackupConfig},
+    database::{Database, PersistedDocument, CURRENT_FORMAT_VERSION},
+    freeze::Compression,
+    persistence::FileStore,
+    server, server_with_shutdown, ServerConfig,
 };
 use serde_json::json;
 use tempfile::NamedTempFile;
-use tokio::time;
+use tokio::{sync::oneshot, time};
 
 pub mod common;
 
@@ -38,6 +43,7 @@ async fn test_database() -> Result<()> {
     let doc1 = PersistedDocument {
         text: "Hello Text".into(),
         language: None,
+        ..Default::default()
     };
 
     assert!(database.store("hello", &doc1).await.is_ok());
@@ -47,6 +53,7 @@ async fn test_database() -> Result<()> {
     let doc2 = PersistedDocument {
         text: "print('World Text :)')".into(),
         language: Some("python".into()),
+        ..Default::default()
     };
 
     assert!(database.store("world", &doc2).await.is_ok());
@@ -59,6 +66,110 @@ async fn test_database() -> Result<()> {
     Ok(())
 }
 
+#[tokio::test]
+async fn test_backup() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+
+    let database = Database::new(&temp_sqlite_uri()?).await?;
+    let doc = PersistedDocument {
+        text: "Backed up".into(),
+        language: Some("markdown".into()),
+        ..Default::default()
+    };
+    database.store("hello", &doc).await?;
+
+    let dir = tempfile::tempdir()?;
+    std::fs::write(dir.path().join("notes.txt"), "not a backup")?;
+    let config = BackupConfig {
+        dir: dir.path().to_path_buf(),
+        interval: Duration::from_secs(3600),
+        retain: 2,
+    };
+    let mut paths = Vec::new();
+    for _ in 0..3 {
+        paths.push(run_backup(&database, &config).await?);
+    }
+
+    // Only the newest backups are kept.
+    assert!(!paths[0].exists());
+    assert!(paths[1].exists() && paths[2].exists());
+    assert_eq!(std::fs::read_dir(dir.path())?.count(), 3);
+    assert!(dir.path().join("notes.txt").exists());
+
+    // Each backup is a working database in its own right.
+    let restored = Database::new(&format!("sqlite://{}", paths[2].display())).await?;
+    assert_eq!(restored.load("hello").await?, doc);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_concurrent_stores() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+
+    let database = Database::new(&temp_sqlite_uri()?).await?;
+
+    // Every document becomes dirty at once, as after a burst of activity.
+    let stores = (0..50).map(|i| {
+        let database = database.clone();
+        tokio::spawn(async move {
+            let document = PersistedDocument {
+                text: format!("document {}", i),
+                language: None,
+                ..Default::default()
+            };
+            database.store(&format!("doc{}", i), &document).await
+        })
+    });
+    for result in futures::future::join_all(stores).await {
+        result??;
+    }
+
+    assert_eq!(database.count().await?, 50);
+    assert_eq!(database.load("doc42").await?.text, "document 42");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_format_migration() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+
+    let uri = temp_sqlite_uri()?;
+    let database = Database::new(&uri).await?;
+
+    // Simulate rows written before snapshots carried a format version, and
+    // by a newer server with a format this one doesn't understand.
+    let pool = sqlx::SqlitePool::connect(&uri).await?;
+    sqlx::query("INSERT INTO document (id, text, language) VALUES ('old', 'legacy', 'rust')")
+        .execute(&pool)
+        .await?;
+    sqlx::query(
+        "INSERT INTO document (id, text, language, format_version) VALUES ('new', 'x', NULL, 99)",
+    )
+    .execute(&pool)
+    .await?;
+
+    assert_eq!(
+        database.load("old").await?,
+        PersistedDocument {
+            text: "legacy".into(),
+            language: Some("rust".into()),
+            ..Default::default()
+        }
+    );
+    let (version,): (i64,) =
+        sqlx::query_as("SELECT format_version FROM document WHERE id = 'old'")
+            .fetch_one(&pool)
+            .await?;
+    assert_eq!(version, CURRENT_FORMAT_VERSION);
+
+    let err = database.load("new").await.expect_err("newer format is rejected");
+    assert!(err.to_string().contains("format version 99"));
+
+    Ok(())
+}
+
 #[tokio::test]
 async fn test_persist() -> Result<()> {
     pretty_env_logger::try_init().ok();
@@ -66,6 +177,7 @@ async fn test_persist() -> Result<()> {
     let filter = server(ServerConfig {
         expiry_days: 2,
         database: Some(Database::new(&temp_sqlite_uri()?).await?),
+        ..ServerConfig::default()
     });
 
     expect_text(&filter, "persist", "").await;
@@ -104,3 +216,480 @@ async fn test_persist() -> Result<()> {
 
     Ok(())
 }
+
+#[tokio::test]
+async fn test_snapshot_on_demand() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+
+    let database = Database::new(&temp_sqlite_uri()?).await?;
+    let filter = server(ServerConfig {
+        database: Some(database.clone()),
+        ..ServerConfig::default()
+    });
+
+    let mut client = connect(&filter, "snap").await?;
+    assert_eq!(client.recv().await?, json!({ "Identity": 0 }));
+
+    let mut operation = OperationSeq::default();
+    operation.insert("hello");
+    client
+        .send(&json!({ "Edit": { "revision": 0, "operation": operation } }))
+        .await;
+    client.recv().await?;
+    assert!(database.load("snap").await.is_err());
+
+    let snapshot = || {
+        warp::test::request()
+            .method("POST")
+            .path("/api/documents/snap/snapshot")
+            .reply(&filter)
+    };
+
+    // The document is written immediately, without waiting for the persister.
+    let resp = snapshot().await;
+    assert_eq!(resp.status(), 200);
+    let body: serde_json::Value = serde_json::from_slice(resp.body())?;
+    assert_eq!(body, json!({ "revision": 1, "persisted": true }));
+    assert_eq!(database.load("snap").await?.text, "hello");
+
+    let resp = snapshot().await;
+    assert_eq!(resp.status(), 429);
+    assert!(resp.headers().contains_key("retry-after"));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_shutdown_flush() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+
+    let database = Database::new(&temp_sqlite_uri()?).await?;
+    let (trigger, signal) = oneshot::channel::<()>();
+    let (filter, shutdown) = server_with_shutdown(
+        ServerConfig {
+            database: Some(database.clone()),
+            ..ServerConfig::default()
+        },
+        async move {
+            signal.await.ok();
+        },
+    );
+
+    let mut client = connect(&filter, "flush").await?;
+    assert_eq!(client.recv().await?, json!({ "Identity": 0 }));
+
+    let mut operation = OperationSeq::default();
+    operation.insert("goodbye");
+    client
+        .send(&json!({ "Edit": { "revision": 0, "operation": operation } }))
+        .await;
+    client.recv().await?;
+    assert!(database.load("flush").await.is_err());
+
+    // The edit is flushed on shutdown, well before the persister would run.
+    trigger.send(()).ok();
+    shutdown.await;
+    assert_eq!(database.load("flush").await?.text, "goodbye");
+
+    // New connections are refused once shutdown has begun.
+    assert!(connect(&filter, "flush").await.is_err());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_persistence_status() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+
+    let filter = server(ServerConfig {
+        database: Some(Database::new(&temp_sqlite_uri()?).await?),
+        persistence_status: true,
+        ..ServerConfig::default()
+    });
+
+    let mut client = connect(&filter, "status").await?;
+    assert_eq!(client.recv().await?, json!({ "Identity": 0 }));
+
+    let mut operation = OperationSeq::default();
+    operation.insert("hello");
+    client
+        .send(&json!({ "Edit": { "revision": 0, "operation": operation } }))
+        .await;
+    client.recv().await?;
+
+    // Fires once the persister's next tick has written the document.
+    let msg = time::timeout(Duration::from_secs(10), client.recv()).await??;
+    assert_eq!(msg, json!({ "Persisted": 1 }));
+
+    let mut client2 = connect(&filter, "status").await?;
+    assert_eq!(client2.recv().await?, json!({ "Identity": 1 }));
+    client2.recv().await?; // History
+    assert_eq!(client2.recv().await?, json!({ "Persisted": 1 }));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_persistence_routes() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+
+    let dir = tempfile::tempdir()?;
+    let database = Database::new(&temp_sqlite_uri()?).await?;
+    let file_store = FileStore::new(dir.path())?;
+    let filter = server(ServerConfig {
+        database: Some(database.clone()),
+        file_store: Some(file_store.clone()),
+        persistence_routes: "scratch-=none;notes-=file".parse()?,
+        ..ServerConfig::default()
+    });
+
+    let create = |query: &str| {
+        warp::test::request()
+            .method("POST")
+            .path(&format!("/api/documents/new?{}", query))
+            .reply(&filter)
+    };
+    let snapshot = |id: &str| {
+        warp::test::request()
+            .method("POST")
+            .path(&format!("/api/documents/{}/snapshot", id))
+            .reply(&filter)
+    };
+
+    for query in [
+        "id=notes-a",
+        "id=scratch-b",
+        "id=other",
+        "id=scratch-c&persistence=db",
+    ] {
+        assert_eq!(create(query).await.status(), 200);
+    }
+
+    let persisted = |body: &[u8]| -> Result<bool> {
+        let body: serde_json::Value = serde_json::from_slice(body)?;
+        Ok(body["persisted"].as_bool().unwrap())
+    };
+    assert!(persisted(snapshot("notes-a").await.body())?);
+    assert!(!persisted(snapshot("scratch-b").await.body())?);
+    assert!(persisted(snapshot("other").await.body())?);
+    assert!(persisted(snapshot("scratch-c").await.body())?);
+
+    assert!(file_store.exists("notes-a").await?);
+    assert!(!database.exists("notes-a").await?);
+    assert!(!file_store.exists("scratch-b").await?);
+    assert!(!database.exists("scratch-b").await?);
+    assert!(database.exists("other").await?);
+    assert!(database.exists("scratch-c").await?);
+
+    // Documents in the file store can be read back.
+    let resp = warp::test::request()
+        .path("/api/text/notes-a")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_corrupt_document() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+
+    let uri = temp_sqlite_uri()?;
+    let database = Database::new(&uri).await?;
+
+    // A snapshot whose text is not valid UTF-8 can't be decoded.
+    let pool = sqlx::SqlitePool::connect(&uri).await?;
+    for id in ["strict", "lenient", "quarantine"] {
+        sqlx::query("INSERT INTO document (id, text, format_version) VALUES ($1, X'FFFE00', 1)")
+            .bind(id)
+            .execute(&pool)
+            .await?;
+    }
+
+    let filter = |policy: &str| -> Result<_> {
+        Ok(server(ServerConfig {
+            database: Some(database.clone()),
+            load_failure_policy: policy.parse()?,
+            ..ServerConfig::default()
+        }))
+    };
+
+    let strict = filter("strict")?;
+    assert!(connect(&strict, "strict").await.is_err());
+    let resp = warp::test::request()
+        .path("/api/text/strict")
+        .reply(&strict)
+        .await;
+    assert_eq!(resp.status(), 500);
+    assert!(database.exists("strict").await?);
+
+    let lenient = filter("lenient")?;
+    let mut client = connect(&lenient, "lenient").await?;
+    let msg = client.recv().await?;
+    assert_eq!(msg, json!({ "Identity": 0 }));
+    expect_text(&lenient, "lenient", "").await;
+    assert!(database.exists("lenient").await?);
+
+    let quarantine = filter("quarantine")?;
+    let mut client = connect(&quarantine, "quarantine").await?;
+    let msg = client.recv().await?;
+    assert_eq!(msg, json!({ "Identity": 0 }));
+    assert!(!database.exists("quarantine").await?);
+    assert_eq!(database.quarantined().await?, ["quarantine"]);
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_concurrent_cold_load() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+
+    let database = Database::new(&temp_sqlite_uri()?).await?;
+    let document = PersistedDocument {
+        text: "cold".into(),
+        ..Default::default()
+    };
+    database.store("cold", &document).await?;
+    let filter = server(ServerConfig {
+        database: Some(database),
+        ..ServerConfig::default()
+    });
+
+    // Every connection races to open the document before any has loaded it.
+    let connections = (0..16).map(|_| connect(&filter, "cold"));
+    let clients = futures::future::try_join_all(connections).await?;
+    assert_eq!(clients.len(), 16);
+    expect_text(&filter, "cold", "cold").await;
+
+    let resp = warp::test::request().path("/api/stats").reply(&filter).await;
+    let stats: serde_json::Value = serde_json::from_slice(resp.body())?;
+    assert_eq!(stats["persisted_loads"], 1);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_metadata() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+
+    let database = Database::new(&temp_sqlite_uri()?).await?;
+    let filter = server(ServerConfig {
+        database: Some(database.clone()),
+        ..ServerConfig::default()
+    });
+
+    let mut client = connect(&filter, "meta").await?;
+    assert_eq!(client.recv().await?, json!({ "Identity": 0 }));
+    let mut operation = OperationSeq::default();
+    operation.insert("hello");
+    client
+        .send(&json!({ "Edit": { "revision": 0, "operation": operation } }))
+        .await;
+    client.recv().await?;
+
+    let set_metadata = |body: serde_json::Value| {
+        warp::test::request()
+            .method("PUT")
+            .path("/api/documents/meta/metadata")
+            .json(&body)
+            .reply(&filter)
+    };
+    let resp = set_metadata(json!({ "tags": ["draft"], "custom": { "project": "rustpad" } })).await;
+    assert_eq!(resp.status(), 200);
+    let metadata: serde_json::Value = serde_json::from_slice(resp.body())?;
+    assert_eq!(metadata["tags"], json!(["draft"]));
+    assert!(metadata["created_at"].is_string());
+
+    // Metadata is written right away, along with the text it sits beside.
+    let stored = database.load("meta").await?;
+    assert_eq!(stored.text, "hello");
+    assert_eq!(stored.metadata.tags, ["draft"]);
+    assert_eq!(stored.metadata.custom["project"], "rustpad");
+
+    // Later edits to the content leave the metadata alone.
+    let mut operation = OperationSeq::default();
+    operation.retain(5);
+    operation.insert(" world");
+    client
+        .send(&json!({ "Edit": { "revision": 1, "operation": operation } }))
+        .await;
+    client.recv().await?;
+    let resp = warp::test::request()
+        .method("POST")
+        .path("/api/documents/meta/snapshot")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+    let stored = database.load("meta").await?;
+    assert_eq!(stored.text, "hello world");
+    assert_eq!(stored.metadata.tags, ["draft"]);
+
+    // Metadata over the size limit is refused without changing anything.
+    let huge = "x".repeat(10_000);
+    let resp = set_metadata(json!({ "custom": { "notes": huge } })).await;
+    assert_eq!(resp.status(), 413);
+
+    let resp = warp::test::request().path("/api/stats").reply(&filter).await;
+    let stats: serde_json::Value = serde_json::from_slice(resp.body())?;
+    assert_eq!(stats["tags"], json!({ "draft": 1 }));
+
+    // A server starting from the database sees the same metadata.
+    drop(client);
+    let filter = server(ServerConfig {
+        database: Some(database),
+        ..ServerConfig::default()
+    });
+    let resp = warp::test::request()
+        .path("/api/text/meta")
+        .header("Accept", "application/json")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+    let body: serde_json::Value = serde_json::from_slice(resp.body())?;
+    assert_eq!(body["text"], "hello world");
+    assert_eq!(body["metadata"]["tags"], json!(["draft"]));
+    assert_eq!(body["metadata"]["custom"], json!({ "project": "rustpad" }));
+    assert_eq!(body["metadata"]["created_at"], metadata["created_at"]);
+    expect_text(&filter, "meta", "hello world").await;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_list_database() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+
+    let uri = temp_sqlite_uri()?;
+    let database = Database::new(&uri).await?;
+    for (id, text, language) in [("b", "héllo", Some("rust")), ("a", "x", None), ("c", "", None)] {
+        let document = PersistedDocument {
+            text: text.into(),
+            language: language.map(String::from),
+            ..Default::default()
+        };
+        database.store(id, &document).await?;
+    }
+    // Rows stored before modification times were tracked have none.
+    let pool = sqlx::SqlitePool::connect(&uri).await?;
+    sqlx::query("INSERT INTO document (id, text) VALUES ('d', 'legacy')")
+        .execute(&pool)
+        .await?;
+
+    let page = database.list(2, 0).await?;
+    let ids: Vec<_> = page.iter().map(|summary| summary.id.as_str()).collect();
+    assert_eq!(ids, ["a", "b"]);
+    assert_eq!(page[1].size, 6);
+    assert_eq!(page[1].language.as_deref(), Some("rust"));
+    assert!(page[1].updated_at.is_some());
+    let page = database.list(2, 2).await?;
+    assert_eq!(page.len(), 2);
+    assert_eq!((page[1].id.as_str(), page[1].size), ("d", 6));
+    assert!(page[1].updated_at.is_none());
+    assert!(database.list(2, 4).await?.is_empty());
+
+    // Over HTTP, the listing is only for admins.
+    let dir = tempfile::tempdir()?;
+    let auth_manager = AuthManager::new(AuthConfig {
+        enabled: true,
+        data_dir: dir.path().join("users"),
+        ..AuthConfig::default()
+    })?;
+    auth_manager.register("admin", "hunter22", false, true)?;
+    auth_manager.register("alice", "hunter22", false, false)?;
+    let filter = server(ServerConfig {
+        database: Some(database),
+        auth_manager: Some(Arc::new(auth_manager)),
+        ..ServerConfig::default()
+    });
+    let list = |auth: &'static str| {
+        warp::test::request()
+            .path("/api/documents/db/list?offset=1&limit=2")
+            .header("Authorization", auth)
+            .reply(&filter)
+    };
+    assert_eq!(list("Basic YWxpY2U6aHVudGVyMjI=").await.status(), 403); // alice:hunter22
+    let resp = list("Basic YWRtaW46aHVudGVyMjI=").await; // admin:hunter22
+    assert_eq!(resp.status(), 200);
+    let body: serde_json::Value = serde_json::from_slice(resp.body())?;
+    assert_eq!(body["total"], 4);
+    assert_eq!((&body["offset"], &body["limit"]), (&json!(1), &json!(2)));
+    assert_eq!(body["documents"][0]["id"], "b");
+    assert_eq!(body["documents"][1]["id"], "c");
+    assert_eq!(body["documents"][1]["size"], 0);
+    assert!(body["documents"][1]["updated_at"].is_string());
+    assert!(body["documents"][0].get("text").is_none());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_compressed_database() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+
+    let uri = temp_sqlite_uri()?;
+    let plain = Database::new(&uri).await?;
+    let zstd = Database::new(&uri).await?.with_compression(Some(Compression::Zstd));
+    let gzip = Database::new(&uri).await?.with_compression(Some(Compression::Gzip));
+
+    // A large document of source code, like most that are stored.
+    let text: String = (0..5000)
+        .map(|i| format!("fn item_{}(x: usize) -> usize {{\n    x * {} + 1\n}}\n\n", i, i % 7))
+        .collect();
+    let large = PersistedDocument {
+        text: text.clone(),
+        language: Some("rust".into()),
+        ..Default::default()
+    };
+    let small = PersistedDocument {
+        text: "hi".into(),
+        ..Default::default()
+    };
+    plain.store("plain", &large).await?;
+    zstd.store("zstd", &large).await?;
+    gzip.store("gzip", &large).await?;
+    zstd.store("small", &small).await?;
+
+    // Every row loads the same, however it was stored and whatever the
+    // loading database would store with.
+    for database in [&plain, &zstd, &gzip] {
+        for id in ["plain", "zstd", "gzip"] {
+            assert_eq!(database.load(id).await?, large);
+        }
+        assert_eq!(database.load("small").await?, small);
+    }
+
+    // Compressed rows take a fraction of the space, and text that wouldn't
+    // shrink is stored as it is.
+    let pool = sqlx::SqlitePool::connect(&uri).await?;
+    let rows: Vec<(String, Option<String>)> =
+        sqlx::query_as("SELECT id, compression FROM document ORDER BY id")
+            .fetch_all(&pool)
+            .await?;
+    let expected = [
+        ("gzip", Some("gzip")),
+        ("plain", None),
+        ("small", None),
+        ("zstd", Some("zstd")),
+    ];
+    let expected: Vec<_> = expected
+        .iter()
+        .map(|(id, compression)| (id.to_string(), compression.map(String::from)))
+        .collect();
+    assert_eq!(rows, expected);
+    let sizes: Vec<u64> = plain.list(10, 0).await?.iter().map(|summary| summary.size).collect();
+    assert_eq!(sizes[1], text.len() as u64);
+    assert!(sizes[0] * 10 < text.len() as u64, "gzip stored {} bytes", sizes[0]);
+    assert!(sizes[3] * 10 < text.len() as u64, "zstd stored {} bytes", sizes[3]);
+
+    // Storing again without compression rewrites the row plainly.
+    plain.store("zstd", &large).await?;
+    assert_eq!(zstd.load("zstd").await?, large);
+    let (compression,): (Option<String>,) =
+        sqlx::query_as("SELECT compression FROM document WHERE id = 'zstd'")
+            .fetch_one(&pool)
+            .await?;
+    assert_eq!(compression, None);
+
+    Ok(())
+}
@@ -0,0 +1,38 @@
+//! Tests for generating QR codes that link to documents.
+
+use anyhow::Result;
+use rustpad_server::{server, ServerConfig};
+
+#[tokio::test]
+async fn test_qr_code() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig {
+        public_url: Some("https://pad.example.com/".into()),
+        ..ServerConfig::default()
+    });
+
+    let resp = warp::test::request()
+        .path("/api/documents/abc123/qr")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.headers()["content-type"], "image/svg+xml");
+    assert!(std::str::from_utf8(resp.body())?.contains("<svg"));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_qr_code_without_public_url() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig::default());
+
+    let resp = warp::test::request()
+        .path("/api/documents/abc123/qr")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 501);
+    assert!(std::str::from_utf8(resp.body())?.contains("PUBLIC_URL"));
+
+    Ok(())
+}
@@ -0,0 +1,29 @@
+//! Tests for request ids and request logging.
+
+use anyhow::Result;
+use rustpad_server::{server, ServerConfig};
+use uuid::Uuid;
+
+#[tokio::test]
+async fn test_request_id() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig::default());
+
+    let id = |resp: &warp::http::Response<_>| -> Result<Uuid> {
+        Ok(resp.headers()["x-request-id"].to_str()?.parse()?)
+    };
+    let first = warp::test::request().path("/api/text/doc").reply(&filter).await;
+    assert_eq!(first.status(), 200);
+    let second = warp::test::request().path("/api/text/doc").reply(&filter).await;
+    assert_ne!(id(&first)?, id(&second)?);
+
+    // Handler errors carry an id too, to find them in the logs.
+    let resp = warp::test::request()
+        .path("/api/admin/stats/history")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 404);
+    id(&resp)?;
+
+    Ok(())
+}
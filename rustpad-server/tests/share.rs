@@ -0,0 +1,102 @@
+//! Tests for read-only share links.
+
+use anyhow::Result;
+use common::*;
+use rustpad_server::{server, ServerConfig};
+use serde_json::{json, Value};
+use warp::{filters::BoxedFilter, Reply};
+
+pub mod common;
+
+async fn share(filter: &BoxedFilter<(impl Reply + 'static,)>, method: &str) -> (u16, Value) {
+    let resp = warp::test::request()
+        .method(method)
+        .path("/api/documents/doc/share")
+        .header("X-Document-Password", "hunter2")
+        .reply(filter)
+        .await;
+    let body = serde_json::from_slice(resp.body()).unwrap_or(Value::Null);
+    (resp.status().as_u16(), body)
+}
+
+#[tokio::test]
+async fn test_share_link() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let dir = tempfile::tempdir()?;
+    let config = ServerConfig {
+        public_url: Some("https://pad.example.com/".into()),
+        ..ServerConfig::default()
+    };
+    let (database, filter) = database_server(&dir, config).await?;
+
+    let mut owner = connect(&filter, "doc").await?;
+    assert_eq!(owner.recv().await?, json!({ "Identity": 0 }));
+    owner
+        .send(&json!({ "Edit": { "revision": 0, "operation": ["hello"] } }))
+        .await;
+    owner.recv().await?;
+    let resp = warp::test::request()
+        .method("PUT")
+        .path("/api/documents/doc/password")
+        .json(&json!({ "password": "hunter2" }))
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+
+    // Sharing a protected document takes its password.
+    let resp = warp::test::request()
+        .method("POST")
+        .path("/api/documents/doc/share")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 401);
+    let (status, body) = share(&filter, "POST").await;
+    assert_eq!(status, 200);
+    let token = body["token"].as_str().expect("token is a string").to_string();
+    assert_eq!(
+        body["url"],
+        json!(format!("https://pad.example.com/?share={}#doc", token))
+    );
+
+    // The link opens the document without its password, but only to view it.
+    let path = format!("doc?share={}", token);
+    let mut viewer = connect(&filter, &path).await?;
+    assert_eq!(viewer.recv().await?, json!({ "Identity": 1 }));
+    assert_eq!(viewer.recv().await?, json!("ReadOnly"));
+    viewer.recv().await?; // History
+    viewer
+        .send(&json!({ "Edit": { "revision": 1, "operation": [5, " world"] } }))
+        .await;
+    let msg = viewer.recv().await?;
+    assert!(msg.get("Rejected").is_some(), "expected rejection, got {}", msg);
+    expect_text(&filter, "doc", "hello").await;
+
+    // The viewer stays connected, and the owner can still edit.
+    owner
+        .send(&json!({ "Edit": { "revision": 1, "operation": ["> ", 5] } }))
+        .await;
+    owner.recv().await?;
+    let msg = viewer.recv().await?;
+    assert_eq!(msg["History"]["start"], 1);
+
+    assert!(connect(&filter, "doc?share=wrong").await.is_err());
+
+    // Tokens are stored with the document, as hashes.
+    let stored = database.load("doc").await?;
+    assert_eq!(stored.share_tokens.len(), 1);
+    assert_ne!(stored.share_tokens[0], token);
+    let filter = server(ServerConfig {
+        database: Some(database.clone()),
+        ..ServerConfig::default()
+    });
+    let mut viewer = connect(&filter, &path).await?;
+    assert_eq!(viewer.recv().await?, json!({ "Identity": 0 }));
+    assert_eq!(viewer.recv().await?, json!("ReadOnly"));
+
+    // Revoking the links stops them from opening the document.
+    assert_eq!(share(&filter, "DELETE").await, (200, json!({ "revoked": 1 })));
+    assert!(connect(&filter, &path).await.is_err());
+    assert!(database.load("doc").await?.share_tokens.is_empty());
+
+    Ok(())
+}
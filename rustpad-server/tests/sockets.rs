@@ -228,3 +228,60 @@ async fn test_set_language() -> Result<()> {
     expect_text(&filter, "foobar", "").await;
     Ok(())
 }
+
+#[tokio::test]
+async fn test_allowed_origins() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig {
+        allowed_origins: Some(vec!["https://pad.example.com".into()]),
+        ..ServerConfig::default()
+    });
+
+    let upgrade = |origin: &str| {
+        warp::test::request()
+            .path("/api/socket/foobar")
+            .header("connection", "upgrade")
+            .header("upgrade", "websocket")
+            .header("sec-websocket-version", "13")
+            .header("sec-websocket-key", "dGhlIHNhbXBsZSBub25jZQ==")
+            .header("origin", origin)
+            .reply(&filter)
+    };
+
+    assert_eq!(upgrade("https://evil.example.com").await.status(), 403);
+    assert_eq!(upgrade("https://pad.example.com").await.status(), 101);
+    assert_eq!(upgrade("https://PAD.example.com/").await.status(), 101);
+
+    // Clients that send no origin, like this test client, are allowed.
+    let mut client = connect(&filter, "foobar").await?;
+    assert_eq!(client.recv().await?, json!({ "Identity": 0 }));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_deflate_fallback() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig::default());
+
+    // Browsers offer permessage-deflate; the server declines it.
+    let resp = warp::test::request()
+        .path("/api/socket/foobar")
+        .header("connection", "upgrade")
+        .header("upgrade", "websocket")
+        .header("sec-websocket-version", "13")
+        .header("sec-websocket-key", "dGhlIHNhbXBsZSBub25jZQ==")
+        .header(
+            "sec-websocket-extensions",
+            "permessage-deflate; client_max_window_bits",
+        )
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 101);
+    assert!(!resp.headers().contains_key("sec-websocket-extensions"));
+
+    let mut client = connect(&filter, "foobar").await?;
+    assert_eq!(client.recv().await?, json!({ "Identity": 0 }));
+
+    Ok(())
+}
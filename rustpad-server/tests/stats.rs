@@ -0,0 +1,93 @@
+//! Tests for server statistics and health checks.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+use common::*;
+use rustpad_server::{
+    auth::{AuthConfig, AuthManager},
+    server, ServerConfig,
+};
+use serde_json::{json, Value};
+use tokio::time;
+use warp::{filters::BoxedFilter, Reply};
+
+pub mod common;
+
+#[tokio::test]
+async fn test_stats_history() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let dir = tempfile::tempdir()?;
+    let auth_manager = Arc::new(AuthManager::new(AuthConfig {
+        enabled: true,
+        data_dir: dir.path().join("users"),
+        ..AuthConfig::default()
+    })?);
+    auth_manager.register("admin", "hunter22", false, true)?;
+    auth_manager.register("alice", "hunter22", false, false)?;
+
+    let filter = server(ServerConfig {
+        auth_manager: Some(auth_manager),
+        stats_interval: Duration::from_millis(20),
+        stats_retention: Duration::from_millis(60),
+        ..ServerConfig::default()
+    });
+
+    let mut client = connect(&filter, "foobar").await?;
+    assert_eq!(client.recv().await?, json!({ "Identity": 0 }));
+    client
+        .send(&json!({ "Edit": { "revision": 0, "operation": ["hello"] } }))
+        .await;
+    client.recv().await?;
+    time::sleep(Duration::from_millis(200)).await;
+
+    let history = |auth: &'static str| {
+        warp::test::request()
+            .path("/api/admin/stats/history")
+            .header("Authorization", auth)
+            .reply(&filter)
+    };
+    assert_ne!(history("Basic YWxpY2U6aHVudGVyMjI=").await.status(), 200); // alice:hunter22
+
+    let resp = history("Basic YWRtaW46aHVudGVyMjI=").await; // admin:hunter22
+    assert_eq!(resp.status(), 200);
+    let body: Value = serde_json::from_slice(resp.body())?;
+    // Only as many samples as fit in the retention period are kept.
+    let samples = body["samples"].as_array().expect("samples is an array");
+    assert_eq!(samples.len(), 3);
+    let latest = &samples[2];
+    assert_eq!(latest["num_documents"], 1);
+    assert_eq!(latest["connections"], 1);
+    assert_eq!(latest["memory_bytes"], 5);
+    assert!(samples[0]["timestamp"].as_u64() <= latest["timestamp"].as_u64());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_health() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+
+    async fn health(filter: BoxedFilter<(impl Reply + 'static,)>) -> Value {
+        let resp = warp::test::request()
+            .path("/api/health")
+            .reply(&filter)
+            .await;
+        assert_eq!(resp.status(), 200);
+        serde_json::from_slice::<Value>(resp.body()).expect("health is JSON")
+    }
+
+    let body = health(server(ServerConfig::default())).await;
+    assert_eq!(body["status"], "ok");
+    assert_eq!(body["database"], "disabled");
+    assert!(body["uptime_secs"].is_u64());
+
+    let dir = tempfile::tempdir()?;
+    let (_, filter) = database_server(&dir, ServerConfig::default()).await?;
+    let body = health(filter).await;
+    assert_eq!(body["status"], "ok");
+    assert_eq!(body["database"], "up");
+
+    Ok(())
+}
@@ -0,0 +1,89 @@
+//! Tests for undoing and redoing edits on the server.
+
+use anyhow::Result;
+use common::*;
+use rustpad_server::{server, ServerConfig};
+use serde_json::json;
+
+pub mod common;
+
+#[tokio::test]
+async fn test_undo_redo() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig {
+        undo_limit: Some(100),
+        ..ServerConfig::default()
+    });
+
+    let mut alice = connect(&filter, "undo").await?;
+    assert_eq!(alice.recv().await?, json!({ "Identity": 0 }));
+    let mut bob = connect(&filter, "undo").await?;
+    assert_eq!(bob.recv().await?, json!({ "Identity": 1 }));
+
+    alice
+        .send(&json!({ "Edit": { "revision": 0, "operation": ["hello"] } }))
+        .await;
+    alice.recv().await?;
+    bob.recv().await?;
+    bob.send(&json!({ "Edit": { "revision": 1, "operation": [5, " world"] } }))
+        .await;
+    alice.recv().await?;
+    bob.recv().await?;
+
+    // Only Alice's edit is reversed, transformed past Bob's.
+    alice.send(&json!("Undo")).await;
+    let msg = json!({
+        "History": {
+            "start": 2,
+            "operations": [{ "id": u64::MAX, "operation": [-5, 6] }]
+        }
+    });
+    assert_eq!(alice.recv().await?, msg);
+    assert_eq!(bob.recv().await?, msg);
+    expect_text(&filter, "undo", " world").await;
+
+    alice.send(&json!("Redo")).await;
+    alice.recv().await?;
+    bob.recv().await?;
+    expect_text(&filter, "undo", "hello world").await;
+
+    // Bob's edit is undone past Alice's undo and redo of her own.
+    bob.send(&json!("Undo")).await;
+    alice.recv().await?;
+    bob.recv().await?;
+    expect_text(&filter, "undo", "hello").await;
+
+    // A new edit discards the edits Bob could have redone.
+    bob.send(&json!({ "Edit": { "revision": 5, "operation": ["> ", 5] } }))
+        .await;
+    alice.recv().await?;
+    bob.recv().await?;
+    bob.send(&json!("Redo")).await;
+    expect_text(&filter, "undo", "> hello").await;
+
+    // Other connections have nothing to undo, so nothing happens.
+    let mut carol = connect(&filter, "undo").await?;
+    assert_eq!(carol.recv().await?, json!({ "Identity": 2 }));
+    carol.recv().await?; // History
+    carol.send(&json!("Undo")).await;
+    expect_text(&filter, "undo", "> hello").await;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_undo_disabled() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig::default());
+
+    let mut client = connect(&filter, "undo").await?;
+    assert_eq!(client.recv().await?, json!({ "Identity": 0 }));
+    client
+        .send(&json!({ "Edit": { "revision": 0, "operation": ["hello"] } }))
+        .await;
+    client.recv().await?;
+    client.send(&json!("Undo")).await;
+    expect_text(&filter, "undo", "hello").await;
+
+    Ok(())
+}
@@ -233,3 +233,60 @@ async fn test_unicode_cursors() -> Result<()> {
 
     Ok(())
 }
+
+#[tokio::test]
+async fn test_normalize_unicode() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig {
+        normalize_unicode: true,
+        ..ServerConfig::default()
+    });
+
+    let mut client = connect(&filter, "nfc").await?;
+    assert_eq!(client.recv().await?, json!({ "Identity": 0 }));
+
+    // "cafe" followed by a combining acute accent (NFD).
+    let mut operation = OperationSeq::default();
+    operation.insert("cafe\u{301}");
+    client
+        .send(&json!({ "Edit": { "revision": 0, "operation": operation } }))
+        .await;
+
+    // The edit is followed by a server operation converting it to NFC.
+    let mut normalization = OperationSeq::default();
+    normalization.delete(5);
+    normalization.insert("caf\u{e9}");
+    assert_eq!(
+        client.recv().await?,
+        json!({
+            "History": {
+                "start": 0,
+                "operations": [
+                    { "id": 0, "operation": operation },
+                    { "id": u64::MAX, "operation": normalization }
+                ]
+            }
+        })
+    );
+    expect_text(&filter, "nfc", "caf\u{e9}").await;
+
+    // Text that is already NFC passes through untouched.
+    let mut operation = OperationSeq::default();
+    operation.retain(4);
+    operation.insert(" \u{e9}t\u{e9}");
+    client
+        .send(&json!({ "Edit": { "revision": 2, "operation": operation } }))
+        .await;
+    assert_eq!(
+        client.recv().await?,
+        json!({
+            "History": {
+                "start": 2,
+                "operations": [{ "id": 0, "operation": operation }]
+            }
+        })
+    );
+    expect_text(&filter, "nfc", "caf\u{e9} \u{e9}t\u{e9}").await;
+
+    Ok(())
+}
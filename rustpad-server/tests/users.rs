@@ -1,9 +1,12 @@
 //! Tests for synchronization of user presence.
 
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
 use anyhow::Result;
 use common::*;
-use rustpad_server::{server, ServerConfig};
-use serde_json::json;
+use rustpad_server::{names::AnonymousNames, server, ServerConfig};
+use serde_json::{json, Value};
 
 pub mod common;
 
@@ -180,3 +183,209 @@ async fn test_cursors() -> Result<()> {
 
     Ok(())
 }
+
+#[tokio::test]
+async fn test_anonymous_names() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig {
+        anonymous_names: Some(Arc::new(AnonymousNames::from_lines("Otter\n")?)),
+        ..ServerConfig::default()
+    });
+
+    let mut client = connect(&filter, "names").await?;
+    assert_eq!(client.recv().await?, json!({ "Identity": 0 }));
+    let msg = client.recv().await?;
+    assert_eq!(msg["UserInfo"]["id"], json!(0));
+    assert_eq!(msg["UserInfo"]["info"]["name"], json!("Anonymous Otter"));
+
+    // Names stay unique within the document once the word list runs out.
+    let mut client2 = connect(&filter, "names").await?;
+    assert_eq!(client2.recv().await?, json!({ "Identity": 1 }));
+    assert_eq!(client2.recv().await?["UserInfo"]["id"], json!(0));
+    let msg = client2.recv().await?;
+    assert_eq!(msg["UserInfo"]["id"], json!(1));
+    assert_eq!(msg["UserInfo"]["info"]["name"], json!("Anonymous Otter 2"));
+    assert_eq!(client.recv().await?, msg);
+
+    // A client without a display name keeps the one it was given.
+    client2
+        .send(&json!({ "ClientInfo": { "name": "", "hue": 7 } }))
+        .await;
+    let info = json!({
+        "UserInfo": {
+            "id": 1,
+            "info": { "name": "Anonymous Otter 2", "hue": 7 }
+        }
+    });
+    assert_eq!(client.recv().await?, info);
+
+    client2
+        .send(&json!({ "ClientInfo": { "name": "Bob", "hue": 7 } }))
+        .await;
+    assert_eq!(client.recv().await?["UserInfo"]["info"]["name"], json!("Bob"));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_participants() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig {
+        anonymous_names: Some(Arc::new(AnonymousNames::from_lines("Otter\n")?)),
+        ..ServerConfig::default()
+    });
+    let participants = |id: &'static str| {
+        warp::test::request()
+            .path(&format!("/api/documents/{}/participants", id))
+            .header("X-Document-Password", "hunter2")
+            .reply(&filter)
+    };
+    let names = |body: &Value| -> Vec<String> {
+        body["participants"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|participant| participant["name"].as_str().unwrap().to_string())
+            .collect()
+    };
+
+    // Nobody is connected to a document that isn't loaded.
+    let resp = participants("room").await;
+    assert_eq!(resp.status(), 200);
+    let body: Value = serde_json::from_slice(resp.body())?;
+    assert_eq!(body, json!({ "participants": [], "connections": 0 }));
+
+    let mut alice = connect(&filter, "room").await?;
+    assert_eq!(alice.recv().await?, json!({ "Identity": 0 }));
+    alice.recv().await?; // Own anonymous name
+    let mut bob = connect(&filter, "room").await?;
+    assert_eq!(bob.recv().await?, json!({ "Identity": 1 }));
+    bob.recv().await?; // Alice's name
+    bob.recv().await?; // Own anonymous name
+    alice.recv().await?;
+    let mut carol = connect(&filter, "room").await?;
+    assert_eq!(carol.recv().await?, json!({ "Identity": 2 }));
+    alice.recv().await?;
+
+    alice
+        .send(&json!({ "ClientInfo": { "name": "Alice", "hue": 42 } }))
+        .await;
+    alice.recv().await?;
+    let body: Value = serde_json::from_slice(participants("room").await.body())?;
+    assert_eq!(body["connections"], 3);
+    assert_eq!(
+        names(&body),
+        ["Alice", "Anonymous Otter 2", "Anonymous Otter 3"].map(String::from)
+    );
+    assert_eq!(body["participants"][0], json!({ "id": 0, "name": "Alice", "hue": 42 }));
+
+    // Participants leave the list when they disconnect.
+    drop(bob);
+    assert_eq!(alice.recv().await?["UserInfo"], json!({ "id": 1, "info": null }));
+    let body: Value = serde_json::from_slice(participants("room").await.body())?;
+    assert_eq!(names(&body), ["Alice", "Anonymous Otter 3"].map(String::from));
+
+    // Callers need the same access as to read the document.
+    let resp = warp::test::request()
+        .method("PUT")
+        .path("/api/documents/room/password")
+        .json(&json!({ "password": "hunter2" }))
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+    let resp = warp::test::request()
+        .path("/api/documents/room/participants")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 401);
+    assert_eq!(participants("room").await.status(), 200);
+    drop(carol);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_resume_cursor() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig {
+        cursor_grace: Some(Duration::from_secs(30)),
+        ..ServerConfig::default()
+    });
+    let path = "doc?client=0123456789abcdef";
+
+    let mut alice = connect(&filter, path).await?;
+    assert_eq!(alice.recv().await?, json!({ "Identity": 0 }));
+    let info = json!({ "name": "Alice", "hue": 42 });
+    alice.send(&json!({ "ClientInfo": info })).await;
+    alice.recv().await?;
+    let cursors = json!({ "cursors": [4], "selections": [[1, 3]] });
+    alice.send(&json!({ "CursorData": cursors })).await;
+    alice.recv().await?;
+
+    let mut bob = connect(&filter, "doc").await?;
+    assert_eq!(bob.recv().await?, json!({ "Identity": 1 }));
+    assert_eq!(bob.recv().await?, json!({ "UserInfo": { "id": 0, "info": info } }));
+    assert_eq!(bob.recv().await?, json!({ "UserCursor": { "id": 0, "data": cursors } }));
+
+    // Alice's connection drops, but her name and cursor stay for now.
+    alice.send(&json!({ "Invalid": "please close" })).await;
+    alice.recv_closed().await?;
+    loop {
+        let resp = warp::test::request()
+            .path("/api/documents/doc/participants")
+            .reply(&filter)
+            .await;
+        let body: Value = serde_json::from_slice(resp.body())?;
+        if body["participants"] == json!([]) {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+
+    // The kept cursor follows edits made in the meantime.
+    bob.send(&json!({ "Edit": { "revision": 0, "operation": ["hello"] } }))
+        .await;
+    bob.recv().await?;
+
+    // Reconnecting with the same client id resumes them under a new id.
+    let mut alice = connect(&filter, path).await?;
+    assert_eq!(alice.recv().await?, json!({ "Identity": 2 }));
+    alice.recv().await?; // History
+    let moved = json!({ "cursors": [9], "selections": [[6, 8]] });
+    assert_eq!(alice.recv().await?, json!({ "UserInfo": { "id": 2, "info": info } }));
+    assert_eq!(alice.recv().await?, json!({ "UserCursor": { "id": 2, "data": moved } }));
+    assert_eq!(bob.recv().await?, json!({ "UserInfo": { "id": 0, "info": null } }));
+    assert_eq!(bob.recv().await?, json!({ "UserInfo": { "id": 2, "info": info } }));
+    assert_eq!(bob.recv().await?, json!({ "UserCursor": { "id": 2, "data": moved } }));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_cursor_grace_expiry() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let grace = Duration::from_millis(200);
+    let filter = server(ServerConfig {
+        cursor_grace: Some(grace),
+        ..ServerConfig::default()
+    });
+
+    let mut alice = connect(&filter, "doc?client=0123456789abcdef").await?;
+    assert_eq!(alice.recv().await?, json!({ "Identity": 0 }));
+    alice
+        .send(&json!({ "ClientInfo": { "name": "Alice", "hue": 42 } }))
+        .await;
+    alice.recv().await?;
+    let mut bob = connect(&filter, "doc").await?;
+    assert_eq!(bob.recv().await?, json!({ "Identity": 1 }));
+    bob.recv().await?; // Alice's info
+
+    // Without a reconnect, others see her leave once the grace period ends.
+    let start = Instant::now();
+    alice.send(&json!({ "Invalid": "please close" })).await;
+    alice.recv_closed().await?;
+    assert_eq!(bob.recv().await?, json!({ "UserInfo": { "id": 0, "info": null } }));
+    assert!(start.elapsed() >= grace);
+
+    Ok(())
+}
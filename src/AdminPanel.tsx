@@ -39,8 +39,11 @@ import {
 import { useState, useEffect, useRef } from "react";
 import { VscTrash, VscRefresh, VscKey, VscEye, VscEyeClosed, VscCheck } from "react-icons/vsc";
 
+import apiError from "./apiError";
+
 type User = {
   username: string;
+  display_name: string;
   created_at: string;
   ai_enabled: boolean;
   is_admin: boolean;
@@ -69,6 +72,7 @@ function AdminPanel({
 }: AdminPanelProps) {
   const toast = useToast();
   const [users, setUsers] = useState<User[]>([]);
+  const [total, setTotal] = useState(0);
   const [isLoading, setIsLoading] = useState(false);
   const [deleteUser, setDeleteUser] = useState<string | null>(null);
   const { isOpen: isDeleteOpen, onOpen: onDeleteOpen, onClose: onDeleteClose } = useDisclosure();
@@ -80,6 +84,7 @@ function AdminPanel({
   const [newApiKey, setNewApiKey] = useState("");
   const [showApiKey, setShowApiKey] = useState(false);
   const [isSavingApiKey, setIsSavingApiKey] = useState(false);
+  const [isTestingAi, setIsTestingAi] = useState(false);
 
   // Load users and settings when panel opens
   useEffect(() => {
@@ -89,25 +94,27 @@ function AdminPanel({
     }
   }, [isOpen]);
 
-  async function loadUsers() {
+  /** Load a page of users, replacing the list or adding to its end. */
+  async function loadUsers(offset = 0) {
     if (!username || !password) return;
 
     setIsLoading(true);
     try {
       const authHeader = btoa(`${username}:${password}`);
-      const response = await fetch("/api/admin/users", {
+      const response = await fetch(`/api/admin/users?offset=${offset}`, {
         headers: {
           Authorization: `Basic ${authHeader}`,
         },
       });
 
       if (!response.ok) {
-        const error = await response.text();
+        const error = await apiError(response);
         throw new Error(error || "Failed to load users");
       }
 
-      const data = await response.json();
-      setUsers(data);
+      const page: { users: User[]; total: number } = await response.json();
+      setUsers((users) => (offset === 0 ? page.users : [...users, ...page.users]));
+      setTotal(page.total);
     } catch (error) {
       toast({
         title: "Failed to load users",
@@ -133,7 +140,7 @@ function AdminPanel({
       });
 
       if (!response.ok) {
-        const error = await response.text();
+        const error = await apiError(response);
         throw new Error(error || "Failed to load settings");
       }
 
@@ -160,7 +167,7 @@ function AdminPanel({
       });
 
       if (!response.ok) {
-        const error = await response.text();
+        const error = await apiError(response);
         throw new Error(error || "Failed to update API key");
       }
 
@@ -187,6 +194,45 @@ function AdminPanel({
     }
   }
 
+  async function testAiConnection() {
+    if (!username || !password) return;
+
+    setIsTestingAi(true);
+    try {
+      const authHeader = btoa(`${username}:${password}`);
+      const response = await fetch("/api/admin/ai/test", {
+        method: "POST",
+        headers: {
+          Authorization: `Basic ${authHeader}`,
+        },
+      });
+
+      if (!response.ok) {
+        const error = await apiError(response);
+        throw new Error(error || "Failed to test AI connection");
+      }
+
+      const data = await response.json();
+      toast({
+        title: data.status === "ok" ? "AI connection works" : "AI connection failed",
+        description: `${data.message} (${data.latency_ms} ms)`,
+        status: data.status === "ok" ? "success" : "error",
+        duration: 6000,
+        isClosable: true,
+      });
+    } catch (error) {
+      toast({
+        title: "Failed to test AI connection",
+        description: error instanceof Error ? error.message : "Unknown error",
+        status: "error",
+        duration: 4000,
+        isClosable: true,
+      });
+    } finally {
+      setIsTestingAi(false);
+    }
+  }
+
   async function toggleAiAccess(targetUsername: string, currentStatus: boolean) {
     if (!username || !password) return;
 
@@ -202,7 +248,7 @@ function AdminPanel({
       });
 
       if (!response.ok) {
-        const error = await response.text();
+        const error = await apiError(response);
         throw new Error(error || "Failed to update AI access");
       }
 
@@ -262,12 +308,13 @@ function AdminPanel({
       });
 
       if (!response.ok) {
-        const error = await response.text();
+        const error = await apiError(response);
         throw new Error(error || "Failed to delete user");
       }
 
       // Remove from local state
       setUsers(users.filter(u => u.username !== deleteUser));
+      setTotal((total) => total - 1);
 
       toast({
         title: "User deleted",
@@ -290,7 +337,7 @@ function AdminPanel({
     }
   }
 
-  const totalUsers = users.length;
+  const totalUsers = total;
   const aiEnabledUsers = users.filter(u => u.ai_enabled).length;
   const adminUsers = users.filter(u => u.is_admin).length;
 
@@ -310,7 +357,7 @@ function AdminPanel({
                 icon={isLoading ? <Spinner size="sm" /> : <VscRefresh />}
                 aria-label="Refresh users"
                 size="sm"
-                onClick={loadUsers}
+                onClick={() => loadUsers()}
                 isDisabled={isLoading}
                 variant="ghost"
                 color={darkMode ? "#cbcaca" : "inherit"}
@@ -399,6 +446,17 @@ function AdminPanel({
                     >
                       Save API Key
                     </Button>
+
+                    <Button
+                      size="sm"
+                      variant="outline"
+                      leftIcon={isTestingAi ? <Spinner size="xs" /> : undefined}
+                      onClick={testAiConnection}
+                      isDisabled={!settings?.api_key_configured || isTestingAi}
+                      width="fit-content"
+                    >
+                      Test Connection
+                    </Button>
                   </VStack>
                 </Box>
               </Collapse>
@@ -477,7 +535,7 @@ function AdminPanel({
                       <Tr key={user.username}>
                         <Td color={darkMode ? "#cbcaca" : "inherit"}>
                           <Text fontWeight={user.username === username ? "bold" : "normal"}>
-                            {user.username}
+                            {user.display_name}
                             {user.username === username && " (You)"}
                           </Text>
                         </Td>
@@ -523,6 +581,17 @@ function AdminPanel({
                 </Table>
               </Box>
             )}
+
+            {!isLoading && users.length < total && (
+              <Button
+                size="sm"
+                variant="ghost"
+                mt={2}
+                onClick={() => loadUsers(users.length)}
+              >
+                Load more
+              </Button>
+            )}
           </ModalBody>
         </ModalContent>
       </Modal>
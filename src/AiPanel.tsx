@@ -21,6 +21,8 @@ import {
 import { useState, useEffect, useRef } from "react";
 import { VscSend, VscTrash } from "react-icons/vsc";
 
+import apiError from "./apiError";
+
 type Message = {
   role: "user" | "assistant" | "system";
   content: string;
@@ -157,7 +159,7 @@ Remember: NO markdown formatting, NO explanations, JUST the code/content!`,
       });
 
       if (!response.ok) {
-        const error = await response.text();
+        const error = await apiError(response);
         throw new Error(error || "AI request failed");
       }
 
@@ -1,10 +1,11 @@
 import { Box, Flex, HStack, Icon, Text, useToast } from "@chakra-ui/react";
-import Editor from "@monaco-editor/react";
+import Editor, { Monaco } from "@monaco-editor/react";
 import { editor } from "monaco-editor/esm/vs/editor/editor.api";
 import { useEffect, useRef, useState } from "react";
 import { VscChevronRight, VscFolderOpened, VscGist } from "react-icons/vsc";
 import useLocalStorageState from "use-local-storage-state";
 
+import apiError from "./apiError";
 import AdminPanel from "./AdminPanel";
 import AiPanel from "./AiPanel";
 import FileBrowserModal from "./FileBrowserModal";
@@ -19,9 +20,24 @@ import useHash from "./useHash";
 function getWsUri(id: string) {
   let url = new URL(`api/socket/${id}`, window.location.href);
   url.protocol = url.protocol == "https:" ? "wss:" : "ws:";
+  const session = localStorage.getItem("rustpad_session_id");
+  if (session) {
+    url.searchParams.set("session", session);
+  }
+  // Read-only share links carry their token in the page's query string.
+  const share = new URLSearchParams(window.location.search).get("share");
+  if (share) {
+    url.searchParams.set("share", share);
+  }
   return url.href;
 }
 
+/** Public configuration of this deployment, from `GET /api/config`. */
+type PublicConfig = {
+  instance_name: string;
+  theme_color: string | null;
+};
+
 function generateName() {
   return "Anonymous " + animals[Math.floor(Math.random() * animals.length)];
 }
@@ -44,11 +60,26 @@ function App() {
     defaultValue: generateHue,
   });
   const [editor, setEditor] = useState<editor.IStandaloneCodeEditor>();
+  const [monaco, setMonaco] = useState<Monaco>();
+  const [saved, setSaved] = useState<boolean>();
   const [darkMode, setDarkMode] = useLocalStorageState("darkMode", {
     defaultValue: false,
   });
   const rustpad = useRef<Rustpad>();
   const id = useHash();
+  const [config, setConfig] = useState<PublicConfig>();
+
+  useEffect(() => {
+    fetch("/api/config")
+      .then((response) => (response.ok ? response.json() : undefined))
+      .then((config?: PublicConfig) => {
+        if (config) {
+          setConfig(config);
+          document.title = config.instance_name;
+        }
+      })
+      .catch(() => {});
+  }, []);
 
   const [fileBrowserOpen, setFileBrowserOpen] = useState(false);
   const [loginModalOpen, setLoginModalOpen] = useState(false);
@@ -72,6 +103,7 @@ function App() {
       const model = editor.getModel()!;
       model.setValue("");
       model.setEOL(0); // LF
+      setSaved(undefined);
       rustpad.current = new Rustpad({
         uri: getWsUri(id),
         editor,
@@ -92,13 +124,61 @@ function App() {
           }
         },
         onChangeUsers: setUsers,
+        onSaveStateChange: setSaved,
+        onExpiring: (seconds) =>
+          toast({
+            title: "Document expiring soon",
+            description: `It will be removed from memory in about ${Math.ceil(
+              seconds / 60,
+            )} minutes. Save or freeze it to keep your work.`,
+            status: "warning",
+            duration: null,
+            isClosable: true,
+          }),
+        onDraining: (seconds) =>
+          toast({
+            title: "Document reloading",
+            description: `The server is unloading this document, and will reload it in ${seconds} seconds. Edits made after that may be lost.`,
+            status: "info",
+            duration: seconds * 1000,
+            isClosable: true,
+          }),
+        onRejected: (reason) =>
+          toast({
+            title: "Change not saved",
+            description: `The server refused your edit because ${reason}.`,
+            status: "warning",
+            duration: 5000,
+            isClosable: true,
+          }),
+        onDiagnostics: (diagnostics) => {
+          monaco?.editor.setModelMarkers(
+            model,
+            "rustpad-lint",
+            diagnostics.map(({ line, column, message }) => ({
+              startLineNumber: line,
+              startColumn: column,
+              endLineNumber: line,
+              endColumn: column + 1,
+              message,
+              severity: monaco.MarkerSeverity.Warning,
+            })),
+          );
+        },
       });
       return () => {
         rustpad.current?.dispose();
         rustpad.current = undefined;
       };
     }
-  }, [id, editor, toast, setUsers]);
+  }, [id, editor, monaco, toast, setUsers]);
+
+  useEffect(() => {
+    // Ask the server to persist the document right away when the tab closes.
+    const handler = () => navigator.sendBeacon(`/api/documents/${id}/snapshot`);
+    window.addEventListener("pagehide", handler);
+    return () => window.removeEventListener("pagehide", handler);
+  }, [id]);
 
   useEffect(() => {
     if (connection === "connected") {
@@ -152,7 +232,7 @@ function App() {
       });
 
       if (!response.ok) {
-        const error = await response.text();
+        const error = await apiError(response);
         throw new Error(error || "Failed to freeze document");
       }
 
@@ -280,18 +360,19 @@ function App() {
     >
       <Box
         flexShrink={0}
-        bgColor={darkMode ? "#333333" : "#e8e8e8"}
+        bgColor={config?.theme_color ?? (darkMode ? "#333333" : "#e8e8e8")}
         color={darkMode ? "#cccccc" : "#383838"}
         textAlign="center"
         fontSize="sm"
         py={0.5}
       >
-        Rustpad
+        {config?.instance_name ?? "Rustpad"}
       </Box>
       <Flex flex="1 0" minH={0}>
         <Sidebar
           documentId={id}
           connection={connection}
+          saved={saved}
           darkMode={darkMode}
           language={language}
           currentUser={{ name, hue }}
@@ -331,7 +412,10 @@ function App() {
                 automaticLayout: true,
                 fontSize: 13,
               }}
-              onMount={(editor) => setEditor(editor)}
+              onMount={(editor, monaco) => {
+                setEditor(editor);
+                setMonaco(monaco);
+              }}
             />
           </Box>
         </Flex>
@@ -4,9 +4,15 @@ import { VscCircleFilled } from "react-icons/vsc";
 type ConnectionStatusProps = {
   connection: "connected" | "disconnected" | "desynchronized";
   darkMode: boolean;
+  /** Whether all edits are persisted, if the server reports it. */
+  saved?: boolean;
 };
 
-function ConnectionStatus({ connection, darkMode }: ConnectionStatusProps) {
+function ConnectionStatus({
+  connection,
+  darkMode,
+  saved,
+}: ConnectionStatusProps) {
   return (
     <HStack spacing={1}>
       <Icon
@@ -31,6 +37,9 @@ function ConnectionStatus({ connection, darkMode }: ConnectionStatusProps) {
             desynchronized: "Disconnected, please refresh.",
           }[connection]
         }
+        {connection === "connected" &&
+          saved !== undefined &&
+          (saved ? " All changes saved." : " Saving...")}
       </Text>
     </HStack>
   );
@@ -26,7 +26,7 @@ import {
   AlertDialogOverlay,
 } from "@chakra-ui/react";
 import { useState, useEffect, useRef } from "react";
-import { VscFile, VscHistory, VscTrash } from "react-icons/vsc";
+import { VscFile, VscHistory, VscRefresh, VscTrash } from "react-icons/vsc";
 
 type FrozenDocument = {
   document_id: string;
@@ -57,6 +57,7 @@ function FileBrowserModal({
 }: FileBrowserModalProps) {
   const toast = useToast();
   const [documents, setDocuments] = useState<FrozenDocument[]>([]);
+  const [total, setTotal] = useState(0);
   const [isLoading, setIsLoading] = useState(false);
   const [documentToDelete, setDocumentToDelete] = useState<string | null>(null);
   const { isOpen: isDeleteOpen, onOpen: onDeleteOpen, onClose: onDeleteClose } = useDisclosure();
@@ -72,13 +73,14 @@ function FileBrowserModal({
     }
   }, [isOpen, username, password]);
 
-  async function loadDocuments() {
+  /** Load a page of documents, replacing the list or adding to its end. */
+  async function loadDocuments(offset = 0) {
     if (!username || !password) return;
 
     setIsLoading(true);
     try {
       const authHeader = btoa(`${username}:${password}`);
-      const response = await fetch("/api/documents/list", {
+      const response = await fetch(`/api/documents/list?offset=${offset}`, {
         headers: {
           Authorization: `Basic ${authHeader}`,
         },
@@ -88,8 +90,12 @@ function FileBrowserModal({
         throw new Error("Failed to load documents");
       }
 
-      const docs: FrozenDocument[] = await response.json();
-      setDocuments(docs);
+      const page: { documents: FrozenDocument[]; total: number } =
+        await response.json();
+      setDocuments((docs) =>
+        offset === 0 ? page.documents : [...docs, ...page.documents],
+      );
+      setTotal(page.total);
     } catch (error) {
       toast({
         title: "Failed to load documents",
@@ -136,6 +142,42 @@ function FileBrowserModal({
     onDeleteOpen();
   }
 
+  async function handleExtend(docId: string, event: React.MouseEvent) {
+    event.stopPropagation(); // Prevent opening the document
+    if (!username || !password) return;
+
+    try {
+      const authHeader = btoa(`${username}:${password}`);
+      const response = await fetch(`/api/documents/${docId}/extend`, {
+        method: "POST",
+        headers: {
+          Authorization: `Basic ${authHeader}`,
+          "Content-Type": "application/json",
+        },
+        body: JSON.stringify({ days: 30 }),
+      });
+
+      if (!response.ok) {
+        throw new Error("This document cannot be kept any longer");
+      }
+
+      const { expires_at } = await response.json();
+      setDocuments(
+        documents.map((d) =>
+          d.document_id === docId ? { ...d, expires_at } : d,
+        ),
+      );
+    } catch (error) {
+      toast({
+        title: "Extend failed",
+        description: error instanceof Error ? error.message : "Unknown error",
+        status: "error",
+        duration: 4000,
+        isClosable: true,
+      });
+    }
+  }
+
   async function handleConfirmDelete() {
     if (!documentToDelete || !username || !password) return;
 
@@ -154,6 +196,7 @@ function FileBrowserModal({
 
       // Remove from local state
       setDocuments(documents.filter(d => d.document_id !== documentToDelete));
+      setTotal((total) => total - 1);
 
       toast({
         title: "Document deleted",
@@ -188,8 +231,7 @@ function FileBrowserModal({
           <VStack spacing={3} align="stretch">
             <HStack justifyContent="space-between">
               <Text fontSize="sm" color={darkMode ? "#888" : "gray.600"}>
-                {documents.length} document{documents.length !== 1 ? "s" : ""}{" "}
-                found
+                {total} document{total !== 1 ? "s" : ""} found
               </Text>
               <Text fontSize="xs" color={darkMode ? "#888" : "gray.600"}>
                 {username}
@@ -251,6 +293,14 @@ function FileBrowserModal({
                           >
                             {daysLeft}d left
                           </Badge>
+                          <IconButton
+                            aria-label="Keep for 30 more days"
+                            title="Keep for 30 more days"
+                            icon={<VscRefresh />}
+                            size="xs"
+                            variant="ghost"
+                            onClick={(e) => handleExtend(doc.document_id, e)}
+                          />
                           <IconButton
                             aria-label="Delete document"
                             icon={<VscTrash />}
@@ -279,6 +329,16 @@ function FileBrowserModal({
                   );
               })
             )}
+
+            {!isLoading && documents.length < total && (
+              <Button
+                size="sm"
+                variant="ghost"
+                onClick={() => loadDocuments(documents.length)}
+              >
+                Load more
+              </Button>
+            )}
           </VStack>
         </ModalBody>
 
@@ -23,6 +23,8 @@ import {
 } from "@chakra-ui/react";
 import { useState } from "react";
 
+import apiError from "./apiError";
+
 type LoginModalProps = {
   isOpen: boolean;
   onClose: () => void;
@@ -70,23 +72,26 @@ function LoginModal({
       });
 
       if (!response.ok) {
-        const error = await response.text();
+        const error = await apiError(response);
         throw new Error(error || "Login failed");
       }
 
       const data = await response.json();
       localStorage.setItem("rustpad_ai_enabled", String(data.ai_enabled));
       localStorage.setItem("rustpad_is_admin", String(data.is_admin));
+      if (data.session_id) {
+        localStorage.setItem("rustpad_session_id", data.session_id);
+      }
 
       toast({
         title: "Login successful",
-        description: `Welcome back, ${username}!`,
+        description: `Welcome back, ${data.display_name}!`,
         status: "success",
         duration: 3000,
         isClosable: true,
       });
 
-      onSuccess(username, password);
+      onSuccess(data.username, password);
       onClose();
       resetForm();
     } catch (error) {
@@ -118,7 +123,7 @@ function LoginModal({
       });
 
       if (!response.ok) {
-        const error = await response.text();
+        const error = await apiError(response);
         throw new Error(error || "Registration failed");
       }
 
@@ -128,13 +133,13 @@ function LoginModal({
 
       toast({
         title: "Registration successful",
-        description: `Welcome, ${username}! You can now freeze documents${data.ai_enabled ? " and use AI features" : ""}.`,
+        description: `Welcome, ${data.display_name}! You can now freeze documents${data.ai_enabled ? " and use AI features" : ""}.`,
         status: "success",
         duration: 4000,
         isClosable: true,
       });
 
-      onSuccess(username, password);
+      onSuccess(data.username, password);
       onClose();
       resetForm();
     } catch (error) {
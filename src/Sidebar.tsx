@@ -23,6 +23,7 @@ import type { UserInfo } from "./rustpad";
 export type SidebarProps = {
   documentId: string;
   connection: "connected" | "disconnected" | "desynchronized";
+  saved?: boolean;
   darkMode: boolean;
   language: string;
   currentUser: UserInfo;
@@ -41,6 +42,7 @@ export type SidebarProps = {
 function Sidebar({
   documentId,
   connection,
+  saved,
   darkMode,
   language,
   currentUser,
@@ -81,7 +83,11 @@ function Sidebar({
       lineHeight={1.4}
       py={4}
     >
-      <ConnectionStatus darkMode={darkMode} connection={connection} />
+      <ConnectionStatus
+        darkMode={darkMode}
+        connection={connection}
+        saved={saved}
+      />
 
       <Flex justifyContent="space-between" mt={4} mb={1.5} w="full">
         <Heading size="sm">Dark Mode</Heading>
@@ -0,0 +1,15 @@
+/** Read the message from a failed API response, sent as JSON or plain text. */
+async function apiError(response: Response): Promise<string> {
+  const text = await response.text();
+  try {
+    const body = JSON.parse(text);
+    if (typeof body?.error === "string") {
+      return body.error;
+    }
+  } catch {
+    // Not JSON, so the text is the message.
+  }
+  return text;
+}
+
+export default apiError;
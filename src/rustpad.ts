@@ -15,6 +15,11 @@ export type RustpadOptions = {
   readonly onDesynchronized?: () => void;
   readonly onChangeLanguage?: (language: string) => void;
   readonly onChangeUsers?: (users: Record<number, UserInfo>) => void;
+  readonly onDiagnostics?: (diagnostics: Diagnostic[]) => void;
+  readonly onSaveStateChange?: (saved: boolean) => void;
+  readonly onExpiring?: (seconds: number) => void;
+  readonly onDraining?: (seconds: number) => void;
+  readonly onRejected?: (reason: string) => void;
   readonly reconnectInterval?: number;
 };
 
@@ -24,11 +29,21 @@ export type UserInfo = {
   readonly hue: number;
 };
 
+/** A problem reported by the server's linter. */
+export type Diagnostic = {
+  readonly line: number;
+  readonly column: number;
+  readonly message: string;
+};
+
 /** Browser client for Rustpad. */
 class Rustpad {
   private ws?: WebSocket;
   private connecting?: boolean;
   private recentFailures: number = 0;
+  private retryAfter: number = 0;
+  private reloading: boolean = false;
+  private received: Promise<void> = Promise.resolve();
   private readonly model: editor.ITextModel;
   private readonly onChangeHandle: IDisposable;
   private readonly onCursorHandle: IDisposable;
@@ -36,10 +51,16 @@ class Rustpad {
   private readonly beforeUnload: (event: BeforeUnloadEvent) => void;
   private readonly tryConnectId: number;
   private readonly resetFailuresId: number;
+  /** Random id sent on every connection, so reconnects resume our cursor. */
+  private readonly clientId: string = Array.from(
+    crypto.getRandomValues(new Uint8Array(16)),
+    (byte) => byte.toString(16).padStart(2, "0"),
+  ).join("");
 
   // Client-server state
   private me: number = -1;
   private revision: number = 0;
+  private persisted?: number;
   private outstanding?: OpSeq;
   private buffer?: OpSeq;
   private users: Record<number, UserInfo> = {};
@@ -121,8 +142,11 @@ class Rustpad {
    */
   private tryConnect() {
     if (this.connecting || this.ws) return;
+    if (Date.now() < this.retryAfter) return;
     this.connecting = true;
-    const ws = new WebSocket(this.options.uri);
+    const uri = new URL(this.options.uri);
+    uri.searchParams.set("client", this.clientId);
+    const ws = new WebSocket(uri.toString());
     ws.onopen = () => {
       this.connecting = false;
       this.ws = ws;
@@ -131,15 +155,32 @@ class Rustpad {
       this.options.onChangeUsers?.(this.users);
       this.sendInfo();
       this.sendCursorData();
+      if (this.reloading) {
+        this.reload();
+      }
       if (this.outstanding) {
         this.sendOperation(this.outstanding);
       }
     };
-    ws.onclose = () => {
+    ws.onclose = (event) => {
+      const reason = this.handleCloseReason(event.reason);
+      if (reason === "evicted") {
+        // Another connection by the same user took our place; reconnecting
+        // would only evict that one in turn.
+        this.ws = undefined;
+        this.dispose();
+        this.options.onDesynchronized?.();
+        return;
+      }
       if (this.ws) {
         this.ws = undefined;
         this.options.onDisconnected?.();
-        if (++this.recentFailures >= 5) {
+        if (reason === "drained" || reason === "rejected") {
+          // The server unloaded the document on purpose, after persisting it,
+          // or refused an edit we already applied, so the next connection
+          // starts over from the server's copy.
+          this.reloading = true;
+        } else if (++this.recentFailures >= 5) {
           // If we disconnect 5 times within 15 reconnection intervals, then the
           // client is likely desynchronized and needs to refresh.
           this.dispose();
@@ -151,24 +192,74 @@ class Rustpad {
     };
     ws.onmessage = ({ data }) => {
       if (typeof data === "string") {
-        this.handleMessage(JSON.parse(data));
+        // Decompressing large operations is asynchronous, so messages are
+        // queued to be handled in the order they arrived.
+        const msg: ServerMsg = JSON.parse(data);
+        this.received = this.received
+          .then(() => inflateOperations(msg))
+          .then((msg) => this.handleMessage(msg))
+          .catch((error) => {
+            console.warn("Failed to handle message:", error);
+            this.ws?.close();
+          });
       }
     };
   }
 
+  /**
+   * Honor the server's suggested reconnect delay, if one was given, and
+   * return the reason the server closed the connection.
+   */
+  private handleCloseReason(reason: string): string | undefined {
+    try {
+      const { reason: kind, retry_ms } = JSON.parse(reason);
+      if (typeof retry_ms === "number") {
+        this.retryAfter = Date.now() + retry_ms;
+      }
+      return kind;
+    } catch {
+      // Not a structured close reason.
+      return undefined;
+    }
+  }
+
+  /** Discard local state, so the server's history is applied from scratch. */
+  private reload() {
+    this.reloading = false;
+    this.revision = 0;
+    this.persisted = undefined;
+    this.outstanding = undefined;
+    this.buffer = undefined;
+    this.ignoreChanges = true;
+    this.model.setValue("");
+    this.lastValue = "";
+    this.ignoreChanges = false;
+  }
+
   private handleMessage(msg: ServerMsg) {
-    if (msg.Identity !== undefined) {
+    if (msg === "ReadOnly") {
+      this.options.editor.updateOptions({ readOnly: true });
+    } else if (msg.Identity !== undefined) {
       this.me = msg.Identity;
+      // Cleared on every connection, since access may have changed.
+      this.options.editor.updateOptions({ readOnly: false });
     } else if (msg.History !== undefined) {
       const { start, operations } = msg.History;
       if (start > this.revision) {
-        console.warn("History message has start greater than last operation.");
-        this.ws?.close();
-        return;
+        if (this.revision === 0 && !this.outstanding) {
+          // A fresh client joining a document whose history was compacted.
+          this.revision = start;
+        } else {
+          console.warn("History message has start greater than last operation.");
+          this.ws?.close();
+          return;
+        }
       }
-      for (let i = this.revision - start; i < operations.length; i++) {
-        let { id, operation } = operations[i];
-        this.revision++;
+      let revision = start;
+      for (let { id, operation, revisions = 1 } of operations) {
+        revision += revisions;
+        if (revision <= this.revision) continue;
+        this.revision = revision;
         if (id === this.me) {
           this.serverAck();
         } else {
@@ -176,6 +267,7 @@ class Rustpad {
           this.applyServer(operation);
         }
       }
+      this.updateSaveState();
     } else if (msg.Language !== undefined) {
       this.options.onChangeLanguage?.(msg.Language);
     } else if (msg.UserInfo !== undefined) {
@@ -197,9 +289,32 @@ class Rustpad {
         this.userCursors[id] = data;
         this.updateCursors();
       }
+    } else if (msg.Persisted !== undefined) {
+      this.persisted = msg.Persisted;
+      this.updateSaveState();
+    } else if (msg.Expiring !== undefined) {
+      this.options.onExpiring?.(msg.Expiring);
+    } else if (msg.Draining !== undefined) {
+      this.options.onDraining?.(msg.Draining);
+    } else if (msg.Rejected !== undefined) {
+      console.warn("Server rejected a change:", msg.Rejected);
+      this.options.onRejected?.(msg.Rejected);
+    } else if (msg.Diagnostics !== undefined) {
+      const { revision, diagnostics } = msg.Diagnostics;
+      // Positions are only meaningful for the exact text that was linted.
+      if (revision === this.revision && !this.outstanding) {
+        this.options.onDiagnostics?.(diagnostics);
+      }
     }
   }
 
+  /** Report whether all edits seen so far have been durably persisted. */
+  private updateSaveState() {
+    if (this.persisted === undefined) return; // Not reported by the server.
+    const saved = !this.outstanding && this.persisted >= this.revision;
+    this.options.onSaveStateChange?.(saved);
+  }
+
   private serverAck() {
     if (!this.outstanding) {
       console.warn("Received serverAck with no outstanding operation.");
@@ -410,6 +525,7 @@ class Rustpad {
       }
       this.applyClient(operation);
       this.lastValue = this.model.getValue();
+      this.updateSaveState();
     }
   }
 
@@ -429,7 +545,11 @@ class Rustpad {
 
 type UserOperation = {
   id: number;
-  operation: any;
+  operation?: any;
+  /** The operation's JSON, gzip-compressed and base64-encoded. */
+  compressed?: string;
+  /** Number of revisions composed into this operation, if more than one. */
+  revisions?: number;
 };
 
 type CursorData = {
@@ -437,22 +557,51 @@ type CursorData = {
   selections: [number, number][];
 };
 
-type ServerMsg = {
-  Identity?: number;
-  History?: {
-    start: number;
-    operations: UserOperation[];
-  };
-  Language?: string;
-  UserInfo?: {
-    id: number;
-    info: UserInfo | null;
-  };
-  UserCursor?: {
-    id: number;
-    data: CursorData;
-  };
-};
+type ServerMsg =
+  | "ReadOnly"
+  | {
+      Identity?: number;
+      History?: {
+        start: number;
+        operations: UserOperation[];
+      };
+      Language?: string;
+      UserInfo?: {
+        id: number;
+        info: UserInfo | null;
+      };
+      UserCursor?: {
+        id: number;
+        data: CursorData;
+      };
+      Diagnostics?: {
+        revision: number;
+        diagnostics: Diagnostic[];
+      };
+      Persisted?: number;
+      Expiring?: number;
+      Draining?: number;
+      Rejected?: string;
+    };
+
+/** Decodes operations that the server compressed because they were large. */
+async function inflateOperations(msg: ServerMsg): Promise<ServerMsg> {
+  if (msg === "ReadOnly" || msg.History === undefined) return msg;
+  const operations = await Promise.all(
+    msg.History.operations.map(
+      async ({ id, operation, compressed, revisions }) => {
+        if (compressed === undefined) return { id, operation, revisions };
+        const bytes = Uint8Array.from(atob(compressed), (c) => c.charCodeAt(0));
+        const stream = new Blob([bytes])
+          .stream()
+          .pipeThrough(new DecompressionStream("gzip"));
+        const text = await new Response(stream).text();
+        return { id, operation: JSON.parse(text), revisions };
+      },
+    ),
+  );
+  return { History: { ...msg.History, operations } };
+}
 
 /** Returns the number of Unicode codepoints in a string. */
 function unicodeLength(str: string): number {